@@ -0,0 +1,16 @@
+//! Fuzz the YAML config parser.
+//!
+//! Run with: `cargo +nightly fuzz run config_parse`
+//!
+//! Arbitrary input must only ever produce `Ok` or `Err` - never a panic.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use sidecar::config;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(text) = std::str::from_utf8(data) {
+        let _ = config::Config::from_yaml(text);
+    }
+});
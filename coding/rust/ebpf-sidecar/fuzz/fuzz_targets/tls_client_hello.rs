@@ -0,0 +1,17 @@
+//! Fuzz the TLS ClientHello parser and JA4 fingerprinting.
+//!
+//! Run with: `cargo +nightly fuzz run tls_client_hello`
+//!
+//! The input is whatever a process sent, copied by the kernel: it must only
+//! ever parse or be rejected - never panic.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use sidecar::tls_fingerprint;
+
+fuzz_target!(|data: &[u8]| {
+    if let Some(hello) = tls_fingerprint::ClientHello::parse(data) {
+        let _ = hello.ja4();
+    }
+});
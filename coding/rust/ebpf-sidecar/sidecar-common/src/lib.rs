@@ -10,26 +10,252 @@
 
 #![no_std]
 
+use core::mem::size_of;
+use core::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// Version of the shared struct layout.
+///
+/// Bump this whenever a field is added, removed, reordered or resized in any
+/// of the `#[repr(C)]` types below. Userspace refuses to run against an eBPF
+/// object built with a different value.
+pub const LAYOUT_VERSION: u32 = 40;
+
 /// Connection identifier - used as a key in the connections map.
 ///
 /// Uniquely identifies a TCP connection by its 4-tuple:
 /// source IP, destination IP, source port, destination port.
+///
+/// Addresses are 16 bytes whatever the family, as the kernel stores them
+/// (network byte order). An IPv4 address fills the first 4 bytes and leaves
+/// the rest zero; IPv6 sockets talking to v4-mapped addresses are keyed as
+/// IPv4. The explicit padding keeps the key free of uninitialized bytes, as
+/// the kernel hashes all of them.
 #[repr(C)]
 #[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
 pub struct ConnKey {
     /// Source IP address (network byte order)
-    pub src_ip: u32,
+    pub src_ip: [u8; 16],
     /// Destination IP address (network byte order)
-    pub dst_ip: u32,
+    pub dst_ip: [u8; 16],
     /// Source port (host byte order)
     pub src_port: u16,
     /// Destination port (host byte order)
     pub dst_port: u16,
+    /// Family of both addresses (see `address_family`)
+    pub address_family: u8,
+    /// Padding for alignment
+    pub _padding: [u8; 3],
 }
 
 #[cfg(feature = "user")]
 unsafe impl aya::Pod for ConnKey {}
 
+impl ConnKey {
+    /// An IPv4 connection's key, from addresses in network byte order read
+    /// as native `u32`s (the kernel's `__be32`).
+    ///
+    /// ```
+    /// use sidecar_common::ConnKey;
+    /// use std::net::{IpAddr, Ipv4Addr};
+    ///
+    /// let key = ConnKey::ipv4(
+    ///     u32::from_ne_bytes([10, 0, 0, 1]),
+    ///     u32::from_ne_bytes([10, 0, 0, 5]),
+    ///     51234,
+    ///     443,
+    /// );
+    /// assert_eq!(key.src_addr(), IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)));
+    /// assert_eq!(key.dst_ipv4(), Some(u32::from_ne_bytes([10, 0, 0, 5])));
+    /// ```
+    pub fn ipv4(src_ip: u32, dst_ip: u32, src_port: u16, dst_port: u16) -> Self {
+        let mut key = Self {
+            src_port,
+            dst_port,
+            address_family: address_family::INET,
+            ..Default::default()
+        };
+        key.src_ip[..4].copy_from_slice(&src_ip.to_ne_bytes());
+        key.dst_ip[..4].copy_from_slice(&dst_ip.to_ne_bytes());
+        key
+    }
+
+    /// Whether the local port is assigned. `tcp_connect` often runs before
+    /// it is, and a key with port 0 never matches the one send/recv see, so
+    /// the first send or receive creates the entry instead.
+    #[inline(always)]
+    pub fn is_bound(&self) -> bool {
+        self.src_port != 0
+    }
+
+    /// What a probe seeing this key does with its `CONNECTIONS` entry, given
+    /// whether the entry `exists` and whether the connection `may_create`
+    /// one (false under `new_connections_only` for sockets opened before
+    /// startup; only asked when there is no entry).
+    ///
+    /// A fresh outbound connection is not tracked at connect time while its
+    /// local port is unassigned. The first send or receive creates the
+    /// entry under the bound key, and both directions add to it:
+    ///
+    /// ```
+    /// use sidecar_common::{ConnKey, ConnMetrics, ConnUpsert};
+    /// use std::collections::HashMap;
+    ///
+    /// let dst = u32::from_ne_bytes([10, 0, 0, 5]);
+    /// let src = u32::from_ne_bytes([10, 0, 0, 1]);
+    /// let mut connections: HashMap<ConnKey, ConnMetrics> = HashMap::new();
+    /// // What a probe does with the entry of `key`, as the kernel does it
+    /// let mut probe = |key: ConnKey, count: &dyn Fn(&mut ConnMetrics)| {
+    ///     let upsert = key.upsert(connections.contains_key(&key), || true);
+    ///     match upsert {
+    ///         ConnUpsert::Update => count(connections.get_mut(&key).unwrap()),
+    ///         ConnUpsert::Create => count(connections.entry(key).or_default()),
+    ///         ConnUpsert::Skip => {}
+    ///     }
+    ///     upsert
+    /// };
+    ///
+    /// // tcp_connect: no local port yet, so no entry
+    /// let at_connect = ConnKey::ipv4(src, dst, 0, 443);
+    /// assert_eq!(probe(at_connect, &|_| {}), ConnUpsert::Skip);
+    ///
+    /// // tcp_sendmsg: the bound key creates the entry
+    /// let bound = ConnKey::ipv4(src, dst, 51234, 443);
+    /// assert_eq!(probe(bound, &|m| m.record_send(517)), ConnUpsert::Create);
+    /// // tcp_recvmsg: counted on entry, its bytes added on return
+    /// let recv = |m: &mut ConnMetrics| {
+    ///     m.packets_recv += 1;
+    ///     m.record_recv(1_380);
+    /// };
+    /// assert_eq!(probe(bound, &recv), ConnUpsert::Update);
+    /// assert_eq!(probe(bound, &|m| m.record_send(80)), ConnUpsert::Update);
+    ///
+    /// assert_eq!(connections.len(), 1);
+    /// let m = &connections[&bound];
+    /// assert_eq!((m.bytes_sent, m.packets_sent), (597, 2));
+    /// assert_eq!((m.bytes_recv, m.packets_recv), (1_380, 1));
+    ///
+    /// // A connection from before startup, under `new_connections_only`
+    /// let old = ConnKey::ipv4(src, dst, 40000, 443);
+    /// assert_eq!(old.upsert(false, || false), ConnUpsert::Skip);
+    /// ```
+    #[inline(always)]
+    pub fn upsert(&self, exists: bool, may_create: impl FnOnce() -> bool) -> ConnUpsert {
+        if exists {
+            ConnUpsert::Update
+        } else if self.is_bound() && may_create() {
+            ConnUpsert::Create
+        } else {
+            ConnUpsert::Skip
+        }
+    }
+
+    /// Whether both addresses are IPv4.
+    pub fn is_ipv4(&self) -> bool {
+        self.address_family == address_family::INET
+    }
+
+    /// The source address, IPv4 for a v4-mapped IPv6 one.
+    pub fn src_addr(&self) -> IpAddr {
+        ip_addr(self.address_family, &self.src_ip)
+    }
+
+    /// The destination address, IPv4 for a v4-mapped IPv6 one.
+    ///
+    /// ```
+    /// use sidecar_common::{address_family, ConnKey};
+    /// use std::net::{IpAddr, Ipv6Addr};
+    ///
+    /// let v6 = |ip: Ipv6Addr| ConnKey {
+    ///     dst_ip: ip.octets(),
+    ///     address_family: address_family::INET6,
+    ///     ..Default::default()
+    /// };
+    /// let native: Ipv6Addr = "2001:db8::5".parse().unwrap();
+    /// assert_eq!(v6(native).dst_addr(), IpAddr::V6(native));
+    /// let mapped: Ipv6Addr = "::ffff:10.0.0.5".parse().unwrap();
+    /// assert_eq!(v6(mapped).dst_addr().to_string(), "10.0.0.5");
+    /// ```
+    pub fn dst_addr(&self) -> IpAddr {
+        ip_addr(self.address_family, &self.dst_ip)
+    }
+
+    /// The source address in network byte order read as a native `u32`,
+    /// as the IPv4-only maps and events hold it; None for IPv6.
+    pub fn src_ipv4(&self) -> Option<u32> {
+        self.is_ipv4().then(|| ipv4_word(&self.src_ip))
+    }
+
+    /// The destination address like `src_ipv4`; None for IPv6.
+    pub fn dst_ipv4(&self) -> Option<u32> {
+        self.is_ipv4().then(|| ipv4_word(&self.dst_ip))
+    }
+
+    /// The connection's endpoint pair, without the ephemeral source port.
+    pub fn endpoint_key(&self) -> EndpointKey {
+        EndpointKey {
+            src_ip: self.src_ip,
+            dst_ip: self.dst_ip,
+            dst_port: self.dst_port,
+            address_family: self.address_family,
+        }
+    }
+}
+
+/// What a probe does with a connection's `CONNECTIONS` entry; see
+/// `ConnKey::upsert`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnUpsert {
+    /// Count on the existing entry
+    Update,
+    /// Insert a fresh entry, then count on it
+    Create,
+    /// Leave the connection untracked
+    Skip,
+}
+
+/// The first 4 bytes of an address as a native `u32`
+fn ipv4_word(ip: &[u8; 16]) -> u32 {
+    u32::from_ne_bytes([ip[0], ip[1], ip[2], ip[3]])
+}
+
+/// An address of `ConnKey`, by its family
+fn ip_addr(family: u8, ip: &[u8; 16]) -> IpAddr {
+    match family {
+        address_family::INET6 => {
+            let v6 = Ipv6Addr::from(*ip);
+            match v6.to_ipv4_mapped() {
+                Some(v4) => IpAddr::V4(v4),
+                None => IpAddr::V6(v6),
+            }
+        }
+        _ => IpAddr::V4(Ipv4Addr::new(ip[0], ip[1], ip[2], ip[3])),
+    }
+}
+
+/// Values of `ConnKey::address_family`: the kernel's `AF_*` numbers.
+pub mod address_family {
+    /// `AF_INET`
+    pub const INET: u8 = 2;
+    /// `AF_INET6`
+    pub const INET6: u8 = 10;
+}
+
+/// A source host and destination endpoint: every connection from `src_ip`
+/// to `dst_ip:dst_port`, whatever its source port.
+///
+/// Userspace-only grouping key; not stored in any map.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct EndpointKey {
+    /// Source IP address (network byte order), as in `ConnKey`
+    pub src_ip: [u8; 16],
+    /// Destination IP address (network byte order), as in `ConnKey`
+    pub dst_ip: [u8; 16],
+    /// Destination port (host byte order)
+    pub dst_port: u16,
+    /// Family of both addresses (see `address_family`)
+    pub address_family: u8,
+}
+
 /// Per-connection metrics stored in eBPF map.
 ///
 /// Updated by kernel eBPF programs on every packet send/receive.
@@ -37,7 +263,7 @@ unsafe impl aya::Pod for ConnKey {}
 #[repr(C)]
 #[derive(Clone, Copy, Debug, Default)]
 pub struct ConnMetrics {
-    /// Total bytes sent on this connection
+    /// Total application bytes sent on this connection (`tcp_sendmsg` size)
     pub bytes_sent: u64,
     /// Total bytes received on this connection
     pub bytes_recv: u64,
@@ -51,13 +277,164 @@ pub struct ConnMetrics {
     pub last_seen_ns: u64,
     /// Number of TCP retransmissions (indicates network quality)
     pub retransmits: u32,
-    /// Padding for 8-byte alignment
-    pub _padding: u32,
+    /// Current send MSS (`tcp_sock.mss_cache`) as of the last send, in bytes.
+    /// Point-in-time: shrinks if path MTU discovery finds a smaller MTU.
+    pub mss: u32,
+    /// Number of RTT samples folded into the sums below
+    pub rtt_samples: u64,
+    /// Sum of sampled RTTs (microseconds)
+    pub rtt_sum_us: u64,
+    /// Sum of squared sampled RTTs (microseconds^2), for variance/jitter
+    pub rtt_sum_sq_us: u64,
+    /// Bytes handed to IP for this connection: TCP/IP headers plus payload,
+    /// including retransmits and pure ACKs. Excludes link-layer framing.
+    pub wire_bytes_sent: u64,
+    /// Path MTU last applied to the connection (`icsk_pmtu_cookie`) as of
+    /// the last send, in bytes. 0 until known.
+    pub pmtu: u32,
+    /// Process (tgid) that created the entry
+    pub pid: u32,
+    /// RST segments sent (`tcp_send_active_reset`)
+    pub rst_sent: u32,
+    /// RST segments received (`tcp_reset`)
+    pub rst_recv: u32,
+    /// SACK blocks received on ACKs (`tcp_check_dsack`'s `num_sacks`)
+    pub sack_blocks: u32,
+    /// Segments the peer reported as received twice through DSACK
+    /// (`tcp_sock.dsack_dups`, read on each SACK and send)
+    pub dsacks: u32,
+    /// Congestion control algorithm (`icsk_ca_ops->name`, e.g. "cubic"),
+    /// NUL-terminated, read when the entry is created
+    pub cc_algo: [u8; CC_NAME_LEN],
+    /// Inode of the socket's network namespace (`sock_net(sk)->ns.inum`),
+    /// read when the entry is created; 0 if it couldn't be read
+    pub netns_inode: u32,
+    /// Token of the MPTCP connection this is a subflow of (0 for plain
+    /// TCP). The MPTCP socket's own entry shares the first subflow's key.
+    pub mptcp_token: u32,
+    /// What the connection was seen carrying, a `conn_flag` bitmask
+    pub flags: u8,
+    /// HTTP version the connection was seen speaking, an `http_version`
+    /// value
+    pub http_version: u8,
+    /// Reserved for future fields; always 0
+    pub _padding: [u8; 2],
+    /// File descriptor of the socket in the process that first sent or
+    /// received on it through a system call the kernel saw; -1 until then
+    pub fd: i32,
+    /// Identity of the socket, the same for every entry it gets when its
+    /// 4-tuple changes (e.g. NAT rebinding) and not reused after it closes.
+    /// 0 if unknown. Userspace merges entries sharing a cookie.
+    pub cookie: u64,
+    /// `SO_PRIORITY` of the socket (`sock.sk_priority`) as of the last send;
+    /// 0, the default, unless the application or a policy set one
+    pub so_priority: u32,
+    /// IPv6 flow label (20 bits, host byte order) read when the entry is
+    /// created: the socket's own, else the one the kernel derives from its
+    /// transmit hash. 0 for IPv4 connections and if it couldn't be read.
+    pub flow_label: u32,
+}
+
+impl ConnMetrics {
+    /// Time between the first and the most recent observed activity, in
+    /// nanoseconds. A `last_seen_ns` behind `start_ns` (a stale or torn
+    /// update) counts as no time rather than wrapping around.
+    ///
+    /// ```
+    /// use sidecar_common::ConnMetrics;
+    ///
+    /// let mut m = ConnMetrics { start_ns: 1_000, last_seen_ns: 4_000, ..Default::default() };
+    /// assert_eq!(m.duration_ns(), 3_000);
+    /// m.last_seen_ns = 999;
+    /// assert_eq!(m.duration_ns(), 0);
+    /// ```
+    #[inline(always)]
+    pub fn duration_ns(&self) -> u64 {
+        self.last_seen_ns.saturating_sub(self.start_ns)
+    }
+
+    /// Count a send of `bytes` on the connection; see `ConnKey::upsert` for
+    /// the entry it lands on.
+    #[inline(always)]
+    pub fn record_send(&mut self, bytes: u64) {
+        self.bytes_sent += bytes;
+        self.packets_sent += 1;
+    }
+
+    /// Count `bytes` received on the connection. The receive call itself is
+    /// counted in `packets_recv` when it starts; its size is only known once
+    /// it returns.
+    #[inline(always)]
+    pub fn record_recv(&mut self, bytes: u64) {
+        self.bytes_recv += bytes;
+    }
+}
+
+/// Bits of `ConnMetrics::flags`. A protocol bit is set once an L7 parser
+/// recognises the protocol on the connection and never cleared.
+pub mod conn_flag {
+    /// HTTP/1.x requests or responses
+    pub const HTTP: u8 = 1 << 0;
+    /// The HTTP/2 connection preface
+    pub const HTTP2: u8 = 1 << 1;
+    /// TLS records
+    pub const TLS: u8 = 1 << 2;
+    /// DNS messages
+    pub const DNS: u8 = 1 << 3;
+    /// TCP timestamps (RFC 7323) were negotiated; not a protocol, so not in
+    /// `NAMES`
+    pub const TCP_TIMESTAMPS: u8 = 1 << 4;
+    /// Every protocol flag with its name, for userspace
+    pub const NAMES: [(u8, &str); 4] =
+        [(HTTP, "http"), (HTTP2, "http2"), (TLS, "tls"), (DNS, "dns")];
 }
 
+/// Values of `ConnMetrics::http_version`. Set by the HTTP parser: HTTP/2
+/// from the connection preface, HTTP/1.x from the version of a status line.
+pub mod http_version {
+    /// Not seen yet, or not HTTP
+    pub const UNKNOWN: u8 = 0;
+    /// `HTTP/1.0`
+    pub const HTTP_1_0: u8 = 1;
+    /// `HTTP/1.1`
+    pub const HTTP_1_1: u8 = 2;
+    /// HTTP/2
+    pub const HTTP_2: u8 = 3;
+
+    /// The version as exported in the `version` label, `None` for `UNKNOWN`
+    pub fn name(version: u8) -> Option<&'static str> {
+        match version {
+            HTTP_1_0 => Some("1.0"),
+            HTTP_1_1 => Some("1.1"),
+            HTTP_2 => Some("2"),
+            _ => None,
+        }
+    }
+}
+
+/// Size of `ConnMetrics::cc_algo`; matches the kernel's `TCP_CA_NAME_MAX`.
+pub const CC_NAME_LEN: usize = 16;
+
+/// Largest RTT sample (microseconds) accumulated into `ConnMetrics`.
+///
+/// Samples are clamped to this value so `rtt_sum_sq_us` cannot overflow
+/// within one `RTT_WINDOW_SAMPLES` window: 10^7^2 * 2^16 < u64::MAX.
+pub const RTT_SAMPLE_MAX_US: u64 = 10_000_000;
+
+/// Number of RTT samples after which the running sums are halved.
+///
+/// Halving keeps mean and variance intact while bounding the sums, and acts
+/// as a slow decay so long-lived connections still reflect recent behavior.
+pub const RTT_WINDOW_SAMPLES: u64 = 1 << 16;
+
 #[cfg(feature = "user")]
 unsafe impl aya::Pod for ConnMetrics {}
 
+/// Size of an executable path in the `PROCESS_EXES` map, NUL included.
+///
+/// Longer paths are truncated to `EXE_PATH_LEN - 1` bytes.
+pub const EXE_PATH_LEN: usize = 256;
+
 /// HTTP request/response event sent via perf buffer.
 ///
 /// Captures HTTP-level metrics for L7 observability.
@@ -74,8 +451,25 @@ pub struct HttpEvent {
     pub method: u8,
     /// Padding for alignment
     pub _padding: u8,
-    /// Request path hash (for grouping similar requests)
-    pub path_hash: u32,
+    /// Bytes already in the socket ahead of the request when it was sent,
+    /// unsent or unacknowledged (`write_seq - snd_una`)
+    pub queued_bytes: u32,
+    /// Smoothed RTT when the request was sent (microseconds)
+    pub srtt_us: u32,
+    /// Congestion window when the request was sent (segments)
+    pub snd_cwnd: u32,
+    /// Send MSS when the request was sent (bytes)
+    pub mss: u32,
+    /// HTTP/2 stream the request was sent on; 0 for HTTP/1.x
+    pub stream_id: u32,
+    /// FNV-1a 64 hash of the HTTP/1.x request path, query string excluded;
+    /// 0 for HTTP/2 and when the path couldn't be read
+    pub path_hash: u64,
+    /// FNV-1a 32 hash of the same path, to tell apart paths whose
+    /// `path_hash` collides; 0 whenever `path_hash` is
+    pub path_check: u32,
+    /// Padding for alignment
+    pub _padding2: u32,
 }
 
 #[cfg(feature = "user")]
@@ -106,23 +500,393 @@ unsafe impl aya::Pod for ProcessInfo {}
 pub struct SidecarConfig {
     /// Target PID to monitor (0 = all processes)
     pub target_pid: u32,
-    /// Target cgroup ID to monitor (0 = all cgroups)
+    /// Target cgroup ID to monitor (0 = all cgroups); its descendants are
+    /// matched through `TARGET_CGROUPS`
     pub target_cgroup: u64,
     /// Ports to monitor (0 = all ports, otherwise filter)
     pub target_ports: [u16; 8],
     /// Number of ports in target_ports array
     pub num_target_ports: u8,
-    /// Enable HTTP parsing (L7 inspection)
+    /// Enable HTTP parsing (L7 inspection); also makes the receive path
+    /// hand messages to the HTTP parser
     pub enable_http: u8,
     /// Enable detailed per-packet logging (debug mode)
     pub debug_mode: u8,
-    /// Padding
-    pub _padding: u8,
+    /// Skip connections whose source and destination are both loopback
+    pub exclude_loopback: u8,
+    /// More ports were configured than fit in `target_ports`: track
+    /// connections on other ports too and leave the rest to userspace
+    pub ports_overflow: u8,
+    /// `ALWAYS_TRACK` has entries: connections to them bypass every filter
+    /// above
+    pub always_track: u8,
+    /// Only track connections seen at `tcp_connect` or accept; send/recv
+    /// don't create entries for connections opened before the sidecar
+    pub new_connections_only: u8,
+    /// Percentage of calls each probe counts, indexed by `probe`; calls
+    /// left out are skipped before any other work
+    pub sample_rates: [u8; probe::COUNT as usize],
+    /// `CONFIG_VERSION` of the userspace that wrote this config; 0 until
+    /// it has written one. Fills what was an alignment hole.
+    pub version: u8,
+    /// Payload bytes copied into each `PayloadEvent` (0 = payload capture
+    /// off); at most `PAYLOAD_CAPTURE_MAX`
+    pub capture_max_bytes: u16,
+    /// Captures allowed per second over all CPUs, whatever the sampling
+    /// yields
+    pub capture_max_per_sec: u16,
+    /// Capture one in this many sends and receives on tracked connections
+    pub capture_every: u32,
+    /// Report the gap before one in this many sends and receives on tracked
+    /// connections as a `GapEvent` (0 = off)
+    pub gap_every: u32,
+    /// Gap reports allowed per second over all CPUs, whatever the sampling
+    /// yields
+    pub gap_max_per_sec: u32,
+    /// Value of the kernel's `SKB_DROP_REASON_TCP_RFC7323_PAWS`, counted
+    /// into `PAWS_DROPS` (0 = off); it differs between kernel versions
+    pub paws_drop_reason: u32,
+    /// Offset of `reason` in the `skb:kfree_skb` tracepoint record
+    pub kfree_skb_reason_offset: u32,
+    /// Send the TLS ClientHellos the TLS parser sees as `TlsHelloEvent`s,
+    /// for fingerprinting (0 = off)
+    pub tls_fingerprints: u32,
+    /// Capture the call stacks of one in this many tracked connects as a
+    /// `ConnectStackEvent` (0 = off)
+    pub stack_every: u32,
+    /// Stack captures allowed per second over all CPUs, whatever the
+    /// sampling yields
+    pub stack_max_per_sec: u32,
 }
 
 #[cfg(feature = "user")]
 unsafe impl aya::Pod for SidecarConfig {}
 
+/// Value userspace writes to `SidecarConfig::version`. Bump it when the
+/// meaning of a config field changes without its layout changing.
+pub const CONFIG_VERSION: u8 = 1;
+
+/// Key for the connect-failures map: destination plus failure reason.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct ConnFailKey {
+    /// Destination IP address (network byte order)
+    pub dst_ip: u32,
+    /// Destination port (host byte order)
+    pub dst_port: u16,
+    /// Failure reason (see `connect_failure`)
+    pub reason: u8,
+    /// Padding for alignment
+    pub _padding: u8,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for ConnFailKey {}
+
+/// Final connection totals sent via perf buffer when a connection closes.
+///
+/// Emitted exactly once per tracked connection, so userspace can observe
+/// lifetime distributions without double counting across scans.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ConnCloseEvent {
+    /// Connection that closed
+    pub conn: ConnKey,
+    /// Total bytes sent over the connection's lifetime
+    pub bytes_sent: u64,
+    /// Total bytes received over the connection's lifetime
+    pub bytes_recv: u64,
+    /// Connection lifetime in nanoseconds
+    pub duration_ns: u64,
+    /// Total packets sent over the connection's lifetime
+    pub packets_sent: u64,
+    /// Total packets received over the connection's lifetime
+    pub packets_recv: u64,
+    /// Total TCP retransmissions over the connection's lifetime
+    pub retransmits: u32,
+    /// Padding for 8-byte alignment
+    pub _padding: u32,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for ConnCloseEvent {}
+
+/// Data of a `POLICY_DENYLIST` or `ALWAYS_TRACK` LPM trie key: a destination
+/// port and address.
+///
+/// The port comes first so a rule's prefix is the port and padding (32 bits)
+/// plus the address prefix. Port 0 matches any port: the kernel looks up the
+/// actual port first, then 0.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct PolicyTarget {
+    /// Destination port (host byte order), 0 for any
+    pub dst_port: u16,
+    /// Padding for alignment; always 0
+    pub _padding: u16,
+    /// Destination IP address (network byte order)
+    pub dst_ip: u32,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for PolicyTarget {}
+
+/// An outbound connect to a denylisted destination, sent via perf buffer.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PolicyEvent {
+    /// Process that called `connect()`
+    pub pid: u32,
+    /// Index of the matching rule in the configured denylist
+    pub rule: u32,
+    /// Destination IP address (network byte order)
+    pub dst_ip: u32,
+    /// Destination port (host byte order)
+    pub dst_port: u16,
+    /// Address family of the socket (`AF_INET` or `AF_INET6` for a
+    /// v4-mapped destination)
+    pub family: u16,
+    /// Task name (`comm`) of the caller
+    pub comm: [u8; 16],
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for PolicyEvent {}
+
+/// Key for the interface traffic map: a network device within its
+/// namespace (interface indexes are only unique per namespace).
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct IfaceKey {
+    /// `dev->ifindex`
+    pub ifindex: u32,
+    /// Inode of the device's network namespace
+    pub netns_inode: u32,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for IfaceKey {}
+
+/// Bytes through one network interface, whatever connection (or protocol)
+/// they belong to.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct IfaceTraffic {
+    /// Bytes received (`netif_receive_skb`)
+    pub rx_bytes: u64,
+    /// Bytes handed to the driver for sending (`net_dev_xmit`)
+    pub tx_bytes: u64,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for IfaceTraffic {}
+
+/// Which way a message went on a connection.
+pub mod direction {
+    /// Passed to `tcp_sendmsg` (`udp_sendmsg` for UDP)
+    pub const SENT: u8 = 0;
+    /// Returned by `tcp_recvmsg` (`udp_recvmsg` for UDP)
+    pub const RECEIVED: u8 = 1;
+}
+
+/// Upper bounds (bytes, inclusive) of the UDP datagram size buckets, at the
+/// sizes where datagrams start to be dropped or fragmented: 512 (DNS without
+/// EDNS), 576 (smallest IPv4 reassembly buffer), 1232 and 1280 (IPv6 minimum
+/// MTU, with and without IPv6 and UDP headers), 1472 and 1500 (Ethernet), 8972
+/// (jumbo frames) and 65507 (largest IPv4 UDP payload). Larger datagrams count
+/// in a last, unbounded bucket.
+pub const UDP_SIZE_BUCKETS: [u32; 12] = [
+    64, 128, 256, 512, 576, 1232, 1280, 1472, 1500, 8972, 16384, 65507,
+];
+
+/// UDP datagram sizes in one direction, indexed by `direction` in
+/// `UDP_SIZES`.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct UdpSizeHistogram {
+    /// Datagrams per `UDP_SIZE_BUCKETS` bucket, not cumulative; the last
+    /// counts those above every bound
+    pub counts: [u64; UDP_SIZE_BUCKETS.len() + 1],
+    /// Sum of the datagram sizes (bytes)
+    pub sum: u64,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for UdpSizeHistogram {}
+
+/// Largest payload prefix a `PayloadEvent` carries.
+pub const PAYLOAD_CAPTURE_MAX: usize = 256;
+
+/// The first bytes of a sampled send or receive, sent via perf buffer when
+/// payload capture is on (`SidecarConfig::capture_every`).
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct PayloadEvent {
+    /// Connection the message went over
+    pub conn: ConnKey,
+    /// Process that sent or received it
+    pub pid: u32,
+    /// Leading bytes of `data` holding payload
+    pub captured_len: u16,
+    /// `direction` of the message
+    pub direction: u8,
+    /// Padding for alignment
+    pub _padding: u8,
+    /// Capture time (nanoseconds since boot)
+    pub timestamp_ns: u64,
+    /// Size of the whole message
+    pub size: u64,
+    /// Start of the payload
+    pub data: [u8; PAYLOAD_CAPTURE_MAX],
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for PayloadEvent {}
+
+/// Time between a send or receive and the previous one on its connection,
+/// in either direction, sent via perf buffer for a sample of them
+/// (`SidecarConfig::gap_every`).
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct GapEvent {
+    /// Connection the send or receive was on
+    pub conn: ConnKey,
+    /// `direction` of the send or receive ending the gap
+    pub direction: u8,
+    /// Padding for alignment
+    pub _padding: [u8; 7],
+    /// Nanoseconds since the connection's previous send or receive
+    pub gap_ns: u64,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for GapEvent {}
+
+/// Bytes of a ClientHello copied into a `TlsHelloEvent`, its record header
+/// included; enough for most clients, but not for hellos carrying large key
+/// shares (e.g. post-quantum ones), whose trailing extensions are cut off.
+pub const TLS_HELLO_MAX: usize = 2048;
+
+/// The start of a TLS ClientHello sent by a monitored process, sent via perf
+/// buffer when `SidecarConfig::tls_fingerprints` is on.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct TlsHelloEvent {
+    /// Connection the hello was sent on
+    pub conn: ConnKey,
+    /// Process that sent it
+    pub pid: u32,
+    /// Leading bytes of `data` holding the message
+    pub captured_len: u16,
+    /// Padding for alignment
+    pub _padding: u16,
+    /// Start of the message: the TLS record header, then the handshake
+    pub data: [u8; TLS_HELLO_MAX],
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for TlsHelloEvent {}
+
+/// Call stacks of a tracked connect, sent via perf buffer for a sample of
+/// them (`SidecarConfig::stack_every`). The stacks themselves stay in the
+/// `STACK_TRACES` map, by id.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ConnectStackEvent {
+    /// Connection being opened; `src_port` is 0 if the local port isn't
+    /// bound yet
+    pub conn: ConnKey,
+    /// Process opening it
+    pub pid: u32,
+    /// Kernel stack in `STACK_TRACES`, or a negative errno if it wasn't
+    /// recorded
+    pub kernel_stack_id: i32,
+    /// User stack in `STACK_TRACES`, or a negative errno if it wasn't
+    /// recorded
+    pub user_stack_id: i32,
+    /// `comm` of the calling thread
+    pub comm: [u8; 16],
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for ConnectStackEvent {}
+
+/// Buckets in a `ByteHistory` ring: one hour of minutes
+pub const HISTORY_MINUTES: usize = 60;
+
+/// Width of a `ByteHistory` bucket, in `bpf_ktime_get_ns()` nanoseconds
+pub const HISTORY_BUCKET_NS: u64 = 60_000_000_000;
+
+/// One CPU's bytes sent and received per minute over the last hour.
+///
+/// Minute `m` (`bpf_ktime_get_ns() / HISTORY_BUCKET_NS`) lives in bucket
+/// `m % HISTORY_MINUTES`, so the ring wraps every hour. The first write in a
+/// new minute finds the bucket still stamped with an older minute and resets
+/// it. A bucket nothing was written to since is left stale, so readers must
+/// only count buckets whose `minute` is the one they expect.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct ByteHistory {
+    /// Minute each bucket holds
+    pub minute: [u64; HISTORY_MINUTES],
+    /// Bytes passed to `tcp_sendmsg` in that minute
+    pub bytes_sent: [u64; HISTORY_MINUTES],
+    /// Bytes returned by `tcp_recvmsg` in that minute
+    pub bytes_recv: [u64; HISTORY_MINUTES],
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for ByteHistory {}
+
+/// Layout fingerprint embedded in the eBPF object and checked by userspace.
+///
+/// The kernel side exposes this through the read-only `.rodata.layout` map so
+/// ABI skew between the two halves is caught at load time instead of showing
+/// up as garbage metrics.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LayoutInfo {
+    /// `LAYOUT_VERSION` the object was built with
+    pub version: u32,
+    /// `size_of::<ConnKey>()`
+    pub conn_key_size: u32,
+    /// `size_of::<ConnMetrics>()`
+    pub conn_metrics_size: u32,
+    /// `size_of::<HttpEvent>()`
+    pub http_event_size: u32,
+    /// `size_of::<ProcessInfo>()`
+    pub process_info_size: u32,
+    /// `size_of::<SidecarConfig>()`
+    pub sidecar_config_size: u32,
+    /// `size_of::<ConnFailKey>()`
+    pub conn_fail_key_size: u32,
+    /// `size_of::<ConnCloseEvent>()`
+    pub conn_close_event_size: u32,
+}
+
+impl LayoutInfo {
+    /// Layout of the types as compiled into the current binary.
+    pub const fn current() -> Self {
+        Self {
+            version: LAYOUT_VERSION,
+            conn_key_size: size_of::<ConnKey>() as u32,
+            conn_metrics_size: size_of::<ConnMetrics>() as u32,
+            http_event_size: size_of::<HttpEvent>() as u32,
+            process_info_size: size_of::<ProcessInfo>() as u32,
+            sidecar_config_size: size_of::<SidecarConfig>() as u32,
+            conn_fail_key_size: size_of::<ConnFailKey>() as u32,
+            conn_close_event_size: size_of::<ConnCloseEvent>() as u32,
+        }
+    }
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for LayoutInfo {}
+
+/// Size of the architecture name in the eBPF object's `.rodata.arch` map,
+/// NUL-padded; the names are Rust's `target_arch` values.
+pub const BPF_ARCH_LEN: usize = 16;
+
 /// HTTP method constants
 pub mod http_method {
     pub const GET: u8 = 0;
@@ -133,4 +897,283 @@ pub mod http_method {
     pub const HEAD: u8 = 5;
     pub const OPTIONS: u8 = 6;
     pub const UNKNOWN: u8 = 255;
+
+    /// Name of a method code, `"UNKNOWN"` for any other value.
+    pub const fn method_name(m: u8) -> &'static str {
+        match m {
+            GET => "GET",
+            POST => "POST",
+            PUT => "PUT",
+            DELETE => "DELETE",
+            PATCH => "PATCH",
+            HEAD => "HEAD",
+            OPTIONS => "OPTIONS",
+            _ => "UNKNOWN",
+        }
+    }
+
+    /// Method of the request line `b` starts with: the method name followed
+    /// by a space. Anything else, lowercase names and methods without a
+    /// code included, is `UNKNOWN`.
+    ///
+    /// Used by the kernel parser on the first bytes of a send, so it only
+    /// looks at fixed positions.
+    ///
+    /// ```
+    /// use sidecar_common::http_method::*;
+    ///
+    /// for m in [GET, POST, PUT, DELETE, PATCH, HEAD, OPTIONS] {
+    ///     let line = format!("{} /health HTTP/1.1\r\n", method_name(m));
+    ///     assert_eq!(method_from_bytes(line.as_bytes()), m);
+    /// }
+    /// assert_eq!(method_from_bytes(b"GET"), UNKNOWN);
+    /// assert_eq!(method_from_bytes(b"get / HTTP/1.1"), UNKNOWN);
+    /// assert_eq!(method_from_bytes(b"GETS / HTTP/1.1"), UNKNOWN);
+    /// assert_eq!(method_from_bytes(b"CONNECT example.com:443 HTTP/1.1"), UNKNOWN);
+    /// assert_eq!(method_name(UNKNOWN), "UNKNOWN");
+    /// assert_eq!(method_name(42), "UNKNOWN");
+    /// ```
+    #[inline(always)]
+    pub fn method_from_bytes(b: &[u8]) -> u8 {
+        match b {
+            [b'G', b'E', b'T', b' ', ..] => GET,
+            [b'P', b'O', b'S', b'T', b' ', ..] => POST,
+            [b'P', b'U', b'T', b' ', ..] => PUT,
+            [b'D', b'E', b'L', b'E', b'T', b'E', b' ', ..] => DELETE,
+            [b'P', b'A', b'T', b'C', b'H', b' ', ..] => PATCH,
+            [b'H', b'E', b'A', b'D', b' ', ..] => HEAD,
+            [b'O', b'P', b'T', b'I', b'O', b'N', b'S', b' ', ..] => OPTIONS,
+            _ => UNKNOWN,
+        }
+    }
+}
+
+/// Hashing of HTTP request paths into `HttpEvent::path_hash`: FNV-1a 64
+/// over the request target up to its query string. `HttpEvent::path_check`
+/// is FNV-1a 32 over the same bytes, a second hash that two paths colliding
+/// on the first are unlikely to share too.
+pub mod http_path {
+    /// FNV-1a 64 offset basis: the hash of an empty path
+    pub const HASH_SEED: u64 = 0xcbf2_9ce4_8422_2325;
+    /// FNV-1a 32 offset basis: the check of an empty path
+    pub const CHECK_SEED: u32 = 0x811c_9dc5;
+    /// Most bytes of a path hashed; longer paths hash like their start
+    pub const MAX_HASHED_LEN: usize = 128;
+
+    const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+    const FNV32_PRIME: u32 = 0x0100_0193;
+
+    /// Fold the next byte of a path into `hash`.
+    #[inline(always)]
+    pub const fn hash_byte(hash: u64, byte: u8) -> u64 {
+        (hash ^ byte as u64).wrapping_mul(FNV_PRIME)
+    }
+
+    /// Fold the next byte of a path into `check`.
+    #[inline(always)]
+    pub const fn check_byte(check: u32, byte: u8) -> u32 {
+        (check ^ byte as u32).wrapping_mul(FNV32_PRIME)
+    }
+
+    /// Whether `byte` ends the hashed part of a request target: the start of
+    /// the query string or fragment, or the end of the target.
+    #[inline(always)]
+    pub const fn ends_path(byte: u8) -> bool {
+        matches!(byte, b' ' | b'?' | b'#' | b'\r' | b'\n' | 0)
+    }
+
+    /// `HttpEvent::path_hash` of a request target, as the kernel computes it.
+    ///
+    /// ```
+    /// use sidecar_common::http_path::{hash, HASH_SEED, MAX_HASHED_LEN};
+    ///
+    /// assert_eq!(hash(b"a"), 0xaf63_dc4c_8601_ec8c);
+    /// assert_eq!(hash(b"/users/42?page=2"), hash(b"/users/42"));
+    /// assert_eq!(hash(b"/users/42 HTTP/1.1"), hash(b"/users/42"));
+    /// assert_ne!(hash(b"/users/42"), hash(b"/users/43"));
+    /// assert_eq!(hash(b""), HASH_SEED);
+    ///
+    /// let long = [b'x'; MAX_HASHED_LEN + 1];
+    /// assert_eq!(hash(&long), hash(&long[..MAX_HASHED_LEN]));
+    /// ```
+    pub fn hash(target: &[u8]) -> u64 {
+        target
+            .iter()
+            .take(MAX_HASHED_LEN)
+            .take_while(|&&byte| !ends_path(byte))
+            .fold(HASH_SEED, |hash, &byte| hash_byte(hash, byte))
+    }
+
+    /// `HttpEvent::path_check` of a request target, as the kernel computes
+    /// it.
+    ///
+    /// ```
+    /// use sidecar_common::http_path::{check, CHECK_SEED};
+    ///
+    /// assert_eq!(check(b"a"), 0xe40c_292c);
+    /// assert_eq!(check(b"/users/42?page=2"), check(b"/users/42"));
+    /// assert_ne!(check(b"/users/42"), check(b"/users/43"));
+    /// assert_eq!(check(b""), CHECK_SEED);
+    /// ```
+    pub fn check(target: &[u8]) -> u32 {
+        target
+            .iter()
+            .take(MAX_HASHED_LEN)
+            .take_while(|&&byte| !ends_path(byte))
+            .fold(CHECK_SEED, |check, &byte| check_byte(check, byte))
+    }
+}
+
+/// Connect failure reasons, derived from `sk_err` when a socket goes
+/// SYN_SENT -> CLOSE
+pub mod connect_failure {
+    /// RST in reply to SYN (ECONNREFUSED)
+    pub const REFUSED: u8 = 0;
+    /// SYN retries exhausted (ETIMEDOUT)
+    pub const TIMEOUT: u8 = 1;
+    /// ICMP host/network unreachable (EHOSTUNREACH, ENETUNREACH)
+    pub const UNREACHABLE: u8 = 2;
+    /// Anything else, including a close() before the handshake finished
+    pub const OTHER: u8 = 255;
+}
+
+/// Probes with their own sample rate, indexing
+/// `SidecarConfig::sample_rates`. `tcp_close`, accept and the process
+/// tracepoints keep the map consistent and always run.
+pub mod probe {
+    /// `tcp_connect`
+    pub const CONNECT: u32 = 0;
+    /// `tcp_sendmsg`, including the L7 parsers it hands sends to
+    pub const SENDMSG: u32 = 1;
+    /// `tcp_recvmsg` and its return probe
+    pub const RECVMSG: u32 = 2;
+    /// `ip_queue_xmit`
+    pub const QUEUE_XMIT: u32 = 3;
+    /// The `tcp_retransmit_skb` tracepoint
+    pub const RETRANSMIT: u32 = 4;
+    /// `tcp_reset` and `tcp_send_active_reset`
+    pub const RESET: u32 = 5;
+    /// Number of probes
+    pub const COUNT: u32 = 6;
+}
+
+/// Indices into the per-CPU `CONN_STATS` counters
+pub mod conn_stat {
+    /// Entries inserted into `CONNECTIONS`
+    pub const INSERTED: u32 = 0;
+    /// Entries removed from `CONNECTIONS` by `tcp_close`
+    pub const CLOSED: u32 = 1;
+    /// New connections that could not be inserted into `CONNECTIONS`
+    /// (`-E2BIG` from a full map, `-ENOMEM` when the LRU can't free a slot)
+    pub const INSERT_FAILED: u32 = 2;
+    /// Entries removed from `CONNECTIONS` by userspace when they stopped
+    /// matching the monitored ports; counted on CPU 0 only
+    pub const CLEARED: u32 = 3;
+    /// Number of counters
+    pub const COUNT: u32 = 4;
+}
+
+/// Indices into the shared `ACTIVE_CONNS` counters. Unlike `conn_stat` these
+/// are one value for all CPUs, so they can be compared against each other.
+pub mod active_conn {
+    /// Entries in `CONNECTIONS`, counted up on insert and down on close.
+    /// Entries evicted by the LRU are never counted down; userspace resets
+    /// it to the live entry count at each read.
+    pub const CURRENT: u32 = 0;
+    /// Highest `CURRENT` since userspace last reset it
+    pub const PEAK: u32 = 1;
+    /// Number of counters
+    pub const COUNT: u32 = 2;
+}
+
+/// Indices into the per-CPU `FILTER_DECISIONS` counters: one per outcome of
+/// each process/connection filter check. A check only counts when its filter
+/// is configured, so one event can pass a PID check and fail a port check.
+pub mod filter_decision {
+    /// The PID is the target or one of its descendants
+    pub const MATCHED_PID: u32 = 0;
+    /// The PID filter is set and the PID isn't in the monitored tree
+    pub const FILTERED_PID: u32 = 1;
+    /// The task is in the target cgroup subtree
+    pub const MATCHED_CGROUP: u32 = 2;
+    /// The cgroup filter is set and the task is outside the subtree
+    pub const FILTERED_CGROUP: u32 = 3;
+    /// The connection is on none of the target ports
+    pub const FILTERED_PORT: u32 = 4;
+    /// The connection is loopback-to-loopback with `exclude_loopback` set
+    pub const FILTERED_LOOPBACK: u32 = 5;
+    /// Neither a PID nor a cgroup filter is configured
+    pub const NO_FILTER: u32 = 6;
+    /// Number of counters
+    pub const COUNT: u32 = 7;
+}
+
+/// Why an eBPF program gave up on an event.
+///
+/// Every `try_*` function in the kernel program returns
+/// `Result<(), ProbeError>`. The discriminant is a stable error code, so a
+/// future per-code counter can be indexed by it; [`ProbeError::name`] is what
+/// the `warn!` logs print.
+#[repr(i64)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ProbeError {
+    /// A probe argument or return value was not available
+    ArgMissing = 1,
+    /// Reading a `struct sock` / `tcp_sock` field failed, or it held a null
+    /// pointer
+    SockReadFailed = 2,
+    /// Reading an `sk_buff` field failed
+    SkbReadFailed = 3,
+    /// Reading the `msghdr` / `iov_iter` of a send failed
+    MsgReadFailed = 4,
+    /// The send's `iov_iter` is of a type the L7 peek can't follow
+    UnsupportedIter = 5,
+    /// Copying from user memory failed (e.g. the page is not resident)
+    UserReadFailed = 6,
+    /// Reading a kernel string failed
+    StrReadFailed = 7,
+    /// Reading a tracepoint field failed
+    TracepointReadFailed = 8,
+    /// A map lookup found no entry where one was expected
+    MapLookupFailed = 9,
+    /// A map insert was rejected (usually a full map)
+    MapInsertFailed = 10,
+}
+
+impl ProbeError {
+    /// The stable error code.
+    pub const fn code(self) -> i64 {
+        self as i64
+    }
+
+    /// The variant name, for logs.
+    pub const fn name(self) -> &'static str {
+        match self {
+            ProbeError::ArgMissing => "ArgMissing",
+            ProbeError::SockReadFailed => "SockReadFailed",
+            ProbeError::SkbReadFailed => "SkbReadFailed",
+            ProbeError::MsgReadFailed => "MsgReadFailed",
+            ProbeError::UnsupportedIter => "UnsupportedIter",
+            ProbeError::UserReadFailed => "UserReadFailed",
+            ProbeError::StrReadFailed => "StrReadFailed",
+            ProbeError::TracepointReadFailed => "TracepointReadFailed",
+            ProbeError::MapLookupFailed => "MapLookupFailed",
+            ProbeError::MapInsertFailed => "MapInsertFailed",
+        }
+    }
+}
+
+/// Slots in the `L7_PARSERS` tail-call program array, one per protocol
+///
+/// Userspace only fills the slots of enabled parsers; a tail call into an
+/// empty slot fails and the send path carries on without L7 parsing.
+pub mod l7_proto {
+    /// HTTP/1.x requests and responses
+    pub const HTTP: u32 = 0;
+    /// TLS records
+    pub const TLS: u32 = 1;
+    /// DNS over TCP
+    pub const DNS: u32 = 2;
+    /// Number of slots
+    pub const COUNT: u32 = 3;
 }
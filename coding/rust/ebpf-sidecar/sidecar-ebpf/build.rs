@@ -0,0 +1,35 @@
+//! Record the architecture the probes are built for.
+//!
+//! kprobe arguments are read from `struct pt_regs`, whose layout and
+//! argument registers depend on the architecture the object will run on, not
+//! the `bpfel` target it's compiled to. aya-ebpf picks them from
+//! `$CARGO_CFG_BPF_TARGET_ARCH`, falling back to the build host's arch; the
+//! same choice is passed to the crate as `SIDECAR_BPF_TARGET_ARCH`, so the
+//! loader can refuse an object built for another architecture.
+
+use std::env;
+
+/// Architectures aya-ebpf knows the `pt_regs` layout of
+const SUPPORTED: [&str; 6] = ["x86_64", "aarch64", "arm", "riscv64", "powerpc64", "s390x"];
+
+fn main() {
+    println!("cargo:rerun-if-env-changed=CARGO_CFG_BPF_TARGET_ARCH");
+
+    let arch = match env::var("CARGO_CFG_BPF_TARGET_ARCH") {
+        Ok(arch) => arch,
+        // Same fallback as aya-ebpf: the arch of the host triple
+        Err(_) => env::var("HOST")
+            .unwrap()
+            .split('-')
+            .next()
+            .unwrap()
+            .to_string(),
+    };
+    if !SUPPORTED.contains(&arch.as_str()) {
+        panic!(
+            "unsupported BPF target arch {:?}; set CARGO_CFG_BPF_TARGET_ARCH to one of {:?}",
+            arch, SUPPORTED
+        );
+    }
+    println!("cargo:rustc-env=SIDECAR_BPF_TARGET_ARCH={}", arch);
+}
@@ -4,313 +4,3295 @@
 //! without modifying the application or adding latency through userspace proxying.
 //!
 //! # Attach Points
-//! - `tcp_connect`: Track new outbound connections
-//! - `tcp_sendmsg`: Track bytes sent
-//! - `tcp_recvmsg`: Track bytes received  
+//! - `tcp_connect`: Track new outbound connections (once the local port is bound)
+//! - `inet_csk_accept`: Mark accepted connections as new for
+//!   `new_connections_only` (return probe)
+//! - `tcp_sendmsg`: Track bytes sent (creates the entry if missing)
+//! - `tcp_recvmsg`: Track bytes received (creates the entry if missing); on
+//!   return, hand received messages to the HTTP parser
+//! - `mptcp_sendmsg` / `mptcp_recvmsg`: Track bytes on MPTCP sockets, whose
+//!   data reaches the subflows without `tcp_sendmsg`/`tcp_recvmsg` (kprobes
+//!   only; skipped on kernels without MPTCP)
 //! - `tcp_close`: Clean up connection tracking
-//! - `tcp_retransmit_skb`: Track retransmissions
+//! - `ip_queue_xmit`: Track on-the-wire bytes sent (headers + retransmits)
+//! - `tcp_retransmit_skb`: Track retransmissions, by tracepoint or, on
+//!   kernels without it, by kprobe (never both, or every retransmit counts
+//!   twice; no fentry)
+//! - `tcp_reset` / `tcp_send_active_reset`: Count RSTs received / sent
+//! - `sched_process_fork` / `sched_process_exit`: Follow children of monitored PIDs
+//! - `sched_process_exec`: Record each process's full executable path
+//! - `inet_sock_set_state`: Count connects that never reach ESTABLISHED;
+//!   note whether TCP timestamps were negotiated once they do
+//! - `net_dev_xmit` / `netif_receive_skb`: Sum bytes per network interface
+//!   (attached only with interface metrics on)
+//! - `kfree_skb`: Count TCP segments dropped by the PAWS check (attached only
+//!   with PAWS drop counting on)
+//!
+//! Each kprobe on a `tcp_*` function and on `ip_queue_xmit` has an
+//! `fentry_*` twin (`fexit_tcp_recvmsg` for the `tcp_recvmsg` pair) sharing
+//! its body; userspace attaches the faster fentry/fexit program when the
+//! kernel supports it and the kprobe otherwise.
+//!
+//! The counting probes skip a random share of calls when their
+//! `SidecarConfig::sample_rates` entry (indexed by `probe`) is under 100.
+//!
+//! # Policy Hooks
+//! - `policy_connect4` / `policy_connect6`: cgroup `connect()` hooks reporting
+//!   attempts to denylisted destinations (observe only, never deny)
+//!
+//! # Tail-Called Programs
+//! - `parse_http` / `parse_tls` / `parse_dns`: L7 parsers, reached from
+//!   `tcp_sendmsg` through `L7_PARSERS` so each stays under the verifier limit;
+//!   `parse_http` is also reached from the `tcp_recvmsg` return probe
 //!
 //! # Maps
-//! - `CONNECTIONS`: Per-connection metrics (HashMap)
+//! - `CONNECTIONS`: Per-connection metrics (LruHashMap)
+//! - `CONN_STATS`: Insert/close/failed-insert counters for map accounting (PerCpuArray)
+//! - `ACTIVE_CONNS`: Tracked connections now and at their peak (Array)
+//! - `FILTER_DECISIONS`: Outcomes of the process/connection filter checks (PerCpuArray)
 //! - `CONFIG`: Runtime configuration (Array)
+//! - `TARGET_PIDS`: Monitored process tree (HashMap)
+//! - `TARGET_CGROUPS`: Monitored cgroup subtree (HashMap)
+//! - `ALWAYS_TRACK`: Destination prefixes tracked regardless of filters (LpmTrie)
+//! - `PROCESS_EXES`: Executable path per PID, captured at exec (HashMap)
+//! - `CONNECT_FAILURES`: Failed connect attempts per destination/reason (HashMap)
+//! - `IFACE_TRAFFIC`: Bytes received/sent per network interface (LruPerCpuHashMap)
+//! - `UDP_SIZES`: Sizes of the UDP datagrams sent and received (PerCpuArray)
+//! - `PAWS_DROPS`: Segments dropped by the PAWS check, host-wide (PerCpuArray)
+//! - `ARG_READ_ERRORS`: kprobe arguments that couldn't be read (PerCpuArray)
 //! - `EVENTS`: HTTP events perf buffer
+//! - `CLOSE_EVENTS`: Final per-connection totals perf buffer
+//! - `POLICY_DENYLIST`: Denied destination prefixes to rule index (LpmTrie)
+//! - `POLICY_EVENTS`: Denylisted connect attempts perf buffer
+//! - `L7_PARSERS`: L7 parser programs by protocol (ProgramArray)
+//! - `L7_SCRATCH`: Send/receive-path hand-off to the L7 parsers (PerCpuArray)
+//! - `RECV_ARGS`: `tcp_recvmsg` arguments kept for its return probe (HashMap)
+//! - `NEW_SOCKS`: Sockets connected or accepted since startup (LruHashMap)
+//! - `SOCK_COOKIES`: Stable identity of each tracked socket (LruHashMap)
+//! - `HTTP_STREAMS`: In-flight HTTP requests per connection, by stream (LruHashMap)
+//! - `BYTE_HISTORY`: Bytes sent/received per minute over the last hour (PerCpuArray)
+//! - `SYSCALL_FDS`: Descriptor of each thread's latest socket system call (LruHashMap)
+//! - `PAYLOAD_EVENTS`: Sampled payload captures perf buffer
+//! - `PAYLOAD_SCRATCH`: `PayloadEvent` under construction (PerCpuArray)
+//! - `CAPTURE_BUDGET`: Payload captures taken this second (Array)
+//! - `GAP_EVENTS`: Sampled gaps between sends/receives perf buffer
+//! - `GAP_BUDGET`: Gap reports sent this second (Array)
+//! - `TLS_HELLO_EVENTS`: Outgoing TLS ClientHellos perf buffer
+//! - `TLS_HELLO_SCRATCH`: `TlsHelloEvent` under construction (PerCpuArray)
+//! - `STACK_TRACES`: Kernel and user call stacks of sampled connects (StackTrace)
+//! - `STACK_EVENTS`: Sampled connects with their stack ids perf buffer
+//! - `STACK_BUDGET`: Stack captures taken this second (Array)
+//! - `.rodata.layout`: Shared struct layout fingerprint (read-only)
+//! - `.rodata.arch`: Architecture kprobe arguments are read for (read-only)
+//!
+//! All but `EXE_SCRATCH`, `L7_PARSERS`, `L7_SCRATCH`, `PAYLOAD_SCRATCH`,
+//! `CAPTURE_BUDGET`, `GAP_BUDGET`, `TLS_HELLO_SCRATCH` and `STACK_BUDGET` are
+//! pinned by name and survive a program reload.
 
 #![no_std]
 #![no_main]
 
 use aya_ebpf::{
-    bindings::BPF_F_NO_PREALLOC,
-    helpers::{bpf_get_current_pid_tgid, bpf_ktime_get_ns, bpf_probe_read_kernel},
-    macros::{kprobe, kretprobe, map, tracepoint},
-    maps::{Array, HashMap, PerfEventArray},
-    programs::{ProbeContext, RetProbeContext, TracePointContext},
+    bindings::{
+        BPF_F_FAST_STACK_CMP, BPF_F_MMAPABLE, BPF_F_NO_PREALLOC, BPF_F_USER_STACK, BPF_NOEXIST,
+    },
+    helpers::{
+        bpf_get_current_cgroup_id, bpf_get_current_pid_tgid, bpf_get_current_task,
+        bpf_get_prandom_u32, bpf_ktime_get_ns, bpf_probe_read_kernel,
+        bpf_probe_read_kernel_str_bytes, bpf_probe_read_user, bpf_probe_read_user_buf,
+    },
+    macros::{cgroup_sock_addr, fentry, fexit, kprobe, kretprobe, map, tracepoint},
+    maps::{
+        lpm_trie::Key, Array, HashMap, LpmTrie, LruHashMap, LruPerCpuHashMap, PerCpuArray,
+        PerfEventArray, ProgramArray, StackTrace,
+    },
+    programs::{
+        FEntryContext, FExitContext, ProbeContext, RetProbeContext, SockAddrContext,
+        TracePointContext,
+    },
     EbpfContext,
 };
-use aya_log_ebpf::{debug, info, warn};
-use sidecar_common::{ConnKey, ConnMetrics, HttpEvent, SidecarConfig};
+use aya_log_ebpf::{debug, warn};
+use core::ptr::addr_of_mut;
+use core::sync::atomic::{AtomicU64, Ordering};
+use sidecar_common::{
+    active_conn, address_family, conn_flag, conn_stat, connect_failure, direction, filter_decision,
+    http_method, http_path, http_version, l7_proto, probe, ByteHistory, ConnCloseEvent,
+    ConnFailKey, ConnKey, ConnMetrics, ConnUpsert, ConnectStackEvent, GapEvent, HttpEvent,
+    IfaceKey, IfaceTraffic, LayoutInfo, PayloadEvent, PolicyEvent, PolicyTarget, ProbeError,
+    SidecarConfig, TlsHelloEvent, UdpSizeHistogram, BPF_ARCH_LEN, CC_NAME_LEN, EXE_PATH_LEN,
+    HISTORY_BUCKET_NS, HISTORY_MINUTES, PAYLOAD_CAPTURE_MAX, RTT_SAMPLE_MAX_US, RTT_WINDOW_SAMPLES,
+    TLS_HELLO_MAX, UDP_SIZE_BUCKETS,
+};
+
+/// Payload bytes copied from each send for L7 protocol detection
+const L7_PEEK_LEN: usize = 16;
+
+/// What the send or receive path already knows about a message, for the L7
+/// parsers
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct L7Scratch {
+    /// Connection the message is sent or received on
+    conn: ConnKey,
+    /// Bytes passed to `tcp_sendmsg`, or returned by `tcp_recvmsg`
+    size: u64,
+    /// User address of the payload, for parsers that read past `head`
+    buf: u64,
+    /// Bytes readable from `buf` in one piece: `size`, or less when the
+    /// message spans several iovecs
+    buf_len: u64,
+    /// Start of the payload; bytes past `size` are whatever followed the
+    /// buffer in user memory
+    head: [u8; L7_PEEK_LEN],
+    /// Send-queue state when the message was sent, for `HttpEvent`; zero for
+    /// received messages
+    queue: SendQueue,
+    /// Which way the message went (`direction`)
+    direction: u8,
+}
+
+/// What `tcp_recvmsg` was called with, kept until it returns
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct RecvArgs {
+    conn: ConnKey,
+    /// User address of the receive buffer; 0 when neither HTTP parsing nor
+    /// payload capture wants it
+    buf: u64,
+    /// Size of the first piece of the receive buffer
+    buf_len: u64,
+    /// Whether the HTTP parser looks at the received bytes
+    http: u8,
+    /// Whether this receive was sampled for payload capture
+    capture: u8,
+    _padding: [u8; 6],
+}
+
+/// Payload captures, gap reports or stack captures taken by all CPUs in the
+/// current second (see `take_budget`)
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct CaptureBudget {
+    /// `bpf_ktime_get_ns()` second the count is for
+    second: u64,
+    used: u64,
+}
+
+/// Requests timed at once per connection; when all are taken, a new request
+/// replaces the oldest. Bounded so the slot searches stay verifier-friendly.
+const MAX_HTTP_STREAMS: usize = 8;
+
+/// HTTP/2 frames looked at per message; a message with more loses frame sync
+const MAX_H2_FRAMES: usize = 8;
+
+/// An HTTP request waiting for its response
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct PendingRequest {
+    /// When the request was seen; 0 marks a free slot
+    start_ns: u64,
+    /// HTTP/2 stream id, 0 for HTTP/1.x
+    stream_id: u32,
+    /// `http_method` of the request
+    method: u8,
+    /// Which way the request went (`direction`); the response goes the other way
+    direction: u8,
+    _padding: u16,
+    /// Send-queue state when the request was sent
+    queue: SendQueue,
+    /// `HttpEvent::path_hash` of the request
+    path_hash: u64,
+    /// `HttpEvent::path_check` of the request
+    path_check: u32,
+    _padding2: u32,
+}
+
+/// HTTP state of one connection: requests in flight, keyed by stream id,
+/// and HTTP/2 framing progress
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct HttpStreams {
+    /// The HTTP/2 connection preface was seen; messages are frames from then on
+    h2: u8,
+    /// Direction the preface went (`direction`): the client's side
+    h2_client: u8,
+    /// Frame sync lost, per direction; no more frames are parsed that way
+    h2_lost: [u8; 2],
+    /// Bytes of a frame that continued past the last message, per direction
+    h2_skip: [u32; 2],
+    pending: [PendingRequest; MAX_HTTP_STREAMS],
+}
+
+/// How much data sits ahead of a new message and how fast it can drain
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct SendQueue {
+    /// Unsent plus unacknowledged bytes (`write_seq - snd_una`)
+    queued_bytes: u32,
+    srtt_us: u32,
+    snd_cwnd: u32,
+    mss: u32,
+}
 
 // ============================================================================
 // eBPF Maps - Shared data structures between kernel and userspace
 // ============================================================================
+//
+// Maps holding state are `pinned`: userspace loads them under its pin
+// directory, so an object loaded by a reload takes over the same maps. The
+// scratch buffers and `L7_PARSERS` belong to each object.
 
 /// Per-connection metrics storage
 /// Key: ConnKey (4-tuple), Value: ConnMetrics
+/// LRU so a full map evicts the least recently active connection instead of
+/// rejecting new ones. Long-idle connections may therefore disappear before
+/// they close; if they become active again they are re-created with fresh
+/// counters and a new start time.
+#[map]
+static CONNECTIONS: LruHashMap<ConnKey, ConnMetrics> = LruHashMap::pinned(10240, 0);
+
+/// Insert/close/failed-insert counters, indexed by `conn_stat`
+/// Userspace derives LRU evictions as inserted - closed - live entries.
+#[map]
+static CONN_STATS: PerCpuArray<u64> = PerCpuArray::pinned(conn_stat::COUNT, 0);
+
+/// Concurrently tracked connections and their high-water mark, indexed by
+/// `active_conn`; one value for all CPUs. Userspace maps it to reset both
+/// atomically at each read.
+#[map]
+static ACTIVE_CONNS: Array<u64> = Array::pinned(active_conn::COUNT, BPF_F_MMAPABLE);
+
+/// Filter check outcomes, indexed by `filter_decision`
+#[map]
+static FILTER_DECISIONS: PerCpuArray<u64> = PerCpuArray::pinned(filter_decision::COUNT, 0);
+
+/// Runtime configuration from userspace
+/// Index 0 contains the current SidecarConfig
+#[map]
+static CONFIG: Array<SidecarConfig> = Array::pinned(1, 0);
+
+/// Monitored PIDs when `target_pid` is set
+/// Seeded with the target by userspace; forked children are added and exited
+/// tasks removed by the sched tracepoints below. Value is unused.
+#[map]
+static TARGET_PIDS: HashMap<u32, u8> = HashMap::pinned(4096, 0);
+
+/// Monitored cgroup ids when `target_cgroup` is set
+/// The target cgroup and all its descendants; kept in sync by userspace as
+/// child cgroups come and go. Value is unused.
+#[map]
+static TARGET_CGROUPS: HashMap<u64, u8> = HashMap::pinned(4096, 0);
+
+/// Executable path of each traced process, recorded at exec
+/// Key: PID, Value: NUL-terminated path. Removed when the process exits.
+#[map]
+static PROCESS_EXES: HashMap<u32, [u8; EXE_PATH_LEN]> = HashMap::pinned(8192, 0);
+
+/// Staging buffer for `PROCESS_EXES` values, too large for the BPF stack
+#[map]
+static EXE_SCRATCH: PerCpuArray<[u8; EXE_PATH_LEN]> = PerCpuArray::with_max_entries(1, 0);
+
+/// Failed outbound connection attempts
+/// Key: destination + reason, Value: cumulative count
+#[map]
+static CONNECT_FAILURES: HashMap<ConnFailKey, u64> = HashMap::pinned(4096, BPF_F_NO_PREALLOC);
+
+/// Bytes through each network interface, summed over CPUs by userspace
+/// LRU so interfaces that come and go (container veths) can't fill it.
+#[map]
+static IFACE_TRAFFIC: LruPerCpuHashMap<IfaceKey, IfaceTraffic> = LruPerCpuHashMap::pinned(1024, 0);
+
+/// Sizes of the UDP datagrams sent and received, by `direction`; summed
+/// over CPUs by userspace
+#[map]
+static UDP_SIZES: PerCpuArray<UdpSizeHistogram> = PerCpuArray::pinned(2, 0);
+
+/// TCP segments dropped by the PAWS check, on every socket of the host
+#[map]
+static PAWS_DROPS: PerCpuArray<u64> = PerCpuArray::pinned(1, 0);
+
+/// kprobe arguments and return values the probe context couldn't produce,
+/// e.g. an argument index past the registers the target arch passes
+/// arguments in
+#[map]
+static ARG_READ_ERRORS: PerCpuArray<u64> = PerCpuArray::pinned(1, 0);
+
+/// HTTP events sent to userspace via perf buffer
+#[map]
+static EVENTS: PerfEventArray<HttpEvent> = PerfEventArray::pinned(0);
+
+/// Connection close events sent to userspace via perf buffer
+#[map]
+static CLOSE_EVENTS: PerfEventArray<ConnCloseEvent> = PerfEventArray::pinned(0);
+
+/// Destinations tracked regardless of the PID, cgroup, port and loopback
+/// filters, written by userspace
+/// Key: port + address prefix (see `PolicyTarget`), Value: unused
+#[map]
+static ALWAYS_TRACK: LpmTrie<PolicyTarget, u8> = LpmTrie::pinned(256, BPF_F_NO_PREALLOC);
+
+/// Denied destinations, written by userspace
+/// Key: port + address prefix (see `PolicyTarget`), Value: rule index
+#[map]
+static POLICY_DENYLIST: LpmTrie<PolicyTarget, u32> = LpmTrie::pinned(1024, BPF_F_NO_PREALLOC);
+
+/// Connect attempts matching `POLICY_DENYLIST`, sent via perf buffer
+#[map]
+static POLICY_EVENTS: PerfEventArray<PolicyEvent> = PerfEventArray::pinned(0);
+
+/// L7 parser programs, indexed by `l7_proto`
+/// Userspace only fills the slots of enabled parsers.
+#[map]
+static L7_PARSERS: ProgramArray = ProgramArray::with_max_entries(l7_proto::COUNT, 0);
+
+/// Hand-off from `trace_tcp_sendmsg` / `trace_tcp_recvmsg_ret` to the parser
+/// they tail-call
+/// Per-CPU is enough: the tail call runs next, on the same CPU.
 #[map]
-static CONNECTIONS: HashMap<ConnKey, ConnMetrics> =
-    HashMap::with_max_entries(10240, BPF_F_NO_PREALLOC);
+static L7_SCRATCH: PerCpuArray<L7Scratch> = PerCpuArray::with_max_entries(1, 0);
+
+/// `tcp_recvmsg` arguments on tracked connections, from entry to return
+/// Key: pid_tgid of the receiving thread
+#[map]
+static RECV_ARGS: HashMap<u64, RecvArgs> = HashMap::pinned(10240, 0);
+
+/// Sockets seen at `tcp_connect` or accept while `new_connections_only` is
+/// set; the only ones send/recv may create entries for then
+/// Key: `struct sock` address, Value: unused. Removed when the socket closes.
+#[map]
+static NEW_SOCKS: LruHashMap<u64, u8> = LruHashMap::pinned(10240, 0);
+
+/// Identity of each socket with an entry, for `ConnMetrics::cookie`
+/// Key: `struct sock` address, Value: cookie. Removed when the socket closes.
+#[map]
+static SOCK_COOKIES: LruHashMap<u64, u64> = LruHashMap::pinned(10240, 0);
+
+/// In-flight HTTP requests of each connection, so concurrent HTTP/2 streams
+/// are timed separately
+/// LRU like `CONNECTIONS`; removed when the connection closes.
+#[map]
+static HTTP_STREAMS: LruHashMap<ConnKey, HttpStreams> = LruHashMap::pinned(10240, 0);
+
+/// Per-minute byte ring of each CPU (see `ByteHistory`), so userspace can
+/// read the last hour of traffic at once; summed over CPUs by userspace
+#[map]
+static BYTE_HISTORY: PerCpuArray<ByteHistory> = PerCpuArray::pinned(1, 0);
+
+/// Descriptor passed to each thread's latest socket I/O system call (by
+/// TID), for `ConnMetrics::fd`. Only filled with `--fd-label`
+#[map]
+static SYSCALL_FDS: LruHashMap<u32, i32> = LruHashMap::with_max_entries(10240, 0);
+
+/// Sampled payload captures sent to userspace via perf buffer
+#[map]
+static PAYLOAD_EVENTS: PerfEventArray<PayloadEvent> = PerfEventArray::pinned(0);
+
+/// `PayloadEvent` being filled in, too large for the BPF stack
+#[map]
+static PAYLOAD_SCRATCH: PerCpuArray<PayloadEvent> = PerCpuArray::with_max_entries(1, 0);
+
+/// Rate limit state of payload capture, shared by all CPUs (see
+/// `capture_sampled`)
+#[map]
+static CAPTURE_BUDGET: Array<CaptureBudget> = Array::with_max_entries(1, 0);
+
+/// Sampled gaps between sends/receives for userspace (see `touch_conn`)
+#[map]
+static GAP_EVENTS: PerfEventArray<GapEvent> = PerfEventArray::pinned(0);
+
+/// Rate limit state of gap reports, shared by all CPUs (see `touch_conn`)
+#[map]
+static GAP_BUDGET: Array<CaptureBudget> = Array::with_max_entries(1, 0);
+
+/// Outgoing TLS ClientHellos for fingerprinting in userspace (see
+/// `try_parse_tls`)
+#[map]
+static TLS_HELLO_EVENTS: PerfEventArray<TlsHelloEvent> = PerfEventArray::pinned(0);
+
+/// `TlsHelloEvent` being filled in, too large for the BPF stack
+#[map]
+static TLS_HELLO_SCRATCH: PerCpuArray<TlsHelloEvent> = PerCpuArray::with_max_entries(1, 0);
+
+/// Kernel and user call stacks of sampled connects, by the id
+/// `bpf_get_stackid` gives them (see `report_connect_stack`)
+#[map]
+static STACK_TRACES: StackTrace = StackTrace::pinned(2048, 0);
+
+/// Sampled connects for userspace to attribute to their stacks (see
+/// `report_connect_stack`)
+#[map]
+static STACK_EVENTS: PerfEventArray<ConnectStackEvent> = PerfEventArray::pinned(0);
+
+/// Rate limit state of stack captures, shared by all CPUs (see
+/// `report_connect_stack`)
+#[map]
+static STACK_BUDGET: Array<CaptureBudget> = Array::with_max_entries(1, 0);
+
+/// Layout fingerprint of the shared types this object was compiled against.
+/// Lives in its own rodata section so the loader sees it as a frozen,
+/// single-entry array map named `.rodata.layout`.
+#[no_mangle]
+#[used]
+#[link_section = ".rodata.layout"]
+static SIDECAR_LAYOUT: LayoutInfo = LayoutInfo::current();
+
+/// Architecture whose calling convention the probes read arguments with (see
+/// build.rs), NUL-padded; the loader refuses to attach them on another one.
+#[no_mangle]
+#[used]
+#[link_section = ".rodata.arch"]
+static SIDECAR_ARCH: [u8; BPF_ARCH_LEN] = bpf_arch(env!("SIDECAR_BPF_TARGET_ARCH"));
+
+const fn bpf_arch(name: &str) -> [u8; BPF_ARCH_LEN] {
+    let name = name.as_bytes();
+    let mut arch = [0u8; BPF_ARCH_LEN];
+    let mut i = 0;
+    while i < name.len() && i < BPF_ARCH_LEN {
+        arch[i] = name[i];
+        i += 1;
+    }
+    arch
+}
+
+// ============================================================================
+// Helper Functions
+// ============================================================================
+
+/// Whether this call of `probe` is counted, at its rate in `sample_rates`
+#[inline(always)]
+fn sampled(probe: u32) -> bool {
+    let rate = match CONFIG.get(0) {
+        Some(config) => config.sample_rates[probe as usize] as u32,
+        None => return true,
+    };
+    rate >= 100 || unsafe { bpf_get_prandom_u32() } % 100 < rate
+}
+
+/// Check if we should trace this process based on config
+#[inline(always)]
+fn should_trace(ctx: &impl EbpfContext) -> bool {
+    let config = match unsafe { CONFIG.get(0) } {
+        Some(c) => c,
+        None => return true, // No config = trace everything
+    };
+
+    if config.target_pid == 0 && config.target_cgroup == 0 {
+        bump_filter_decision(filter_decision::NO_FILTER);
+        return true;
+    }
+
+    // If target_pid is set, only trace that PID and its descendants
+    if config.target_pid != 0 {
+        let pid = (bpf_get_current_pid_tgid() >> 32) as u32;
+        if pid != config.target_pid && unsafe { TARGET_PIDS.get(&pid) }.is_none() {
+            bump_filter_decision(filter_decision::FILTERED_PID);
+            return false;
+        }
+        bump_filter_decision(filter_decision::MATCHED_PID);
+    }
+
+    // If target_cgroup is set, only trace tasks somewhere in its subtree
+    if config.target_cgroup != 0 {
+        let cgroup = unsafe { bpf_get_current_cgroup_id() };
+        if unsafe { TARGET_CGROUPS.get(&cgroup) }.is_none() {
+            bump_filter_decision(filter_decision::FILTERED_CGROUP);
+            return false;
+        }
+        bump_filter_decision(filter_decision::MATCHED_CGROUP);
+    }
+
+    true
+}
+
+/// Read the tracepoint record field at `offset`
+#[inline(always)]
+fn tp_field<T>(ctx: &TracePointContext, offset: usize) -> Result<T, ProbeError> {
+    unsafe { ctx.read_at(offset) }.map_err(|_| ProbeError::TracepointReadFailed)
+}
+
+/// Count a kprobe argument or return value the probe context couldn't
+/// produce, for `ctx.arg(n).ok_or_else(arg_read_error)`.
+///
+/// Which register holds argument `n` depends on the target arch's calling
+/// convention; aya-ebpf picks it from `bpf_target_arch` (see build.rs), and
+/// has none for arguments past the ones passed in registers (6 on x86_64, 8
+/// on aarch64 and riscv64)
+#[inline(always)]
+fn arg_read_error() -> ProbeError {
+    if let Some(count) = ARG_READ_ERRORS.get_ptr_mut(0) {
+        unsafe { *count += 1 };
+    }
+    ProbeError::ArgMissing
+}
+
+/// Whether a probe should record this connection: always for an
+/// `ALWAYS_TRACK` destination, otherwise only if both the process and the
+/// connection filters pass
+#[inline(always)]
+fn should_track(ctx: &impl EbpfContext, key: &ConnKey) -> bool {
+    is_always_tracked(key) || (should_trace(ctx) && should_trace_conn(key))
+}
+
+/// Check if the connection's destination is in `ALWAYS_TRACK`
+#[inline(always)]
+fn is_always_tracked(key: &ConnKey) -> bool {
+    match CONFIG.get(0) {
+        Some(config) if config.always_track != 0 => match key.dst_ipv4() {
+            // Rules are IPv4
+            Some(dst_ip) => match_destination(&ALWAYS_TRACK, dst_ip, key.dst_port).is_some(),
+            None => false,
+        },
+        _ => false,
+    }
+}
+
+/// Look a destination up in a `PolicyTarget` trie: a rule for its port
+/// first, then an any-port (0) rule
+#[inline(always)]
+fn match_destination<V>(trie: &LpmTrie<PolicyTarget, V>, dst_ip: u32, dst_port: u16) -> Option<&V> {
+    // Prefix covering the port, padding and the whole address
+    const FULL_PREFIX: u32 = 64;
+
+    let mut target = PolicyTarget {
+        dst_port,
+        _padding: 0,
+        dst_ip,
+    };
+    if let Some(value) = trie.get(&Key::new(FULL_PREFIX, target)) {
+        return Some(value);
+    }
+    target.dst_port = 0;
+    trie.get(&Key::new(FULL_PREFIX, target))
+}
+
+/// Check if we should trace this connection based on config
+#[inline(always)]
+fn should_trace_conn(key: &ConnKey) -> bool {
+    let config = match CONFIG.get(0) {
+        Some(c) => c,
+        None => return true,
+    };
+
+    let loopback = is_loopback(key, &key.src_ip) && is_loopback(key, &key.dst_ip);
+    if config.exclude_loopback != 0 && loopback {
+        bump_filter_decision(filter_decision::FILTERED_LOOPBACK);
+        return false;
+    }
+
+    // With more ports than slots the kernel can't tell which unlisted
+    // connections are wanted; userspace filters those at export
+    if config.num_target_ports != 0 && config.ports_overflow == 0 && !in_target_ports(config, key) {
+        bump_filter_decision(filter_decision::FILTERED_PORT);
+        return false;
+    }
+
+    true
+}
+
+/// Check if either end of the connection uses one of the `target_ports`
+#[inline(always)]
+fn in_target_ports(config: &SidecarConfig, key: &ConnKey) -> bool {
+    for i in 0..config.target_ports.len() {
+        if i >= config.num_target_ports as usize {
+            break;
+        }
+        let port = config.target_ports[i];
+        if port == key.src_port || port == key.dst_port {
+            return true;
+        }
+    }
+
+    false
+}
+
+/// Loopback check on one of `key`'s addresses: 127.0.0.0/8 for IPv4, ::1
+/// for IPv6
+#[inline(always)]
+fn is_loopback(key: &ConnKey, ip: &[u8; 16]) -> bool {
+    if key.address_family != address_family::INET6 {
+        return ip[0] == 127;
+    }
+    for byte in &ip[..15] {
+        if *byte != 0 {
+            return false;
+        }
+    }
+    ip[15] == 1
+}
+
+/// The first 4 bytes of an address, all of an IPv4 one, for debug logs
+#[inline(always)]
+fn log_ip(ip: &[u8; 16]) -> u32 {
+    u32::from_be_bytes([ip[0], ip[1], ip[2], ip[3]])
+}
+
+/// Extract connection key from sock struct pointer
+///
+/// # Safety
+/// Caller must ensure sock pointer is valid
+#[inline(always)]
+unsafe fn read_conn_key_from_sock(sock: *const u8) -> Result<ConnKey, ProbeError> {
+    // Offsets into struct sock -> __sk_common
+    // These are for Linux 5.x+ kernels - may need adjustment
+    // In production, use CO-RE (Compile Once Run Everywhere) for portability
+    const SK_COMMON_OFFSET: usize = 0;
+    const SKADDR_OFFSET: usize = 4; // __sk_common.skc_rcv_saddr
+    const DADDR_OFFSET: usize = 0; // __sk_common.skc_daddr
+    const SPORT_OFFSET: usize = 14; // __sk_common.skc_num (source port)
+    const DPORT_OFFSET: usize = 12; // __sk_common.skc_dport (dest port, network order)
+    const FAMILY_OFFSET: usize = 16; // __sk_common.skc_family
+    const V6_DADDR_OFFSET: usize = 56; // __sk_common.skc_v6_daddr
+    const V6_SADDR_OFFSET: usize = 72; // __sk_common.skc_v6_rcv_saddr
+
+    let common = sock.add(SK_COMMON_OFFSET);
+
+    let src_port = bpf_probe_read_kernel(common.add(SPORT_OFFSET) as *const u16)
+        .map_err(|_| ProbeError::SockReadFailed)?;
+    let dst_port_be = bpf_probe_read_kernel(common.add(DPORT_OFFSET) as *const u16)
+        .map_err(|_| ProbeError::SockReadFailed)?;
+    let family = bpf_probe_read_kernel(common.add(FAMILY_OFFSET) as *const u16)
+        .map_err(|_| ProbeError::SockReadFailed)?;
+
+    // IPv6 sockets talking to v4-mapped addresses have the IPv4 fields set
+    // too; they are keyed as IPv4 so filters and rules apply to them
+    if family == address_family::INET6 as u16 {
+        let dst_ip = bpf_probe_read_kernel(common.add(V6_DADDR_OFFSET) as *const [u8; 16])
+            .map_err(|_| ProbeError::SockReadFailed)?;
+        if !is_v4_mapped(&dst_ip) {
+            let src_ip = bpf_probe_read_kernel(common.add(V6_SADDR_OFFSET) as *const [u8; 16])
+                .map_err(|_| ProbeError::SockReadFailed)?;
+            return Ok(ConnKey {
+                src_ip,
+                dst_ip,
+                src_port,
+                dst_port: u16::from_be(dst_port_be),
+                address_family: address_family::INET6,
+                _padding: [0; 3],
+            });
+        }
+    }
+
+    let src_ip = bpf_probe_read_kernel(common.add(SKADDR_OFFSET) as *const u32)
+        .map_err(|_| ProbeError::SockReadFailed)?;
+    let dst_ip = bpf_probe_read_kernel(common.add(DADDR_OFFSET) as *const u32)
+        .map_err(|_| ProbeError::SockReadFailed)?;
+
+    Ok(ConnKey::ipv4(
+        src_ip,
+        dst_ip,
+        src_port,
+        u16::from_be(dst_port_be),
+    ))
+}
+
+/// Whether an IPv6 address is v4-mapped (`::ffff:a.b.c.d`)
+#[inline(always)]
+fn is_v4_mapped(ip: &[u8; 16]) -> bool {
+    for byte in &ip[..10] {
+        if *byte != 0 {
+            return false;
+        }
+    }
+    ip[10] == 0xff && ip[11] == 0xff
+}
+
+/// Read the smoothed RTT (microseconds) from a tcp_sock
+///
+/// # Safety
+/// Caller must ensure sock pointer is valid
+#[inline(always)]
+unsafe fn read_srtt_us(sock: *const u8) -> Result<u64, ProbeError> {
+    // Offset of tcp_sock.srtt_us - kernel-version specific, like the
+    // __sk_common offsets above. The kernel stores it left-shifted by 3.
+    const SRTT_US_OFFSET: usize = 1616;
+
+    let srtt = bpf_probe_read_kernel(sock.add(SRTT_US_OFFSET) as *const u32)
+        .map_err(|_| ProbeError::SockReadFailed)?;
+    Ok((srtt >> 3) as u64)
+}
+
+/// Read the count of segments DSACKed as duplicates from a tcp_sock
+///
+/// # Safety
+/// Caller must ensure sock pointer is valid
+#[inline(always)]
+unsafe fn read_dsack_dups(sock: *const u8) -> Result<u32, ProbeError> {
+    // Offset of tcp_sock.dsack_dups (4.19+) - kernel-version specific, like
+    // the offsets above
+    const DSACK_DUPS_OFFSET: usize = 1864;
+
+    bpf_probe_read_kernel(sock.add(DSACK_DUPS_OFFSET) as *const u32)
+        .map_err(|_| ProbeError::SockReadFailed)
+}
+
+/// Whether TCP timestamps were negotiated on a tcp_sock
+/// (`rx_opt.tstamp_ok`); known once the connection is established
+///
+/// # Safety
+/// Caller must ensure sock pointer is valid
+#[inline(always)]
+unsafe fn read_tstamp_ok(sock: *const u8) -> Result<bool, ProbeError> {
+    // Offset of the tcp_sock.rx_opt bitfield byte holding saw_tstamp (bit 0)
+    // and tstamp_ok (bit 1) - kernel-version specific, like the offsets above
+    const RX_OPT_FLAGS_OFFSET: usize = 1708;
+    const TSTAMP_OK: u8 = 1 << 1;
+
+    let flags = bpf_probe_read_kernel(sock.add(RX_OPT_FLAGS_OFFSET))
+        .map_err(|_| ProbeError::SockReadFailed)?;
+    Ok(flags & TSTAMP_OK != 0)
+}
+
+/// Read the current send MSS and path MTU from a tcp_sock
+///
+/// # Safety
+/// Caller must ensure sock pointer is valid
+#[inline(always)]
+unsafe fn read_mss_pmtu(sock: *const u8) -> Result<(u32, u32), ProbeError> {
+    // Offsets of inet_connection_sock.icsk_pmtu_cookie and tcp_sock.mss_cache
+    // - kernel-version specific, like the offsets above
+    const PMTU_COOKIE_OFFSET: usize = 1244;
+    const MSS_CACHE_OFFSET: usize = 1548;
+
+    let pmtu = bpf_probe_read_kernel(sock.add(PMTU_COOKIE_OFFSET) as *const u32)
+        .map_err(|_| ProbeError::SockReadFailed)?;
+    let mss = bpf_probe_read_kernel(sock.add(MSS_CACHE_OFFSET) as *const u32)
+        .map_err(|_| ProbeError::SockReadFailed)?;
+    Ok((mss, pmtu))
+}
+
+/// Read the socket's `SO_PRIORITY`
+///
+/// # Safety
+/// Caller must ensure sock pointer is valid
+#[inline(always)]
+unsafe fn read_sk_priority(sock: *const u8) -> Result<u32, ProbeError> {
+    // Offset of sock.sk_priority - kernel-version specific, like the offsets
+    // above
+    const SK_PRIORITY_OFFSET: usize = 520;
+
+    bpf_probe_read_kernel(sock.add(SK_PRIORITY_OFFSET) as *const u32)
+        .map_err(|_| ProbeError::SockReadFailed)
+}
+
+/// Read the IPv6 flow label of an `AF_INET6` socket: the one the socket set
+/// (`inet6_sk(sk)->flow_label`, e.g. through `IPV6_FLOWLABEL_MGR`), else the
+/// one the kernel derives from the socket's transmit hash (`sk_txhash`) the
+/// way `ip6_make_flowlabel` does with the default `auto_flowlabels`. A
+/// rehash after a timeout changes the latter; the value read is kept.
+///
+/// # Safety
+/// Caller must ensure sock pointer is valid
+#[inline(always)]
+unsafe fn read_flow_label(sock: *const u8) -> Result<u32, ProbeError> {
+    // Offsets of inet_sock.pinet6, ipv6_pinfo.flow_label and sock.sk_txhash
+    // - kernel-version specific, like the offsets above
+    const PINET6_OFFSET: usize = 784;
+    const NP_FLOW_LABEL_OFFSET: usize = 16;
+    const SK_TXHASH_OFFSET: usize = 564;
+    // The 20 label bits of a host-order flowinfo word
+    const FLOW_LABEL_MASK: u32 = 0x000f_ffff;
+
+    let np = bpf_probe_read_kernel(sock.add(PINET6_OFFSET) as *const *const u8)
+        .map_err(|_| ProbeError::SockReadFailed)?;
+    if !np.is_null() {
+        let label = bpf_probe_read_kernel(np.add(NP_FLOW_LABEL_OFFSET) as *const u32)
+            .map_err(|_| ProbeError::SockReadFailed)?;
+        let label = u32::from_be(label) & FLOW_LABEL_MASK;
+        if label != 0 {
+            return Ok(label);
+        }
+    }
+    // ip6_make_flowlabel: the hash rotated by 16, stored as a __be32
+    let hash = bpf_probe_read_kernel(sock.add(SK_TXHASH_OFFSET) as *const u32)
+        .map_err(|_| ProbeError::SockReadFailed)?;
+    Ok(u32::from_be(hash.rotate_left(16)) & FLOW_LABEL_MASK)
+}
+
+/// Copy the congestion control algorithm name into `name`
+///
+/// # Safety
+/// Caller must ensure sock pointer is valid
+#[inline(always)]
+unsafe fn read_cc_name(sock: *const u8, name: &mut [u8; CC_NAME_LEN]) -> Result<(), ProbeError> {
+    // Offsets of inet_connection_sock.icsk_ca_ops and tcp_congestion_ops.name
+    // - kernel-version specific, like the offsets above
+    const CA_OPS_OFFSET: usize = 1248;
+    const CA_NAME_OFFSET: usize = 88;
+
+    let ops = bpf_probe_read_kernel(sock.add(CA_OPS_OFFSET) as *const *const u8)
+        .map_err(|_| ProbeError::SockReadFailed)?;
+    if ops.is_null() {
+        return Err(ProbeError::SockReadFailed);
+    }
+    // Bounded by the buffer and always NUL-terminated
+    bpf_probe_read_kernel_str_bytes(ops.add(CA_NAME_OFFSET), name)
+        .map_err(|_| ProbeError::StrReadFailed)?;
+    Ok(())
+}
+
+/// Read the inode number of the socket's network namespace
+///
+/// Follows `sock_net(sk)->ns.inum`: `sk->__sk_common.skc_net` is a
+/// `possible_net_t` wrapping the `struct net *`, whose embedded
+/// `struct ns_common ns` holds `inum` - the number shown by
+/// `readlink /proc/<pid>/ns/net` as `net:[inum]`.
+///
+/// # Safety
+/// Caller must ensure sock pointer is valid
+#[inline(always)]
+unsafe fn read_netns_inode(sock: *const u8) -> Result<u32, ProbeError> {
+    // Offset of __sk_common.skc_net - kernel-version specific, like the
+    // offsets above
+    const SKC_NET_OFFSET: usize = 48;
+
+    let net = bpf_probe_read_kernel(sock.add(SKC_NET_OFFSET) as *const *const u8)
+        .map_err(|_| ProbeError::SockReadFailed)?;
+    read_net_inode(net).ok_or(ProbeError::SockReadFailed)
+}
+
+/// Read `net->ns.inum` of a `struct net *`
+///
+/// # Safety
+/// Caller must ensure net is null or a valid `struct net` pointer
+#[inline(always)]
+unsafe fn read_net_inode(net: *const u8) -> Option<u32> {
+    // Offsets of net.ns and ns_common.inum - kernel-version specific
+    const NET_NS_OFFSET: usize = 112;
+    const NS_INUM_OFFSET: usize = 16;
+
+    if net.is_null() {
+        return None;
+    }
+    bpf_probe_read_kernel(net.add(NET_NS_OFFSET + NS_INUM_OFFSET) as *const u32).ok()
+}
+
+/// The interface an `sk_buff` is going out or came in on
+///
+/// # Safety
+/// Caller must ensure skb points to a `struct sk_buff`
+#[inline(always)]
+unsafe fn read_skb_iface(skb: *const u8) -> Result<IfaceKey, ProbeError> {
+    // Offsets of sk_buff.dev, net_device.ifindex and net_device.nd_net
+    // (a possible_net_t) - kernel-version specific, like the offsets above
+    const SKB_DEV_OFFSET: usize = 16;
+    const DEV_IFINDEX_OFFSET: usize = 224;
+    const DEV_ND_NET_OFFSET: usize = 264;
+
+    let dev = bpf_probe_read_kernel(skb.add(SKB_DEV_OFFSET) as *const *const u8)
+        .map_err(|_| ProbeError::SkbReadFailed)?;
+    if dev.is_null() {
+        return Err(ProbeError::SkbReadFailed);
+    }
+    let ifindex = bpf_probe_read_kernel(dev.add(DEV_IFINDEX_OFFSET) as *const u32)
+        .map_err(|_| ProbeError::SkbReadFailed)?;
+    let net = bpf_probe_read_kernel(dev.add(DEV_ND_NET_OFFSET) as *const *const u8)
+        .map_err(|_| ProbeError::SkbReadFailed)?;
+
+    Ok(IfaceKey {
+        ifindex,
+        netns_inode: read_net_inode(net).unwrap_or(0),
+    })
+}
+
+/// Add `len` bytes to an interface's received or sent total
+#[inline(always)]
+fn count_iface_bytes(key: &IfaceKey, len: u64, received: bool) {
+    match IFACE_TRAFFIC.get_ptr_mut(key) {
+        Some(traffic) => unsafe {
+            if received {
+                (*traffic).rx_bytes += len;
+            } else {
+                (*traffic).tx_bytes += len;
+            }
+        },
+        None => {
+            let traffic = if received {
+                IfaceTraffic {
+                    rx_bytes: len,
+                    tx_bytes: 0,
+                }
+            } else {
+                IfaceTraffic {
+                    rx_bytes: 0,
+                    tx_bytes: len,
+                }
+            };
+            // Creates the other CPUs' slots zeroed; if one of them got
+            // there first, this packet's bytes are lost
+            let _ = IFACE_TRAFFIC.insert(key, &traffic, BPF_NOEXIST as u64);
+        }
+    }
+}
+
+/// Read the token of the MPTCP connection a subflow belongs to, or 0 for a
+/// plain TCP socket
+///
+/// A subflow is a tcp_sock whose ULP is "mptcp"; its
+/// `mptcp_subflow_context` (the ULP data) holds the token. Checking the
+/// name keeps other ULPs' data (e.g. kTLS) from being misread.
+///
+/// # Safety
+/// Caller must ensure sock pointer is valid
+#[inline(always)]
+unsafe fn read_mptcp_token(sock: *const u8) -> Result<u32, ProbeError> {
+    // Offsets of inet_connection_sock.icsk_ulp_ops and icsk_ulp_data,
+    // tcp_ulp_ops.name and mptcp_subflow_context.token - kernel-version
+    // specific, like the offsets above
+    const ULP_OPS_OFFSET: usize = 1264;
+    const ULP_DATA_OFFSET: usize = 1272;
+    const ULP_NAME_OFFSET: usize = 64;
+    const SUBFLOW_TOKEN_OFFSET: usize = 60;
+
+    let ops = bpf_probe_read_kernel(sock.add(ULP_OPS_OFFSET) as *const *const u8)
+        .map_err(|_| ProbeError::SockReadFailed)?;
+    if ops.is_null() {
+        return Ok(0);
+    }
+    let name = bpf_probe_read_kernel(ops.add(ULP_NAME_OFFSET) as *const [u8; 6])
+        .map_err(|_| ProbeError::SockReadFailed)?;
+    if &name != b"mptcp\0" {
+        return Ok(0);
+    }
+
+    let subflow = bpf_probe_read_kernel(sock.add(ULP_DATA_OFFSET) as *const *const u8)
+        .map_err(|_| ProbeError::SockReadFailed)?;
+    if subflow.is_null() {
+        return Ok(0);
+    }
+    bpf_probe_read_kernel(subflow.add(SUBFLOW_TOKEN_OFFSET) as *const u32)
+        .map_err(|_| ProbeError::SockReadFailed)
+}
+
+/// Read the token of an MPTCP socket (`mptcp_sock.token`)
+///
+/// # Safety
+/// Caller must ensure msk points to a `struct mptcp_sock`
+#[inline(always)]
+unsafe fn read_msk_token(msk: *const u8) -> Result<u32, ProbeError> {
+    // Offset of mptcp_sock.token - kernel-version specific, like the
+    // offsets above
+    const MSK_TOKEN_OFFSET: usize = 1560;
+
+    bpf_probe_read_kernel(msk.add(MSK_TOKEN_OFFSET) as *const u32)
+        .map_err(|_| ProbeError::SockReadFailed)
+}
+
+/// Read the send-queue state a new message will wait behind
+///
+/// # Safety
+/// Caller must ensure sock pointer is valid
+#[inline(always)]
+unsafe fn read_send_queue(sock: *const u8) -> Result<SendQueue, ProbeError> {
+    // Offsets of tcp_sock.snd_una, snd_cwnd and write_seq - kernel-version
+    // specific, like the offsets above
+    const SND_UNA_OFFSET: usize = 1496;
+    const SND_CWND_OFFSET: usize = 1660;
+    const WRITE_SEQ_OFFSET: usize = 1760;
+
+    let snd_una = bpf_probe_read_kernel(sock.add(SND_UNA_OFFSET) as *const u32)
+        .map_err(|_| ProbeError::SockReadFailed)?;
+    let write_seq = bpf_probe_read_kernel(sock.add(WRITE_SEQ_OFFSET) as *const u32)
+        .map_err(|_| ProbeError::SockReadFailed)?;
+    let snd_cwnd = bpf_probe_read_kernel(sock.add(SND_CWND_OFFSET) as *const u32)
+        .map_err(|_| ProbeError::SockReadFailed)?;
+    let (mss, _) = read_mss_pmtu(sock)?;
+
+    Ok(SendQueue {
+        // Sequence numbers wrap
+        queued_bytes: write_seq.wrapping_sub(snd_una),
+        srtt_us: read_srtt_us(sock)? as u32,
+        snd_cwnd,
+        mss,
+    })
+}
+
+/// Add bytes to the current minute of this CPU's `BYTE_HISTORY` ring
+#[inline(always)]
+fn record_byte_history(sent: u64, recv: u64) {
+    let history = match BYTE_HISTORY.get_ptr_mut(0) {
+        Some(h) => unsafe { &mut *h },
+        None => return,
+    };
+    let minute = unsafe { bpf_ktime_get_ns() } / HISTORY_BUCKET_NS;
+    let slot = (minute % HISTORY_MINUTES as u64) as usize;
+    // Already true, but the verifier doesn't track bounds through a modulo
+    if slot >= HISTORY_MINUTES {
+        return;
+    }
+
+    // Still holding the minute an hour (or more) ago: start it afresh. Two
+    // probes on one CPU can't interleave, so no update is lost.
+    if history.minute[slot] != minute {
+        history.minute[slot] = minute;
+        history.bytes_sent[slot] = 0;
+        history.bytes_recv[slot] = 0;
+    }
+    history.bytes_sent[slot] += sent;
+    history.bytes_recv[slot] += recv;
+}
+
+/// Fold an RTT sample into the connection's running sum / sum-of-squares
+#[inline(always)]
+fn record_rtt_sample(m: &mut ConnMetrics, rtt_us: u64) {
+    if rtt_us == 0 {
+        return; // No estimate yet (handshake not complete)
+    }
+    let rtt_us = rtt_us.min(RTT_SAMPLE_MAX_US);
+
+    // Halve the window so the sums stay bounded on long-lived connections
+    if m.rtt_samples >= RTT_WINDOW_SAMPLES {
+        m.rtt_samples /= 2;
+        m.rtt_sum_us /= 2;
+        m.rtt_sum_sq_us /= 2;
+    }
+
+    m.rtt_samples += 1;
+    m.rtt_sum_us += rtt_us;
+    m.rtt_sum_sq_us += rtt_us * rtt_us;
+}
+
+/// Increment one of the `CONN_STATS` counters
+#[inline(always)]
+fn bump_conn_stat(index: u32) {
+    if let Some(count) = CONN_STATS.get_ptr_mut(index) {
+        unsafe { *count += 1 };
+    }
+}
+
+/// Count a connection into (`delta` 1) or out of (-1) `ACTIVE_CONNS`,
+/// raising the peak when it grows
+#[inline(always)]
+fn track_active(delta: i64) {
+    // Tries at raising the peak; each one lost means another CPU raised it
+    const PEAK_TRIES: usize = 4;
+
+    let (Some(current), Some(peak)) = (
+        ACTIVE_CONNS.get_ptr_mut(active_conn::CURRENT),
+        ACTIVE_CONNS.get_ptr_mut(active_conn::PEAK),
+    ) else {
+        return;
+    };
+    // The map values live as long as the map and are 8-byte aligned. Unlike
+    // `take_budget` these use the atomics' results (`BPF_FETCH` and
+    // `BPF_CMPXCHG`, Linux 5.12+), so no CPU's update is lost.
+    let (current, peak) = unsafe {
+        (
+            &*(current as *const AtomicU64),
+            &*(peak as *const AtomicU64),
+        )
+    };
+    if delta < 0 {
+        // Wraps below zero if a close lands right after userspace resets the
+        // count to the live entries
+        current.fetch_sub(delta.unsigned_abs(), Ordering::Relaxed);
+        return;
+    }
+    let now = current
+        .fetch_add(delta as u64, Ordering::Relaxed)
+        .wrapping_add(delta as u64) as i64;
+
+    let mut seen = peak.load(Ordering::Relaxed);
+    for _ in 0..PEAK_TRIES {
+        if now <= seen as i64 {
+            return;
+        }
+        match peak.compare_exchange(seen, now as u64, Ordering::Relaxed, Ordering::Relaxed) {
+            Ok(_) => return,
+            Err(actual) => seen = actual,
+        }
+    }
+}
+
+/// Increment one of the `FILTER_DECISIONS` counters
+#[inline(always)]
+fn bump_filter_decision(index: u32) {
+    if let Some(count) = FILTER_DECISIONS.get_ptr_mut(index) {
+        unsafe { *count += 1 };
+    }
+}
+
+/// Insert a new `CONNECTIONS` entry, counting the outcome in `CONN_STATS`
+///
+/// Returns whether this call created the entry. A full map is not an error
+/// for the caller: the connection just goes untracked and
+/// `conn_stat::INSERT_FAILED` records it.
+#[inline(always)]
+fn insert_conn(key: &ConnKey, metrics: &ConnMetrics) -> bool {
+    const EEXIST: i64 = 17;
+
+    match CONNECTIONS.insert(key, metrics, BPF_NOEXIST as u64) {
+        Ok(()) => {
+            bump_conn_stat(conn_stat::INSERTED);
+            track_active(1);
+            true
+        }
+        // Another CPU raced us, which is fine
+        Err(e) if e == -EEXIST => false,
+        Err(_) => {
+            bump_conn_stat(conn_stat::INSERT_FAILED);
+            false
+        }
+    }
+}
+
+/// Fresh metrics for connection `key`, first seen now on `sock`
+///
+/// The congestion control algorithm, network namespace and IPv6 flow label
+/// are read once here; a later change through `setsockopt(TCP_CONGESTION)`
+/// is not picked up.
+///
+/// # Safety
+/// Caller must ensure sock pointer is valid
+#[inline(always)]
+unsafe fn new_conn_metrics(sock: *const u8, key: &ConnKey) -> ConnMetrics {
+    let now = bpf_ktime_get_ns();
+    let mut metrics = ConnMetrics {
+        start_ns: now,
+        last_seen_ns: now,
+        pid: (bpf_get_current_pid_tgid() >> 32) as u32,
+        netns_inode: read_netns_inode(sock).unwrap_or(0),
+        fd: -1,
+        ..Default::default()
+    };
+    // Left empty if unreadable; the connection is still worth tracking
+    let _ = read_cc_name(sock, &mut metrics.cc_algo);
+    metrics.mptcp_token = read_mptcp_token(sock).unwrap_or(0);
+    metrics.so_priority = read_sk_priority(sock).unwrap_or(0);
+    if key.address_family == address_family::INET6 {
+        metrics.flow_label = read_flow_label(sock).unwrap_or(0);
+    }
+    metrics.cookie = sock_cookie(sock, now);
+    // Still unset before the handshake; inet_sock_set_state fills it in then
+    if read_tstamp_ok(sock).unwrap_or(false) {
+        metrics.flags |= conn_flag::TCP_TIMESTAMPS;
+    }
+    metrics
+}
+
+/// Stable identity of `sock` for the rest of its life, whatever its
+/// addresses: the time it was first seen in nanoseconds (`now` if that's
+/// now), kept in `SOCK_COOKIES`. Two sockets would have to be first seen in
+/// the same nanosecond to share one. 0 if it couldn't be stored.
+///
+/// `bpf_get_socket_cookie` would be the natural choice, but kprobes can't
+/// call it, and the kernel only fills `sk_cookie` once something asks for it.
+/// The socket's address alone isn't enough: it is reused after close, and
+/// the entry of a connection whose 4-tuple changed outlives it.
+#[inline(always)]
+fn sock_cookie(sock: *const u8, now: u64) -> u64 {
+    let addr = sock as u64;
+    if let Some(cookie) = unsafe { SOCK_COOKIES.get(&addr) } {
+        return *cookie;
+    }
+    // Another CPU may insert first; its cookie then wins on the next read
+    let _ = SOCK_COOKIES.insert(&addr, &now, BPF_NOEXIST as u64);
+    unsafe { SOCK_COOKIES.get(&addr) }.copied().unwrap_or(0)
+}
+
+/// Read the `struct file *` behind descriptor `fd` of the current task
+///
+/// # Safety
+/// Must run in the context of a task (not an interrupt)
+#[inline(always)]
+unsafe fn read_fd_file(fd: i32) -> Result<*const u8, ProbeError> {
+    // Offsets of task_struct.files, files_struct.fdt, fdtable.max_fds and
+    // fdtable.fd - fixed constants, not read from BTF, and kernel-version
+    // specific like the offsets above. task_struct.files also moves with the
+    // kernel config. On another layout the file read won't be the socket's,
+    // so note_fd leaves the descriptor unknown rather than recording a wrong one
+    const TASK_FILES_OFFSET: usize = 1824;
+    const FILES_FDT_OFFSET: usize = 32;
+    const FDT_MAX_FDS_OFFSET: usize = 0;
+    const FDT_FD_OFFSET: usize = 8;
+
+    let task = bpf_get_current_task() as *const u8;
+    let files = bpf_probe_read_kernel(task.add(TASK_FILES_OFFSET) as *const *const u8)
+        .map_err(|_| ProbeError::SockReadFailed)?;
+    let fdt = bpf_probe_read_kernel(files.add(FILES_FDT_OFFSET) as *const *const u8)
+        .map_err(|_| ProbeError::SockReadFailed)?;
+    let max_fds = bpf_probe_read_kernel(fdt.add(FDT_MAX_FDS_OFFSET) as *const u32)
+        .map_err(|_| ProbeError::SockReadFailed)?;
+    if fd < 0 || fd as u32 >= max_fds {
+        return Err(ProbeError::SockReadFailed);
+    }
+    let fds = bpf_probe_read_kernel(fdt.add(FDT_FD_OFFSET) as *const *const *const u8)
+        .map_err(|_| ProbeError::SockReadFailed)?;
+    bpf_probe_read_kernel(fds.add(fd as usize)).map_err(|_| ProbeError::SockReadFailed)
+}
+
+/// Fill in `m.fd` from the system call the current thread is in, once the
+/// descriptor is confirmed to refer to `sock`: the stashed one may be left
+/// over from a call on another file, or the data may come from io_uring or
+/// sendfile, which never pass through the traced system calls.
+///
+/// # Safety
+/// Caller must ensure sock pointer is valid
+#[inline(always)]
+unsafe fn note_fd(sock: *const u8, m: &mut ConnMetrics) {
+    // Offsets of sock.sk_socket and socket.file - fixed constants, not read
+    // from BTF, and kernel-version specific like the offsets above
+    const SK_SOCKET_OFFSET: usize = 288;
+    const SOCKET_FILE_OFFSET: usize = 16;
+
+    if m.fd >= 0 {
+        return;
+    }
+    let tid = bpf_get_current_pid_tgid() as u32;
+    let Some(&fd) = SYSCALL_FDS.get(&tid) else {
+        return;
+    };
+    let Ok(socket) = bpf_probe_read_kernel(sock.add(SK_SOCKET_OFFSET) as *const *const u8) else {
+        return;
+    };
+    let Ok(file) = bpf_probe_read_kernel(socket.add(SOCKET_FILE_OFFSET) as *const *const u8) else {
+        return;
+    };
+    if !file.is_null() && read_fd_file(fd) == Ok(file) {
+        m.fd = fd;
+    }
+}
+
+/// Look up the metrics entry for a connection, creating it if absent.
+///
+/// Connections are created lazily here rather than relying on `tcp_connect`:
+/// the local port is frequently still 0 at connect time, so the key seen by
+/// later send/recv probes would never match a connect-created entry.
+///
+/// Under `new_connections_only`, only sockets in `NEW_SOCKS` get an entry;
+/// `None` means the connection predates the sidecar and is not tracked.
+///
+/// # Safety
+/// Caller must ensure sock pointer is valid
+#[inline(always)]
+unsafe fn get_or_insert_conn(
+    sock: *const u8,
+    key: &ConnKey,
+) -> Result<Option<*mut ConnMetrics>, ProbeError> {
+    let existing = CONNECTIONS.get_ptr_mut(key);
+    let may_create = || !new_connections_only() || NEW_SOCKS.get(&(sock as u64)).is_some();
+    match key.upsert(existing.is_some(), may_create) {
+        ConnUpsert::Update => return Ok(existing),
+        ConnUpsert::Skip => return Ok(None),
+        ConnUpsert::Create => {}
+    }
+    insert_conn(key, &new_conn_metrics(sock, key));
+
+    CONNECTIONS
+        .get_ptr_mut(key)
+        .map(Some)
+        .ok_or(ProbeError::MapLookupFailed)
+}
+
+/// Whether only connections opened after startup are tracked
+#[inline(always)]
+fn new_connections_only() -> bool {
+    CONFIG.get(0).is_some_and(|c| c.new_connections_only != 0)
+}
+
+/// Let send/recv create an entry for `sock` under `new_connections_only`
+#[inline(always)]
+fn mark_new_sock(sock: *const u8) -> Result<(), ProbeError> {
+    if !new_connections_only() {
+        return Ok(());
+    }
+    NEW_SOCKS
+        .insert(&(sock as u64), &0, 0)
+        .map_err(|_| ProbeError::MapInsertFailed)
+}
+
+/// User address and length of the first piece of a message's buffer
+///
+/// # Safety
+/// Caller must ensure msg points to the `struct msghdr` passed to
+/// tcp_sendmsg or tcp_recvmsg, on entry (the iterator advances as data is
+/// copied)
+#[inline(always)]
+unsafe fn read_msg_buf(msg: *const u8) -> Result<(*const u8, u64), ProbeError> {
+    // Offsets into struct msghdr -> msg_iter (struct iov_iter)
+    // Linux 6.x layout - kernel-version specific like the offsets above
+    const MSG_ITER_OFFSET: usize = 16;
+    const ITER_TYPE_OFFSET: usize = 0; // iov_iter.iter_type
+    const ITER_BUF_OFFSET: usize = 16; // iov_iter.ubuf / iov_iter.__iov
+    const ITER_COUNT_OFFSET: usize = 24; // iov_iter.count
+    const IOV_LEN_OFFSET: usize = 8; // iovec.iov_len
+    const ITER_UBUF: u8 = 0;
+    const ITER_IOVEC: u8 = 1;
+
+    let iter = msg.add(MSG_ITER_OFFSET);
+    let iter_type =
+        bpf_probe_read_kernel(iter.add(ITER_TYPE_OFFSET)).map_err(|_| ProbeError::MsgReadFailed)?;
+    let buf = bpf_probe_read_kernel(iter.add(ITER_BUF_OFFSET) as *const *const u8)
+        .map_err(|_| ProbeError::MsgReadFailed)?;
+
+    match iter_type {
+        ITER_UBUF => {
+            let len = bpf_probe_read_kernel(iter.add(ITER_COUNT_OFFSET) as *const u64)
+                .map_err(|_| ProbeError::MsgReadFailed)?;
+            Ok((buf, len))
+        }
+        // __iov points at the first struct iovec, whose iov_base comes first
+        ITER_IOVEC => {
+            let base = bpf_probe_read_kernel(buf as *const *const u8)
+                .map_err(|_| ProbeError::MsgReadFailed)?;
+            let len = bpf_probe_read_kernel(buf.add(IOV_LEN_OFFSET) as *const u64)
+                .map_err(|_| ProbeError::MsgReadFailed)?;
+            Ok((base, len))
+        }
+        _ => Err(ProbeError::UnsupportedIter),
+    }
+}
+
+/// HTTP version of a response status line (`HTTP/1.x NNN`)
+#[inline(always)]
+fn http_version_of(head: &[u8; L7_PEEK_LEN]) -> u8 {
+    match head {
+        [b'H', b'T', b'T', b'P', b'/', b'1', b'.', b'0', ..] => http_version::HTTP_1_0,
+        [b'H', b'T', b'T', b'P', b'/', b'1', b'.', b'1', ..] => http_version::HTTP_1_1,
+        _ => http_version::UNKNOWN,
+    }
+}
+
+/// HTTP status code of a response status line (`HTTP/1.x NNN`)
+#[inline(always)]
+fn http_status_of(head: &[u8; L7_PEEK_LEN]) -> Option<u16> {
+    match head {
+        [b'H', b'T', b'T', b'P', b'/', _, _, _, b' ', a, b, c, ..]
+            if a.is_ascii_digit() && b.is_ascii_digit() && c.is_ascii_digit() =>
+        {
+            Some((a - b'0') as u16 * 100 + (b - b'0') as u16 * 10 + (c - b'0') as u16)
+        }
+        _ => None,
+    }
+}
+
+/// Whether a payload starts with the HTTP/2 client connection preface
+/// (`PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n`; the first 16 bytes are enough)
+#[inline(always)]
+fn is_h2_preface(head: &[u8; L7_PEEK_LEN]) -> bool {
+    head == b"PRI * HTTP/2.0\r\n"
+}
+
+/// Guess the L7 protocol of an outgoing message (`l7_proto`)
+#[inline(always)]
+fn detect_l7_proto(key: &ConnKey, head: &[u8; L7_PEEK_LEN]) -> Option<u32> {
+    const DNS_PORT: u16 = 53;
+
+    // TLS record header: alert/handshake/application data, major version 3
+    if (0x15..=0x17).contains(&head[0]) && head[1] == 0x03 {
+        return Some(l7_proto::TLS);
+    }
+    if key.dst_port == DNS_PORT || key.src_port == DNS_PORT {
+        return Some(l7_proto::DNS);
+    }
+    if is_http(key, head) {
+        return Some(l7_proto::HTTP);
+    }
+
+    None
+}
+
+/// Whether a message looks like HTTP: a request or status line, the HTTP/2
+/// connection preface, or anything on a connection that sent the preface
+#[inline(always)]
+fn is_http(key: &ConnKey, head: &[u8; L7_PEEK_LEN]) -> bool {
+    http_method::method_from_bytes(head) != http_method::UNKNOWN
+        || http_status_of(head).is_some()
+        || is_h2_preface(head)
+        || HTTP_STREAMS
+            .get_ptr(key)
+            .is_some_and(|s| unsafe { (*s).h2 } != 0)
+}
+
+/// Whether L7 parsing applies to this connection: overflow ports only get
+/// tracked, parsing is reserved for the ports in the kernel slots
+#[inline(always)]
+fn l7_enabled_for(key: &ConnKey) -> bool {
+    match CONFIG.get(0) {
+        Some(config) => config.num_target_ports == 0 || in_target_ports(config, key),
+        None => true,
+    }
+}
+
+/// Tail-call into the parser for this message's protocol.
+///
+/// Only returns if no parser ran (unknown protocol, parser disabled, or the
+/// payload could not be read), so it must be the last thing a probe does.
+#[inline(always)]
+fn dispatch_l7(ctx: &ProbeContext, sock: *const u8, key: &ConnKey, msg: *const u8, size: usize) {
+    if !l7_enabled_for(key) {
+        return;
+    }
+
+    let scratch = match L7_SCRATCH.get_ptr_mut(0) {
+        Some(s) => unsafe { &mut *s },
+        None => return,
+    };
+    let (buf, buf_len) = match unsafe { read_msg_buf(msg) } {
+        Ok(b) => b,
+        Err(_) => return,
+    };
+    if unsafe { bpf_probe_read_user_buf(buf, &mut scratch.head) }.is_err() {
+        return;
+    }
+    let proto = match detect_l7_proto(key, &scratch.head) {
+        Some(p) => p,
+        None => return,
+    };
+    scratch.conn = *key;
+    scratch.size = size as u64;
+    scratch.buf = buf as u64;
+    scratch.buf_len = buf_len.min(size as u64);
+    scratch.direction = direction::SENT;
+    scratch.queue = unsafe { read_send_queue(sock) }.unwrap_or_default();
+
+    // Fails when the slot is empty (parser not enabled)
+    let _ = unsafe { L7_PARSERS.tail_call(ctx, proto) };
+}
+
+/// Whether to capture the payload of this send or receive: one in
+/// `capture_every` calls, up to `capture_max_per_sec` per second
+#[inline(always)]
+fn capture_sampled() -> bool {
+    let (every, max_per_sec) = match CONFIG.get(0) {
+        Some(c) if c.capture_max_bytes != 0 && c.capture_every != 0 => {
+            (c.capture_every, c.capture_max_per_sec as u64)
+        }
+        _ => return false,
+    };
+    if unsafe { bpf_get_prandom_u32() } % every != 0 {
+        return false;
+    }
+    take_budget(&CAPTURE_BUDGET, max_per_sec)
+}
+
+/// Whether to report the gap ending with this send or receive: one in
+/// `gap_every` calls, up to `gap_max_per_sec` per second
+#[inline(always)]
+fn gap_sampled() -> bool {
+    let (every, max_per_sec) = match CONFIG.get(0) {
+        Some(c) if c.gap_every != 0 => (c.gap_every, c.gap_max_per_sec as u64),
+        _ => return false,
+    };
+    if unsafe { bpf_get_prandom_u32() } % every != 0 {
+        return false;
+    }
+    take_budget(&GAP_BUDGET, max_per_sec)
+}
+
+/// Whether to capture the stacks of this connect: one in `stack_every`
+/// tracked connects, up to `stack_max_per_sec` per second
+#[inline(always)]
+fn stack_sampled() -> bool {
+    let (every, max_per_sec) = match CONFIG.get(0) {
+        Some(c) if c.stack_every != 0 => (c.stack_every, c.stack_max_per_sec as u64),
+        _ => return false,
+    };
+    if unsafe { bpf_get_prandom_u32() } % every != 0 {
+        return false;
+    }
+    take_budget(&STACK_BUDGET, max_per_sec)
+}
+
+/// Count one use against the `max_per_sec` all CPUs share for the current
+/// second; false once they are used up
+///
+/// Uses are counted with an atomic add whose result goes unused (a plain
+/// `BPF_XADD`, which every kernel supports), so none is lost. The check
+/// before it is not atomic with the add: CPUs passing it at the same
+/// instant can each take one more, and so can CPUs racing the reset at the
+/// start of a second.
+#[inline(always)]
+fn take_budget(budgets: &Array<CaptureBudget>, max_per_sec: u64) -> bool {
+    const NS_PER_SEC: u64 = 1_000_000_000;
+
+    let budget = match budgets.get_ptr_mut(0) {
+        Some(b) => b,
+        None => return false,
+    };
+    // The map value lives as long as the map and is 8-byte aligned
+    let (window, used) = unsafe {
+        (
+            &*(addr_of_mut!((*budget).second) as *const AtomicU64),
+            &*(addr_of_mut!((*budget).used) as *const AtomicU64),
+        )
+    };
+    let second = unsafe { bpf_ktime_get_ns() } / NS_PER_SEC;
+    if window.load(Ordering::Relaxed) != second {
+        window.store(second, Ordering::Relaxed);
+        used.store(0, Ordering::Relaxed);
+    }
+    if used.load(Ordering::Relaxed) >= max_per_sec {
+        return false;
+    }
+    used.fetch_add(1, Ordering::Relaxed);
+    true
+}
+
+/// Copy up to `capture_max_bytes` of a message's payload into a
+/// `PayloadEvent` for userspace
+///
+/// `buf_len` bytes are readable at the user address `buf`; `size` is the
+/// whole message.
+#[inline(always)]
+fn capture_payload<C: EbpfContext>(
+    ctx: &C,
+    key: &ConnKey,
+    buf: *const u8,
+    buf_len: u64,
+    size: u64,
+    dir: u8,
+) -> Result<(), ProbeError> {
+    let max = CONFIG.get(0).map_or(0, |c| c.capture_max_bytes as u64);
+    let event = unsafe {
+        &mut *PAYLOAD_SCRATCH
+            .get_ptr_mut(0)
+            .ok_or(ProbeError::MapLookupFailed)?
+    };
+    let len = (buf_len.min(size).min(max) as usize).min(PAYLOAD_CAPTURE_MAX);
+    if len == 0 {
+        return Ok(());
+    }
+    unsafe { bpf_probe_read_user_buf(buf, &mut event.data[..len]) }
+        .map_err(|_| ProbeError::UserReadFailed)?;
+
+    event.conn = *key;
+    event.pid = (bpf_get_current_pid_tgid() >> 32) as u32;
+    event.timestamp_ns = unsafe { bpf_ktime_get_ns() };
+    event.size = size;
+    event.captured_len = len as u16;
+    event.direction = dir;
+    PAYLOAD_EVENTS.output(ctx, event, 0);
+    Ok(())
+}
+
+/// Mark a send or receive on `m`, counted already, as its latest activity.
+///
+/// A sample of them report the time since the previous one as a
+/// `GapEvent`; the connection's first send or receive has no previous one.
+#[inline(always)]
+fn touch_conn<C: EbpfContext>(ctx: &C, key: &ConnKey, m: &mut ConnMetrics, dir: u8) {
+    let now = unsafe { bpf_ktime_get_ns() };
+    let gap_ns = now.saturating_sub(m.last_seen_ns);
+    m.last_seen_ns = now;
+    if m.packets_sent + m.packets_recv > 1 && gap_sampled() {
+        let event = GapEvent {
+            conn: *key,
+            direction: dir,
+            _padding: [0; 7],
+            gap_ns,
+        };
+        GAP_EVENTS.output(ctx, &event, 0);
+    }
+}
+
+// ============================================================================
+// Kprobe Programs - Attach to kernel functions
+// ============================================================================
+
+/// Track new TCP connections (outbound connect)
+#[kprobe]
+pub fn trace_tcp_connect(ctx: ProbeContext) -> u32 {
+    match try_trace_tcp_connect(&ctx) {
+        Ok(()) => 0,
+        Err(e) => {
+            warn!(&ctx, "tcp_connect error: {}", e.name());
+            1
+        }
+    }
+}
+
+fn try_trace_tcp_connect(ctx: &ProbeContext) -> Result<(), ProbeError> {
+    // First argument is struct sock *
+    let sock: *const u8 = ctx.arg(0).ok_or_else(arg_read_error)?;
+    on_tcp_connect(ctx, sock)
+}
+
+fn on_tcp_connect(ctx: &impl EbpfContext, sock: *const u8) -> Result<(), ProbeError> {
+    if !sampled(probe::CONNECT) {
+        return Ok(());
+    }
+    let key = unsafe { read_conn_key_from_sock(sock)? };
+    if !should_track(ctx, &key) {
+        return Ok(());
+    }
+    mark_new_sock(sock)?;
+    if stack_sampled() {
+        report_connect_stack(ctx, &key);
+    }
+
+    // Skipped while the local port isn't bound yet - the entry will be
+    // created by the first send/recv once the real 4-tuple is known. An
+    // entry already there is left alone by `insert_conn`.
+    if key.upsert(false, || true) != ConnUpsert::Create {
+        return Ok(());
+    }
+
+    let metrics = unsafe { new_conn_metrics(sock, &key) };
+    if !insert_conn(&key, &metrics) {
+        return Ok(());
+    }
+
+    debug!(
+        ctx,
+        "NEW CONN: {}:{} -> {}:{}",
+        log_ip(&key.src_ip),
+        key.src_port,
+        log_ip(&key.dst_ip),
+        key.dst_port
+    );
+
+    Ok(())
+}
+
+/// Record the kernel and user stacks of the connect in progress in
+/// `STACK_TRACES` and send their ids to userspace.
+///
+/// Identical stacks share an id, so the map holds each kernel call path
+/// once; userspace deletes user stacks once read, as their addresses differ
+/// per process. Without `BPF_F_REUSE_STACKID` a stack whose hash collides
+/// with a stored one is dropped (`-EEXIST`) rather than replacing it, which
+/// keeps the ids userspace has already read valid.
+#[inline(always)]
+fn report_connect_stack<C: EbpfContext>(ctx: &C, key: &ConnKey) {
+    let kernel_stack_id =
+        unsafe { STACK_TRACES.get_stackid(ctx, BPF_F_FAST_STACK_CMP as u64) }.unwrap_or_else(|e| e);
+    let user_stack_id =
+        unsafe { STACK_TRACES.get_stackid(ctx, (BPF_F_USER_STACK | BPF_F_FAST_STACK_CMP) as u64) }
+            .unwrap_or_else(|e| e);
+    let event = ConnectStackEvent {
+        conn: *key,
+        pid: (bpf_get_current_pid_tgid() >> 32) as u32,
+        kernel_stack_id: kernel_stack_id as i32,
+        user_stack_id: user_stack_id as i32,
+        comm: ctx.command().unwrap_or_default(),
+    };
+    STACK_EVENTS.output(ctx, &event, 0);
+}
+
+/// Track new TCP connections (inbound accept)
+///
+/// Only needed for `new_connections_only`: otherwise accepted connections
+/// get their entry from the first send/recv like any other.
+#[kretprobe]
+pub fn trace_inet_csk_accept(ctx: RetProbeContext) -> u32 {
+    match try_trace_inet_csk_accept(&ctx) {
+        Ok(()) => 0,
+        Err(_) => 1,
+    }
+}
+
+fn try_trace_inet_csk_accept(ctx: &RetProbeContext) -> Result<(), ProbeError> {
+    if !new_connections_only() {
+        return Ok(());
+    }
+    // The accepted struct sock *, or null on error
+    let sock: *const u8 = ctx.ret().ok_or_else(arg_read_error)?;
+    if sock.is_null() {
+        return Ok(());
+    }
+
+    let key = unsafe { read_conn_key_from_sock(sock)? };
+    if !should_track(ctx, &key) {
+        return Ok(());
+    }
+    mark_new_sock(sock)
+}
+
+/// Track TCP send operations
+#[kprobe]
+pub fn trace_tcp_sendmsg(ctx: ProbeContext) -> u32 {
+    match try_trace_tcp_sendmsg(&ctx) {
+        Ok(()) => 0,
+        Err(_) => 1,
+    }
+}
+
+fn try_trace_tcp_sendmsg(ctx: &ProbeContext) -> Result<(), ProbeError> {
+    let sock: *const u8 = ctx.arg(0).ok_or_else(arg_read_error)?;
+    let msg: *const u8 = ctx.arg(1).ok_or_else(arg_read_error)?;
+    let size: usize = ctx.arg(2).ok_or_else(arg_read_error)?;
+
+    if let Some(key) = on_tcp_sendmsg(ctx, sock, size)? {
+        if capture_sampled() {
+            if let Ok((buf, buf_len)) = unsafe { read_msg_buf(msg) } {
+                let _ = capture_payload(ctx, &key, buf, buf_len, size as u64, direction::SENT);
+            }
+        }
+        // Must stay last: a successful tail call does not return here
+        dispatch_l7(ctx, sock, &key, msg, size);
+    }
+
+    Ok(())
+}
+
+/// Count a send on its connection; returns the connection if it is tracked.
+fn on_tcp_sendmsg(
+    ctx: &impl EbpfContext,
+    sock: *const u8,
+    size: usize,
+) -> Result<Option<ConnKey>, ProbeError> {
+    if !sampled(probe::SENDMSG) {
+        return Ok(None);
+    }
+    let key = unsafe { read_conn_key_from_sock(sock)? };
+    if !should_track(ctx, &key) {
+        return Ok(None);
+    }
+
+    let Some(m) = (unsafe { get_or_insert_conn(sock, &key)? }) else {
+        return Ok(None);
+    };
+    let m = unsafe { &mut *m };
+    m.record_send(size as u64);
+    touch_conn(ctx, &key, m, direction::SENT);
+    unsafe { note_fd(sock, m) };
+    record_byte_history(size as u64, 0);
+
+    if let Ok(srtt_us) = unsafe { read_srtt_us(sock) } {
+        record_rtt_sample(m, srtt_us);
+    }
+
+    // Re-read on every send: PMTU discovery can lower both at any time
+    if let Ok((mss, pmtu)) = unsafe { read_mss_pmtu(sock) } {
+        m.mss = mss;
+        m.pmtu = pmtu;
+    }
+    if let Ok(dsacks) = unsafe { read_dsack_dups(sock) } {
+        m.dsacks = dsacks;
+    }
+    // setsockopt(SO_PRIORITY) can come at any point of the connection
+    if let Ok(priority) = unsafe { read_sk_priority(sock) } {
+        m.so_priority = priority;
+    }
+
+    Ok(Some(key))
+}
+
+/// Track sends on MPTCP sockets
+///
+/// The MPTCP socket pushes data onto its subflows directly, so
+/// `tcp_sendmsg` never sees it. The MPTCP socket carries the addresses of
+/// its first subflow, so the bytes land on that subflow's entry.
+#[kprobe]
+pub fn trace_mptcp_sendmsg(ctx: ProbeContext) -> u32 {
+    match try_trace_mptcp_sendmsg(&ctx) {
+        Ok(()) => 0,
+        Err(_) => 1,
+    }
+}
+
+fn try_trace_mptcp_sendmsg(ctx: &ProbeContext) -> Result<(), ProbeError> {
+    let msk: *const u8 = ctx.arg(0).ok_or_else(arg_read_error)?;
+    let size: usize = ctx.arg(2).ok_or_else(arg_read_error)?;
+
+    if !sampled(probe::SENDMSG) {
+        return Ok(());
+    }
+    let key = unsafe { read_conn_key_from_sock(msk)? };
+    if !should_track(ctx, &key) {
+        return Ok(());
+    }
+
+    // No RTT/MSS here: an mptcp_sock is not a tcp_sock; the subflows
+    // carry those
+    let Some(m) = (unsafe { get_or_insert_mptcp_conn(msk, &key)? }) else {
+        return Ok(());
+    };
+    let m = unsafe { &mut *m };
+    m.record_send(size as u64);
+    touch_conn(ctx, &key, m, direction::SENT);
+    // The application's descriptor refers to the MPTCP socket, not a subflow
+    unsafe { note_fd(msk, m) };
+    record_byte_history(size as u64, 0);
+
+    Ok(())
+}
+
+/// `get_or_insert_conn` for an MPTCP socket, filling in its token.
+///
+/// # Safety
+/// Caller must ensure msk points to a `struct mptcp_sock`
+#[inline(always)]
+unsafe fn get_or_insert_mptcp_conn(
+    msk: *const u8,
+    key: &ConnKey,
+) -> Result<Option<*mut ConnMetrics>, ProbeError> {
+    let Some(m) = get_or_insert_conn(msk, key)? else {
+        return Ok(None);
+    };
+    // An entry first created from the MPTCP socket has no subflow to read
+    // the token from
+    if (*m).mptcp_token == 0 {
+        (*m).mptcp_token = read_msk_token(msk).unwrap_or(0);
+    }
+    Ok(Some(m))
+}
+
+/// Track segment bytes as they leave TCP for IP
+///
+/// Unlike `tcp_sendmsg` (application bytes), this sees every segment: TCP
+/// headers, retransmissions and pure ACKs.
+#[kprobe]
+pub fn trace_ip_queue_xmit(ctx: ProbeContext) -> u32 {
+    match try_trace_ip_queue_xmit(&ctx) {
+        Ok(()) => 0,
+        Err(_) => 1,
+    }
+}
+
+fn try_trace_ip_queue_xmit(ctx: &ProbeContext) -> Result<(), ProbeError> {
+    let sock: *const u8 = ctx.arg(0).ok_or_else(arg_read_error)?;
+    let skb: *const u8 = ctx.arg(1).ok_or_else(arg_read_error)?;
+    on_ip_queue_xmit(sock, skb)
+}
+
+fn on_ip_queue_xmit(sock: *const u8, skb: *const u8) -> Result<(), ProbeError> {
+    // Offset of sk_buff.len - kernel-version specific
+    const SKB_LEN_OFFSET: usize = 112;
+    // IPv4 header without options; pushed after this probe fires
+    const IPV4_HEADER_LEN: u64 = 20;
+
+    if !sampled(probe::QUEUE_XMIT) {
+        return Ok(());
+    }
+
+    // No should_trace(): ACK-clocked transmits run in softirq context where
+    // the current PID is unrelated. Only connections already tracked count.
+    let key = unsafe { read_conn_key_from_sock(sock)? };
+
+    if let Some(metrics) = CONNECTIONS.get_ptr_mut(&key) {
+        // skb->len covers the TCP header and payload at this point
+        let len = unsafe { bpf_probe_read_kernel(skb.add(SKB_LEN_OFFSET) as *const u32) }
+            .map_err(|_| ProbeError::SkbReadFailed)?;
+        let m = unsafe { &mut *metrics };
+        m.wire_bytes_sent += len as u64 + IPV4_HEADER_LEN;
+    }
+
+    Ok(())
+}
+
+/// Count RSTs received on a tracked connection
+///
+/// Not PID-filtered: incoming segments are processed in softirq context.
+#[kprobe]
+pub fn trace_tcp_reset(ctx: ProbeContext) -> u32 {
+    match try_trace_tcp_reset(&ctx) {
+        Ok(()) => 0,
+        Err(_) => 1,
+    }
+}
+
+fn try_trace_tcp_reset(ctx: &ProbeContext) -> Result<(), ProbeError> {
+    let sock: *const u8 = ctx.arg(0).ok_or_else(arg_read_error)?;
+    on_tcp_reset(ctx, sock)
+}
+
+fn on_tcp_reset(ctx: &impl EbpfContext, sock: *const u8) -> Result<(), ProbeError> {
+    if !sampled(probe::RESET) {
+        return Ok(());
+    }
+    let key = unsafe { read_conn_key_from_sock(sock)? };
+
+    if let Some(metrics) = CONNECTIONS.get_ptr_mut(&key) {
+        let m = unsafe { &mut *metrics };
+        m.rst_recv += 1;
+        debug!(
+            ctx,
+            "RST RECV: {}:{} <- {}:{}",
+            log_ip(&key.src_ip),
+            key.src_port,
+            log_ip(&key.dst_ip),
+            key.dst_port
+        );
+    }
+
+    Ok(())
+}
+
+/// Count RSTs sent to abort a tracked connection
+///
+/// Resets sent by `close()` itself (unread data, SO_LINGER 0) happen after
+/// `trace_tcp_close` removed the entry, so they are not counted.
+#[kprobe]
+pub fn trace_tcp_send_active_reset(ctx: ProbeContext) -> u32 {
+    match try_trace_tcp_send_active_reset(&ctx) {
+        Ok(()) => 0,
+        Err(_) => 1,
+    }
+}
+
+fn try_trace_tcp_send_active_reset(ctx: &ProbeContext) -> Result<(), ProbeError> {
+    let sock: *const u8 = ctx.arg(0).ok_or_else(arg_read_error)?;
+    on_tcp_send_active_reset(ctx, sock)
+}
+
+fn on_tcp_send_active_reset(ctx: &impl EbpfContext, sock: *const u8) -> Result<(), ProbeError> {
+    if !sampled(probe::RESET) {
+        return Ok(());
+    }
+    let key = unsafe { read_conn_key_from_sock(sock)? };
+
+    if let Some(metrics) = CONNECTIONS.get_ptr_mut(&key) {
+        let m = unsafe { &mut *metrics };
+        m.rst_sent += 1;
+        debug!(
+            ctx,
+            "RST SENT: {}:{} -> {}:{}",
+            log_ip(&key.src_ip),
+            key.src_port,
+            log_ip(&key.dst_ip),
+            key.dst_port
+        );
+    }
+
+    Ok(())
+}
+
+/// Track TCP receive operations
+#[kprobe]
+pub fn trace_tcp_recvmsg(ctx: ProbeContext) -> u32 {
+    match try_trace_tcp_recvmsg(&ctx) {
+        Ok(()) => 0,
+        Err(_) => 1,
+    }
+}
+
+fn try_trace_tcp_recvmsg(ctx: &ProbeContext) -> Result<(), ProbeError> {
+    // A skipped call leaves no RECV_ARGS, so its return probe skips it too
+    if !sampled(probe::RECVMSG) {
+        return Ok(());
+    }
+    let sock: *const u8 = ctx.arg(0).ok_or_else(arg_read_error)?;
+    let msg: *const u8 = ctx.arg(1).ok_or_else(arg_read_error)?;
+    let key = unsafe { read_conn_key_from_sock(sock)? };
+    if !should_track(ctx, &key) || count_recv(ctx, sock, &key)?.is_none() {
+        return Ok(());
+    }
+
+    // Keep the call for the return probe, which counts the bytes received
+    // and, for the HTTP parser and payload capture, looks at what landed in
+    // the buffer
+    let mut args = RecvArgs {
+        conn: key,
+        buf: 0,
+        buf_len: 0,
+        http: 0,
+        capture: 0,
+        _padding: [0; 6],
+    };
+    let http = CONFIG.get(0).is_some_and(|c| c.enable_http != 0);
+    args.http = (http && l7_enabled_for(&key)) as u8;
+    args.capture = capture_sampled() as u8;
+    if args.http != 0 || args.capture != 0 {
+        if let Ok((buf, buf_len)) = unsafe { read_msg_buf(msg) } {
+            args.buf = buf as u64;
+            args.buf_len = buf_len;
+        }
+    }
+    RECV_ARGS
+        .insert(&bpf_get_current_pid_tgid(), &args, 0)
+        .map_err(|_| ProbeError::MapInsertFailed)?;
+
+    Ok(())
+}
+
+/// Track receives on MPTCP sockets, which pull data from their subflows
+/// without `tcp_recvmsg` (see `trace_mptcp_sendmsg`)
+#[kprobe]
+pub fn trace_mptcp_recvmsg(ctx: ProbeContext) -> u32 {
+    match try_trace_mptcp_recvmsg(&ctx) {
+        Ok(()) => 0,
+        Err(_) => 1,
+    }
+}
+
+fn try_trace_mptcp_recvmsg(ctx: &ProbeContext) -> Result<(), ProbeError> {
+    if !sampled(probe::RECVMSG) {
+        return Ok(());
+    }
+    let msk: *const u8 = ctx.arg(0).ok_or_else(arg_read_error)?;
+    let key = unsafe { read_conn_key_from_sock(msk)? };
+    if !should_track(ctx, &key) {
+        return Ok(());
+    }
+
+    let Some(m) = (unsafe { get_or_insert_mptcp_conn(msk, &key)? }) else {
+        return Ok(());
+    };
+    let m = unsafe { &mut *m };
+    m.packets_recv += 1;
+    touch_conn(ctx, &key, m, direction::RECEIVED);
+
+    // The return probe adds the bytes received to this entry, found again
+    // by `conn`; no HTTP parsing on MPTCP sockets
+    let args = RecvArgs {
+        conn: key,
+        buf: 0,
+        buf_len: 0,
+        http: 0,
+        capture: 0,
+        _padding: [0; 6],
+    };
+    RECV_ARGS
+        .insert(&bpf_get_current_pid_tgid(), &args, 0)
+        .map_err(|_| ProbeError::MapInsertFailed)?;
+
+    Ok(())
+}
+
+/// Return half of `trace_mptcp_recvmsg`
+#[kretprobe]
+pub fn trace_mptcp_recvmsg_ret(ctx: RetProbeContext) -> u32 {
+    match try_trace_tcp_recvmsg_ret(&ctx) {
+        Ok(()) => 0,
+        Err(_) => 1,
+    }
+}
+
+/// Count a receive call on a tracked connection and return its entry;
+/// None if the connection is not tracked (see `get_or_insert_conn`)
+fn count_recv(
+    ctx: &impl EbpfContext,
+    sock: *const u8,
+    key: &ConnKey,
+) -> Result<Option<*mut ConnMetrics>, ProbeError> {
+    let Some(metrics) = (unsafe { get_or_insert_conn(sock, key)? }) else {
+        return Ok(None);
+    };
+    let m = unsafe { &mut *metrics };
+    m.packets_recv += 1;
+    touch_conn(ctx, key, m, direction::RECEIVED);
+    unsafe { note_fd(sock, m) };
+    Ok(Some(metrics))
+}
+
+/// Track TCP receive return to get actual bytes received
+#[kretprobe]
+pub fn trace_tcp_recvmsg_ret(ctx: RetProbeContext) -> u32 {
+    match try_trace_tcp_recvmsg_ret(&ctx) {
+        Ok(()) => 0,
+        Err(_) => 1,
+    }
+}
+
+fn try_trace_tcp_recvmsg_ret(ctx: &RetProbeContext) -> Result<(), ProbeError> {
+    // Only there if the entry probe tracked the connection
+    let pid_tgid = bpf_get_current_pid_tgid();
+    let args = match unsafe { RECV_ARGS.get(&pid_tgid) } {
+        Some(args) => *args,
+        None => return Ok(()),
+    };
+    let _ = RECV_ARGS.remove(&pid_tgid);
+
+    // Return value is bytes received (or negative error)
+    let ret: i64 = ctx.ret().ok_or_else(arg_read_error)?;
+    if ret <= 0 {
+        return Ok(()); // Error or no data
+    }
+    // The entry probe counted the call on this entry
+    if let Some(m) = CONNECTIONS.get_ptr_mut(&args.conn) {
+        unsafe { (*m).record_recv(ret as u64) };
+    }
+    record_byte_history(0, ret as u64);
+    if args.buf == 0 {
+        return Ok(());
+    }
+    if args.capture != 0 {
+        let (buf, size) = (args.buf as *const u8, ret as u64);
+        let _ = capture_payload(
+            ctx,
+            &args.conn,
+            buf,
+            args.buf_len,
+            size,
+            direction::RECEIVED,
+        );
+    }
+    if args.http == 0 {
+        return Ok(());
+    }
+
+    let scratch = unsafe {
+        &mut *L7_SCRATCH
+            .get_ptr_mut(0)
+            .ok_or(ProbeError::MapLookupFailed)?
+    };
+    unsafe { bpf_probe_read_user_buf(args.buf as *const u8, &mut scratch.head) }
+        .map_err(|_| ProbeError::UserReadFailed)?;
+    if !is_http(&args.conn, &scratch.head) {
+        return Ok(());
+    }
+    scratch.conn = args.conn;
+    scratch.size = ret as u64;
+    scratch.buf = args.buf;
+    scratch.buf_len = args.buf_len.min(ret as u64);
+    scratch.direction = direction::RECEIVED;
+    scratch.queue = SendQueue::default();
+
+    // Fails when the slot is empty (parser not enabled)
+    let _ = unsafe { L7_PARSERS.tail_call(ctx, l7_proto::HTTP) };
+
+    Ok(())
+}
+
+/// Track TCP connection close for cleanup
+#[kprobe]
+pub fn trace_tcp_close(ctx: ProbeContext) -> u32 {
+    match try_trace_tcp_close(&ctx) {
+        Ok(()) => 0,
+        Err(_) => 1,
+    }
+}
+
+fn try_trace_tcp_close(ctx: &ProbeContext) -> Result<(), ProbeError> {
+    let sock: *const u8 = ctx.arg(0).ok_or_else(arg_read_error)?;
+    on_tcp_close(ctx, sock)
+}
+
+fn on_tcp_close(ctx: &impl EbpfContext, sock: *const u8) -> Result<(), ProbeError> {
+    let key = unsafe { read_conn_key_from_sock(sock)? };
+
+    // Hand the final stats to userspace, which also logs them, before removing
+    if let Some(metrics) = unsafe { CONNECTIONS.get(&key) } {
+        // Never wraps around, whatever start_ns holds
+        let duration_ns = unsafe { bpf_ktime_get_ns() }.saturating_sub(metrics.start_ns);
+        let event = ConnCloseEvent {
+            conn: key,
+            bytes_sent: metrics.bytes_sent,
+            bytes_recv: metrics.bytes_recv,
+            duration_ns,
+            packets_sent: metrics.packets_sent,
+            packets_recv: metrics.packets_recv,
+            retransmits: metrics.retransmits,
+            _padding: 0,
+        };
+        CLOSE_EVENTS.output(ctx, &event, 0);
+    }
+
+    // Remove from map (cleanup); fails if the entry was already evicted
+    if CONNECTIONS.remove(&key).is_ok() {
+        bump_conn_stat(conn_stat::CLOSED);
+        track_active(-1);
+    }
+    // Requests still in flight never get their response
+    let _ = HTTP_STREAMS.remove(&key);
+    let _ = NEW_SOCKS.remove(&(sock as u64));
+    let _ = SOCK_COOKIES.remove(&(sock as u64));
+
+    Ok(())
+}
+
+// ============================================================================
+// Fentry/Fexit Programs - Preferred over the kprobes above when the kernel
+// has BTF (5.5+); the loader attaches one or the other, never both
+// ============================================================================
+
+/// Arguments of `tcp_recvmsg` before its return value in an fexit context -
+/// kernel-version specific (5.19 dropped `nonblock`; 6 before that)
+const TCP_RECVMSG_ARGS: usize = 5;
+
+/// fentry variant of `trace_tcp_connect`
+#[fentry(function = "tcp_connect")]
+pub fn fentry_tcp_connect(ctx: FEntryContext) -> u32 {
+    match on_tcp_connect(&ctx, unsafe { ctx.arg(0) }) {
+        Ok(()) => 0,
+        Err(e) => {
+            warn!(&ctx, "tcp_connect error: {}", e.name());
+            1
+        }
+    }
+}
+
+/// fentry variant of `trace_tcp_sendmsg`, without the L7 parsers: they are
+/// kprobe programs, which a tracing program cannot tail-call
+#[fentry(function = "tcp_sendmsg")]
+pub fn fentry_tcp_sendmsg(ctx: FEntryContext) -> u32 {
+    match on_tcp_sendmsg(&ctx, unsafe { ctx.arg(0) }, unsafe { ctx.arg(2) }) {
+        Ok(_) => 0,
+        Err(_) => 1,
+    }
+}
+
+/// fentry variant of `trace_ip_queue_xmit`
+#[fentry(function = "ip_queue_xmit")]
+pub fn fentry_ip_queue_xmit(ctx: FEntryContext) -> u32 {
+    match on_ip_queue_xmit(unsafe { ctx.arg(0) }, unsafe { ctx.arg(1) }) {
+        Ok(()) => 0,
+        Err(_) => 1,
+    }
+}
+
+/// fentry variant of `trace_tcp_reset`
+#[fentry(function = "tcp_reset")]
+pub fn fentry_tcp_reset(ctx: FEntryContext) -> u32 {
+    match on_tcp_reset(&ctx, unsafe { ctx.arg(0) }) {
+        Ok(()) => 0,
+        Err(_) => 1,
+    }
+}
+
+/// fentry variant of `trace_tcp_send_active_reset`
+#[fentry(function = "tcp_send_active_reset")]
+pub fn fentry_tcp_send_active_reset(ctx: FEntryContext) -> u32 {
+    match on_tcp_send_active_reset(&ctx, unsafe { ctx.arg(0) }) {
+        Ok(()) => 0,
+        Err(_) => 1,
+    }
+}
+
+/// fexit replacement for the `trace_tcp_recvmsg` / `trace_tcp_recvmsg_ret`
+/// pair: the arguments and the return value are both at hand, so nothing is
+/// kept in `RECV_ARGS`. Responses are not handed to the HTTP parser.
+#[fexit(function = "tcp_recvmsg")]
+pub fn fexit_tcp_recvmsg(ctx: FExitContext) -> u32 {
+    match try_fexit_tcp_recvmsg(&ctx) {
+        Ok(()) => 0,
+        Err(_) => 1,
+    }
+}
+
+fn try_fexit_tcp_recvmsg(ctx: &FExitContext) -> Result<(), ProbeError> {
+    if !sampled(probe::RECVMSG) {
+        return Ok(());
+    }
+    let sock: *const u8 = unsafe { ctx.arg(0) };
+    // int: only the low 32 bits of the slot are defined
+    let ret: i32 = unsafe { ctx.arg(TCP_RECVMSG_ARGS) };
+    let key = unsafe { read_conn_key_from_sock(sock)? };
+    if !should_track(ctx, &key) {
+        return Ok(());
+    }
+    let Some(m) = count_recv(ctx, sock, &key)? else {
+        return Ok(());
+    };
+
+    if ret > 0 {
+        unsafe { (*m).record_recv(ret as u64) };
+        record_byte_history(0, ret as u64);
+    }
+
+    Ok(())
+}
+
+/// fentry variant of `trace_tcp_close`
+#[fentry(function = "tcp_close")]
+pub fn fentry_tcp_close(ctx: FEntryContext) -> u32 {
+    match on_tcp_close(&ctx, unsafe { ctx.arg(0) }) {
+        Ok(()) => 0,
+        Err(_) => 1,
+    }
+}
+
+// ============================================================================
+// L7 Parser Programs - Tail-called from the send/receive path, never attached
+// ============================================================================
+
+/// Read the message the send or receive path handed off in `L7_SCRATCH`
+#[inline(always)]
+fn l7_scratch() -> Result<&'static L7Scratch, ProbeError> {
+    L7_SCRATCH
+        .get_ptr(0)
+        .map(|s| unsafe { &*s })
+        .ok_or(ProbeError::MapLookupFailed)
+}
+
+/// Set a `conn_flag` on a connection, e.g. the protocol it carries.
+/// Connections without an entry (filtered, or the map was full) are left
+/// alone.
+#[inline(always)]
+fn flag_conn(key: &ConnKey, flag: u8) {
+    if let Some(metrics) = CONNECTIONS.get_ptr_mut(key) {
+        unsafe { (*metrics).flags |= flag };
+    }
+}
+
+/// Record the HTTP version a connection speaks; `UNKNOWN` leaves it as is.
+#[inline(always)]
+fn set_http_version(key: &ConnKey, version: u8) {
+    if version == http_version::UNKNOWN {
+        return;
+    }
+    if let Some(metrics) = CONNECTIONS.get_ptr_mut(key) {
+        unsafe { (*metrics).http_version = version };
+    }
+}
+
+/// HTTP/1.x and HTTP/2 parser
+///
+/// Times each request until its response goes the other way on the same
+/// connection: HTTP/1.x by request and status line, HTTP/2 by the HEADERS
+/// frames of each stream. Completed requests become `HttpEvent`s.
+#[kprobe]
+pub fn parse_http(ctx: ProbeContext) -> u32 {
+    match try_parse_http(&ctx) {
+        Ok(()) => 0,
+        Err(_) => 1,
+    }
+}
+
+fn try_parse_http(ctx: &ProbeContext) -> Result<(), ProbeError> {
+    let msg = l7_scratch()?;
+    let conn = &msg.conn;
+    let streams = unsafe { &mut *http_streams(conn)? };
+    let now = unsafe { bpf_ktime_get_ns() };
+
+    if is_h2_preface(&msg.head) {
+        debug!(
+            ctx,
+            "HTTP/2 PREFACE: {}:{} -> {}:{}",
+            log_ip(&conn.src_ip),
+            conn.src_port,
+            log_ip(&conn.dst_ip),
+            conn.dst_port
+        );
+        streams.h2 = 1;
+        streams.h2_client = msg.direction;
+        streams.h2_lost = [0; 2];
+        streams.h2_skip = [0; 2];
+        streams.h2_skip[(msg.direction & 1) as usize] = H2_PREFACE_LEN;
+        flag_conn(conn, conn_flag::HTTP2);
+        set_http_version(conn, http_version::HTTP_2);
+    }
+    if streams.h2 != 0 {
+        return parse_h2_frames(ctx, msg, streams, now);
+    }
+
+    let method = http_method::method_from_bytes(&msg.head);
+    if method != http_method::UNKNOWN {
+        debug!(
+            ctx,
+            "HTTP REQUEST: method {} {}:{} -> {}:{} ({} bytes, {} queued ahead, srtt {}us, cwnd {}x{})",
+            method,
+            log_ip(&conn.src_ip),
+            conn.src_port,
+            log_ip(&conn.dst_ip),
+            conn.dst_port,
+            msg.size,
+            msg.queue.queued_bytes,
+            msg.queue.srtt_us,
+            msg.queue.snd_cwnd,
+            msg.queue.mss
+        );
+        flag_conn(conn, conn_flag::HTTP);
+        // Pipelined requests: the first response answers the oldest one
+        if find_request(streams, 0).is_none() {
+            let path = request_path_hash(msg, method);
+            start_request(streams, 0, method, path, msg, now);
+        }
+    } else if let Some(status) = http_status_of(&msg.head) {
+        debug!(
+            ctx,
+            "HTTP RESPONSE: status {} {}:{} -> {}:{} ({} bytes)",
+            status,
+            log_ip(&conn.src_ip),
+            conn.src_port,
+            log_ip(&conn.dst_ip),
+            conn.dst_port,
+            msg.size
+        );
+        flag_conn(conn, conn_flag::HTTP);
+        set_http_version(conn, http_version_of(&msg.head));
+        finish_request(ctx, streams, 0, status, msg, now);
+    }
+
+    Ok(())
+}
+
+/// Length of the HTTP/2 client connection preface
+const H2_PREFACE_LEN: u32 = 24;
+
+/// HTTP/2 frame header: length (24 bits), type, flags, stream id (31 bits)
+const H2_FRAME_HEADER_LEN: u64 = 9;
+
+/// Walk the HTTP/2 frames of a message, starting and finishing requests on
+/// HEADERS frames: from the client a request, from the server its response.
+///
+/// A frame split across messages is skipped into the next one. When a frame
+/// header can't be read whole (split across messages or iovecs) or a message
+/// has more than `MAX_H2_FRAMES` frames, the direction loses frame sync and
+/// is no longer parsed.
+#[inline(always)]
+fn parse_h2_frames(
+    ctx: &ProbeContext,
+    msg: &L7Scratch,
+    streams: &mut HttpStreams,
+    now: u64,
+) -> Result<(), ProbeError> {
+    const FRAME_HEADERS: u8 = 0x1;
+    const FRAME_RST_STREAM: u8 = 0x3;
+
+    let dir = (msg.direction & 1) as usize;
+    if streams.h2_lost[dir] != 0 {
+        return Ok(());
+    }
+
+    let mut offset = streams.h2_skip[dir] as u64;
+    for _ in 0..MAX_H2_FRAMES {
+        if offset >= msg.size {
+            break;
+        }
+        if offset + H2_FRAME_HEADER_LEN > msg.buf_len {
+            streams.h2_lost[dir] = 1;
+            return Ok(());
+        }
+
+        let header: [u8; H2_FRAME_HEADER_LEN as usize] =
+            unsafe { bpf_probe_read_user((msg.buf + offset) as *const _) }
+                .map_err(|_| ProbeError::UserReadFailed)?;
+        let len = u32::from_be_bytes([0, header[0], header[1], header[2]]) as u64;
+        let frame_type = header[3];
+        let stream_id =
+            u32::from_be_bytes([header[5], header[6], header[7], header[8]]) & 0x7fff_ffff;
+
+        match frame_type {
+            FRAME_HEADERS => {
+                let first = h2_first_header_byte(msg, offset, len, header[4]);
+                // Client streams are odd; even ones are server push
+                let from_client = msg.direction == streams.h2_client;
+                if from_client && stream_id & 1 == 1 {
+                    // Trailers come on a stream that is already pending
+                    if find_request(streams, stream_id).is_none() {
+                        // :path is HPACK-coded, so it is not hashed
+                        start_request(streams, stream_id, h2_method(first), (0, 0), msg, now);
+                    }
+                } else if !from_client {
+                    finish_request(ctx, streams, stream_id, h2_status(first), msg, now);
+                }
+            }
+            FRAME_RST_STREAM => {
+                if let Some(i) = find_request(streams, stream_id) {
+                    streams.pending[i].start_ns = 0;
+                }
+            }
+            _ => {}
+        }
+
+        offset += H2_FRAME_HEADER_LEN + len;
+    }
+
+    if offset < msg.size {
+        streams.h2_lost[dir] = 1;
+    } else {
+        streams.h2_skip[dir] = (offset - msg.size) as u32;
+    }
+
+    Ok(())
+}
+
+/// First byte of a HEADERS frame's header block, past any padding length
+/// and priority fields; 0 if it isn't in this message
+#[inline(always)]
+fn h2_first_header_byte(msg: &L7Scratch, offset: u64, len: u64, flags: u8) -> u8 {
+    const FLAG_PADDED: u8 = 0x8;
+    const FLAG_PRIORITY: u8 = 0x20;
+    // Stream dependency + weight
+    const PRIORITY_LEN: u64 = 5;
+
+    let mut at = offset + H2_FRAME_HEADER_LEN;
+    if flags & FLAG_PADDED != 0 {
+        at += 1;
+    }
+    if flags & FLAG_PRIORITY != 0 {
+        at += PRIORITY_LEN;
+    }
+    if at >= offset + H2_FRAME_HEADER_LEN + len || at >= msg.buf_len {
+        return 0;
+    }
+
+    unsafe { bpf_probe_read_user((msg.buf + at) as *const u8) }.unwrap_or(0)
+}
+
+/// `http_method` of a request header block starting with an indexed
+/// `:method` from the HPACK static table (RFC 7541, Appendix A)
+#[inline(always)]
+fn h2_method(first: u8) -> u8 {
+    match first {
+        0x82 => http_method::GET,
+        0x83 => http_method::POST,
+        _ => http_method::UNKNOWN,
+    }
+}
+
+/// Status of a response header block starting with an indexed `:status`
+/// from the HPACK static table; 0 for any other encoding
+#[inline(always)]
+fn h2_status(first: u8) -> u16 {
+    match first {
+        0x88 => 200,
+        0x89 => 204,
+        0x8a => 206,
+        0x8b => 304,
+        0x8c => 400,
+        0x8d => 404,
+        0x8e => 500,
+        _ => 0,
+    }
+}
+
+/// The connection's `HTTP_STREAMS` entry, created empty if missing
+#[inline(always)]
+fn http_streams(key: &ConnKey) -> Result<*mut HttpStreams, ProbeError> {
+    if let Some(streams) = HTTP_STREAMS.get_ptr_mut(key) {
+        return Ok(streams);
+    }
+
+    HTTP_STREAMS
+        .insert(key, &HttpStreams::default(), BPF_NOEXIST as u64)
+        .map_err(|_| ProbeError::MapInsertFailed)?;
+    HTTP_STREAMS
+        .get_ptr_mut(key)
+        .ok_or(ProbeError::MapLookupFailed)
+}
+
+/// Slot of the pending request on `stream_id`
+#[inline(always)]
+fn find_request(streams: &HttpStreams, stream_id: u32) -> Option<usize> {
+    for i in 0..MAX_HTTP_STREAMS {
+        let pending = &streams.pending[i];
+        if pending.start_ns != 0 && pending.stream_id == stream_id {
+            return Some(i);
+        }
+    }
+    None
+}
+
+/// `http_path` hash and check of the target of the HTTP/1.x request in
+/// `msg`; both 0 if it couldn't be read
+#[inline(always)]
+fn request_path_hash(msg: &L7Scratch, method: u8) -> (u64, u32) {
+    // The target follows the method and one space
+    let start = http_method::method_name(method).len() as u64 + 1;
+    let mut hash = http_path::HASH_SEED;
+    let mut check = http_path::CHECK_SEED;
+    for chunk in 0..(http_path::MAX_HASHED_LEN / 8) as u64 {
+        let offset = start + chunk * 8;
+        if offset >= msg.buf_len {
+            break;
+        }
+        let bytes: [u8; 8] = match unsafe { bpf_probe_read_user((msg.buf + offset) as *const _) } {
+            Ok(bytes) => bytes,
+            Err(_) => return (0, 0),
+        };
+        for (i, &byte) in bytes.iter().enumerate() {
+            if offset + i as u64 >= msg.buf_len || http_path::ends_path(byte) {
+                return (hash, check);
+            }
+            hash = http_path::hash_byte(hash, byte);
+            check = http_path::check_byte(check, byte);
+        }
+    }
+    (hash, check)
+}
+
+/// Start timing a request, in a free slot or else the oldest one's
+#[inline(always)]
+fn start_request(
+    streams: &mut HttpStreams,
+    stream_id: u32,
+    method: u8,
+    (path_hash, path_check): (u64, u32),
+    msg: &L7Scratch,
+    now: u64,
+) {
+    let mut slot = 0;
+    let mut oldest = u64::MAX;
+    for i in 0..MAX_HTTP_STREAMS {
+        let start_ns = streams.pending[i].start_ns;
+        if start_ns == 0 {
+            slot = i;
+            break;
+        }
+        if start_ns < oldest {
+            oldest = start_ns;
+            slot = i;
+        }
+    }
+
+    streams.pending[slot] = PendingRequest {
+        start_ns: now,
+        stream_id,
+        method,
+        direction: msg.direction,
+        _padding: 0,
+        queue: msg.queue,
+        path_hash,
+        path_check,
+        _padding2: 0,
+    };
+}
+
+/// Emit an `HttpEvent` for the request on `stream_id` if this message is its
+/// response (it went the other way), and free its slot
+#[inline(always)]
+fn finish_request(
+    ctx: &ProbeContext,
+    streams: &mut HttpStreams,
+    stream_id: u32,
+    status_code: u16,
+    msg: &L7Scratch,
+    now: u64,
+) {
+    let i = match find_request(streams, stream_id) {
+        Some(i) => i,
+        None => return,
+    };
+    let pending = &mut streams.pending[i];
+    if pending.direction == msg.direction {
+        return;
+    }
+
+    let event = HttpEvent {
+        conn: msg.conn,
+        latency_ns: now.saturating_sub(pending.start_ns),
+        status_code,
+        method: pending.method,
+        _padding: 0,
+        queued_bytes: pending.queue.queued_bytes,
+        srtt_us: pending.queue.srtt_us,
+        snd_cwnd: pending.queue.snd_cwnd,
+        mss: pending.queue.mss,
+        stream_id,
+        path_hash: pending.path_hash,
+        path_check: pending.path_check,
+        _padding2: 0,
+    };
+    EVENTS.output(ctx, &event, 0);
+    pending.start_ns = 0;
+}
+
+/// TLS record parser
+#[kprobe]
+pub fn parse_tls(ctx: ProbeContext) -> u32 {
+    match try_parse_tls(&ctx) {
+        Ok(()) => 0,
+        Err(_) => 1,
+    }
+}
+
+fn try_parse_tls(ctx: &ProbeContext) -> Result<(), ProbeError> {
+    const CONTENT_HANDSHAKE: u8 = 0x16;
+    const HANDSHAKE_CLIENT_HELLO: u8 = 1;
+
+    let msg = l7_scratch()?;
+    let conn = &msg.conn;
+
+    // Record header: content type @ 0, version @ 1, length @ 3;
+    // handshake messages start with their type @ 5 (1 = ClientHello)
+    let content_type = msg.head[0];
+    let version = u16::from_be_bytes([msg.head[1], msg.head[2]]);
+    let handshake_type = if content_type == CONTENT_HANDSHAKE {
+        msg.head[5]
+    } else {
+        0
+    };
+
+    debug!(
+        ctx,
+        "TLS RECORD: type {} version {:x} handshake {} -> {}:{}",
+        content_type,
+        version,
+        handshake_type,
+        log_ip(&conn.dst_ip),
+        conn.dst_port
+    );
+    flag_conn(conn, conn_flag::TLS);
+
+    let fingerprints = CONFIG.get(0).map_or(false, |c| c.tls_fingerprints != 0);
+    if handshake_type == HANDSHAKE_CLIENT_HELLO && fingerprints {
+        send_client_hello(ctx, msg)?;
+    }
+
+    Ok(())
+}
+
+/// Copy the start of a ClientHello into a `TlsHelloEvent` for userspace,
+/// which parses and fingerprints it
+///
+/// Only the first `TLS_HELLO_MAX` bytes of the first piece of the message
+/// are copied; a hello spanning several iovecs or sends arrives cut short.
+#[inline(always)]
+fn send_client_hello(ctx: &ProbeContext, msg: &L7Scratch) -> Result<(), ProbeError> {
+    let event = unsafe {
+        &mut *TLS_HELLO_SCRATCH
+            .get_ptr_mut(0)
+            .ok_or(ProbeError::MapLookupFailed)?
+    };
+    let len = (msg.buf_len.min(msg.size) as usize).min(TLS_HELLO_MAX);
+    if len == 0 {
+        return Ok(());
+    }
+    unsafe { bpf_probe_read_user_buf(msg.buf as *const u8, &mut event.data[..len]) }
+        .map_err(|_| ProbeError::UserReadFailed)?;
+
+    event.conn = msg.conn;
+    event.pid = (bpf_get_current_pid_tgid() >> 32) as u32;
+    event.captured_len = len as u16;
+    TLS_HELLO_EVENTS.output(ctx, event, 0);
+    Ok(())
+}
+
+/// DNS-over-TCP parser
+#[kprobe]
+pub fn parse_dns(ctx: ProbeContext) -> u32 {
+    match try_parse_dns(&ctx) {
+        Ok(()) => 0,
+        Err(_) => 1,
+    }
+}
+
+fn try_parse_dns(ctx: &ProbeContext) -> Result<(), ProbeError> {
+    const FLAG_QR: u16 = 0x8000;
 
-/// Runtime configuration from userspace
-/// Index 0 contains the current SidecarConfig
-#[map]
-static CONFIG: Array<SidecarConfig> = Array::with_max_entries(1, 0);
+    let msg = l7_scratch()?;
+    let conn = &msg.conn;
 
-/// HTTP events sent to userspace via perf buffer
-#[map]
-static EVENTS: PerfEventArray<HttpEvent> = PerfEventArray::new(0);
+    // 2-byte length prefix, then the header: id, flags, qdcount
+    let head = &msg.head;
+    let id = u16::from_be_bytes([head[2], head[3]]);
+    let flags = u16::from_be_bytes([head[4], head[5]]);
+    let questions = u16::from_be_bytes([head[6], head[7]]);
+
+    let (dst_ip, rcode) = (log_ip(&conn.dst_ip), flags & 0xf);
+    if flags & FLAG_QR == 0 {
+        debug!(
+            ctx,
+            "DNS QUERY: id {} questions {} -> {}:{}", id, questions, dst_ip, conn.dst_port
+        );
+    } else {
+        debug!(
+            ctx,
+            "DNS RESPONSE: id {} rcode {} -> {}:{}", id, rcode, dst_ip, conn.dst_port
+        );
+    }
+    flag_conn(conn, conn_flag::DNS);
+
+    Ok(())
+}
 
 // ============================================================================
-// Helper Functions
+// Tracepoint Programs - Attach to kernel tracepoints
 // ============================================================================
 
-/// Check if we should trace this process based on config
-#[inline(always)]
-fn should_trace(ctx: &impl EbpfContext) -> bool {
-    let config = match unsafe { CONFIG.get(0) } {
-        Some(c) => c,
-        None => return true, // No config = trace everything
-    };
+/// Track TCP retransmissions via tracepoint
+/// This is more reliable than kprobing tcp_retransmit_skb
+#[tracepoint]
+pub fn trace_tcp_retransmit(ctx: TracePointContext) -> u32 {
+    match try_trace_tcp_retransmit(&ctx) {
+        Ok(()) => 0,
+        Err(_) => 1,
+    }
+}
 
-    // If target_pid is set, only trace that PID
-    if config.target_pid != 0 {
-        let pid = (bpf_get_current_pid_tgid() >> 32) as u32;
-        if pid != config.target_pid {
-            return false;
-        }
+fn try_trace_tcp_retransmit(ctx: &TracePointContext) -> Result<(), ProbeError> {
+    if !sampled(probe::RETRANSMIT) {
+        return Ok(());
     }
 
-    true
+    // Tracepoint format: tcp:tcp_retransmit_skb
+    // skbaddr @ 8, skaddr @ 16, then state, ports and addresses
+    // (check /sys/kernel/debug/tracing/events/tcp/tcp_retransmit_skb/format)
+    // This is kernel-version specific
+    let skaddr: *const u8 = tp_field(ctx, 16)?;
+    count_retransmit(ctx, skaddr)
 }
 
-/// Extract connection key from sock struct pointer
-/// 
-/// # Safety
-/// Caller must ensure sock pointer is valid
+/// Track TCP retransmissions on kernels without the `tcp_retransmit_skb`
+/// tracepoint; userspace attaches it only when the tracepoint is missing.
+///
+/// `tcp_retransmit_skb(sk, skb, segs)` is called once per retransmission
+/// attempt, including ones that fail to send, which the tracepoint leaves
+/// out, so it can count slightly more.
+#[kprobe]
+pub fn trace_tcp_retransmit_skb(ctx: ProbeContext) -> u32 {
+    match try_trace_tcp_retransmit_skb(&ctx) {
+        Ok(()) => 0,
+        Err(_) => 1,
+    }
+}
+
+fn try_trace_tcp_retransmit_skb(ctx: &ProbeContext) -> Result<(), ProbeError> {
+    if !sampled(probe::RETRANSMIT) {
+        return Ok(());
+    }
+    let sk: *const u8 = ctx.arg(0).ok_or_else(arg_read_error)?;
+    count_retransmit(ctx, sk)
+}
+
+/// Count a retransmission on the tracked connection of socket `sk`.
 #[inline(always)]
-unsafe fn read_conn_key_from_sock(sock: *const u8) -> Result<ConnKey, i64> {
-    // Offsets into struct sock -> __sk_common
-    // These are for Linux 5.x+ kernels - may need adjustment
-    // In production, use CO-RE (Compile Once Run Everywhere) for portability
-    const SK_COMMON_OFFSET: usize = 0;
-    const SKADDR_OFFSET: usize = 4;   // __sk_common.skc_rcv_saddr
-    const DADDR_OFFSET: usize = 0;    // __sk_common.skc_daddr
-    const SPORT_OFFSET: usize = 14;   // __sk_common.skc_num (source port)
-    const DPORT_OFFSET: usize = 12;   // __sk_common.skc_dport (dest port, network order)
+fn count_retransmit<C: EbpfContext>(ctx: &C, sk: *const u8) -> Result<(), ProbeError> {
+    // Build the key from the socket itself, exactly as the probes that
+    // create entries do, rather than from the tracepoint record's copies of
+    // the addresses and ports, whose offsets and byte order differ from
+    // ConnKey
+    let key = unsafe { read_conn_key_from_sock(sk)? };
 
-    let common = sock.add(SK_COMMON_OFFSET);
+    if let Some(metrics) = CONNECTIONS.get_ptr_mut(&key) {
+        let m = unsafe { &mut *metrics };
+        m.retransmits += 1;
 
-    let src_ip = bpf_probe_read_kernel(common.add(SKADDR_OFFSET) as *const u32)
-        .map_err(|_| 1i64)?;
-    let dst_ip = bpf_probe_read_kernel(common.add(DADDR_OFFSET) as *const u32)
-        .map_err(|_| 2i64)?;
-    let src_port = bpf_probe_read_kernel(common.add(SPORT_OFFSET) as *const u16)
-        .map_err(|_| 3i64)?;
-    let dst_port_be = bpf_probe_read_kernel(common.add(DPORT_OFFSET) as *const u16)
-        .map_err(|_| 4i64)?;
+        debug!(
+            ctx,
+            "RETRANSMIT: {}:{} -> {}:{} (count: {})",
+            log_ip(&key.src_ip),
+            key.src_port,
+            log_ip(&key.dst_ip),
+            key.dst_port,
+            m.retransmits
+        );
+    }
 
-    Ok(ConnKey {
-        src_ip,
-        dst_ip,
-        src_port,
-        dst_port: u16::from_be(dst_port_be),
-    })
+    Ok(())
 }
 
-// ============================================================================
-// Kprobe Programs - Attach to kernel functions
-// ============================================================================
-
-/// Track new TCP connections (outbound connect)
+/// Count SACK blocks and DSACKs on ACKs of tracked connections
+///
+/// `tcp_check_dsack` is static, called once per ACK that carries SACK
+/// blocks. Like the retransmit tracepoint it runs in softirq context, so
+/// it only updates connections that are already tracked.
 #[kprobe]
-pub fn trace_tcp_connect(ctx: ProbeContext) -> u32 {
-    match try_trace_tcp_connect(&ctx) {
+pub fn trace_tcp_check_dsack(ctx: ProbeContext) -> u32 {
+    match try_trace_tcp_check_dsack(&ctx) {
         Ok(()) => 0,
-        Err(e) => {
-            warn!(&ctx, "tcp_connect error: {}", e);
-            1
+        Err(_) => 1,
+    }
+}
+
+fn try_trace_tcp_check_dsack(ctx: &ProbeContext) -> Result<(), ProbeError> {
+    // tcp_check_dsack(sk, ack_skb, sp, num_sacks, prior_snd_una, state)
+    let sock: *const u8 = ctx.arg(0).ok_or_else(arg_read_error)?;
+    let num_sacks: i32 = ctx.arg(3).ok_or_else(arg_read_error)?;
+    let key = unsafe { read_conn_key_from_sock(sock)? };
+
+    if let Some(metrics) = CONNECTIONS.get_ptr_mut(&key) {
+        let m = unsafe { &mut *metrics };
+        m.sack_blocks += num_sacks.max(0) as u32;
+        // As of the previous SACK: this one is checked for a DSACK after
+        // the probe runs. The next send or SACK catches up.
+        if let Ok(dsacks) = unsafe { read_dsack_dups(sock) } {
+            m.dsacks = dsacks;
         }
     }
+
+    Ok(())
 }
 
-fn try_trace_tcp_connect(ctx: &ProbeContext) -> Result<(), i64> {
-    if !should_trace(ctx) {
+/// Count outbound connections that fail before reaching ESTABLISHED
+///
+/// Not PID-filtered: the SYN_SENT -> CLOSE transition happens in softirq or
+/// timer context, so the "current" task is unrelated to the socket owner.
+#[tracepoint]
+pub fn trace_inet_sock_set_state(ctx: TracePointContext) -> u32 {
+    match try_trace_inet_sock_set_state(&ctx) {
+        Ok(()) => 0,
+        Err(_) => 1,
+    }
+}
+
+fn try_trace_inet_sock_set_state(ctx: &TracePointContext) -> Result<(), ProbeError> {
+    const TCP_ESTABLISHED: i32 = 1;
+    const TCP_SYN_SENT: i32 = 2;
+    const TCP_CLOSE: i32 = 7;
+    const AF_INET: u16 = 2;
+    const IPPROTO_TCP: u16 = 6;
+    // Offset of sock.sk_err - kernel-version specific
+    const SK_ERR_OFFSET: usize = 372;
+
+    const ECONNREFUSED: i32 = 111;
+    const ETIMEDOUT: i32 = 110;
+    const EHOSTUNREACH: i32 = 113;
+    const ENETUNREACH: i32 = 101;
+
+    // Tracepoint format: sock:inet_sock_set_state
+    // skaddr @ 8, oldstate @ 16, newstate @ 20, sport @ 24, dport @ 26,
+    // family @ 28, protocol @ 30, saddr[4] @ 32, daddr[4] @ 36
+    let oldstate: i32 = tp_field(ctx, 16)?;
+    let newstate: i32 = tp_field(ctx, 20)?;
+    if newstate == TCP_ESTABLISHED {
+        // The handshake settled the options; connect-time entries predate it
+        let skaddr: *const u8 = tp_field(ctx, 8)?;
+        if unsafe { read_tstamp_ok(skaddr) }? {
+            let key = unsafe { read_conn_key_from_sock(skaddr)? };
+            flag_conn(&key, conn_flag::TCP_TIMESTAMPS);
+        }
+        return Ok(());
+    }
+    if oldstate != TCP_SYN_SENT || newstate != TCP_CLOSE {
         return Ok(());
     }
 
-    // First argument is struct sock *
-    let sock: *const u8 = ctx.arg(0).ok_or(1i64)?;
-    let key = unsafe { read_conn_key_from_sock(sock)? };
+    let family: u16 = tp_field(ctx, 28)?;
+    let protocol: u16 = tp_field(ctx, 30)?;
+    if family != AF_INET || protocol != IPPROTO_TCP {
+        return Ok(());
+    }
 
-    let now = unsafe { bpf_ktime_get_ns() };
-    let metrics = ConnMetrics {
-        bytes_sent: 0,
-        bytes_recv: 0,
-        packets_sent: 0,
-        packets_recv: 0,
-        start_ns: now,
-        last_seen_ns: now,
-        retransmits: 0,
+    let skaddr: *const u8 = tp_field(ctx, 8)?;
+    let dport: u16 = tp_field(ctx, 26)?;
+    let daddr: u32 = tp_field(ctx, 36)?;
+
+    let sk_err =
+        unsafe { bpf_probe_read_kernel(skaddr.add(SK_ERR_OFFSET) as *const i32) }.unwrap_or(0);
+    let reason = match sk_err {
+        ECONNREFUSED => connect_failure::REFUSED,
+        ETIMEDOUT => connect_failure::TIMEOUT,
+        EHOSTUNREACH | ENETUNREACH => connect_failure::UNREACHABLE,
+        _ => connect_failure::OTHER,
+    };
+
+    let key = ConnFailKey {
+        dst_ip: daddr,
+        dst_port: dport,
+        reason,
         _padding: 0,
     };
 
-    CONNECTIONS.insert(&key, &metrics, 0)?;
+    match CONNECT_FAILURES.get_ptr_mut(&key) {
+        Some(count) => unsafe { *count += 1 },
+        None => {
+            let _ = CONNECT_FAILURES.insert(&key, &1, BPF_NOEXIST as u64);
+        }
+    }
 
-    debug!(
-        ctx,
-        "NEW CONN: {}:{} -> {}:{}",
-        key.src_ip,
-        key.src_port,
-        key.dst_ip,
-        key.dst_port
-    );
+    debug!(ctx, "CONNECT FAILED: {}:{} (err {})", daddr, dport, sk_err);
 
     Ok(())
 }
 
-/// Track TCP send operations
-#[kprobe]
-pub fn trace_tcp_sendmsg(ctx: ProbeContext) -> u32 {
-    match try_trace_tcp_sendmsg(&ctx) {
+/// Follow forks of monitored processes
+#[tracepoint]
+pub fn trace_sched_process_fork(ctx: TracePointContext) -> u32 {
+    match try_trace_sched_process_fork(&ctx) {
         Ok(()) => 0,
         Err(_) => 1,
     }
 }
 
-fn try_trace_tcp_sendmsg(ctx: &ProbeContext) -> Result<(), i64> {
-    if !should_trace(ctx) {
+fn try_trace_sched_process_fork(ctx: &TracePointContext) -> Result<(), ProbeError> {
+    // Tracepoint format: sched:sched_process_fork
+    // parent_comm[16] @ 8, parent_pid @ 24, child_comm[16] @ 28, child_pid @ 44
+    let parent_pid: u32 = tp_field(ctx, 24)?;
+    if unsafe { TARGET_PIDS.get(&parent_pid) }.is_none() {
         return Ok(());
     }
 
-    let sock: *const u8 = ctx.arg(0).ok_or(1i64)?;
-    let size: usize = ctx.arg(2).ok_or(2i64)?;
+    let child_pid: u32 = tp_field(ctx, 44)?;
+    // Fails when the map is full; exit handling below frees slots
+    TARGET_PIDS
+        .insert(&child_pid, &0, BPF_NOEXIST as u64)
+        .map_err(|_| ProbeError::MapInsertFailed)?;
 
-    let key = unsafe { read_conn_key_from_sock(sock)? };
+    debug!(ctx, "FORK: {} -> {} (now monitored)", parent_pid, child_pid);
 
-    if let Some(metrics) = unsafe { CONNECTIONS.get_ptr_mut(&key) } {
-        let m = unsafe { &mut *metrics };
-        m.bytes_sent += size as u64;
-        m.packets_sent += 1;
-        m.last_seen_ns = unsafe { bpf_ktime_get_ns() };
+    Ok(())
+}
+
+/// Stop following exited tasks so the bounded TARGET_PIDS map doesn't fill up
+#[tracepoint]
+pub fn trace_sched_process_exit(ctx: TracePointContext) -> u32 {
+    match try_trace_sched_process_exit(&ctx) {
+        Ok(()) => 0,
+        Err(_) => 1,
     }
+}
+
+fn try_trace_sched_process_exit(ctx: &TracePointContext) -> Result<(), ProbeError> {
+    // Tracepoint format: sched:sched_process_exit
+    // comm[16] @ 8, pid @ 24
+    let pid: u32 = tp_field(ctx, 24)?;
+    let _ = TARGET_PIDS.remove(&pid);
+    let _ = PROCESS_EXES.remove(&pid);
 
     Ok(())
 }
 
-/// Track TCP receive operations
+/// Count a UDP datagram of `len` bytes in the `direction` histogram
+#[inline(always)]
+fn count_udp_datagram(dir: u8, len: u64) {
+    let Some(histogram) = UDP_SIZES.get_ptr_mut(dir as u32) else {
+        return;
+    };
+    let mut bucket = 0;
+    while bucket < UDP_SIZE_BUCKETS.len() && len > UDP_SIZE_BUCKETS[bucket] as u64 {
+        bucket += 1;
+    }
+    let histogram = unsafe { &mut *histogram };
+    if let Some(count) = histogram.counts.get_mut(bucket) {
+        *count += 1;
+    }
+    histogram.sum += len;
+}
+
+/// Count the size of each UDP datagram sent; attached to `udp_sendmsg` and
+/// `udpv6_sendmsg`
 #[kprobe]
-pub fn trace_tcp_recvmsg(ctx: ProbeContext) -> u32 {
-    match try_trace_tcp_recvmsg(&ctx) {
+pub fn trace_udp_sendmsg(ctx: ProbeContext) -> u32 {
+    match try_trace_udp_sendmsg(&ctx) {
         Ok(()) => 0,
         Err(_) => 1,
     }
 }
 
-fn try_trace_tcp_recvmsg(ctx: &ProbeContext) -> Result<(), i64> {
+fn try_trace_udp_sendmsg(ctx: &ProbeContext) -> Result<(), ProbeError> {
+    // udp_sendmsg(sk, msg, len), and the same for udpv6_sendmsg
+    let len: usize = ctx.arg(2).ok_or_else(arg_read_error)?;
     if !should_trace(ctx) {
         return Ok(());
     }
+    count_udp_datagram(direction::SENT, len as u64);
 
-    let sock: *const u8 = ctx.arg(0).ok_or(1i64)?;
-    let key = unsafe { read_conn_key_from_sock(sock)? };
+    Ok(())
+}
 
-    // Note: We increment packet count here, but can't easily get size
-    // For accurate byte counts, use kretprobe to capture return value
-    if let Some(metrics) = unsafe { CONNECTIONS.get_ptr_mut(&key) } {
-        let m = unsafe { &mut *metrics };
-        m.packets_recv += 1;
-        m.last_seen_ns = unsafe { bpf_ktime_get_ns() };
+/// Count the size of each UDP datagram received, as returned to the
+/// application; attached to `udp_recvmsg` and `udpv6_recvmsg`
+#[kretprobe]
+pub fn trace_udp_recvmsg_ret(ctx: RetProbeContext) -> u32 {
+    match try_trace_udp_recvmsg_ret(&ctx) {
+        Ok(()) => 0,
+        Err(_) => 1,
+    }
+}
+
+fn try_trace_udp_recvmsg_ret(ctx: &RetProbeContext) -> Result<(), ProbeError> {
+    // Bytes of the datagram (0 is a valid, empty one) or a negative error
+    let ret: i64 = ctx.ret().ok_or_else(arg_read_error)?;
+    if ret < 0 || !should_trace(ctx) {
+        return Ok(());
     }
+    count_udp_datagram(direction::RECEIVED, ret as u64);
 
     Ok(())
 }
 
-/// Track TCP receive return to get actual bytes received
-#[kretprobe]
-pub fn trace_tcp_recvmsg_ret(ctx: RetProbeContext) -> u32 {
-    match try_trace_tcp_recvmsg_ret(&ctx) {
+/// Count bytes handed to a network device's driver
+#[tracepoint]
+pub fn trace_net_dev_xmit(ctx: TracePointContext) -> u32 {
+    match try_trace_net_dev_xmit(&ctx) {
         Ok(()) => 0,
         Err(_) => 1,
     }
 }
 
-fn try_trace_tcp_recvmsg_ret(ctx: &RetProbeContext) -> Result<(), i64> {
-    // Return value is bytes received (or negative error)
-    let ret: i64 = ctx.ret().ok_or(1i64)?;
-    if ret <= 0 {
-        return Ok(()); // Error or no data
+fn try_trace_net_dev_xmit(ctx: &TracePointContext) -> Result<(), ProbeError> {
+    const NETDEV_TX_OK: i32 = 0;
+
+    // Tracepoint format: net:net_dev_xmit
+    // skbaddr @ 8, len @ 16, rc @ 20, then the device name
+    let rc: i32 = tp_field(ctx, 20)?;
+    // A busy driver gets the same skb again later
+    if rc != NETDEV_TX_OK {
+        return Ok(());
     }
+    let skb: *const u8 = tp_field(ctx, 8)?;
+    let len: u32 = tp_field(ctx, 16)?;
+    let key = unsafe { read_skb_iface(skb)? };
+    count_iface_bytes(&key, len as u64, false);
 
-    // We need to track which connection this belongs to
-    // This is tricky without the sock pointer - in production use
-    // a temporary map to store sock -> key mapping
-    
     Ok(())
 }
 
-/// Track TCP connection close for cleanup
-#[kprobe]
-pub fn trace_tcp_close(ctx: ProbeContext) -> u32 {
-    match try_trace_tcp_close(&ctx) {
+/// Count TCP segments dropped by the PAWS check (RFC 7323): their timestamp
+/// is older than the last one seen on the connection. Host-wide, like the
+/// `PAWSEstab` counter of `nstat`.
+#[tracepoint]
+pub fn trace_kfree_skb(ctx: TracePointContext) -> u32 {
+    match try_trace_kfree_skb(&ctx) {
         Ok(()) => 0,
         Err(_) => 1,
     }
 }
 
-fn try_trace_tcp_close(ctx: &ProbeContext) -> Result<(), i64> {
-    let sock: *const u8 = ctx.arg(0).ok_or(1i64)?;
-    let key = unsafe { read_conn_key_from_sock(sock)? };
+fn try_trace_kfree_skb(ctx: &TracePointContext) -> Result<(), ProbeError> {
+    // Tracepoint format: skb:kfree_skb
+    // The offset of reason and the value for PAWS vary between kernels;
+    // userspace reads both from the tracepoint's format file
+    let (paws, offset) = match CONFIG.get(0) {
+        Some(c) if c.paws_drop_reason != 0 => (c.paws_drop_reason, c.kfree_skb_reason_offset),
+        _ => return Ok(()),
+    };
+    let reason: u32 = tp_field(ctx, offset as usize)?;
+    if reason == paws {
+        if let Some(count) = PAWS_DROPS.get_ptr_mut(0) {
+            unsafe { *count += 1 };
+        }
+    }
 
-    // Log final stats before removing
-    if let Some(metrics) = unsafe { CONNECTIONS.get(&key) } {
-        let duration_ns = unsafe { bpf_ktime_get_ns() } - metrics.start_ns;
-        info!(
-            ctx,
-            "CLOSE: {}:{} -> {}:{} | TX:{} RX:{} RTX:{} dur:{}ms",
-            key.src_ip,
-            key.src_port,
-            key.dst_ip,
-            key.dst_port,
-            metrics.bytes_sent,
-            metrics.bytes_recv,
-            metrics.retransmits,
-            duration_ns / 1_000_000
-        );
+    Ok(())
+}
+
+/// Count bytes a network device passed up the stack
+#[tracepoint]
+pub fn trace_netif_receive_skb(ctx: TracePointContext) -> u32 {
+    match try_trace_netif_receive_skb(&ctx) {
+        Ok(()) => 0,
+        Err(_) => 1,
     }
+}
 
-    // Remove from map (cleanup)
-    let _ = CONNECTIONS.remove(&key);
+fn try_trace_netif_receive_skb(ctx: &TracePointContext) -> Result<(), ProbeError> {
+    // Tracepoint format: net:netif_receive_skb
+    // skbaddr @ 8, len @ 16, then the device name
+    let skb: *const u8 = tp_field(ctx, 8)?;
+    let len: u32 = tp_field(ctx, 16)?;
+    let key = unsafe { read_skb_iface(skb)? };
+    count_iface_bytes(&key, len as u64, true);
 
     Ok(())
 }
 
-// ============================================================================
-// Tracepoint Programs - Attach to kernel tracepoints
-// ============================================================================
+/// Remember the descriptor a socket I/O system call was made on, for
+/// `note_fd`. One program, attached to the entry of each of those calls
+#[tracepoint]
+pub fn trace_sys_enter_fd(ctx: TracePointContext) -> u32 {
+    match try_trace_sys_enter_fd(&ctx) {
+        Ok(()) => 0,
+        Err(_) => 1,
+    }
+}
 
-/// Track TCP retransmissions via tracepoint
-/// This is more reliable than kprobing tcp_retransmit_skb
+fn try_trace_sys_enter_fd(ctx: &TracePointContext) -> Result<(), ProbeError> {
+    if !should_trace(ctx) {
+        return Ok(());
+    }
+
+    // Tracepoint format: syscalls:sys_enter_{read,write,sendto,...}
+    // __syscall_nr @ 8, then each argument as 8 bytes; fd is the first @ 16
+    let fd: u64 = tp_field(ctx, 16)?;
+    let tid = bpf_get_current_pid_tgid() as u32;
+    SYSCALL_FDS
+        .insert(&tid, &(fd as i32), 0)
+        .map_err(|_| ProbeError::MapInsertFailed)
+}
+
+/// Record the full executable path of traced processes
+///
+/// Unlike comm, this isn't truncated to 16 bytes. Processes that exec'd
+/// before the sidecar started have no entry; userspace falls back to /proc.
 #[tracepoint]
-pub fn trace_tcp_retransmit(ctx: TracePointContext) -> u32 {
-    match try_trace_tcp_retransmit(&ctx) {
+pub fn trace_sched_process_exec(ctx: TracePointContext) -> u32 {
+    match try_trace_sched_process_exec(&ctx) {
         Ok(()) => 0,
         Err(_) => 1,
     }
 }
 
-fn try_trace_tcp_retransmit(ctx: &TracePointContext) -> Result<(), i64> {
-    // Tracepoint format: tcp:tcp_retransmit_skb
-    // Fields at specific offsets (check /sys/kernel/debug/tracing/events/tcp/tcp_retransmit_skb/format)
-    // This is kernel-version specific
-    
-    let saddr: u32 = unsafe { ctx.read_at(16)? };
-    let daddr: u32 = unsafe { ctx.read_at(20)? };
-    let sport: u16 = unsafe { ctx.read_at(24)? };
-    let dport: u16 = unsafe { ctx.read_at(26)? };
-
-    let key = ConnKey {
-        src_ip: saddr,
-        dst_ip: daddr,
-        src_port: sport,
-        dst_port: dport,
+fn try_trace_sched_process_exec(ctx: &TracePointContext) -> Result<(), ProbeError> {
+    if !should_trace(ctx) {
+        return Ok(());
+    }
+
+    // Tracepoint format: sched:sched_process_exec
+    // __data_loc char[] filename @ 8, pid @ 12, old_pid @ 16
+    let filename_loc: u32 = tp_field(ctx, 8)?;
+    let pid: u32 = tp_field(ctx, 12)?;
+
+    let buf = unsafe {
+        &mut *EXE_SCRATCH
+            .get_ptr_mut(0)
+            .ok_or(ProbeError::MapLookupFailed)?
     };
+    // __data_loc: low 16 bits are the offset from the start of the record
+    let filename = unsafe { (ctx.as_ptr() as *const u8).add((filename_loc & 0xffff) as usize) };
+    // Always NUL-terminates, truncating paths that don't fit
+    let len = unsafe { bpf_probe_read_kernel_str_bytes(filename, buf) }
+        .map_err(|_| ProbeError::StrReadFailed)?
+        .len();
 
-    if let Some(metrics) = unsafe { CONNECTIONS.get_ptr_mut(&key) } {
-        let m = unsafe { &mut *metrics };
-        m.retransmits += 1;
-        
-        debug!(ctx, "RETRANSMIT: {}:{} -> {}:{} (count: {})", 
-            saddr, sport, daddr, dport, m.retransmits);
+    PROCESS_EXES
+        .insert(&pid, buf, 0)
+        .map_err(|_| ProbeError::MapInsertFailed)?;
+
+    debug!(ctx, "EXEC: {} ({} byte path)", pid, len);
+
+    Ok(())
+}
+
+// ============================================================================
+// Policy Programs - Attach to a cgroup's connect() hook
+// ============================================================================
+//
+// These only observe: they always return 1 (allow). Returning 0 would fail
+// the connect() with EPERM.
+
+/// TCP in `bpf_sock_addr.protocol`; other protocols are not checked
+const POLICY_PROTOCOL: u32 = 6;
+
+/// Report IPv4 connects to denylisted destinations
+#[cgroup_sock_addr(connect4)]
+pub fn policy_connect4(ctx: SockAddrContext) -> i32 {
+    let _ = try_policy_connect4(&ctx);
+    1
+}
+
+fn try_policy_connect4(ctx: &SockAddrContext) -> Result<(), ProbeError> {
+    const AF_INET: u16 = 2;
+
+    let addr = unsafe { &*ctx.sock_addr };
+    if addr.protocol != POLICY_PROTOCOL {
+        return Ok(());
+    }
+
+    check_policy(ctx, addr.user_ip4, addr.user_port, AF_INET)
+}
+
+/// Report IPv6 connects to denylisted destinations
+///
+/// Only v4-mapped destinations (`::ffff:a.b.c.d`) can match: rules are IPv4.
+#[cgroup_sock_addr(connect6)]
+pub fn policy_connect6(ctx: SockAddrContext) -> i32 {
+    let _ = try_policy_connect6(&ctx);
+    1
+}
+
+fn try_policy_connect6(ctx: &SockAddrContext) -> Result<(), ProbeError> {
+    const AF_INET6: u16 = 10;
+
+    let addr = unsafe { &*ctx.sock_addr };
+    if addr.protocol != POLICY_PROTOCOL {
+        return Ok(());
+    }
+
+    let ip6 = addr.user_ip6;
+    if ip6[0] != 0 || ip6[1] != 0 || u32::from_be(ip6[2]) != 0xffff {
+        return Ok(());
     }
 
+    check_policy(ctx, ip6[3], addr.user_port, AF_INET6)
+}
+
+/// Look up a destination in `POLICY_DENYLIST`, port-specific rules first,
+/// and emit a `PolicyEvent` on a match
+#[inline(always)]
+fn check_policy(
+    ctx: &SockAddrContext,
+    dst_ip: u32,
+    user_port: u32,
+    family: u16,
+) -> Result<(), ProbeError> {
+    // user_port holds the port in network byte order in its low 16 bits
+    let dst_port = u16::from_be(user_port as u16);
+    let rule = match match_destination(&POLICY_DENYLIST, dst_ip, dst_port) {
+        Some(rule) => *rule,
+        None => return Ok(()),
+    };
+
+    let event = PolicyEvent {
+        pid: (bpf_get_current_pid_tgid() >> 32) as u32,
+        rule,
+        dst_ip,
+        dst_port,
+        family,
+        comm: ctx.command().unwrap_or_default(),
+    };
+    POLICY_EVENTS.output(ctx, &event, 0);
+
+    debug!(
+        ctx,
+        "POLICY: connect to {}:{} matches rule {}", dst_ip, dst_port, rule
+    );
+
     Ok(())
 }
 
@@ -0,0 +1,93 @@
+//! Locate the compiled eBPF object to embed in the loader.
+//!
+//! The userspace profile and the eBPF profile don't have to match: a release
+//! loader can embed a debug-built eBPF object and vice versa. Selection order:
+//!
+//! 1. `SIDECAR_BPF_PROFILE=debug|release` - use exactly that profile
+//! 2. the object matching the userspace profile, if it exists
+//! 3. the object from the other profile
+//!
+//! The chosen path is passed to the crate as `SIDECAR_BPF_OBJECT`.
+//!
+//! The commit being built is passed as `SIDECAR_GIT_SHA`, for
+//! `sidecar_build_info`: `$SIDECAR_GIT_SHA` if set (e.g. by a build from a
+//! source tarball), else `git rev-parse`, else "unknown".
+
+use std::env;
+use std::path::PathBuf;
+use std::process::Command;
+
+const PROFILES: [&str; 2] = ["debug", "release"];
+
+fn main() {
+    let manifest_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+    let target_dir = manifest_dir.join("../target/bpfel-unknown-none");
+    let object = |profile: &str| target_dir.join(profile).join("sidecar");
+
+    println!("cargo:rerun-if-env-changed=SIDECAR_BPF_PROFILE");
+    for profile in PROFILES {
+        println!("cargo:rerun-if-changed={}", object(profile).display());
+    }
+
+    let candidates: Vec<&str> = match env::var("SIDECAR_BPF_PROFILE") {
+        Ok(profile) => match profile.as_str() {
+            "debug" => vec!["debug"],
+            "release" => vec!["release"],
+            other => panic!(
+                "SIDECAR_BPF_PROFILE must be one of {:?}, got {:?}",
+                PROFILES, other
+            ),
+        },
+        Err(_) if env::var("PROFILE").as_deref() == Ok("release") => vec!["release", "debug"],
+        Err(_) => vec!["debug", "release"],
+    };
+
+    println!("cargo:rustc-env=SIDECAR_GIT_SHA={}", git_sha());
+
+    match candidates
+        .iter()
+        .map(|p| object(p))
+        .find(|path| path.exists())
+    {
+        Some(path) => {
+            println!("cargo:rustc-env=SIDECAR_BPF_OBJECT={}", path.display());
+        }
+        None => {
+            let searched: Vec<String> = candidates
+                .iter()
+                .map(|p| format!("  {}", object(p).display()))
+                .collect();
+            panic!(
+                "No eBPF object found. Build it first with `cargo xtask build-ebpf` \
+                 (add --release for release). Searched:\n{}",
+                searched.join("\n")
+            );
+        }
+    }
+}
+
+/// The commit being built, short form.
+fn git_sha() -> String {
+    println!("cargo:rerun-if-env-changed=SIDECAR_GIT_SHA");
+    if let Ok(sha) = env::var("SIDECAR_GIT_SHA") {
+        return sha;
+    }
+    let git = |args: &[&str]| {
+        let output = Command::new("git").args(args).output().ok()?;
+        output
+            .status
+            .success()
+            .then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
+    };
+    // Rebuild when HEAD moves, directly or through the branch it points to.
+    // A missing file (e.g. a packed ref) would rerun this on every build.
+    let branch = git(&["rev-parse", "--symbolic-full-name", "HEAD"]);
+    for name in ["HEAD"].into_iter().chain(branch.as_deref()) {
+        if let Some(path) = git(&["rev-parse", "--git-path", name]) {
+            if PathBuf::from(&path).exists() {
+                println!("cargo:rerun-if-changed={}", path);
+            }
+        }
+    }
+    git(&["rev-parse", "--short=12", "HEAD"]).unwrap_or_else(|| "unknown".to_string())
+}
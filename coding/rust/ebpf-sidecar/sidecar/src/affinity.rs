@@ -0,0 +1,126 @@
+//! Confining the sidecar's own threads to a set of CPUs.
+//!
+//! A CPU list is comma-separated CPUs and inclusive ranges, as in
+//! `/sys/devices/system/cpu/online` or `taskset -c`:
+//!
+//! - `0` - CPU 0
+//! - `0-3` - CPUs 0 to 3
+//! - `0-1,6,8-9` - CPUs 0, 1, 6, 8 and 9
+//!
+//! Only userspace is affected: the eBPF programs run on whichever CPU hits
+//! the probe, in the context of the traced process.
+
+use anyhow::{Context, Result};
+use std::collections::BTreeSet;
+use std::str::FromStr;
+
+/// A set of CPUs; see the module docs for the syntax.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CpuList(BTreeSet<usize>);
+
+impl CpuList {
+    /// The CPUs in ascending order.
+    pub fn cpus(&self) -> impl Iterator<Item = usize> + '_ {
+        self.0.iter().copied()
+    }
+
+    fn cpu_set(&self) -> libc::cpu_set_t {
+        // SAFETY: cpu_set_t is a plain bitmask, valid when zeroed
+        let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+        for cpu in self.cpus() {
+            // SAFETY: parsing keeps every CPU below CPU_SETSIZE
+            unsafe { libc::CPU_SET(cpu, &mut set) };
+        }
+        set
+    }
+}
+
+impl FromStr for CpuList {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut cpus = BTreeSet::new();
+        for part in s.split(',') {
+            let part = part.trim();
+            let (first, last) = part.split_once('-').unwrap_or((part, part));
+            let parse = |cpu: &str| {
+                cpu.trim()
+                    .parse::<usize>()
+                    .with_context(|| format!("Invalid CPU {:?} in {:?}", cpu, s))
+            };
+            let (first, last) = (parse(first)?, parse(last)?);
+            if first > last {
+                anyhow::bail!("Invalid CPU range {:?}: {} is above {}", part, first, last);
+            }
+            if last >= libc::CPU_SETSIZE as usize {
+                anyhow::bail!("CPU {} is beyond the {} supported", last, libc::CPU_SETSIZE);
+            }
+            cpus.extend(first..=last);
+        }
+        Ok(Self(cpus))
+    }
+}
+
+impl std::fmt::Display for CpuList {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut cpus = self.cpus().peekable();
+        let mut first = true;
+        while let Some(start) = cpus.next() {
+            let mut end = start;
+            while cpus.next_if_eq(&(end + 1)).is_some() {
+                end += 1;
+            }
+            if !first {
+                f.write_str(",")?;
+            }
+            first = false;
+            if start == end {
+                write!(f, "{}", start)?;
+            } else {
+                write!(f, "{}-{}", start, end)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Restrict every thread of this process to `cpus`, through
+/// `sched_setaffinity` on each thread in `/proc/self/task`. Threads started
+/// afterwards inherit the mask of the thread that starts them, so calling
+/// this once early in `main` covers the async runtime's workers and its
+/// blocking pool.
+///
+/// Fails if none of `cpus` is online or allowed (e.g. by the cgroup's
+/// cpuset).
+pub fn pin_process(cpus: &CpuList) -> Result<()> {
+    let set = cpus.cpu_set();
+    let tasks = std::fs::read_dir("/proc/self/task").context("Failed to read /proc/self/task")?;
+    for entry in tasks {
+        let entry = entry?;
+        let Some(tid) = entry
+            .file_name()
+            .to_str()
+            .and_then(|n| n.parse::<libc::pid_t>().ok())
+        else {
+            continue;
+        };
+        // SAFETY: set is a valid cpu_set_t of the size passed
+        let ret = unsafe { libc::sched_setaffinity(tid, std::mem::size_of_val(&set), &set) };
+        if ret != 0 {
+            let err = std::io::Error::last_os_error();
+            // A thread that exited since the directory was read
+            if err.raw_os_error() == Some(libc::ESRCH) {
+                continue;
+            }
+            if err.raw_os_error() == Some(libc::EINVAL) {
+                anyhow::bail!(
+                    "None of CPUs {} is online and allowed for the sidecar",
+                    cpus
+                );
+            }
+            return Err(err)
+                .with_context(|| format!("Failed to pin thread {} to CPUs {}", tid, cpus));
+        }
+    }
+    Ok(())
+}
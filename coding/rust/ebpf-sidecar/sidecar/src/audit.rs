@@ -0,0 +1,119 @@
+//! Append-only audit log of configuration changes.
+//!
+//! Every change applied to the running sidecar is written as one JSON object
+//! per line, recording when it happened, what triggered it, and the old and
+//! new value of each changed field. Each entry is synced to disk before
+//! `record` returns so the trail survives a crash.
+
+use crate::MonitorOptions;
+use anyhow::{Context, Result};
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// What caused a configuration change.
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuditTrigger {
+    /// Initial configuration at process start
+    Startup,
+    /// Reload of the eBPF programs requested with SIGUSR1
+    Signal,
+    /// Change requested through the HTTP API
+    Http,
+}
+
+/// Handle to the audit log file.
+pub struct AuditLog {
+    file: File,
+}
+
+impl AuditLog {
+    /// Open (or create) the audit log at `path` for appending.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to open audit log {}", path.display()))?;
+        Ok(Self { file })
+    }
+
+    /// Record a change from `old` (`None` at startup) to `new`.
+    ///
+    /// Nothing is written if no field changed.
+    pub fn record(
+        &mut self,
+        trigger: AuditTrigger,
+        old: Option<&MonitorOptions>,
+        new: &MonitorOptions,
+    ) -> Result<()> {
+        let old = match old {
+            Some(old) => serde_json::to_value(old)?,
+            None => Value::Null,
+        };
+        let new = serde_json::to_value(new)?;
+
+        let changes = diff_fields(&old, &new);
+        if changes.is_empty() {
+            return Ok(());
+        }
+        self.write(trigger, changes)
+    }
+
+    /// Record a `SIGUSR1` reload of the eBPF programs, from the object with
+    /// SHA-256 `old_sha` to the one with `new_sha`.
+    ///
+    /// Written even for the same object, with no changes: every program was
+    /// attached again.
+    pub fn record_reload(&mut self, old_sha: &str, new_sha: &str) -> Result<()> {
+        let changes = diff_fields(
+            &json!({ "bpf_object_sha": old_sha }),
+            &json!({ "bpf_object_sha": new_sha }),
+        );
+        self.write(AuditTrigger::Signal, changes)
+    }
+
+    fn write(&mut self, trigger: AuditTrigger, changes: Vec<Value>) -> Result<()> {
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        let entry = json!({
+            "timestamp_ms": timestamp_ms,
+            "trigger": trigger,
+            "changes": changes,
+        });
+
+        writeln!(self.file, "{}", entry)?;
+        self.file.flush()?;
+        self.file.sync_data()?;
+        Ok(())
+    }
+}
+
+/// Per-field `{field, old, new}` records for every top-level field that
+/// differs between two JSON objects. A `Null` old value means "unset".
+fn diff_fields(old: &Value, new: &Value) -> Vec<Value> {
+    let Some(new_fields) = new.as_object() else {
+        return Vec::new();
+    };
+
+    new_fields
+        .iter()
+        .filter_map(|(field, new_value)| {
+            let old_value = old.get(field).unwrap_or(&Value::Null);
+            (old_value != new_value).then(|| {
+                json!({
+                    "field": field,
+                    "old": old_value,
+                    "new": new_value,
+                })
+            })
+        })
+        .collect()
+}
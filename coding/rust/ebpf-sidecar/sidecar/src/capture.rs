@@ -0,0 +1,112 @@
+//! Hex dumps of sampled payloads.
+//!
+//! With payload capture on, the kernel copies the first bytes of a sampled
+//! share of sends and receives into [`PayloadEvent`]s. [`CaptureWriter`]
+//! appends each one to a file as a header line followed by a classic
+//! offset / hex / ASCII dump:
+//!
+//! ```text
+//! 1700000000123 pid=4242 10.0.0.5:41234 -> 10.0.0.9:8080 sent 512 bytes (64 captured)
+//! 00000000  47 45 54 20 2f 20 48 54  54 50 2f 31 2e 31 0d 0a  |GET / HTTP/1.1..|
+//! ```
+//!
+//! Payloads are whatever the application sent, credentials and personal
+//! data included, so the file is created readable by its owner only.
+
+use crate::metrics;
+use anyhow::{Context, Result};
+use sidecar_common::{direction, PayloadEvent, PAYLOAD_CAPTURE_MAX};
+use std::fmt::Write as _;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::net::IpAddr;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Bytes per hex dump line.
+const LINE_BYTES: usize = 16;
+
+/// Handle to the capture file.
+pub struct CaptureWriter {
+    file: File,
+}
+
+impl CaptureWriter {
+    /// Open (or create, mode 0600) the capture file at `path` for appending.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .mode(0o600)
+            .open(path)
+            .with_context(|| format!("Failed to open payload capture file {}", path.display()))?;
+        Ok(Self { file })
+    }
+
+    /// Append one capture.
+    pub fn write(&mut self, event: &PayloadEvent) -> Result<()> {
+        self.file.write_all(format_event(event).as_bytes())?;
+        Ok(())
+    }
+}
+
+/// The header line and hex dump of `event`, newline-terminated.
+pub fn format_event(event: &PayloadEvent) -> String {
+    let addr = |ip: IpAddr, port: u16| format!("{}:{}", metrics::exported_ip(ip), port);
+    let dir = match event.direction {
+        direction::SENT => "sent",
+        direction::RECEIVED => "received",
+        _ => "unknown",
+    };
+    let timestamp_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64;
+    let mut out = format!(
+        "{} pid={} {} -> {} {} {} bytes ({} captured)\n",
+        timestamp_ms,
+        event.pid,
+        addr(event.conn.src_addr(), event.conn.src_port),
+        addr(event.conn.dst_addr(), event.conn.dst_port),
+        dir,
+        event.size,
+        event.captured_len,
+    );
+    let len = (event.captured_len as usize).min(PAYLOAD_CAPTURE_MAX);
+    out.push_str(&hex_dump(&event.data[..len]));
+    out
+}
+
+/// `data` as `hexdump -C` style lines.
+pub fn hex_dump(data: &[u8]) -> String {
+    let mut out = String::new();
+    for (i, line) in data.chunks(LINE_BYTES).enumerate() {
+        let _ = write!(out, "{:08x} ", i * LINE_BYTES);
+        for j in 0..LINE_BYTES {
+            if j % 8 == 0 {
+                out.push(' ');
+            }
+            match line.get(j) {
+                Some(b) => {
+                    let _ = write!(out, "{:02x} ", b);
+                }
+                None => out.push_str("   "),
+            }
+        }
+        out.push_str(" |");
+        out.extend(line.iter().map(|&b| printable(b)));
+        out.push_str("|\n");
+    }
+    out
+}
+
+/// `b` as shown in the ASCII column: itself if printable, `.` otherwise.
+fn printable(b: u8) -> char {
+    if b == b' ' || b.is_ascii_graphic() {
+        b as char
+    } else {
+        '.'
+    }
+}
@@ -0,0 +1,109 @@
+//! Monitoring a whole cgroup v2 subtree.
+//!
+//! The kernel matches the current task's cgroup id against the
+//! `TARGET_CGROUPS` set. A cgroup's id is the inode number of its directory
+//! in the cgroup2 filesystem, so the set is built by walking the target
+//! directory. Containers starting or stopping add and remove child cgroups,
+//! so the set is refreshed periodically with [`CgroupTargets::refresh`].
+
+use anyhow::{Context, Result};
+use aya::maps::{HashMap, MapData};
+use log::debug;
+use std::collections::HashSet;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+/// Where relative cgroup paths are resolved.
+pub const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+/// `path` as an absolute cgroupfs directory: absolute paths are kept,
+/// relative ones are taken from [`CGROUP_ROOT`].
+pub fn cgroup_dir(path: &Path) -> PathBuf {
+    if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        Path::new(CGROUP_ROOT).join(path)
+    }
+}
+
+/// Id of the cgroup at `dir`.
+pub fn cgroup_id(dir: &Path) -> Result<u64> {
+    let metadata = std::fs::metadata(dir)
+        .with_context(|| format!("Failed to read cgroup {}", dir.display()))?;
+    if !metadata.is_dir() {
+        anyhow::bail!("{} is not a cgroup directory", dir.display());
+    }
+    Ok(metadata.ino())
+}
+
+/// Ids of the cgroup at `dir` and every cgroup below it.
+///
+/// Cgroups removed during the walk are skipped.
+pub fn subtree_ids(dir: &Path) -> Result<HashSet<u64>> {
+    let mut ids = HashSet::from([cgroup_id(dir)?]);
+    let mut pending = vec![dir.to_path_buf()];
+
+    while let Some(dir) = pending.pop() {
+        let Ok(entries) = std::fs::read_dir(&dir) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let Ok(metadata) = entry.metadata() else {
+                continue;
+            };
+            if metadata.is_dir() {
+                ids.insert(metadata.ino());
+                pending.push(entry.path());
+            }
+        }
+    }
+
+    Ok(ids)
+}
+
+/// The kernel's `TARGET_CGROUPS` set, kept in sync with a cgroup subtree.
+pub struct CgroupTargets {
+    dir: PathBuf,
+    ids: HashMap<MapData, u64, u8>,
+    current: HashSet<u64>,
+}
+
+impl CgroupTargets {
+    /// Take over `map`, already seeded with `current` by `Monitor::configure`.
+    pub(crate) fn new(dir: PathBuf, ids: HashMap<MapData, u64, u8>, current: HashSet<u64>) -> Self {
+        Self { dir, ids, current }
+    }
+
+    /// The monitored cgroup directory.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Walk the subtree again and add/remove ids so the kernel matches it.
+    /// Returns the number of cgroups now monitored.
+    pub fn refresh(&mut self) -> Result<usize> {
+        let wanted = subtree_ids(&self.dir)?;
+
+        for &id in wanted.difference(&self.current) {
+            self.ids.insert(id, 0, 0)?;
+            debug!("Monitoring new cgroup {}", id);
+        }
+        for &id in self.current.difference(&wanted) {
+            // Only missing if something else removed it
+            let _ = self.ids.remove(&id);
+            debug!("Cgroup {} removed", id);
+        }
+
+        self.current = wanted;
+        Ok(self.current.len())
+    }
+}
+
+/// Seed `map` with the subtree at `dir`, returning the ids written.
+pub(crate) fn seed(map: &mut HashMap<&mut MapData, u64, u8>, dir: &Path) -> Result<HashSet<u64>> {
+    let ids = subtree_ids(dir)?;
+    for &id in &ids {
+        map.insert(id, 0, 0)?;
+    }
+    Ok(ids)
+}
@@ -0,0 +1,192 @@
+//! Detaching the costliest probes while the host is overloaded.
+//!
+//! The probes on `tcp_sendmsg` and `tcp_recvmsg` run in the monitored
+//! application's threads on every message, so under extreme load the
+//! observability tool adds to the incident. The circuit breaker samples the
+//! host's load average and the sidecar's own CPU time on every collection.
+//! Once either has stayed over its threshold for the sustain period, it
+//! trips and the loader detaches those probes. Once both have stayed under
+//! for the same period, it resets and they are attached again.
+//!
+//! # Limitations
+//! - Probe time is charged to the threads that trigger it, not to the
+//!   sidecar: it shows up in the load average, not in the sidecar's own CPU
+//!   usage, which only covers collection and export.
+//! - The 1-minute load average trails the load by about a minute, so the
+//!   breaker reacts that much later than the sustain period alone.
+
+use anyhow::{Context, Result};
+use std::time::{Duration, Instant};
+
+/// When to trip. A threshold left unset is never exceeded.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Thresholds {
+    /// 1-minute load average divided by the number of online CPUs
+    pub load_per_cpu: Option<f64>,
+    /// The sidecar's own CPU time, in percent of one CPU
+    pub own_cpu_percent: Option<f64>,
+}
+
+/// One reading of the values `Thresholds` are checked against.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct LoadSample {
+    pub load_per_cpu: f64,
+    pub own_cpu_percent: f64,
+}
+
+/// A change of the breaker's state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transition {
+    /// Overloaded for the whole sustain period: detach the probes
+    Trip,
+    /// Back under every threshold for the whole sustain period: attach them
+    Reset,
+}
+
+/// Trip/reset decisions over a series of samples.
+///
+/// ```
+/// use sidecar::circuit_breaker::{CircuitBreaker, LoadSample, Thresholds, Transition};
+/// use std::time::{Duration, Instant};
+///
+/// let thresholds = Thresholds { load_per_cpu: Some(2.0), own_cpu_percent: None };
+/// let mut breaker = CircuitBreaker::new(thresholds, Duration::from_secs(30));
+/// let high = LoadSample { load_per_cpu: 3.0, own_cpu_percent: 1.0 };
+/// let low = LoadSample { load_per_cpu: 0.5, own_cpu_percent: 1.0 };
+/// let t0 = Instant::now();
+/// let at = |secs| t0 + Duration::from_secs(secs);
+///
+/// assert_eq!(breaker.observe(high, at(0)), None);
+/// assert_eq!(breaker.observe(high, at(30)), Some(Transition::Trip));
+/// assert!(breaker.tripped());
+/// assert_eq!(breaker.observe(low, at(35)), None);
+/// // Overloaded again: the quiet period starts over
+/// assert_eq!(breaker.observe(high, at(40)), None);
+/// assert_eq!(breaker.observe(low, at(45)), None);
+/// assert_eq!(breaker.observe(low, at(75)), Some(Transition::Reset));
+/// assert!(!breaker.tripped());
+/// ```
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    thresholds: Thresholds,
+    sustain: Duration,
+    tripped: bool,
+    /// Since when samples have been on the other side of the thresholds
+    /// than the current state, if they are
+    crossing_since: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    /// A closed breaker, tripping after `sustain` over `thresholds`.
+    pub fn new(thresholds: Thresholds, sustain: Duration) -> Self {
+        Self {
+            thresholds,
+            sustain,
+            tripped: false,
+            crossing_since: None,
+        }
+    }
+
+    /// Whether the probes should be detached.
+    pub fn tripped(&self) -> bool {
+        self.tripped
+    }
+
+    /// Take a sample taken at `now` into account.
+    pub fn observe(&mut self, sample: LoadSample, now: Instant) -> Option<Transition> {
+        if self.thresholds.exceeded_by(&sample) == self.tripped {
+            self.crossing_since = None;
+            return None;
+        }
+        let since = *self.crossing_since.get_or_insert(now);
+        if now.duration_since(since) < self.sustain {
+            return None;
+        }
+
+        self.tripped = !self.tripped;
+        self.crossing_since = None;
+        Some(if self.tripped {
+            Transition::Trip
+        } else {
+            Transition::Reset
+        })
+    }
+}
+
+impl Thresholds {
+    /// Whether any threshold is set.
+    pub fn any(&self) -> bool {
+        self.load_per_cpu.is_some() || self.own_cpu_percent.is_some()
+    }
+
+    /// Whether `sample` is over any threshold.
+    pub fn exceeded_by(&self, sample: &LoadSample) -> bool {
+        self.load_per_cpu
+            .is_some_and(|max| sample.load_per_cpu > max)
+            || self
+                .own_cpu_percent
+                .is_some_and(|max| sample.own_cpu_percent > max)
+    }
+}
+
+/// Reads `LoadSample`s. Own CPU usage is averaged since the previous read,
+/// so the first read reports it over the sidecar's lifetime.
+#[derive(Debug)]
+pub struct LoadSampler {
+    cpus: usize,
+    started: Instant,
+    previous: Option<(Instant, Duration)>,
+}
+
+impl LoadSampler {
+    /// A sampler normalizing the load by the CPUs online now.
+    pub fn new() -> Result<Self> {
+        let cpus = aya::util::online_cpus()
+            .map_err(|e| anyhow::anyhow!("Failed to read the online CPUs: {}", e))?
+            .len()
+            .max(1);
+        Ok(Self {
+            cpus,
+            started: Instant::now(),
+            previous: None,
+        })
+    }
+
+    /// Sample the load average and the CPU time used so far.
+    pub fn sample(&mut self) -> Result<LoadSample> {
+        let loadavg =
+            std::fs::read_to_string("/proc/loadavg").context("Failed to read /proc/loadavg")?;
+        let load = parse_loadavg(&loadavg).context("Malformed /proc/loadavg")?;
+
+        let now = Instant::now();
+        let cpu_time = own_cpu_time()?;
+        let (since, cpu_before) = self.previous.unwrap_or((self.started, Duration::ZERO));
+        self.previous = Some((now, cpu_time));
+        let wall = now.duration_since(since).as_secs_f64();
+        let own_cpu_percent = match wall > 0.0 {
+            true => cpu_time.saturating_sub(cpu_before).as_secs_f64() / wall * 100.0,
+            false => 0.0,
+        };
+
+        Ok(LoadSample {
+            load_per_cpu: load / self.cpus as f64,
+            own_cpu_percent,
+        })
+    }
+}
+
+/// The 1-minute load average, the first field of `/proc/loadavg`
+/// (`0.52 0.58 0.59 2/1123 4567`).
+fn parse_loadavg(loadavg: &str) -> Option<f64> {
+    loadavg.split_whitespace().next()?.parse().ok()
+}
+
+/// User plus system CPU time of the whole process, all threads included.
+fn own_cpu_time() -> Result<Duration> {
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    if unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) } != 0 {
+        return Err(std::io::Error::last_os_error()).context("getrusage failed");
+    }
+    let time = |t: libc::timeval| Duration::new(t.tv_sec as u64, t.tv_usec as u32 * 1000);
+    Ok(time(usage.ru_utime) + time(usage.ru_stime))
+}
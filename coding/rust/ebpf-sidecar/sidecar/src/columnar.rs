@@ -0,0 +1,219 @@
+//! Columnar archive of connection close events.
+//!
+//! Close events are buffered in memory, turned into an Arrow record batch on
+//! every `flush` and appended to the current Parquet file. Files rotate after
+//! a number of rows or an age, whichever comes first. A file only becomes
+//! readable once it is rotated or the sink is dropped, because that is when
+//! the Parquet footer is written.
+
+use anyhow::{Context, Result};
+use arrow_array::{
+    ArrayRef, RecordBatch, StringArray, TimestampMillisecondArray, UInt16Array, UInt32Array,
+    UInt64Array,
+};
+use arrow_schema::{DataType, Field, Schema, SchemaRef, TimeUnit};
+use log::{error, info};
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::file::properties::WriterProperties;
+use sidecar_common::{ConnCloseEvent, ConnKey};
+use std::fs::File;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// When to finish the current file and start a new one.
+#[derive(Debug, Clone, Copy)]
+pub struct Rotation {
+    /// Rows per file
+    pub max_rows: usize,
+    /// Age of a file, counted from when it was opened
+    pub max_age: Duration,
+}
+
+/// The Parquet file currently being appended to.
+struct OpenFile {
+    writer: ArrowWriter<File>,
+    path: PathBuf,
+    rows: usize,
+    opened: Instant,
+}
+
+/// Writes connection close events to rotating Parquet files in a directory.
+pub struct ParquetSink {
+    dir: PathBuf,
+    rotation: Rotation,
+    schema: SchemaRef,
+    /// Buffered events with their close time (ms since the Unix epoch)
+    pending: Vec<(i64, ConnCloseEvent)>,
+    file: Option<OpenFile>,
+}
+
+impl ParquetSink {
+    /// Write files named `connections-<unix ms>.parquet` into `dir`,
+    /// creating it if needed.
+    pub fn new<P: AsRef<Path>>(dir: P, rotation: Rotation) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("Failed to create parquet directory {}", dir.display()))?;
+
+        Ok(Self {
+            dir,
+            rotation,
+            schema: Arc::new(schema()),
+            pending: Vec::new(),
+            file: None,
+        })
+    }
+
+    /// Buffer a close event, stamped with the current wall-clock time.
+    pub fn record(&mut self, event: &ConnCloseEvent) {
+        self.pending.push((unix_millis(), *event));
+    }
+
+    /// Append buffered events to the current file, rotating it when it is
+    /// full or too old.
+    pub fn flush(&mut self) -> Result<()> {
+        // Checked even without new rows, so quiet periods still rotate
+        if self
+            .file
+            .as_ref()
+            .is_some_and(|f| f.opened.elapsed() >= self.rotation.max_age)
+        {
+            self.rotate()?;
+        }
+
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+
+        let batch = self.record_batch()?;
+        self.pending.clear();
+
+        let file = match self.file.take() {
+            Some(file) => file,
+            None => self.open_file()?,
+        };
+        let file = self.file.insert(file);
+        file.writer.write(&batch)?;
+        // End the row group so the rows don't sit in the writer's memory
+        file.writer.flush()?;
+        file.rows += batch.num_rows();
+
+        if file.rows >= self.rotation.max_rows {
+            self.rotate()?;
+        }
+
+        Ok(())
+    }
+
+    /// Finish the current file, if any, by writing its footer.
+    pub fn rotate(&mut self) -> Result<()> {
+        if let Some(file) = self.file.take() {
+            file.writer
+                .close()
+                .with_context(|| format!("Failed to finish {}", file.path.display()))?;
+            info!("Wrote {} ({} connections)", file.path.display(), file.rows);
+        }
+        Ok(())
+    }
+
+    fn open_file(&self) -> Result<OpenFile> {
+        let path = self
+            .dir
+            .join(format!("connections-{}.parquet", unix_millis()));
+        let file =
+            File::create(&path).with_context(|| format!("Failed to create {}", path.display()))?;
+        let props = WriterProperties::builder()
+            .set_compression(Compression::SNAPPY)
+            .build();
+
+        Ok(OpenFile {
+            writer: ArrowWriter::try_new(file, self.schema.clone(), Some(props))?,
+            path,
+            rows: 0,
+            opened: Instant::now(),
+        })
+    }
+
+    fn record_batch(&self) -> Result<RecordBatch> {
+        let events = &self.pending;
+        let ips = |addr: fn(&ConnKey) -> IpAddr| {
+            StringArray::from_iter_values(events.iter().map(|(_, e)| addr(&e.conn).to_string()))
+        };
+        let timestamps = |f: fn(&(i64, ConnCloseEvent)) -> i64| {
+            TimestampMillisecondArray::from_iter_values(events.iter().map(f)).with_timezone("UTC")
+        };
+
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(timestamps(|(closed, e)| {
+                closed - (e.duration_ns / 1_000_000) as i64
+            })),
+            Arc::new(timestamps(|(closed, _)| *closed)),
+            Arc::new(ips(ConnKey::src_addr)),
+            Arc::new(UInt16Array::from_iter_values(
+                events.iter().map(|(_, e)| e.conn.src_port),
+            )),
+            Arc::new(ips(ConnKey::dst_addr)),
+            Arc::new(UInt16Array::from_iter_values(
+                events.iter().map(|(_, e)| e.conn.dst_port),
+            )),
+            Arc::new(UInt64Array::from_iter_values(
+                events.iter().map(|(_, e)| e.bytes_sent),
+            )),
+            Arc::new(UInt64Array::from_iter_values(
+                events.iter().map(|(_, e)| e.bytes_recv),
+            )),
+            Arc::new(UInt64Array::from_iter_values(
+                events.iter().map(|(_, e)| e.packets_sent),
+            )),
+            Arc::new(UInt64Array::from_iter_values(
+                events.iter().map(|(_, e)| e.packets_recv),
+            )),
+            Arc::new(UInt32Array::from_iter_values(
+                events.iter().map(|(_, e)| e.retransmits),
+            )),
+            Arc::new(UInt64Array::from_iter_values(
+                events.iter().map(|(_, e)| e.duration_ns),
+            )),
+        ];
+
+        Ok(RecordBatch::try_new(self.schema.clone(), columns)?)
+    }
+}
+
+impl Drop for ParquetSink {
+    /// Write out whatever is buffered so the last file is complete.
+    fn drop(&mut self) {
+        if let Err(e) = self.flush().and_then(|()| self.rotate()) {
+            error!("Failed to finish parquet output: {}", e);
+        }
+    }
+}
+
+/// One row per closed connection.
+fn schema() -> Schema {
+    let timestamp = DataType::Timestamp(TimeUnit::Millisecond, Some("UTC".into()));
+    Schema::new(vec![
+        Field::new("start_time", timestamp.clone(), false),
+        Field::new("close_time", timestamp, false),
+        Field::new("src_ip", DataType::Utf8, false),
+        Field::new("src_port", DataType::UInt16, false),
+        Field::new("dst_ip", DataType::Utf8, false),
+        Field::new("dst_port", DataType::UInt16, false),
+        Field::new("bytes_sent", DataType::UInt64, false),
+        Field::new("bytes_recv", DataType::UInt64, false),
+        Field::new("packets_sent", DataType::UInt64, false),
+        Field::new("packets_recv", DataType::UInt64, false),
+        Field::new("retransmits", DataType::UInt32, false),
+        Field::new("duration_ns", DataType::UInt64, false),
+    ])
+}
+
+fn unix_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as i64
+}
@@ -17,6 +17,10 @@ pub struct Config {
     /// Logging configuration
     #[serde(default)]
     pub logging: LoggingConfig,
+
+    /// Per-probe sampling
+    #[serde(default)]
+    pub sample_rates: SampleRates,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
@@ -25,17 +29,35 @@ pub struct TargetConfig {
     #[serde(default)]
     pub pid: u32,
 
-    /// Process name to monitor (alternative to PID)
+    /// PID namespace `pid` is read in, as `process::PidNamespace`; the host
+    /// PID it maps to is monitored
+    #[serde(default)]
+    pub pid_namespace: Option<String>,
+
+    /// Process name to monitor (alternative to PID), matched against
+    /// `/proc/<pid>/comm`. `*` matches any characters (`nginx*`,
+    /// `*-worker`); see `process::NamePattern`.
     #[serde(default)]
     pub process_name: Option<String>,
 
-    /// cgroup path to monitor (for container filtering)
+    /// cgroup v2 path to monitor, including every cgroup below it (for
+    /// container filtering)
     #[serde(default)]
     pub cgroup: Option<String>,
 
     /// Ports to monitor (empty = all)
     #[serde(default)]
     pub ports: Vec<u16>,
+
+    /// Ports kept in the kernel filter when `ports` has more than 8 entries;
+    /// the rest are filtered in userspace at export
+    #[serde(default)]
+    pub priority_ports: Vec<u16>,
+
+    /// Destinations tracked even when the filters above exclude them,
+    /// `ADDR[/PREFIX][:PORT]` as in `policy::PolicyRule`
+    #[serde(default)]
+    pub always_track: Vec<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -48,9 +70,44 @@ pub struct MetricsConfig {
     #[serde(default = "default_interval")]
     pub interval_secs: u64,
 
+    /// How often the collected metrics are published to Prometheus
+    /// (None = every collection). Must not be shorter than `interval_secs`.
+    #[serde(default)]
+    pub flush_secs: Option<u64>,
+
+    /// Retransmit ratio above which an endpoint is reported unhealthy
+    #[serde(default = "default_unhealthy_retransmit_ratio")]
+    pub unhealthy_retransmit_ratio: f64,
+
+    /// Smoothing factor of the per-endpoint throughput average (0 < alpha <= 1)
+    #[serde(default = "default_throughput_ewma_alpha")]
+    pub throughput_ewma_alpha: f64,
+
+    /// Weights and scales of the per-endpoint health score
+    #[serde(default)]
+    pub health_score: HealthScoreConfig,
+
+    /// Export per-connection counters as per-flush deltas (gauges)
+    #[serde(default)]
+    pub delta_mode: bool,
+
     /// Enable HTTP layer 7 metrics
     #[serde(default)]
     pub enable_http: bool,
+
+    /// Rewrites of the per-connection labels, applied in order at export
+    #[serde(default)]
+    pub relabel: Vec<RelabelConfig>,
+
+    /// Per-connection labels the series are keyed on (None = all); the
+    /// others are left blank and the connections sharing the rest merged
+    #[serde(default)]
+    pub group_by: Option<Vec<String>>,
+
+    /// Bucket bounds (seconds, increasing) of
+    /// `sidecar_connection_interpacket_gap_seconds`
+    #[serde(default = "default_interpacket_gap_buckets")]
+    pub interpacket_gap_buckets: Vec<f64>,
 }
 
 impl Default for MetricsConfig {
@@ -58,7 +115,15 @@ impl Default for MetricsConfig {
         Self {
             port: 9090,
             interval_secs: 5,
+            flush_secs: None,
+            unhealthy_retransmit_ratio: 0.05,
+            throughput_ewma_alpha: 0.3,
+            health_score: HealthScoreConfig::default(),
+            delta_mode: false,
             enable_http: false,
+            relabel: Vec::new(),
+            group_by: None,
+            interpacket_gap_buckets: default_interpacket_gap_buckets(),
         }
     }
 }
@@ -71,6 +136,147 @@ fn default_interval() -> u64 {
     5
 }
 
+fn default_unhealthy_retransmit_ratio() -> f64 {
+    0.05
+}
+
+fn default_throughput_ewma_alpha() -> f64 {
+    0.3
+}
+
+/// Default bucket boundaries (seconds) for the inter-packet gap histogram,
+/// from 100us to 10s: finer below 100ms, where jitter on interactive
+/// traffic (VoIP at 20ms per frame, games at 15-60ms per tick) shows.
+pub const INTERPACKET_GAP_SECONDS_BUCKETS: [f64; 14] = [
+    0.0001, 0.0005, 0.001, 0.005, 0.01, 0.015, 0.02, 0.03, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0,
+];
+
+fn default_interpacket_gap_buckets() -> Vec<f64> {
+    INTERPACKET_GAP_SECONDS_BUCKETS.to_vec()
+}
+
+/// Tuning of `sidecar_endpoint_health_score` (see `metrics::health_score`
+/// for the formula). Each input is scaled by its `max_*` into a penalty
+/// between 0 and 1, and the weights set how much of the score each penalty
+/// can take; only their ratios matter.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HealthScoreConfig {
+    /// Weight of the retransmit ratio
+    pub retransmit_weight: f64,
+    /// Weight of the mean RTT
+    pub rtt_weight: f64,
+    /// Weight of the connection failure rate
+    pub failure_weight: f64,
+    /// Retransmit ratio that takes the full retransmit weight
+    pub max_retransmit_ratio: f64,
+    /// Mean RTT (milliseconds) that takes the full RTT weight
+    pub max_rtt_ms: f64,
+    /// Share of failed connects that takes the full failure weight
+    pub max_failure_ratio: f64,
+}
+
+impl Default for HealthScoreConfig {
+    fn default() -> Self {
+        Self {
+            retransmit_weight: 0.4,
+            rtt_weight: 0.3,
+            failure_weight: 0.3,
+            max_retransmit_ratio: 0.05,
+            max_rtt_ms: 200.0,
+            max_failure_ratio: 0.2,
+        }
+    }
+}
+
+impl HealthScoreConfig {
+    /// Fail on a negative weight, all-zero weights or a non-positive scale.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let weights = [
+            ("retransmit_weight", self.retransmit_weight),
+            ("rtt_weight", self.rtt_weight),
+            ("failure_weight", self.failure_weight),
+        ];
+        for (name, weight) in weights {
+            if !(weight.is_finite() && weight >= 0.0) {
+                anyhow::bail!("metrics.health_score.{} must be >= 0, got {}", name, weight);
+            }
+        }
+        if self.retransmit_weight + self.rtt_weight + self.failure_weight <= 0.0 {
+            anyhow::bail!("metrics.health_score needs at least one non-zero weight");
+        }
+
+        let scales = [
+            ("max_retransmit_ratio", self.max_retransmit_ratio),
+            ("max_rtt_ms", self.max_rtt_ms),
+            ("max_failure_ratio", self.max_failure_ratio),
+        ];
+        for (name, scale) in scales {
+            if !(scale.is_finite() && scale > 0.0) {
+                anyhow::bail!("metrics.health_score.{} must be > 0, got {}", name, scale);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// One relabeling rule, modelled on Prometheus' `relabel_configs` but
+/// limited to the per-connection labels (`scrape::CONNECTION_LABELS`): a
+/// rule can rewrite or blank their values, or drop a connection's series,
+/// but not add labels. See `relabel::Relabeler` for how each action works.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RelabelConfig {
+    /// Labels whose values, joined with `separator`, are matched against
+    /// `regex`
+    #[serde(default)]
+    pub source_labels: Vec<String>,
+
+    #[serde(default = "default_relabel_separator")]
+    pub separator: String,
+
+    /// Regular expression, anchored at both ends. With `labeldrop` it is
+    /// matched against label names instead.
+    #[serde(default = "default_relabel_regex")]
+    pub regex: String,
+
+    #[serde(default)]
+    pub action: RelabelAction,
+
+    /// Label written by `replace`
+    #[serde(default)]
+    pub target_label: Option<String>,
+
+    /// Value written by `replace`; `$1`, `${name}` refer to groups of `regex`
+    #[serde(default = "default_relabel_replacement")]
+    pub replacement: String,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RelabelAction {
+    /// Set `target_label` to `replacement` when `regex` matches
+    #[default]
+    Replace,
+    /// Drop the series of connections that don't match
+    Keep,
+    /// Drop the series of connections that match
+    Drop,
+    /// Blank every label whose name matches
+    LabelDrop,
+}
+
+fn default_relabel_separator() -> String {
+    ";".to_string()
+}
+
+fn default_relabel_regex() -> String {
+    "(.*)".to_string()
+}
+
+fn default_relabel_replacement() -> String {
+    "$1".to_string()
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LoggingConfig {
     /// Log level: trace, debug, info, warn, error
@@ -95,11 +301,76 @@ fn default_log_level() -> String {
     "info".to_string()
 }
 
+/// Percentage (0-100) of calls each kernel probe counts. A skipped call
+/// costs next to nothing, so lowering a hot probe's rate cuts its overhead;
+/// its counters then only cover the sampled calls.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SampleRates {
+    /// `tcp_connect`
+    pub connect: u8,
+    /// `tcp_sendmsg`, including L7 parsing of sends
+    pub sendmsg: u8,
+    /// `tcp_recvmsg`, including HTTP parsing of receives
+    pub recvmsg: u8,
+    /// `ip_queue_xmit` (wire bytes)
+    pub queue_xmit: u8,
+    /// The `tcp_retransmit_skb` tracepoint
+    pub retransmit: u8,
+    /// `tcp_reset` and `tcp_send_active_reset`
+    pub reset: u8,
+}
+
+impl Default for SampleRates {
+    fn default() -> Self {
+        Self {
+            connect: 100,
+            sendmsg: 100,
+            recvmsg: 100,
+            queue_xmit: 100,
+            retransmit: 100,
+            reset: 100,
+        }
+    }
+}
+
+impl SampleRates {
+    /// Fail on a rate over 100.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        for (name, rate) in self.named() {
+            if rate > 100 {
+                anyhow::bail!("sample_rates.{} must be at most 100, got {}", name, rate);
+            }
+        }
+        Ok(())
+    }
+
+    /// Each rate with its config key.
+    pub fn named(&self) -> [(&'static str, u8); 6] {
+        [
+            ("connect", self.connect),
+            ("sendmsg", self.sendmsg),
+            ("recvmsg", self.recvmsg),
+            ("queue_xmit", self.queue_xmit),
+            ("retransmit", self.retransmit),
+            ("reset", self.reset),
+        ]
+    }
+}
+
 impl Config {
     /// Load configuration from a YAML file.
     pub fn load<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
         let contents = std::fs::read_to_string(path)?;
-        let config: Config = serde_yaml::from_str(&contents)?;
+        Self::from_yaml(&contents)
+    }
+
+    /// Parse configuration from YAML text.
+    ///
+    /// Must never panic on malformed input - this is the fuzzed entry point
+    /// (see `fuzz/fuzz_targets/config_parse.rs`).
+    pub fn from_yaml(contents: &str) -> anyhow::Result<Self> {
+        let config: Config = serde_yaml::from_str(contents)?;
         Ok(config)
     }
 
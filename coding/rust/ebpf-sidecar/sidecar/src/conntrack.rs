@@ -0,0 +1,415 @@
+//! Original destinations of NATed connections, from the netfilter
+//! connection tracking table (`--conntrack`).
+//!
+//! The probes see a socket's own addresses. Behind DNAT, e.g. a service
+//! reached through a load balancer's or kube-proxy's virtual IP, the
+//! accepting side only sees its real address and the client; the address the
+//! client dialled lives in the conntrack entry. Each connection's entry is
+//! fetched over ctnetlink (`IPCTNL_MSG_CT_GET`), looking the socket's tuple up
+//! as the entry's original direction (connections it opened) and then as the
+//! reply direction (connections it accepted).
+//!
+//! Lookups are cached for the connection's life: a conntrack entry's NAT
+//! mapping is set by its first packet and never changes. Only the sidecar's
+//! own network namespace is looked up, as conntrack tables are per
+//! namespace. Reading the table needs `CAP_NET_ADMIN`.
+
+use anyhow::{Context, Result};
+use sidecar_common::ConnKey;
+use std::collections::HashMap;
+use std::io;
+use std::net::Ipv4Addr;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+/// `NETLINK_NETFILTER` protocol of netlink sockets
+const NETLINK_NETFILTER: libc::c_int = 12;
+
+/// `nlmsghdr` then `nfgenmsg`
+const NLMSG_HEADER_LEN: usize = 16;
+const NFGENMSG_LEN: usize = 4;
+
+/// `NLMSG_ERROR` message type
+const NLMSG_ERROR: u16 = 2;
+/// `NLM_F_REQUEST` flag
+const NLM_F_REQUEST: u16 = 1;
+
+/// `NFNL_SUBSYS_CTNETLINK << 8 | IPCTNL_MSG_CT_GET`
+const CT_GET: u16 = 1 << 8 | 1;
+/// `NFNL_SUBSYS_CTNETLINK << 8 | IPCTNL_MSG_CT_NEW`, the type of the entry
+/// a `CT_GET` is answered with
+const CT_NEW: u16 = 1 << 8;
+
+/// `NLA_F_NESTED`; attribute types are the low 14 bits
+const NLA_F_NESTED: u16 = 1 << 15;
+const NLA_TYPE_MASK: u16 = 0x3fff;
+
+/// ctnetlink attributes
+const CTA_TUPLE_ORIG: u16 = 1;
+const CTA_TUPLE_REPLY: u16 = 2;
+const CTA_TUPLE_IP: u16 = 1;
+const CTA_TUPLE_PROTO: u16 = 2;
+const CTA_IP_V4_SRC: u16 = 1;
+const CTA_IP_V4_DST: u16 = 2;
+const CTA_PROTO_NUM: u16 = 1;
+const CTA_PROTO_SRC_PORT: u16 = 2;
+const CTA_PROTO_DST_PORT: u16 = 3;
+
+/// Longest a lookup waits for the kernel's answer
+const REPLY_TIMEOUT_US: libc::suseconds_t = 100_000;
+
+/// One direction of a conntrack entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Tuple {
+    /// Source address
+    pub src_ip: Ipv4Addr,
+    /// Source port
+    pub src_port: u16,
+    /// Destination address
+    pub dst_ip: Ipv4Addr,
+    /// Destination port
+    pub dst_port: u16,
+}
+
+impl Tuple {
+    /// The tuple of a socket's own addresses: local as source, peer as
+    /// destination. None for IPv6 sockets, which are not looked up.
+    pub fn of_socket(key: &ConnKey) -> Option<Self> {
+        Some(Self {
+            src_ip: Ipv4Addr::from(key.src_ipv4()?.to_be()),
+            src_port: key.src_port,
+            dst_ip: Ipv4Addr::from(key.dst_ipv4()?.to_be()),
+            dst_port: key.dst_port,
+        })
+    }
+
+    /// The same tuple seen from the other end.
+    pub fn reversed(&self) -> Self {
+        Self {
+            src_ip: self.dst_ip,
+            src_port: self.dst_port,
+            dst_ip: self.src_ip,
+            dst_port: self.src_port,
+        }
+    }
+}
+
+/// The destination a NATed connection was originally addressed to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Origin {
+    /// Original destination address
+    pub dst_ip: Ipv4Addr,
+    /// Original destination port
+    pub dst_port: u16,
+}
+
+/// The original destination of the conntrack entry with directions `orig`
+/// and `reply`, for the socket with tuple `socket`; None unless the entry
+/// was NATed and the original destination is neither end of the socket.
+///
+/// That leaves the connections accepted behind DNAT: a connection the
+/// socket opened already has the dialled address as its destination.
+///
+/// ```
+/// use sidecar::conntrack::{nat_origin, Origin, Tuple};
+///
+/// let tuple = |src: [u8; 4], src_port, dst: [u8; 4], dst_port| Tuple {
+///     src_ip: src.into(),
+///     src_port,
+///     dst_ip: dst.into(),
+///     dst_port,
+/// };
+/// // A client dialled the virtual IP 10.96.0.10:80, DNATed to this pod
+/// let orig = tuple([10, 0, 0, 7], 51000, [10, 96, 0, 10], 80);
+/// let reply = tuple([10, 244, 1, 5], 8080, [10, 0, 0, 7], 51000);
+/// let accepted = reply;
+/// assert_eq!(
+///     nat_origin(&accepted, &orig, &reply),
+///     Some(Origin { dst_ip: [10, 96, 0, 10].into(), dst_port: 80 })
+/// );
+///
+/// // The client's own socket already shows the virtual IP
+/// assert_eq!(nat_origin(&orig, &orig, &reply), None);
+///
+/// // Not NATed
+/// assert_eq!(nat_origin(&orig, &orig, &orig.reversed()), None);
+/// ```
+pub fn nat_origin(socket: &Tuple, orig: &Tuple, reply: &Tuple) -> Option<Origin> {
+    if *reply == orig.reversed() {
+        return None;
+    }
+    let dialled = (orig.dst_ip, orig.dst_port);
+    if dialled == (socket.src_ip, socket.src_port) || dialled == (socket.dst_ip, socket.dst_port) {
+        return None;
+    }
+    Some(Origin {
+        dst_ip: orig.dst_ip,
+        dst_port: orig.dst_port,
+    })
+}
+
+/// A ctnetlink socket and the lookups made through it.
+pub struct Conntrack {
+    socket: OwnedFd,
+    seq: u32,
+    /// Lookups by connection; None for connections that aren't NATed or
+    /// have no entry
+    cache: HashMap<ConnKey, Option<Origin>>,
+}
+
+impl Conntrack {
+    /// Open a ctnetlink socket.
+    pub fn open() -> Result<Self> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                NETLINK_NETFILTER,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error())
+                .context("Failed to open a netfilter netlink socket");
+        }
+        let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        let timeout = libc::timeval {
+            tv_sec: 0,
+            tv_usec: REPLY_TIMEOUT_US,
+        };
+        let ret = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_RCVTIMEO,
+                &timeout as *const libc::timeval as *const libc::c_void,
+                std::mem::size_of::<libc::timeval>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error()).context("Failed to set a netlink timeout");
+        }
+
+        Ok(Self {
+            socket,
+            seq: 0,
+            cache: HashMap::new(),
+        })
+    }
+
+    /// The original destination of connection `key` if it was accepted
+    /// behind DNAT, looked up once per connection.
+    pub fn origin(&mut self, key: &ConnKey) -> io::Result<Option<Origin>> {
+        if let Some(&origin) = self.cache.get(key) {
+            return Ok(origin);
+        }
+        let Some(socket) = Tuple::of_socket(key) else {
+            return Ok(None);
+        };
+        let entry = match self.get(CTA_TUPLE_ORIG, &socket)? {
+            Some(entry) => Some(entry),
+            None => self.get(CTA_TUPLE_REPLY, &socket)?,
+        };
+        let origin = entry.and_then(|(orig, reply)| nat_origin(&socket, &orig, &reply));
+        self.cache.insert(*key, origin);
+        Ok(origin)
+    }
+
+    /// Forget the lookups of connections `live` no longer accepts.
+    pub fn retain(&mut self, live: impl Fn(&ConnKey) -> bool) {
+        self.cache.retain(|key, _| live(key));
+    }
+
+    /// The conntrack entry whose `direction` tuple is `tuple`, as its
+    /// original and reply tuples; None if there is none.
+    fn get(&mut self, direction: u16, tuple: &Tuple) -> io::Result<Option<(Tuple, Tuple)>> {
+        self.seq = self.seq.wrapping_add(1);
+        let request = get_request(self.seq, direction, tuple);
+        let sent = unsafe {
+            libc::send(
+                self.socket.as_raw_fd(),
+                request.as_ptr() as *const libc::c_void,
+                request.len(),
+                0,
+            )
+        };
+        if sent < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut buf = vec![0u8; 8192];
+        loop {
+            let len = unsafe {
+                libc::recv(
+                    self.socket.as_raw_fd(),
+                    buf.as_mut_ptr() as *mut libc::c_void,
+                    buf.len(),
+                    0,
+                )
+            };
+            if len < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // Answers to earlier lookups that timed out are skipped
+            if let Some(reply) = find_reply(&buf[..len as usize], self.seq) {
+                return reply;
+            }
+        }
+    }
+}
+
+/// A `CT_GET` request for the entry whose `direction` tuple is `tuple`.
+fn get_request(seq: u32, direction: u16, tuple: &Tuple) -> Vec<u8> {
+    let ip = [
+        attr(CTA_IP_V4_SRC, &tuple.src_ip.octets()),
+        attr(CTA_IP_V4_DST, &tuple.dst_ip.octets()),
+    ]
+    .concat();
+    let proto = [
+        attr(CTA_PROTO_NUM, &[libc::IPPROTO_TCP as u8]),
+        attr(CTA_PROTO_SRC_PORT, &tuple.src_port.to_be_bytes()),
+        attr(CTA_PROTO_DST_PORT, &tuple.dst_port.to_be_bytes()),
+    ]
+    .concat();
+    let tuple = [
+        attr(CTA_TUPLE_IP | NLA_F_NESTED, &ip),
+        attr(CTA_TUPLE_PROTO | NLA_F_NESTED, &proto),
+    ]
+    .concat();
+    let attrs = attr(direction | NLA_F_NESTED, &tuple);
+
+    let len = (NLMSG_HEADER_LEN + NFGENMSG_LEN + attrs.len()) as u32;
+    let mut msg = Vec::with_capacity(len as usize);
+    msg.extend(len.to_ne_bytes());
+    msg.extend(CT_GET.to_ne_bytes());
+    msg.extend(NLM_F_REQUEST.to_ne_bytes());
+    msg.extend(seq.to_ne_bytes());
+    msg.extend(0u32.to_ne_bytes()); // to the kernel
+    msg.extend([libc::AF_INET as u8, 0, 0, 0]); // nfgenmsg: family, version 0, res_id 0
+    msg.extend(attrs);
+    msg
+}
+
+/// A netlink attribute, padded to 4 bytes.
+fn attr(kind: u16, payload: &[u8]) -> Vec<u8> {
+    let len = 4 + payload.len();
+    let mut out = Vec::with_capacity(align(len));
+    out.extend((len as u16).to_ne_bytes());
+    out.extend(kind.to_ne_bytes());
+    out.extend(payload);
+    out.resize(align(len), 0);
+    out
+}
+
+fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+/// The answer to request `seq` among the netlink messages in `data`: the
+/// entry's tuples, None for no entry, or the error. None if `data` doesn't
+/// answer `seq`.
+fn find_reply(mut data: &[u8], seq: u32) -> Option<io::Result<Option<(Tuple, Tuple)>>> {
+    while data.len() >= NLMSG_HEADER_LEN {
+        let len = u32::from_ne_bytes(data[0..4].try_into().unwrap()) as usize;
+        if len < NLMSG_HEADER_LEN || len > data.len() {
+            return None;
+        }
+        let kind = u16::from_ne_bytes([data[4], data[5]]);
+        let msg_seq = u32::from_ne_bytes(data[8..12].try_into().unwrap());
+        let payload = &data[NLMSG_HEADER_LEN..len];
+        data = &data[align(len).min(data.len())..];
+        if msg_seq != seq {
+            continue;
+        }
+        return Some(match kind {
+            NLMSG_ERROR => {
+                let errno = payload
+                    .get(..4)
+                    .map_or(0, |e| -i32::from_ne_bytes(e.try_into().unwrap()));
+                match errno {
+                    0 | libc::ENOENT => Ok(None),
+                    errno => Err(io::Error::from_raw_os_error(errno)),
+                }
+            }
+            CT_NEW => Ok(payload.get(NFGENMSG_LEN..).and_then(entry_tuples)),
+            _ => Ok(None),
+        });
+    }
+    None
+}
+
+/// The original and reply tuples among a conntrack entry's attributes.
+fn entry_tuples(attrs: &[u8]) -> Option<(Tuple, Tuple)> {
+    let mut orig = None;
+    let mut reply = None;
+    for (kind, payload) in Attrs(attrs) {
+        match kind {
+            CTA_TUPLE_ORIG => orig = parse_tuple(payload),
+            CTA_TUPLE_REPLY => reply = parse_tuple(payload),
+            _ => {}
+        }
+    }
+    Some((orig?, reply?))
+}
+
+/// A `CTA_TUPLE_ORIG`/`CTA_TUPLE_REPLY` payload; None unless IPv4.
+fn parse_tuple(attrs: &[u8]) -> Option<Tuple> {
+    let (mut src_ip, mut dst_ip) = (None, None);
+    let (mut src_port, mut dst_port) = (0, 0);
+    for (kind, payload) in Attrs(attrs) {
+        match kind {
+            CTA_TUPLE_IP => {
+                for (kind, payload) in Attrs(payload) {
+                    let Ok(octets) = <[u8; 4]>::try_from(payload) else {
+                        continue;
+                    };
+                    match kind {
+                        CTA_IP_V4_SRC => src_ip = Some(Ipv4Addr::from(octets)),
+                        CTA_IP_V4_DST => dst_ip = Some(Ipv4Addr::from(octets)),
+                        _ => {}
+                    }
+                }
+            }
+            CTA_TUPLE_PROTO => {
+                for (kind, payload) in Attrs(payload) {
+                    let Ok(port) = <[u8; 2]>::try_from(payload) else {
+                        continue;
+                    };
+                    match kind {
+                        CTA_PROTO_SRC_PORT => src_port = u16::from_be_bytes(port),
+                        CTA_PROTO_DST_PORT => dst_port = u16::from_be_bytes(port),
+                        _ => {}
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    Some(Tuple {
+        src_ip: src_ip?,
+        src_port,
+        dst_ip: dst_ip?,
+        dst_port,
+    })
+}
+
+/// Netlink attributes in a buffer, as their type without flags and their
+/// payload.
+struct Attrs<'a>(&'a [u8]);
+
+impl<'a> Iterator for Attrs<'a> {
+    type Item = (u16, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let data = self.0;
+        if data.len() < 4 {
+            return None;
+        }
+        let len = u16::from_ne_bytes([data[0], data[1]]) as usize;
+        let kind = u16::from_ne_bytes([data[2], data[3]]) & NLA_TYPE_MASK;
+        if len < 4 || len > data.len() {
+            return None;
+        }
+        self.0 = &data[align(len).min(data.len())..];
+        Some((kind, &data[4..len]))
+    }
+}
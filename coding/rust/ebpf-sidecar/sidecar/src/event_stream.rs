@@ -0,0 +1,103 @@
+//! Live event feed for WebSocket clients.
+//!
+//! Events published to an [`EventStream`] are fanned out to every
+//! subscriber through its own bounded queue. A subscriber that falls behind
+//! has events dropped instead of slowing down the publisher or the other
+//! subscribers; each subscription counts its own drops.
+
+use hyper::header::{HeaderMap, CONNECTION, SEC_WEBSOCKET_KEY, SEC_WEBSOCKET_VERSION, UPGRADE};
+use serde_json::Value;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+
+/// Events queued per subscriber before further events are dropped for it.
+pub const SUBSCRIBER_QUEUE: usize = 1024;
+
+struct Subscriber {
+    tx: mpsc::Sender<Arc<str>>,
+    dropped: Arc<AtomicU64>,
+}
+
+/// Fan-out of JSON events to any number of subscribers. Cloning shares the
+/// subscriber list.
+#[derive(Clone, Default)]
+pub struct EventStream {
+    subscribers: Arc<Mutex<Vec<Subscriber>>>,
+}
+
+impl EventStream {
+    /// A stream with no subscribers yet.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queue `event` for every subscriber, dropping it for those whose queue
+    /// is full and forgetting those that went away. Never waits.
+    pub fn publish(&self, event: &Value) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        if subscribers.is_empty() {
+            return;
+        }
+
+        let text: Arc<str> = event.to_string().into();
+        subscribers.retain(|subscriber| match subscriber.tx.try_send(text.clone()) {
+            Ok(()) => true,
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                subscriber.dropped.fetch_add(1, Ordering::Relaxed);
+                true
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => false,
+        });
+    }
+
+    /// Receive the events published from now on.
+    pub fn subscribe(&self) -> Subscription {
+        let (tx, rx) = mpsc::channel(SUBSCRIBER_QUEUE);
+        let dropped = Arc::new(AtomicU64::new(0));
+        self.subscribers.lock().unwrap().push(Subscriber {
+            tx,
+            dropped: dropped.clone(),
+        });
+        Subscription { rx, dropped }
+    }
+}
+
+/// One subscriber's queue. Dropping it unsubscribes.
+pub struct Subscription {
+    rx: mpsc::Receiver<Arc<str>>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl Subscription {
+    /// Next event as JSON text, or None once the stream is gone.
+    pub async fn recv(&mut self) -> Option<Arc<str>> {
+        self.rx.recv().await
+    }
+
+    /// Events dropped for this subscriber since the previous call.
+    pub fn take_dropped(&self) -> u64 {
+        self.dropped.swap(0, Ordering::Relaxed)
+    }
+}
+
+/// The `Sec-WebSocket-Accept` value answering a WebSocket upgrade request
+/// (RFC 6455 section 4.2), or None if `headers` don't ask for one.
+pub fn accept_key(headers: &HeaderMap) -> Option<String> {
+    let has_token = |name, token: &str| {
+        headers
+            .get_all(name)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|value| value.trim().eq_ignore_ascii_case(token))
+    };
+    if !has_token(UPGRADE, "websocket") || !has_token(CONNECTION, "upgrade") {
+        return None;
+    }
+    if headers.get(SEC_WEBSOCKET_VERSION)?.as_bytes() != b"13" {
+        return None;
+    }
+    let key = headers.get(SEC_WEBSOCKET_KEY)?;
+    Some(tokio_tungstenite::tungstenite::handshake::derive_accept_key(key.as_bytes()))
+}
@@ -0,0 +1,187 @@
+//! Newline-delimited JSON events on a named pipe.
+//!
+//! Closed connections, HTTP requests and policy violations are written to a
+//! FIFO, one JSON object per line, for any process to `cat` or tail. The
+//! pipe is opened non-blocking, so the sidecar never waits on its reader: an
+//! event is dropped when nobody has the pipe open or the pipe buffer is
+//! full, and the caller counts it.
+//!
+//! Each line is written with a single `write`, which the kernel keeps whole
+//! for lines up to `PIPE_BUF` (4 KiB); readers never see interleaved or torn
+//! lines.
+
+use crate::metrics;
+use crate::policy;
+use anyhow::{Context, Result};
+use serde_json::{json, Value};
+use sidecar_common::{http_method, ConnCloseEvent, ConnKey, HttpEvent, PolicyEvent};
+use std::ffi::CString;
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::net::IpAddr;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Why an event was not written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dropped {
+    /// No process has the pipe open for reading
+    NoReader,
+    /// The reader is too slow and the pipe buffer is full
+    Full,
+    /// Any other write error
+    Error,
+}
+
+impl Dropped {
+    /// `reason` label value.
+    pub fn reason(self) -> &'static str {
+        match self {
+            Dropped::NoReader => "no_reader",
+            Dropped::Full => "full",
+            Dropped::Error => "error",
+        }
+    }
+}
+
+/// Writer end of the event FIFO.
+///
+/// The pipe is (re)opened on the first write after a reader shows up, so
+/// readers can come and go.
+pub struct FifoWriter {
+    path: PathBuf,
+    file: Option<File>,
+}
+
+impl FifoWriter {
+    /// Create the FIFO at `path` if it is missing. Fails if something else
+    /// than a FIFO is already there.
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        match std::fs::metadata(path) {
+            Ok(meta) if meta.file_type().is_fifo() => {}
+            Ok(_) => anyhow::bail!("{} exists and is not a FIFO", path.display()),
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                let c_path = CString::new(path.as_os_str().as_bytes())?;
+                if unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) } != 0 {
+                    return Err(std::io::Error::last_os_error())
+                        .with_context(|| format!("Failed to create FIFO {}", path.display()));
+                }
+            }
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to stat {}", path.display()));
+            }
+        }
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            file: None,
+        })
+    }
+
+    /// Write `event` as one line, or report why it was dropped.
+    pub fn write(&mut self, event: &Value) -> std::result::Result<(), Dropped> {
+        let file = match self.file {
+            Some(ref mut file) => file,
+            None => self.file.insert(self.open()?),
+        };
+
+        let mut line = event.to_string();
+        line.push('\n');
+        match file.write(line.as_bytes()) {
+            Ok(_) => Ok(()),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Err(Dropped::Full),
+            Err(e) => {
+                // EPIPE: the reader went away. Reopen on the next event.
+                self.file = None;
+                if e.kind() == ErrorKind::BrokenPipe {
+                    Err(Dropped::NoReader)
+                } else {
+                    log::debug!("Failed to write to {}: {}", self.path.display(), e);
+                    Err(Dropped::Error)
+                }
+            }
+        }
+    }
+
+    fn open(&self) -> std::result::Result<File, Dropped> {
+        OpenOptions::new()
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(&self.path)
+            .map_err(|e| match e.raw_os_error() {
+                // Non-blocking open for writing with no reader
+                Some(libc::ENXIO) => Dropped::NoReader,
+                _ => {
+                    log::debug!("Failed to open {}: {}", self.path.display(), e);
+                    Dropped::Error
+                }
+            })
+    }
+}
+
+/// A closed connection with its lifetime totals.
+pub fn close_event(event: &ConnCloseEvent) -> Value {
+    let mut line = endpoint_fields("close", &event.conn);
+    line["bytes_sent"] = json!(event.bytes_sent);
+    line["bytes_recv"] = json!(event.bytes_recv);
+    line["packets_sent"] = json!(event.packets_sent);
+    line["packets_recv"] = json!(event.packets_recv);
+    line["retransmits"] = json!(event.retransmits);
+    line["duration_ms"] = json!(event.duration_ns / 1_000_000);
+    line
+}
+
+/// A timed HTTP request, with its path hash at the exported width if known.
+pub fn http_event(event: &HttpEvent, path_hash: Option<u64>) -> Value {
+    let mut line = endpoint_fields("http", &event.conn);
+    line["protocol"] = json!(metrics::http_protocol(event));
+    line["method"] = json!(http_method::method_name(event.method));
+    line["status"] = json!(event.status_code);
+    line["latency_ms"] = json!(event.latency_ns as f64 / 1e6);
+    if event.stream_id != 0 {
+        line["stream_id"] = json!(event.stream_id);
+    }
+    if let Some(hash) = path_hash {
+        line["path_hash"] = json!(format!("{:x}", hash));
+    }
+    line
+}
+
+/// A connect matching the `--deny` rule `rule`.
+pub fn policy_event(event: &PolicyEvent, rule: &str) -> Value {
+    json!({
+        "event": "policy",
+        "timestamp_ms": timestamp_ms(),
+        "pid": event.pid,
+        "comm": policy::comm(event),
+        "dst_ip": metrics::exported_ip(policy::dst_ip(event)).to_string(),
+        "dst_port": event.dst_port,
+        "rule": rule,
+    })
+}
+
+fn endpoint_fields(event: &str, key: &ConnKey) -> Value {
+    json!({
+        "event": event,
+        "timestamp_ms": timestamp_ms(),
+        "src_ip": ip(key.src_addr()),
+        "src_port": key.src_port,
+        "dst_ip": ip(key.dst_addr()),
+        "dst_port": key.dst_port,
+    })
+}
+
+fn timestamp_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// An address as text, masked under `--anonymize-ips`
+fn ip(addr: IpAddr) -> String {
+    metrics::exported_ip(addr).to_string()
+}
@@ -0,0 +1,155 @@
+//! InfluxDB line protocol output.
+//!
+//! Each flush writes one `sidecar_connection` point per exported connection
+//! (or endpoint, when grouped) to the InfluxDB 1.x `/write` endpoint. The
+//! connection labels are the tags and its counters the fields; all the
+//! points of a flush share one timestamp. Points are POSTed in batches of at
+//! most [`MAX_BATCH_LINES`], the batch size InfluxDB recommends.
+//!
+//! Only plain `http://` URLs are supported; put a local relay (e.g.
+//! Telegraf's `influxdb_listener`) in front of a TLS endpoint.
+
+use crate::scrape::{ExportedConnection, CONNECTION_LABELS};
+use anyhow::{Context, Result};
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Request, Uri};
+use std::fmt::Write;
+
+/// Measurement name of the connection points.
+pub const MEASUREMENT: &str = "sidecar_connection";
+
+/// Most points sent in one request.
+pub const MAX_BATCH_LINES: usize = 5000;
+
+/// Client for the `/write` endpoint of one database.
+pub struct InfluxWriter {
+    client: Client<HttpConnector>,
+    uri: Uri,
+}
+
+impl InfluxWriter {
+    /// A writer to database `db` of the server at `url` (e.g.
+    /// `http://influxdb:8086`).
+    pub fn new(url: &str, db: &str) -> Result<Self> {
+        let uri = write_uri(url, db)?;
+        Ok(Self {
+            client: Client::new(),
+            uri,
+        })
+    }
+
+    /// The `/write` URI points are POSTed to.
+    pub fn uri(&self) -> &Uri {
+        &self.uri
+    }
+
+    /// Write `lines` in batches. Returns how many batches failed; the
+    /// error of each is logged.
+    pub async fn write(&self, lines: &[String]) -> usize {
+        let mut failed = 0;
+        for batch in lines.chunks(MAX_BATCH_LINES) {
+            if let Err(e) = self.post(batch.join("\n")).await {
+                log::warn!(
+                    "Failed to write {} points to InfluxDB: {:#}",
+                    batch.len(),
+                    e
+                );
+                failed += 1;
+            }
+        }
+        failed
+    }
+
+    async fn post(&self, body: String) -> Result<()> {
+        let request = Request::post(self.uri.clone())
+            .header("content-type", "text/plain; charset=utf-8")
+            .body(Body::from(body))?;
+        let response = self.client.request(request).await?;
+        let status = response.status();
+        if !status.is_success() {
+            // InfluxDB explains rejected points in a JSON body
+            let body = hyper::body::to_bytes(response.into_body())
+                .await
+                .unwrap_or_default();
+            anyhow::bail!("{}: {}", status, String::from_utf8_lossy(&body).trim());
+        }
+        Ok(())
+    }
+}
+
+/// `{url}/write?db={db}&precision=ns`, checking that `url` is a plain
+/// HTTP URL.
+///
+/// ```
+/// use sidecar::influx::write_uri;
+///
+/// let uri = write_uri("http://influxdb:8086/", "edge metrics").unwrap();
+/// assert_eq!(uri, "http://influxdb:8086/write?db=edge%20metrics&precision=ns");
+/// assert!(write_uri("https://influxdb:8086", "edge").is_err());
+/// ```
+pub fn write_uri(url: &str, db: &str) -> Result<Uri> {
+    let base: Uri = url
+        .parse()
+        .with_context(|| format!("Invalid InfluxDB URL {:?}", url))?;
+    if base.scheme_str() != Some("http") || base.host().is_none() {
+        anyhow::bail!("InfluxDB URL must be http://host[:port], got {:?}", url);
+    }
+    let uri = format!(
+        "{}/write?db={}&precision=ns",
+        url.trim_end_matches('/'),
+        percent_encode(db)
+    );
+    Ok(uri.parse()?)
+}
+
+/// The point of one exported connection, timestamped `timestamp_ns`.
+///
+/// Empty label values are left out, since InfluxDB rejects empty tags.
+/// Counters are the values exported to Prometheus: per flush under
+/// `--delta-mode`, since the connection was first seen otherwise.
+pub fn connection_line(conn: &ExportedConnection, timestamp_ns: u64) -> String {
+    let mut line = MEASUREMENT.to_string();
+    for (name, value) in CONNECTION_LABELS.iter().zip(&conn.labels) {
+        if !value.is_empty() {
+            let _ = write!(line, ",{}={}", name, escape_tag(value));
+        }
+    }
+    let m = &conn.metrics;
+    let _ = write!(
+        line,
+        " bytes_sent={}i,bytes_recv={}i,packets_sent={}i,packets_recv={}i,\
+         retransmits={}i,duration_seconds={} {}",
+        m.bytes_sent,
+        m.bytes_recv,
+        m.packets_sent,
+        m.packets_recv,
+        m.retransmits,
+        conn.duration_secs,
+        timestamp_ns
+    );
+    line
+}
+
+/// Escape the characters line protocol gives a meaning to in tag values.
+fn escape_tag(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, ',' | '=' | ' ' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn percent_encode(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for b in value.bytes() {
+        if b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.' | b'~') {
+            encoded.push(b as char);
+        } else {
+            let _ = write!(encoded, "%{:02X}", b);
+        }
+    }
+    encoded
+}
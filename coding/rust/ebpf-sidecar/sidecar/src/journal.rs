@@ -0,0 +1,98 @@
+//! Connection open/close events as structured systemd journal entries.
+//!
+//! Entries carry `SIDECAR_EVENT=open|close` plus the endpoints and counters as
+//! journal fields, so they can be filtered with e.g.
+//! `journalctl SYSLOG_IDENTIFIER=sidecar DST_PORT=443`. Opens are inferred
+//! from connections that first appear in a collection; closes come from the
+//! kernel's close events.
+
+use crate::Connection;
+use anyhow::Result;
+use libsystemd::logging::{journal_send, Priority, SD_JOURNAL_SOCK_PATH};
+use sidecar_common::{ConnCloseEvent, ConnKey};
+use std::collections::HashSet;
+use std::path::Path;
+
+/// `SYSLOG_IDENTIFIER` of every entry
+const IDENTIFIER: &str = "sidecar";
+
+/// Whether a journald socket is there to write to.
+pub fn available() -> bool {
+    Path::new(SD_JOURNAL_SOCK_PATH).exists()
+}
+
+/// Log a newly seen connection.
+pub fn send_open(conn: &Connection, exe: &str) -> Result<()> {
+    let message = format!(
+        "connection opened {}:{} -> {}:{}",
+        conn.src_ip(),
+        conn.key.src_port,
+        conn.dst_ip(),
+        conn.key.dst_port
+    );
+    let mut fields = endpoint_fields("open", &conn.key);
+    fields.push(("EXE", exe.to_string()));
+    fields.push(("CC_ALGO", conn.cc_algo()));
+    fields.push(("NETNS", conn.metrics.netns_inode.to_string()));
+
+    journal_send(Priority::Info, &message, fields.into_iter())?;
+    Ok(())
+}
+
+/// Log a closed connection with its lifetime totals.
+pub fn send_close(event: &ConnCloseEvent) -> Result<()> {
+    let message = format!(
+        "connection closed {}:{} -> {}:{}, {} bytes sent, {} received",
+        event.conn.src_addr(),
+        event.conn.src_port,
+        event.conn.dst_addr(),
+        event.conn.dst_port,
+        event.bytes_sent,
+        event.bytes_recv
+    );
+    let mut fields = endpoint_fields("close", &event.conn);
+    fields.extend([
+        ("BYTES_SENT", event.bytes_sent.to_string()),
+        ("BYTES_RECV", event.bytes_recv.to_string()),
+        ("PACKETS_SENT", event.packets_sent.to_string()),
+        ("PACKETS_RECV", event.packets_recv.to_string()),
+        ("RETRANSMITS", event.retransmits.to_string()),
+        ("DURATION_MS", (event.duration_ns / 1_000_000).to_string()),
+    ]);
+
+    journal_send(Priority::Info, &message, fields.into_iter())?;
+    Ok(())
+}
+
+fn endpoint_fields(event: &str, key: &ConnKey) -> Vec<(&'static str, String)> {
+    vec![
+        ("SYSLOG_IDENTIFIER", IDENTIFIER.to_string()),
+        ("SIDECAR_EVENT", event.to_string()),
+        ("SRC_IP", key.src_addr().to_string()),
+        ("SRC_PORT", key.src_port.to_string()),
+        ("DST_IP", key.dst_addr().to_string()),
+        ("DST_PORT", key.dst_port.to_string()),
+    ]
+}
+
+/// Picks out connections that were not in the previous collection.
+///
+/// Only keys still live are remembered, so memory follows the map size. An
+/// entry the LRU evicted and the kernel re-created counts as a new open.
+#[derive(Debug, Default)]
+pub struct OpenTracker {
+    known: HashSet<ConnKey>,
+}
+
+impl OpenTracker {
+    /// Connections in `connections` not seen by the previous call.
+    pub fn new_connections<'a>(&mut self, connections: &'a [Connection]) -> Vec<&'a Connection> {
+        let live: HashSet<ConnKey> = connections.iter().map(|c| c.key).collect();
+        let opened = connections
+            .iter()
+            .filter(|c| !self.known.contains(&c.key))
+            .collect();
+        self.known = live;
+        opened
+    }
+}
@@ -0,0 +1,54 @@
+//! eBPF Sidecar - Library Interface
+//!
+//! The collection core of the sidecar, usable without the Prometheus/CLI
+//! layer. Embedders load and attach the eBPF programs through [`Monitor`]
+//! and pull point-in-time [`Connection`] snapshots from the kernel maps.
+//!
+//! # Example
+//! ```no_run
+//! use sidecar::{ConnectionSource, Monitor, MonitorOptions};
+//!
+//! # fn main() -> anyhow::Result<()> {
+//! let mut monitor = Monitor::load()?;
+//! monitor.configure(&MonitorOptions { target_pid: 1234, ..Default::default() })?;
+//! monitor.attach()?;
+//!
+//! for conn in monitor.snapshot()? {
+//!     println!("{} -> {}:{}", conn.src_ip(), conn.dst_ip(), conn.key.dst_port);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+pub mod affinity;
+pub mod audit;
+pub mod capture;
+pub mod cgroup;
+pub mod circuit_breaker;
+#[cfg(feature = "parquet")]
+pub mod columnar;
+pub mod config;
+pub mod conntrack;
+pub mod event_stream;
+pub mod fifo;
+pub mod influx;
+#[cfg(feature = "journald")]
+pub mod journal;
+pub mod map_usage;
+pub mod metrics;
+pub mod monitor;
+pub mod netns;
+pub mod path_hash;
+pub mod policy;
+pub mod process;
+pub mod relabel;
+pub mod scrape;
+pub mod stacks;
+pub mod time;
+pub mod tls_fingerprint;
+
+pub use monitor::{
+    split_ports, ConnStats, Connection, ConnectionSource, FilterDecisions, GapSampling, L7Protocol,
+    LoadOptions, Monitor, MonitorOptions, PayloadCapture, PinnedMaps, ReaderStatus, StackSampling,
+    VerifierLog, MAX_TARGET_PORTS, READER_STALL_AFTER,
+};
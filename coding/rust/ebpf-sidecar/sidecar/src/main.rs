@@ -2,6 +2,8 @@
 //!
 //! This program loads the eBPF programs into the kernel, attaches them to
 //! the appropriate hooks, and exports collected metrics via Prometheus.
+//! Loading and map access live in the `sidecar` library (`Monitor`); this
+//! binary only adds the CLI and the Prometheus layer.
 //!
 //! # Usage
 //! ```bash
@@ -11,41 +13,101 @@
 //! # Monitor specific PID
 //! sudo ./sidecar --pid 1234
 //!
+//! # Monitor PID 1 of a container, given its PID namespace
+//! sudo ./sidecar --pid 1 --pid-namespace 'pid:[4026532451]'
+//!
+//! # Monitor every process named nginx-something
+//! sudo ./sidecar --process-name 'nginx*'
+//!
 //! # Monitor specific ports
 //! sudo ./sidecar --ports 80,443,8080
 //!
+//! # Run the HTTP and TLS parsers on outgoing traffic
+//! sudo ./sidecar --l7-parsers http,tls
+//!
+//! # Keep the sidecar's threads on CPUs 0 and 1
+//! sudo ./sidecar --cpu-affinity 0-1
+//!
 //! # Custom Prometheus port
 //! sudo ./sidecar --metrics-port 9091
+//!
+//! # Serve a browser dashboard at http://localhost:9090/
+//! sudo ./sidecar --ui
+//!
+//! # Check kernel/userspace struct compatibility and exit
+//! sudo ./sidecar --version-check
+//!
+//! # Archive closed connections to rotating Parquet files (`parquet` feature)
+//! sudo ./sidecar --parquet /var/lib/sidecar/connections
+//!
+//! # Log connection opens/closes to the systemd journal (`journald` feature)
+//! sudo ./sidecar --journald
+//!
+//! # Validate load, capture and export end to end on this kernel
+//! sudo ./sidecar selftest
+//!
+//! # Privileged loader pins its maps; a separate process exports them
+//! sudo ./sidecar --pin-maps /sys/fs/bpf/sidecar
+//! ./sidecar export --from-pinned /sys/fs/bpf/sidecar
+//!
+//! # Swap in a rebuilt eBPF object without losing connection state
+//! sudo ./sidecar --bpf-object ./sidecar-ebpf.o &
+//! kill -USR1 $!
 //! ```
 
+// The metrics `lazy_static!` block outgrew the default macro recursion limit
+#![recursion_limit = "256"]
+
+mod selftest;
+
 use anyhow::{Context, Result};
-use aya::{
-    include_bytes_aligned,
-    maps::{Array, HashMap},
-    programs::{KProbe, TracePoint},
-    Bpf,
-};
-use aya_log::BpfLogger;
-use clap::Parser;
-use log::{debug, error, info, warn};
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand};
+use log::{debug, error, info};
 use prometheus::{
-    register_counter_vec, register_gauge_vec, register_histogram_vec,
-    CounterVec, Encoder, GaugeVec, HistogramVec, TextEncoder,
+    register_counter_vec, register_gauge_vec, register_histogram_vec, CounterVec, Encoder,
+    GaugeVec, HistogramVec, TextEncoder,
+};
+use serde_json::{json, Value};
+use sidecar::affinity::{self, CpuList};
+use sidecar::audit::{AuditLog, AuditTrigger};
+use sidecar::capture::CaptureWriter;
+use sidecar::cgroup::CgroupTargets;
+use sidecar::circuit_breaker::{CircuitBreaker, LoadSampler, Thresholds, Transition};
+use sidecar::config::Config;
+use sidecar::conntrack::Conntrack;
+use sidecar::event_stream::{EventStream, Subscription};
+use sidecar::map_usage::MapDirectory;
+use sidecar::netns::NetnsResolver;
+use sidecar::path_hash::{PathHash, PathHashes};
+use sidecar::policy::{self, PolicyRule};
+use sidecar::relabel::{self, Relabeler};
+use sidecar::scrape::{
+    self, ConnectionView, ExportedConnection, MetricsEndpoint, ScrapeFilter, Shard,
+};
+use sidecar::stacks::{StackAttributor, StackProfile};
+use sidecar::tls_fingerprint::{ClientHello, FingerprintLabels};
+use sidecar::{
+    metrics, split_ports, Connection, ConnectionSource, GapSampling, L7Protocol, LoadOptions,
+    Monitor, MonitorOptions, PayloadCapture, PinnedMaps, ReaderStatus, StackSampling, VerifierLog,
+    MAX_TARGET_PORTS,
 };
-use sidecar_common::{ConnKey, ConnMetrics, SidecarConfig};
+use sidecar_common::{
+    conn_flag, conn_stat, direction, http_version, ConnCloseEvent, ConnFailKey, ConnKey,
+    ConnMetrics, GapEvent, HttpEvent, IfaceKey, IfaceTraffic, LayoutInfo, PolicyEvent,
+    TlsHelloEvent,
+};
+use std::collections::BTreeMap;
 use std::convert::Infallible;
-use std::net::{Ipv4Addr, SocketAddr};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tokio::signal;
-use tokio::sync::RwLock;
+use tokio::signal::unix::SignalKind;
+use tokio::sync::{mpsc, oneshot};
 use tokio::time;
 
-mod config;
-mod metrics;
-
-use config::Config;
-
 // ============================================================================
 // CLI Arguments
 // ============================================================================
@@ -55,22 +117,167 @@ use config::Config;
 #[command(about = "eBPF-based service mesh sidecar for network observability")]
 #[command(version)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Target PID to monitor (0 = all processes)
     #[arg(short, long, default_value = "0")]
     pid: u32,
 
+    /// Read --pid inside this PID namespace, e.g. a container's, and monitor
+    /// the host PID it maps to: `pid:[INODE]` as `readlink /proc/self/ns/pid`
+    /// prints it there, or a namespace file like /proc/<host pid>/ns/pid
+    #[arg(long, requires = "pid")]
+    pid_namespace: Option<sidecar::process::PidNamespace>,
+
+    /// Monitor processes whose name (/proc/<pid>/comm) matches this pattern;
+    /// `*` matches any characters, e.g. `nginx*` or `*-worker`
+    #[arg(long, conflicts_with = "pid")]
+    process_name: Option<sidecar::process::NamePattern>,
+
+    /// Monitor only processes in this cgroup v2 directory and the cgroups
+    /// below it (absolute, or relative to /sys/fs/cgroup)
+    #[arg(long)]
+    cgroup: Option<PathBuf>,
+
+    /// How often to rescan --cgroup for child cgroups created or removed
+    /// since start, in seconds
+    #[arg(long, default_value = "10")]
+    cgroup_refresh_secs: u64,
+
     /// Ports to monitor (comma-separated, empty = all)
     #[arg(long, value_delimiter = ',')]
     ports: Option<Vec<u16>>,
 
+    /// Ports that keep a kernel filter slot when more than 8 are monitored
+    /// (comma-separated); the rest are filtered in userspace
+    #[arg(long, value_delimiter = ',')]
+    priority_ports: Option<Vec<u16>>,
+
+    /// Don't track connections where both ends are loopback (127.0.0.0/8)
+    #[arg(long)]
+    exclude_loopback: bool,
+
+    /// Only track connections opened after startup; connections already
+    /// open are never reported, even when they send or receive
+    #[arg(long)]
+    new_connections_only: bool,
+
+    /// L7 parsers to enable (comma-separated: http, tls, dns; empty = none)
+    #[arg(long, value_delimiter = ',')]
+    l7_parsers: Option<Vec<L7Protocol>>,
+
+    /// Report outbound connects to these destinations (comma-separated
+    /// ADDR[/PREFIX][:PORT], e.g. 10.0.0.0/8:5432); observe only, nothing is
+    /// blocked
+    #[arg(long, value_delimiter = ',')]
+    deny: Option<Vec<PolicyRule>>,
+
+    /// Always track connections to these destinations (comma-separated
+    /// ADDR[/PREFIX][:PORT]), even if the PID, cgroup, port or loopback
+    /// filters exclude them; added to `target.always_track` from --config
+    #[arg(long, value_delimiter = ',')]
+    always_track: Option<Vec<PolicyRule>>,
+
     /// Prometheus metrics port
     #[arg(short, long, default_value = "9090")]
     metrics_port: u16,
 
+    /// Serve the metrics on this many endpoints, on consecutive ports from
+    /// --metrics-port, each with a share of the per-connection series
+    #[arg(long, default_value = "1", value_parser = clap::value_parser!(u32).range(1..=64))]
+    metrics_shards: u32,
+
+    /// Serve scrapes within this long of the previous one (e.g. 10s) the
+    /// metrics it encoded instead of encoding them again; guards against
+    /// scrape storms. Scrapes with a query are never cached.
+    #[arg(long, value_parser = humantime::parse_duration)]
+    metrics_cache_ttl: Option<Duration>,
+
+    /// Export per-connection series per connection, or merged per source
+    /// host and destination endpoint (ignoring the source port)
+    #[arg(long, value_enum, default_value = "connection")]
+    group_by: GroupBy,
+
+    /// Label connections with a name for their network namespace (`host`,
+    /// an `ip netns` name or a container id) instead of its inode number
+    #[arg(long)]
+    resolve_netns: bool,
+
+    /// Look connections up in the netfilter conntrack table and export the
+    /// address clients dialled for those accepted behind DNAT
+    /// (sidecar_connection_nat_info)
+    #[arg(long)]
+    conntrack: bool,
+
+    /// Flag connections idle for longer than this (e.g. 2h, the kernel's
+    /// default tcp_keepalive_time, or a pool's keepalive interval) in
+    /// sidecar_connection_idle_exceeds_keepalive
+    #[arg(long, value_parser = humantime::parse_duration)]
+    keepalive_threshold: Option<Duration>,
+
+    /// Per-connection metric families not to export (comma-separated, e.g.
+    /// packets,retransmits); they are never registered
+    #[arg(long, value_enum, value_delimiter = ',')]
+    disable_metrics: Vec<MetricGroup>,
+
+    /// Export per-connection bytes, packets, retransmits, SACKs and resets as
+    /// gauges of their change since the previous flush (`*_delta`) instead
+    /// of cumulative counters
+    #[arg(long)]
+    delta_mode: bool,
+
+    /// Serve a dashboard at / (and its data at /metrics.json)
+    #[arg(long)]
+    ui: bool,
+
     /// Metrics collection interval in seconds
     #[arg(short, long, default_value = "5")]
     interval: u64,
 
+    /// Stop after this long (e.g. 90s, 5m, 1h30m) as if Ctrl+C was pressed,
+    /// printing a summary of the traffic observed
+    #[arg(long, value_parser = humantime::parse_duration)]
+    duration: Option<Duration>,
+
+    /// How often to publish collected metrics to Prometheus, in seconds
+    /// (default: every collection; must not be shorter than --interval)
+    #[arg(long)]
+    prometheus_flush_interval: Option<u64>,
+
+    /// Sliding window in seconds for HTTP latency percentiles
+    #[arg(long, default_value = "60")]
+    latency_window: u64,
+
+    /// Mask the host part of IP labels (last IPv4 octet)
+    #[arg(long)]
+    anonymize_ips: bool,
+
+    /// Bits of each HTTP request path hash to export (1-64); paths
+    /// colliding at that width are counted, at 64 bits too
+    #[arg(long, default_value = "64", value_parser = clap::value_parser!(u32).range(1..=64))]
+    path_hash_bits: u32,
+
+    /// Latency threshold in milliseconds for the per-endpoint latency SLO
+    #[arg(long, default_value = "300")]
+    slo_latency_ms: u64,
+
+    /// Retransmit ratio (retransmits / packets sent) above which an endpoint
+    /// is reported as unhealthy
+    #[arg(long, default_value = "0.05")]
+    unhealthy_retransmit_ratio: f64,
+
+    /// Smoothing factor (0 < alpha <= 1) of the per-endpoint throughput
+    /// average, applied once per flush; smaller values give a smoother trend
+    #[arg(long, default_value = "0.3")]
+    throughput_ewma_alpha: f64,
+
+    /// Run the sidecar's own threads only on these CPUs (e.g. 0-1,6), to
+    /// keep collection and event reading off the application's cores; the
+    /// eBPF programs still run on whichever CPU triggers them
+    #[arg(long)]
+    cpu_affinity: Option<CpuList>,
+
     /// Enable debug logging from eBPF programs
     #[arg(short, long)]
     debug: bool,
@@ -78,6 +285,261 @@ struct Args {
     /// Config file path (optional)
     #[arg(short, long)]
     config: Option<String>,
+
+    /// Print the effective configuration (--config file with the command
+    /// line applied) as YAML and exit
+    #[arg(long)]
+    print_config: bool,
+
+    /// Verifier log detail printed when a program is rejected at load
+    /// (off, debug, verbose)
+    #[arg(long, default_value = "debug")]
+    verifier_log_level: VerifierLog,
+
+    /// Verify kernel/userspace struct layout compatibility and exit
+    #[arg(long)]
+    version_check: bool,
+
+    /// Append a JSON audit record of every applied configuration change here
+    #[arg(long)]
+    audit_log: Option<PathBuf>,
+
+    /// Pin the state maps under this bpffs directory for `sidecar export`
+    /// (default: unpinned, or with --bpf-object a private directory under
+    /// /sys/fs/bpf, removed at exit)
+    #[arg(long)]
+    pin_maps: Option<PathBuf>,
+
+    /// Load the eBPF programs from this object file instead of the one
+    /// built in; SIGUSR1 reloads them from it, keeping the map state
+    #[arg(long)]
+    bpf_object: Option<PathBuf>,
+
+    /// Stream closed connections, HTTP requests and policy violations as
+    /// newline-delimited JSON to this named pipe (created if missing);
+    /// events are dropped while no reader has it open or it is full
+    #[arg(long)]
+    fifo: Option<PathBuf>,
+
+    /// Serve the events written to --fifo to WebSocket clients at
+    /// /ws/events on the metrics port; events are dropped for clients that
+    /// fall behind
+    #[arg(long)]
+    enable_event_stream: bool,
+
+    /// Accept POST /config/ports and DELETE /config/ports/<port> on the
+    /// metrics port to change the monitored ports at runtime. Requests must
+    /// carry the token of --config-api-token-file
+    #[arg(long, requires = "config_api_token_file")]
+    allow_config_api: bool,
+
+    /// File holding the bearer token of the --allow-config-api endpoints
+    #[arg(long, requires = "allow_config_api")]
+    config_api_token_file: Option<PathBuf>,
+
+    /// When the config API changes the monitored ports, stop tracking the
+    /// connections on none of the new ports instead of keeping them until
+    /// they close
+    #[arg(long, requires = "allow_config_api")]
+    clear_on_reconfigure: bool,
+
+    /// Also write the connection metrics of every flush, in InfluxDB line
+    /// protocol, to the InfluxDB 1.x server at this http:// URL (e.g.
+    /// http://influxdb:8086)
+    #[arg(long, requires = "influx_db")]
+    influx_url: Option<String>,
+
+    /// Database the --influx-url points are written to
+    #[arg(long, requires = "influx_url")]
+    influx_db: Option<String>,
+
+    /// How often to write collected metrics to --influx-url, in seconds
+    /// (default: every collection; must not be shorter than --interval)
+    #[arg(long, requires = "influx_url")]
+    influx_flush_interval: Option<u64>,
+
+    /// Start a new reader for a perf buffer whose reader task stopped
+    /// heartbeating (see sidecar_event_reader_stalled); otherwise stalls are
+    /// only logged and exported
+    #[arg(long)]
+    restart_stalled_readers: bool,
+
+    /// Detach the tcp_sendmsg/tcp_recvmsg probes while the 1-minute load
+    /// average per CPU stays above this, and attach them again once it stays
+    /// below (see --circuit-breaker-after)
+    #[arg(long)]
+    circuit_breaker_load: Option<f64>,
+
+    /// Detach the tcp_sendmsg/tcp_recvmsg probes while the sidecar's own CPU
+    /// usage, in percent of one CPU, stays above this
+    #[arg(long)]
+    circuit_breaker_cpu: Option<f64>,
+
+    /// How long a circuit breaker threshold must stay exceeded before the
+    /// probes are detached, and stay clear before they are attached again
+    #[arg(long, value_parser = humantime::parse_duration, default_value = "30s")]
+    circuit_breaker_after: Duration,
+
+    /// Count the bytes received and sent on every network interface, for
+    /// sidecar_interface_bytes_total; probes every packet on the host
+    #[arg(long)]
+    interface_metrics: bool,
+
+    /// Count the sizes of the UDP datagrams the monitored processes send and
+    /// receive, for sidecar_udp_datagram_size_bytes; probes every UDP send
+    /// and receive on the host
+    #[arg(long)]
+    udp_metrics: bool,
+
+    /// Count the TCP segments the kernel drops for an old timestamp (PAWS),
+    /// for sidecar_paws_drops_total; probes every packet drop on the host
+    #[arg(long)]
+    paws_drops: bool,
+
+    /// Record the file descriptor each connection has in its process and
+    /// export it as the fd label of sidecar_connection_fd_info; traces the
+    /// read/write/send/recv system calls of the monitored processes
+    #[arg(long)]
+    fd_label: bool,
+
+    /// Export the IPv6 flow label of native IPv6 connections as the
+    /// flow_label label of sidecar_connection_flow_label_info (one series
+    /// per connection; high cardinality)
+    #[arg(long)]
+    flow_label: bool,
+
+    /// Append hex dumps of the first bytes of sampled sends and receives to
+    /// this file (created mode 0600). Payloads carry whatever the
+    /// application sent, credentials and personal data included: see
+    /// "Payload Capture" in the README before turning this on
+    #[arg(long)]
+    capture_payload: Option<PathBuf>,
+
+    /// Capture one in this many sends and receives (--capture-payload)
+    #[arg(long, default_value = "1000", value_parser = clap::value_parser!(u32).range(1..))]
+    capture_sample_every: u32,
+
+    /// Payload bytes kept per capture, at most 256 (--capture-payload)
+    #[arg(long, default_value = "64", value_parser = clap::value_parser!(u16).range(1..=256))]
+    capture_max_bytes: u16,
+
+    /// Captures per second over all CPUs, at most 100 (--capture-payload)
+    #[arg(long, default_value = "10", value_parser = clap::value_parser!(u32).range(1..=100))]
+    capture_rate_limit: u32,
+
+    /// Sample the time between sends/receives on each connection into
+    /// sidecar_connection_interpacket_gap_seconds
+    #[arg(long)]
+    interpacket_gaps: bool,
+
+    /// Report one in this many sends and receives (--interpacket-gaps)
+    #[arg(long, default_value = "10", value_parser = clap::value_parser!(u32).range(1..))]
+    gap_sample_every: u32,
+
+    /// Reports per second over all CPUs (--interpacket-gaps)
+    #[arg(long, default_value = "1000", value_parser = clap::value_parser!(u32).range(1..))]
+    gap_rate_limit: u32,
+
+    /// Fingerprint the TLS ClientHellos the monitored processes send (JA4)
+    /// into sidecar_tls_fingerprints_total; needs tls in --l7-parsers
+    #[arg(long)]
+    tls_fingerprints: bool,
+
+    /// Capture the call stacks of a sample of tracked connects and serve
+    /// them as folded stacks, for flamegraphs, on /debug/stacks
+    #[arg(long)]
+    capture_stacks: bool,
+
+    /// Capture the stacks of one in this many connects (--capture-stacks)
+    #[arg(long, default_value = "10", value_parser = clap::value_parser!(u32).range(1..))]
+    stack_sample_every: u32,
+
+    /// Stack captures per second over all CPUs (--capture-stacks)
+    #[arg(long, default_value = "100", value_parser = clap::value_parser!(u32).range(1..))]
+    stack_rate_limit: u32,
+
+    /// Write closed connections to rotating Parquet files in this directory
+    #[cfg(feature = "parquet")]
+    #[arg(long)]
+    parquet: Option<PathBuf>,
+
+    /// Start a new Parquet file after this many connections
+    #[cfg(feature = "parquet")]
+    #[arg(long, default_value = "1000000")]
+    parquet_rotate_rows: usize,
+
+    /// Start a new Parquet file after this many seconds
+    #[cfg(feature = "parquet")]
+    #[arg(long, default_value = "3600")]
+    parquet_rotate_secs: u64,
+
+    /// Send connection open/close events to the systemd journal
+    #[cfg(feature = "journald")]
+    #[arg(long)]
+    journald: bool,
+}
+
+/// What one set of per-connection series stands for (`--group-by`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum GroupBy {
+    /// One set per connection
+    Connection,
+    /// One set per source host and destination endpoint, merging
+    /// connections that differ only in source port
+    Endpoint,
+}
+
+/// Per-connection metric families that `--disable-metrics` can switch off.
+///
+/// A disabled family is never touched, so its lazily registered metric never
+/// reaches the registry or the scrape.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum MetricGroup {
+    /// `sidecar_connection_bytes_{sent,received}_total`
+    Bytes,
+    /// `sidecar_connection_wire_bytes_sent_total`
+    WireBytes,
+    /// `sidecar_connection_packets_{sent,received}_total`
+    Packets,
+    /// `sidecar_connection_retransmits_total`
+    Retransmits,
+    /// `sidecar_connection_resets_total`
+    Resets,
+    /// `sidecar_connection_sack_total` and `sidecar_connection_dsack_total`
+    Sack,
+    /// `sidecar_connection_duration_seconds`
+    Duration,
+    /// `sidecar_connection_rtt_jitter_microseconds`
+    RttJitter,
+    /// `sidecar_connection_mss_bytes`
+    Mss,
+    /// `sidecar_connection_path_mtu_bytes`
+    Pmtu,
+    /// `sidecar_connection_idle_seconds`
+    Idle,
+    /// `sidecar_connection_fd_info` (only with `--fd-label`)
+    Fd,
+    /// `sidecar_connection_timestamps_enabled`
+    Timestamps,
+    /// `sidecar_connection_priority_info`
+    Priority,
+    /// `sidecar_connection_http_version_info`
+    HttpVersion,
+    /// `sidecar_connection_flow_label_info` (only with `--flow-label`)
+    FlowLabel,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Export metrics from maps pinned by a running loader (no eBPF loading)
+    Export {
+        /// bpffs directory the loader pinned its maps into (see --pin-maps)
+        #[arg(long)]
+        from_pinned: PathBuf,
+    },
+    /// Check that programs load, record localhost traffic and export valid
+    /// metrics on this kernel (requires root; exits non-zero on failure)
+    Selftest,
 }
 
 // ============================================================================
@@ -88,280 +550,3053 @@ lazy_static::lazy_static! {
     static ref CONN_BYTES_SENT: CounterVec = register_counter_vec!(
         "sidecar_connection_bytes_sent_total",
         "Total bytes sent per connection",
-        &["src_ip", "dst_ip", "dst_port"]
+        &["src_ip", "dst_ip", "dst_port", "exe", "cc_algo", "netns"]
+    ).unwrap();
+
+    static ref CONN_WIRE_BYTES_SENT: CounterVec = register_counter_vec!(
+        "sidecar_connection_wire_bytes_sent_total",
+        "Total on-the-wire bytes sent per connection (TCP/IP headers, retransmits, ACKs)",
+        &["src_ip", "dst_ip", "dst_port", "exe", "cc_algo", "netns"]
     ).unwrap();
 
     static ref CONN_BYTES_RECV: CounterVec = register_counter_vec!(
         "sidecar_connection_bytes_received_total",
         "Total bytes received per connection",
-        &["src_ip", "dst_ip", "dst_port"]
+        &["src_ip", "dst_ip", "dst_port", "exe", "cc_algo", "netns"]
     ).unwrap();
 
     static ref CONN_PACKETS_SENT: CounterVec = register_counter_vec!(
         "sidecar_connection_packets_sent_total",
         "Total packets sent per connection",
-        &["src_ip", "dst_ip", "dst_port"]
+        &["src_ip", "dst_ip", "dst_port", "exe", "cc_algo", "netns"]
     ).unwrap();
 
     static ref CONN_PACKETS_RECV: CounterVec = register_counter_vec!(
         "sidecar_connection_packets_received_total",
         "Total packets received per connection",
-        &["src_ip", "dst_ip", "dst_port"]
+        &["src_ip", "dst_ip", "dst_port", "exe", "cc_algo", "netns"]
     ).unwrap();
 
     static ref CONN_RETRANSMITS: CounterVec = register_counter_vec!(
         "sidecar_connection_retransmits_total",
         "Total TCP retransmissions per connection",
-        &["src_ip", "dst_ip", "dst_port"]
+        &["src_ip", "dst_ip", "dst_port", "exe", "cc_algo", "netns"]
+    ).unwrap();
+
+    static ref CONN_SACKS: CounterVec = register_counter_vec!(
+        "sidecar_connection_sack_total",
+        "SACK blocks received per connection",
+        &["src_ip", "dst_ip", "dst_port", "exe", "cc_algo", "netns"]
+    ).unwrap();
+
+    static ref CONN_DSACKS: CounterVec = register_counter_vec!(
+        "sidecar_connection_dsack_total",
+        "Segments the peer reported as duplicates (DSACK) per connection; spurious retransmits",
+        &["src_ip", "dst_ip", "dst_port", "exe", "cc_algo", "netns"]
+    ).unwrap();
+
+    static ref CONN_RESETS: CounterVec = register_counter_vec!(
+        "sidecar_connection_resets_total",
+        "TCP RST segments per connection, by direction (sent, received)",
+        &["src_ip", "dst_ip", "dst_port", "exe", "cc_algo", "netns", "direction"]
+    ).unwrap();
+
+    // --delta-mode replacements for the counters above
+    static ref CONN_BYTES_SENT_DELTA: GaugeVec = register_gauge_vec!(
+        "sidecar_connection_bytes_sent_delta",
+        "Bytes sent per connection since the previous flush (--delta-mode)",
+        &["src_ip", "dst_ip", "dst_port", "exe", "cc_algo", "netns"]
+    ).unwrap();
+
+    static ref CONN_BYTES_RECV_DELTA: GaugeVec = register_gauge_vec!(
+        "sidecar_connection_bytes_received_delta",
+        "Bytes received per connection since the previous flush (--delta-mode)",
+        &["src_ip", "dst_ip", "dst_port", "exe", "cc_algo", "netns"]
+    ).unwrap();
+
+    static ref CONN_WIRE_BYTES_SENT_DELTA: GaugeVec = register_gauge_vec!(
+        "sidecar_connection_wire_bytes_sent_delta",
+        "On-wire bytes sent per connection since the previous flush (--delta-mode)",
+        &["src_ip", "dst_ip", "dst_port", "exe", "cc_algo", "netns"]
+    ).unwrap();
+
+    static ref CONN_PACKETS_SENT_DELTA: GaugeVec = register_gauge_vec!(
+        "sidecar_connection_packets_sent_delta",
+        "Packets sent per connection since the previous flush (--delta-mode)",
+        &["src_ip", "dst_ip", "dst_port", "exe", "cc_algo", "netns"]
+    ).unwrap();
+
+    static ref CONN_PACKETS_RECV_DELTA: GaugeVec = register_gauge_vec!(
+        "sidecar_connection_packets_received_delta",
+        "Packets received per connection since the previous flush (--delta-mode)",
+        &["src_ip", "dst_ip", "dst_port", "exe", "cc_algo", "netns"]
+    ).unwrap();
+
+    static ref CONN_SACKS_DELTA: GaugeVec = register_gauge_vec!(
+        "sidecar_connection_sack_delta",
+        "SACK blocks received per connection since the previous flush (--delta-mode)",
+        &["src_ip", "dst_ip", "dst_port", "exe", "cc_algo", "netns"]
+    ).unwrap();
+
+    static ref CONN_DSACKS_DELTA: GaugeVec = register_gauge_vec!(
+        "sidecar_connection_dsack_delta",
+        "Segments DSACKed as duplicates per connection since the previous flush (--delta-mode)",
+        &["src_ip", "dst_ip", "dst_port", "exe", "cc_algo", "netns"]
+    ).unwrap();
+
+    static ref CONN_RETRANSMITS_DELTA: GaugeVec = register_gauge_vec!(
+        "sidecar_connection_retransmits_delta",
+        "TCP retransmits per connection since the previous flush (--delta-mode)",
+        &["src_ip", "dst_ip", "dst_port", "exe", "cc_algo", "netns"]
+    ).unwrap();
+
+    static ref CONN_RESETS_DELTA: GaugeVec = register_gauge_vec!(
+        "sidecar_connection_resets_delta",
+        "TCP RST segments per connection since the previous flush, by direction (--delta-mode)",
+        &["src_ip", "dst_ip", "dst_port", "exe", "cc_algo", "netns", "direction"]
     ).unwrap();
 
     static ref CONN_DURATION: GaugeVec = register_gauge_vec!(
         "sidecar_connection_duration_seconds",
         "Connection duration in seconds",
-        &["src_ip", "dst_ip", "dst_port"]
+        &["src_ip", "dst_ip", "dst_port", "exe", "cc_algo", "netns"]
     ).unwrap();
 
-    static ref ACTIVE_CONNECTIONS: prometheus::IntGauge = prometheus::register_int_gauge!(
-        "sidecar_active_connections",
-        "Number of active connections being tracked"
+    static ref CONN_RTT_JITTER: GaugeVec = register_gauge_vec!(
+        "sidecar_connection_rtt_jitter_microseconds",
+        "RTT jitter (standard deviation of sampled RTTs) per connection",
+        &["src_ip", "dst_ip", "dst_port", "exe", "cc_algo", "netns"]
     ).unwrap();
-}
 
-// ============================================================================
-// Main Entry Point
-// ============================================================================
+    static ref CONN_MSS: GaugeVec = register_gauge_vec!(
+        "sidecar_connection_mss_bytes",
+        "Current send MSS per connection (point-in-time, may drop after PMTU discovery)",
+        &["src_ip", "dst_ip", "dst_port", "exe", "cc_algo", "netns"]
+    ).unwrap();
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let args = Args::parse();
+    static ref CONN_PMTU: GaugeVec = register_gauge_vec!(
+        "sidecar_connection_path_mtu_bytes",
+        "Path MTU per connection (point-in-time, 0 until known)",
+        &["src_ip", "dst_ip", "dst_port", "exe", "cc_algo", "netns"]
+    ).unwrap();
 
-    // Initialize logging
-    env_logger::Builder::from_env(
-        env_logger::Env::default().default_filter_or(if args.debug { "debug" } else { "info" }),
-    )
-    .init();
+    static ref CONN_TIMESTAMPS: GaugeVec = register_gauge_vec!(
+        "sidecar_connection_timestamps_enabled",
+        "1 if the connection negotiated TCP timestamps (RFC 7323), 0 if not or not yet established",
+        &["src_ip", "dst_ip", "dst_port", "exe", "cc_algo", "netns"]
+    ).unwrap();
 
-    info!("Starting eBPF sidecar...");
-    info!("Target PID: {}", if args.pid == 0 { "all".to_string() } else { args.pid.to_string() });
-    info!("Metrics port: {}", args.metrics_port);
+    static ref CONN_IDLE_EXCEEDS_KEEPALIVE: GaugeVec = register_gauge_vec!(
+        "sidecar_connection_idle_exceeds_keepalive",
+        "1 if the connection has been idle for longer than --keepalive-threshold, else 0",
+        &["src_ip", "dst_ip", "dst_port", "exe", "cc_algo", "netns"]
+    ).unwrap();
 
-    // Bump memlock rlimit for eBPF
-    bump_memlock_rlimit()?;
+    static ref CONN_FD_INFO: GaugeVec = register_gauge_vec!(
+        "sidecar_connection_fd_info",
+        "Always 1; the fd label is the connection's descriptor in its process (--fd-label)",
+        &["src_ip", "dst_ip", "dst_port", "exe", "cc_algo", "netns", "fd"]
+    ).unwrap();
 
-    // Load eBPF program
-    let mut bpf = load_ebpf_program()?;
+    static ref CONN_PRIORITY_INFO: GaugeVec = register_gauge_vec!(
+        "sidecar_connection_priority_info",
+        "Always 1; the priority label is the connection's SO_PRIORITY, exported only when not 0",
+        &["src_ip", "dst_ip", "dst_port", "exe", "cc_algo", "netns", "priority"]
+    ).unwrap();
 
-    // Initialize eBPF logging
-    if let Err(e) = BpfLogger::init(&mut bpf) {
-        warn!("Failed to initialize eBPF logger: {}", e);
-    }
+    static ref CONN_FLOW_LABEL_INFO: GaugeVec = register_gauge_vec!(
+        "sidecar_connection_flow_label_info",
+        "Always 1; the flow_label label is the connection's IPv6 flow label in hex (--flow-label)",
+        &["src_ip", "dst_ip", "dst_port", "exe", "cc_algo", "netns", "flow_label"]
+    ).unwrap();
 
-    // Configure the sidecar
-    configure_sidecar(&mut bpf, &args)?;
+    static ref CONN_HTTP_VERSION_INFO: GaugeVec = register_gauge_vec!(
+        "sidecar_connection_http_version_info",
+        "Always 1; the version label is the HTTP version the connection was seen speaking",
+        &["src_ip", "dst_ip", "dst_port", "exe", "cc_algo", "netns", "version"]
+    ).unwrap();
 
-    // Attach programs
-    attach_programs(&mut bpf)?;
+    static ref CONN_NAT_INFO: GaugeVec = register_gauge_vec!(
+        "sidecar_connection_nat_info",
+        "Always 1; the address clients dialled for a connection accepted behind DNAT (--conntrack)",
+        &[
+            "src_ip", "dst_ip", "dst_port", "exe", "cc_algo", "netns",
+            "original_dst_ip", "original_dst_port",
+        ]
+    ).unwrap();
 
-    info!("eBPF programs loaded and attached successfully");
+    static ref CONNTRACK_LOOKUP_ERRORS: prometheus::IntCounter = prometheus::register_int_counter!(
+        "sidecar_conntrack_lookup_errors_total",
+        "Conntrack lookups that failed, e.g. timed out or were refused (--conntrack)"
+    ).unwrap();
 
-    // Start Prometheus HTTP server
-    let metrics_addr: SocketAddr = ([0, 0, 0, 0], args.metrics_port).into();
-    tokio::spawn(async move {
-        if let Err(e) = run_metrics_server(metrics_addr).await {
-            error!("Metrics server error: {}", e);
-        }
-    });
-    info!("Prometheus metrics available at http://0.0.0.0:{}/metrics", args.metrics_port);
+    static ref CONNECT_STACKS: prometheus::IntCounter = prometheus::register_int_counter!(
+        "sidecar_connect_stacks_captured_total",
+        "Connects whose call stacks were captured (--capture-stacks)"
+    ).unwrap();
 
-    // Get reference to connections map
-    let connections: HashMap<_, ConnKey, ConnMetrics> =
-        HashMap::try_from(bpf.map("CONNECTIONS").context("Failed to get CONNECTIONS map")?)?;
+    static ref CONNECT_STACKS_LOST: prometheus::IntCounter = prometheus::register_int_counter!(
+        "sidecar_connect_stacks_lost_total",
+        "Captured connects missing their kernel or user stack, shown as [lost] (--capture-stacks)"
+    ).unwrap();
 
-    // Metrics collection loop
-    let mut interval = time::interval(Duration::from_secs(args.interval));
+    static ref INTERFACE_BYTES: CounterVec = register_counter_vec!(
+        "sidecar_interface_bytes_total",
+        "Bytes received and sent per network interface, all traffic included (--interface-metrics)",
+        &["interface", "netns", "direction"]
+    ).unwrap();
 
-    info!("Sidecar running. Press Ctrl+C to stop.");
+    static ref CONN_FAILURES: CounterVec = register_counter_vec!(
+        "sidecar_connection_failures_total",
+        "Outbound connection attempts that never reached ESTABLISHED",
+        &["dst_ip", "dst_port", "reason"]
+    ).unwrap();
 
-    loop {
-        tokio::select! {
-            _ = interval.tick() => {
-                if let Err(e) = collect_and_export_metrics(&connections) {
-                    error!("Failed to collect metrics: {}", e);
-                }
-            }
-            _ = signal::ctrl_c() => {
-                info!("Received shutdown signal");
-                break;
-            }
-        }
-    }
+    static ref POLICY_VIOLATIONS: CounterVec = register_counter_vec!(
+        "sidecar_policy_violations_total",
+        "Outbound connection attempts matching a --deny rule (observed, not blocked)",
+        &["rule", "dst_ip", "dst_port"]
+    ).unwrap();
 
-    info!("Sidecar stopped");
-    Ok(())
-}
+    static ref CONN_UNHEALTHY: GaugeVec = register_gauge_vec!(
+        "sidecar_connection_unhealthy",
+        "1 if an endpoint's live connections cross a health threshold, by reason; 0 otherwise",
+        &["dst_ip", "dst_port", "reason"]
+    ).unwrap();
 
-// ============================================================================
-// eBPF Loading and Setup
-// ============================================================================
+    static ref MPTCP_SUBFLOWS: GaugeVec = register_gauge_vec!(
+        "sidecar_mptcp_subflows",
+        "Live subflows of each MPTCP connection, labelled like the merged connection",
+        &["mptcp_token", "src_ip", "dst_ip", "dst_port"]
+    ).unwrap();
 
-fn bump_memlock_rlimit() -> Result<()> {
-    let rlim = libc::rlimit {
-        rlim_cur: libc::RLIM_INFINITY,
-        rlim_max: libc::RLIM_INFINITY,
-    };
-    let ret = unsafe { libc::setrlimit(libc::RLIMIT_MEMLOCK, &rlim) };
-    if ret != 0 {
-        anyhow::bail!("Failed to set RLIMIT_MEMLOCK");
-    }
-    Ok(())
-}
+    static ref MPTCP_SUBFLOW_RETRANSMITS: GaugeVec = register_gauge_vec!(
+        "sidecar_mptcp_subflow_retransmits",
+        "Retransmits over the lifetime of each live MPTCP subflow",
+        &["mptcp_token", "src_ip", "src_port", "dst_ip", "dst_port"]
+    ).unwrap();
 
-fn load_ebpf_program() -> Result<Bpf> {
-    // Include the compiled eBPF bytecode at compile time
-    #[cfg(debug_assertions)]
-    let bpf = Bpf::load(include_bytes_aligned!(
-        "../../target/bpfel-unknown-none/debug/sidecar"
-    ))?;
+    static ref ENDPOINT_HEALTH_SCORE: GaugeVec = register_gauge_vec!(
+        "sidecar_endpoint_health_score",
+        "Endpoint health from 0 (worst) to 100, weighing retransmit ratio, RTT and connect failures",
+        &["dst_ip", "dst_port"]
+    ).unwrap();
 
-    #[cfg(not(debug_assertions))]
-    let bpf = Bpf::load(include_bytes_aligned!(
-        "../../target/bpfel-unknown-none/release/sidecar"
-    ))?;
+    static ref ENDPOINT_THROUGHPUT_EWMA: GaugeVec = register_gauge_vec!(
+        "sidecar_endpoint_throughput_ewma_bytes_per_second",
+        "Exponentially weighted moving average of bytes/second sent + received per endpoint",
+        &["dst_ip", "dst_port"]
+    ).unwrap();
 
-    Ok(bpf)
-}
+    static ref BYTES_PER_MINUTE: GaugeVec = register_gauge_vec!(
+        "sidecar_bytes_per_minute",
+        "Bytes per minute on tracked connections over the last hour (minutes_ago 0 = current minute)",
+        &["direction", "minutes_ago"]
+    ).unwrap();
 
-fn configure_sidecar(bpf: &mut Bpf, args: &Args) -> Result<()> {
-    let mut config = SidecarConfig::default();
-    config.target_pid = args.pid;
-    config.debug_mode = if args.debug { 1 } else { 0 };
+    static ref CONNECTIONS_BY_DURATION_TIER: prometheus::IntCounterVec =
+        prometheus::register_int_counter_vec!(
+            "sidecar_connections_by_duration_tier_total",
+            "Closed connections by how long they lasted (under_1s, 1s_to_10s, 10s_to_1m, over_1m)",
+            &["tier"]
+        ).unwrap();
+
+    static ref CONN_TOTAL_BYTES: HistogramVec = register_histogram_vec!(
+        "sidecar_connection_total_bytes",
+        "Bytes sent + received over a connection's lifetime, observed at close",
+        &["dst_ip", "dst_port"],
+        metrics::CONN_TOTAL_BYTES_BUCKETS.to_vec()
+    ).unwrap();
 
-    // Set target ports if specified
-    if let Some(ref ports) = args.ports {
-        for (i, port) in ports.iter().take(8).enumerate() {
-            config.target_ports[i] = *port;
-        }
-        config.num_target_ports = ports.len().min(8) as u8;
-    }
+    static ref CONN_IDLE: prometheus::Histogram = prometheus::register_histogram!(
+        "sidecar_connection_idle_seconds",
+        "Time since each tracked connection's last send/receive, observed per connection every flush",
+        metrics::CONN_IDLE_SECONDS_BUCKETS.to_vec()
+    ).unwrap();
 
-    // Write config to eBPF map
-    let mut config_map: Array<_, SidecarConfig> =
-        Array::try_from(bpf.map_mut("CONFIG").context("Failed to get CONFIG map")?)?;
-    config_map.set(0, config, 0)?;
+    static ref CONNECTIONS_EVICTED: prometheus::IntCounter = prometheus::register_int_counter!(
+        "sidecar_connections_evicted_total",
+        "Connections evicted from the LRU map before close (inserted - closed - live, estimated)"
+    ).unwrap();
 
-    debug!("Configuration applied: {:?}", config);
-    Ok(())
-}
+    static ref CONNECTION_INSERT_FAILURES: prometheus::IntCounter = prometheus::register_int_counter!(
+        "sidecar_connection_insert_failures_total",
+        "New connections left untracked because the connections map was full"
+    ).unwrap();
 
-fn attach_programs(bpf: &mut Bpf) -> Result<()> {
-    // Attach kprobes
-    let programs = [
-        ("trace_tcp_connect", "tcp_connect"),
-        ("trace_tcp_sendmsg", "tcp_sendmsg"),
-        ("trace_tcp_recvmsg", "tcp_recvmsg"),
-        ("trace_tcp_close", "tcp_close"),
-    ];
-
-    for (prog_name, fn_name) in programs {
-        let program: &mut KProbe = bpf
-            .program_mut(prog_name)
-            .context(format!("Failed to get program {}", prog_name))?
-            .try_into()?;
-        program.load()?;
-        program.attach(fn_name, 0)?;
-        info!("Attached {} to {}", prog_name, fn_name);
-    }
-
-    // Attach tracepoint for retransmits
-    let tp: &mut TracePoint = bpf
-        .program_mut("trace_tcp_retransmit")
-        .context("Failed to get trace_tcp_retransmit")?
-        .try_into()?;
-    tp.load()?;
-    tp.attach("tcp", "tcp_retransmit_skb")?;
-    info!("Attached trace_tcp_retransmit to tcp:tcp_retransmit_skb");
+    static ref PAWS_DROPS: prometheus::IntCounter = prometheus::register_int_counter!(
+        "sidecar_paws_drops_total",
+        "TCP segments dropped for an old timestamp (PAWS) on the whole host (--paws-drops)"
+    ).unwrap();
 
-    Ok(())
+    static ref ARG_READ_ERRORS: prometheus::IntCounter = prometheus::register_int_counter!(
+        "sidecar_arg_read_errors_total",
+        "kprobe arguments or return values the eBPF probes couldn't read"
+    ).unwrap();
+
+    static ref FIFO_EVENTS_WRITTEN: prometheus::IntCounter = prometheus::register_int_counter!(
+        "sidecar_fifo_events_written_total",
+        "Events written to the --fifo named pipe"
+    ).unwrap();
+
+    static ref FIFO_EVENTS_DROPPED: prometheus::IntCounterVec = prometheus::register_int_counter_vec!(
+        "sidecar_fifo_events_dropped_total",
+        "Events not written to the --fifo named pipe, by reason (no_reader, full, error)",
+        &["reason"]
+    ).unwrap();
+
+    static ref PATH_HASH_COLLISIONS: prometheus::IntCounter = prometheus::register_int_counter!(
+        "sidecar_path_hash_collisions_total",
+        "Distinct HTTP request paths whose --path-hash-bits hash was already another path's"
+    ).unwrap();
+
+    static ref TLS_FINGERPRINTS: prometheus::IntCounterVec = prometheus::register_int_counter_vec!(
+        "sidecar_tls_fingerprints_total",
+        "TLS ClientHellos sent by the monitored processes, by JA4 fingerprint (--tls-fingerprints)",
+        &["fingerprint"]
+    ).unwrap();
+
+    static ref TLS_HELLOS_UNPARSED: prometheus::IntCounter = prometheus::register_int_counter!(
+        "sidecar_tls_hellos_unparsed_total",
+        "TLS ClientHellos not fingerprinted because the kernel's copy didn't hold all of them"
+    ).unwrap();
+
+    static ref INFLUX_ERRORS: prometheus::IntCounter = prometheus::register_int_counter!(
+        "sidecar_influx_errors_total",
+        "Batches of points not written to --influx-url (request failed, rejected, or skipped \
+         because the previous flush was still being written)"
+    ).unwrap();
+
+    static ref EVENT_READER_STALLED: prometheus::IntGaugeVec = prometheus::register_int_gauge_vec!(
+        "sidecar_event_reader_stalled",
+        "1 while the reader task of a perf buffer has stopped heartbeating, by events and cpu",
+        &["events", "cpu"]
+    ).unwrap();
+
+    static ref CONN_ANOMALIES: prometheus::IntCounterVec = prometheus::register_int_counter_vec!(
+        "sidecar_connection_anomalies_total",
+        "Connections behaving unexpectedly for their socket, by type (src_change)",
+        &["type"]
+    ).unwrap();
+
+    static ref EVENT_READER_RESTARTS: prometheus::IntCounterVec =
+        prometheus::register_int_counter_vec!(
+            "sidecar_event_reader_restarts_total",
+            "Readers started to replace stalled perf buffer readers (--restart-stalled-readers)",
+            &["events"]
+        ).unwrap();
+
+    static ref EVENT_STREAM_CLIENTS: prometheus::IntGauge = prometheus::register_int_gauge!(
+        "sidecar_event_stream_clients",
+        "WebSocket clients connected to /ws/events"
+    ).unwrap();
+
+    static ref EVENT_STREAM_DROPPED: prometheus::IntCounterVec = prometheus::register_int_counter_vec!(
+        "sidecar_event_stream_events_dropped_total",
+        "Events not sent to a /ws/events client that fell behind, by client address",
+        &["client"]
+    ).unwrap();
+
+    static ref FILTER_DECISIONS: prometheus::IntCounterVec = prometheus::register_int_counter_vec!(
+        "sidecar_filter_decisions_total",
+        "Outcomes of the kernel's process and connection filter checks, by outcome",
+        &["outcome"]
+    ).unwrap();
+
+    static ref HTTP_LATENCY_P50: GaugeVec = register_gauge_vec!(
+        "sidecar_http_latency_p50_seconds",
+        "HTTP request latency p50 over the sliding latency window",
+        &["protocol"]
+    ).unwrap();
+
+    static ref HTTP_LATENCY_P95: GaugeVec = register_gauge_vec!(
+        "sidecar_http_latency_p95_seconds",
+        "HTTP request latency p95 over the sliding latency window",
+        &["protocol"]
+    ).unwrap();
+
+    static ref HTTP_LATENCY_P99: GaugeVec = register_gauge_vec!(
+        "sidecar_http_latency_p99_seconds",
+        "HTTP request latency p99 over the sliding latency window",
+        &["protocol"]
+    ).unwrap();
+
+    static ref HTTP_QUEUE_LATENCY: prometheus::Histogram = prometheus::register_histogram!(
+        "sidecar_http_queue_latency_seconds",
+        "Estimated part of HTTP latency spent queued behind earlier data in the local send buffer"
+    ).unwrap();
+
+    static ref HTTP_NETWORK_LATENCY: prometheus::Histogram = prometheus::register_histogram!(
+        "sidecar_http_network_latency_seconds",
+        "Estimated part of HTTP latency spent on the network (one smoothed RTT)"
+    ).unwrap();
+
+    static ref ENDPOINT_SUCCESS_RATIO: GaugeVec = register_gauge_vec!(
+        "sidecar_endpoint_success_ratio",
+        "Fraction of HTTP requests per endpoint without a 5xx status, over the last interval",
+        &["dst_ip", "dst_port"]
+    ).unwrap();
+
+    static ref ENDPOINT_LATENCY_SLO_RATIO: GaugeVec = register_gauge_vec!(
+        "sidecar_endpoint_latency_slo_ratio",
+        "Fraction of HTTP requests per endpoint within --slo-latency-ms, over the last interval",
+        &["dst_ip", "dst_port"]
+    ).unwrap();
+
+    static ref ACTIVE_CONNECTIONS: prometheus::IntGauge = prometheus::register_int_gauge!(
+        "sidecar_active_connections",
+        "Number of active connections being tracked"
+    ).unwrap();
+
+    static ref CIRCUIT_BREAKER_TRIPPED: prometheus::IntGauge = prometheus::register_int_gauge!(
+        "sidecar_circuit_breaker_tripped",
+        "1 while the circuit breaker keeps the tcp_sendmsg/tcp_recvmsg probes detached for load"
+    ).unwrap();
+
+    static ref ACTIVE_CONNECTIONS_PEAK: prometheus::IntGauge = prometheus::register_int_gauge!(
+        "sidecar_active_connections_peak",
+        "Most connections tracked at once since the previous flush, as counted by the kernel"
+    ).unwrap();
+
+    static ref BUILD_INFO: prometheus::IntGaugeVec = prometheus::register_int_gauge_vec!(
+        "sidecar_build_info",
+        "Always 1; the sidecar version, its commit and the SHA-256 of the eBPF object running",
+        &["version", "git_sha", "bpf_object_sha"]
+    ).unwrap();
+
+    static ref RETRANSMIT_TRACKING: prometheus::IntGauge = prometheus::register_int_gauge!(
+        "sidecar_retransmit_tracking_available",
+        "1 if a retransmit counter is attached on this kernel, 0 if retransmits always read 0"
+    ).unwrap();
+
+    static ref CONNECTIONS_BY_PROTOCOL: prometheus::IntGaugeVec =
+        prometheus::register_int_gauge_vec!(
+            "sidecar_connections_by_protocol",
+            "Tracked connections on which an L7 parser recognised the protocol",
+            &["protocol"]
+        ).unwrap();
 }
 
 // ============================================================================
-// Metrics Collection and Export
+// Main Entry Point
 // ============================================================================
 
-fn collect_and_export_metrics(
-    connections: &HashMap<&aya::maps::MapData, ConnKey, ConnMetrics>,
-) -> Result<()> {
-    let mut count = 0;
+#[tokio::main]
+async fn main() -> Result<()> {
+    let matches = Args::command().get_matches();
+    let mut args = Args::from_arg_matches(&matches)?;
 
-    for result in connections.iter() {
-        let (key, metrics) = result?;
+    // Initialize logging
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(if args.debug {
+        "debug"
+    } else {
+        "info"
+    }))
+    .init();
 
-        let src_ip = Ipv4Addr::from(key.src_ip.to_be()).to_string();
-        let dst_ip = Ipv4Addr::from(key.dst_ip.to_be()).to_string();
-        let dst_port = key.dst_port.to_string();
+    // Before anything else starts threads, so they all inherit the mask
+    if let Some(ref cpus) = args.cpu_affinity {
+        affinity::pin_process(cpus)?;
+        info!("Running on CPUs {}", cpus);
+    }
 
-        // Update Prometheus metrics
-        CONN_BYTES_SENT
-            .with_label_values(&[&src_ip, &dst_ip, &dst_port])
-            .inc_by(metrics.bytes_sent as f64);
+    let config = effective_config(&mut args, &matches)?;
+    if args.print_config {
+        print!("{}", serde_yaml::to_string(&config)?);
+        return Ok(());
+    }
 
-        CONN_BYTES_RECV
-            .with_label_values(&[&src_ip, &dst_ip, &dst_port])
-            .inc_by(metrics.bytes_recv as f64);
+    metrics::set_anonymize_ips(args.anonymize_ips);
+    let mut relabeler = Relabeler::new(&config.metrics.relabel)?;
+    if let Some(ref labels) = config.metrics.group_by {
+        relabeler = relabeler.group_by(labels).context("metrics.group_by")?;
+    }
+    relabel::install(relabeler);
+    sidecar::time::init();
+
+    if let Some(Command::Export { ref from_pinned }) = args.command {
+        let mut maps = PinnedMaps::open(from_pinned)?;
+        info!("Exporting from pinned maps in {}", from_pinned.display());
+        let loader_config = maps.config()?;
+        if loader_config.version == 0 {
+            log::warn!(
+                "The loader never wrote a config version; it may be older than this exporter"
+            );
+        }
+        info!("Loader target PID: {}", loader_config.target_pid);
+        if args.allow_config_api {
+            anyhow::bail!(
+                "--allow-config-api needs the loader; `sidecar export` only reads the maps"
+            );
+        }
+        if breaker_thresholds(&args).any() {
+            anyhow::bail!(
+                "The circuit breaker needs the loader; `sidecar export` attaches no probes"
+            );
+        }
+        return run_exporter(&mut maps, &args, &config, None, None, None).await;
+    }
 
-        CONN_PACKETS_SENT
-            .with_label_values(&[&src_ip, &dst_ip, &dst_port])
-            .inc_by(metrics.packets_sent as f64);
+    if let Some(Command::Selftest) = args.command {
+        return selftest::run().await;
+    }
 
-        CONN_PACKETS_RECV
-            .with_label_values(&[&src_ip, &dst_ip, &dst_port])
-            .inc_by(metrics.packets_recv as f64);
+    info!("Starting eBPF sidecar...");
+    let target_pid = match args.pid_namespace {
+        Some(ref namespace) if args.pid != 0 => {
+            let host_pid = sidecar::process::host_pid(namespace, args.pid)?;
+            info!("PID {} in {} is host PID {}", args.pid, namespace, host_pid);
+            host_pid
+        }
+        _ => args.pid,
+    };
+    let extra_pids = match args.process_name {
+        Some(ref pattern) => {
+            let pids = sidecar::process::find_pids(pattern)?;
+            if pids.is_empty() {
+                anyhow::bail!("No running process matches --process-name {}", pattern);
+            }
+            info!("Target processes matching {}: {:?}", pattern, pids);
+            pids
+        }
+        None => {
+            info!(
+                "Target PID: {}",
+                if target_pid == 0 {
+                    "all".to_string()
+                } else {
+                    target_pid.to_string()
+                }
+            );
+            Vec::new()
+        }
+    };
+    info!("Metrics port: {}", args.metrics_port);
 
-        CONN_RETRANSMITS
-            .with_label_values(&[&src_ip, &dst_ip, &dst_port])
-            .inc_by(metrics.retransmits as f64);
+    if args.tls_fingerprints
+        && !args
+            .l7_parsers
+            .as_deref()
+            .unwrap_or_default()
+            .contains(&L7Protocol::Tls)
+    {
+        anyhow::bail!("--tls-fingerprints needs the TLS parser: add tls to --l7-parsers");
+    }
 
-        let duration_secs = (metrics.last_seen_ns - metrics.start_ns) as f64 / 1_000_000_000.0;
-        CONN_DURATION
-            .with_label_values(&[&src_ip, &dst_ip, &dst_port])
-            .set(duration_secs);
+    // Load eBPF program (refuses to run against a mismatched struct layout)
+    let mut monitor = Monitor::load_with(LoadOptions {
+        verifier_log: args.verifier_log_level,
+        object: args.bpf_object.clone(),
+        pin_dir: args.pin_maps.clone(),
+        reloadable: args.bpf_object.is_some(),
+    })?;
+    if args.version_check {
+        info!("Layout check passed: {:?}", LayoutInfo::current());
+        return Ok(());
+    }
 
-        count += 1;
+    // Configure the sidecar
+    let always_track = config
+        .target
+        .always_track
+        .iter()
+        .map(|rule| rule.parse())
+        .collect::<Result<Vec<PolicyRule>>>()?;
+    if !always_track.is_empty() {
+        info!("Always tracking: {}", config.target.always_track.join(", "));
+    }
+    let options = MonitorOptions {
+        target_pid,
+        extra_pids,
+        ports: args.ports.clone().unwrap_or_default(),
+        priority_ports: args.priority_ports.clone().unwrap_or_default(),
+        debug: args.debug,
+        exclude_loopback: args.exclude_loopback,
+        new_connections_only: args.new_connections_only,
+        sample_rates: config.sample_rates,
+        l7_parsers: args.l7_parsers.clone().unwrap_or_default(),
+        cgroup: args.cgroup.clone(),
+        denylist: args.deny.clone().unwrap_or_default(),
+        always_track,
+        interface_metrics: args.interface_metrics,
+        udp_metrics: args.udp_metrics,
+        paws_drops: args.paws_drops,
+        fd_tracking: args.fd_label,
+        payload_capture: args.capture_payload.is_some().then_some(PayloadCapture {
+            sample_every: args.capture_sample_every,
+            max_bytes: args.capture_max_bytes,
+            max_per_sec: args.capture_rate_limit,
+        }),
+        gap_sampling: args.interpacket_gaps.then_some(GapSampling {
+            sample_every: args.gap_sample_every,
+            max_per_sec: args.gap_rate_limit,
+        }),
+        tls_fingerprints: args.tls_fingerprints,
+        stack_sampling: args.capture_stacks.then_some(StackSampling {
+            sample_every: args.stack_sample_every,
+            max_per_sec: args.stack_rate_limit,
+        }),
+    };
+    monitor.configure(&options)?;
+    if let Some(targets) = monitor.cgroup_targets()? {
+        spawn_cgroup_refresh(targets, Duration::from_secs(args.cgroup_refresh_secs));
     }
 
-    ACTIVE_CONNECTIONS.set(count);
-    debug!("Collected metrics for {} connections", count);
+    let audit_log = match args.audit_log {
+        Some(ref path) => {
+            let mut audit_log = AuditLog::open(path)?;
+            audit_log.record(AuditTrigger::Startup, None, &options)?;
+            Some(audit_log)
+        }
+        None => None,
+    };
 
-    Ok(())
-}
+    // Attach programs
+    monitor.attach()?;
 
-// ============================================================================
-// Prometheus HTTP Server
-// ============================================================================
+    info!("eBPF programs loaded and attached successfully");
 
-async fn run_metrics_server(addr: SocketAddr) -> Result<()> {
-    use hyper::service::{make_service_fn, service_fn};
-    use hyper::{Body, Request, Response, Server};
-
-    let make_svc = make_service_fn(|_conn| async {
-        Ok::<_, Infallible>(service_fn(|req: Request<Body>| async move {
-            match req.uri().path() {
-                "/metrics" => {
-                    let encoder = TextEncoder::new();
-                    let metric_families = prometheus::gather();
-                    let mut buffer = Vec::new();
-                    encoder.encode(&metric_families, &mut buffer).unwrap();
-                    Ok::<_, Infallible>(Response::new(Body::from(buffer)))
+    if let Some(dir) = monitor.pin_dir() {
+        info!("State maps pinned in {}", dir.display());
+    }
+
+    // Close events are only available to the loader, not pinned-map exporters
+    let mut close_events = monitor.close_events()?;
+    // Every tier from the start, so that rates over them are defined
+    for tier in metrics::DURATION_TIERS {
+        CONNECTIONS_BY_DURATION_TIER.with_label_values(&[tier]);
+    }
+    #[cfg(feature = "parquet")]
+    let parquet_events = match args.parquet {
+        Some(ref dir) => Some(spawn_parquet_sink(dir, &args)?),
+        None => None,
+    };
+    // run_exporter warns when the journal is missing
+    #[cfg(feature = "journald")]
+    let journald = args.journald && sidecar::journal::available();
+    let event_stream = args.enable_event_stream.then(EventStream::new);
+    let events = spawn_event_sinks(args.fifo.as_deref(), event_stream.clone())?;
+    let close_sink = events.clone();
+    tokio::spawn(async move {
+        while let Some(event) = close_events.recv().await {
+            log_close_event(&event);
+            observe_close_event(&event);
+            if let Some(ref tx) = close_sink {
+                let _ = tx.send(sidecar::fifo::close_event(&event));
+            }
+            #[cfg(feature = "journald")]
+            if journald {
+                if let Err(e) = sidecar::journal::send_close(&event) {
+                    debug!("Failed to write close event to the journal: {}", e);
                 }
-                "/health" => Ok(Response::new(Body::from("OK"))),
-                _ => Ok(Response::builder()
-                    .status(404)
-                    .body(Body::from("Not Found"))
-                    .unwrap()),
             }
-        }))
+            #[cfg(feature = "parquet")]
+            if let Some(ref tx) = parquet_events {
+                let _ = tx.send(event);
+            }
+        }
     });
 
-    Server::bind(&addr).serve(make_svc).await?;
-    Ok(())
+    let http_events = monitor.http_events()?;
+    let latency_window = Duration::from_secs(args.latency_window);
+    let slo_latency = Duration::from_millis(args.slo_latency_ms);
+    let refresh = Duration::from_secs(args.interval);
+    let http_sink = events.clone();
+    let path_hashes = PathHashes::new(args.path_hash_bits);
+    tokio::spawn(track_http_events(
+        http_events,
+        latency_window,
+        slo_latency,
+        refresh,
+        path_hashes,
+        http_sink,
+    ));
+
+    if !options.denylist.is_empty() {
+        let mut policy_events = monitor.policy_events()?;
+        let rules = options.denylist.clone();
+        tokio::spawn(async move {
+            while let Some(event) = policy_events.recv().await {
+                observe_policy_event(&event, &rules);
+                if let (Some(tx), Some(rule)) = (&events, rules.get(event.rule as usize)) {
+                    let _ = tx.send(sidecar::fifo::policy_event(&event, &rule.to_string()));
+                }
+            }
+        });
+    }
+
+    if let Some(ref path) = args.capture_payload {
+        let mut writer = CaptureWriter::open(path)?;
+        let mut payload_events = monitor.payload_events()?;
+        log::warn!("Writing captured payloads to {}", path.display());
+        tokio::spawn(async move {
+            while let Some(event) = payload_events.recv().await {
+                if let Err(e) = writer.write(&event) {
+                    error!("Failed to write payload capture: {}", e);
+                }
+            }
+        });
+    }
+
+    if args.interpacket_gaps {
+        let gaps = prometheus::register_histogram_vec!(
+            "sidecar_connection_interpacket_gap_seconds",
+            "Time between a sampled send or receive and the previous one on its connection",
+            &["dst_ip", "dst_port", "direction"],
+            config.metrics.interpacket_gap_buckets.clone()
+        )
+        .context("Invalid metrics.interpacket_gap_buckets")?;
+        let mut gap_events = monitor.gap_events()?;
+        info!(
+            "Sampling inter-packet gaps: 1 in {} sends/receives, at most {}/s",
+            args.gap_sample_every, args.gap_rate_limit
+        );
+        tokio::spawn(async move {
+            while let Some(event) = gap_events.recv().await {
+                observe_gap_event(&gaps, &event);
+            }
+        });
+    }
+
+    if args.tls_fingerprints {
+        let mut hellos = monitor.tls_hellos()?;
+        info!("Fingerprinting outgoing TLS ClientHellos");
+        tokio::spawn(async move {
+            let mut labels = FingerprintLabels::default();
+            while let Some(event) = hellos.recv().await {
+                observe_tls_hello(&mut labels, &event);
+            }
+        });
+    }
+
+    let stack_profile = match args.capture_stacks {
+        true => {
+            let (mut events, traces) = monitor.connect_stacks()?;
+            let profile = StackProfile::new();
+            let attributed = profile.clone();
+            info!(
+                "Capturing connect stacks: 1 in {} connects, at most {}/s",
+                args.stack_sample_every, args.stack_rate_limit
+            );
+            // Symbolizing reads /proc/kallsyms, /proc/<pid>/maps and whole
+            // binaries, so it runs on its own thread rather than a runtime worker
+            std::thread::Builder::new()
+                .name("stack-attributor".into())
+                .spawn(move || {
+                    let mut attributor = StackAttributor::new(traces, attributed);
+                    while let Some(event) = events.blocking_recv() {
+                        CONNECT_STACKS.inc();
+                        if !attributor.record(&event) {
+                            CONNECT_STACKS_LOST.inc();
+                        }
+                    }
+                })
+                .context("Failed to start the stack attributor thread")?;
+            Some(profile)
+        }
+        false => None,
+    };
+
+    let loader = LoaderState { options, audit_log };
+    run_exporter(
+        &mut monitor,
+        &args,
+        &config,
+        event_stream,
+        Some(loader),
+        stack_profile,
+    )
+    .await
+}
+
+/// Export `sidecar_build_info` for the eBPF object `source` runs, replacing
+/// the series of the previous one, and whether its retransmit counter is
+/// attached. The object hash is empty for an exporter, which doesn't load
+/// it, and it leaves `sidecar_retransmit_tracking_available` out.
+fn set_build_info(source: &impl ConnectionSource) {
+    BUILD_INFO.reset();
+    BUILD_INFO
+        .with_label_values(&[
+            env!("CARGO_PKG_VERSION"),
+            env!("SIDECAR_GIT_SHA"),
+            source.bpf_object_sha().unwrap_or(""),
+        ])
+        .set(1);
+    if let Some(tracked) = source.retransmit_tracking() {
+        RETRANSMIT_TRACKING.set(tracked as i64);
+    }
+}
+
+/// Log the sizing and population of every eBPF map at debug level.
+fn log_map_usage(maps: &MapDirectory) {
+    if !log::log_enabled!(log::Level::Debug) {
+        return;
+    }
+    for map in maps.usage() {
+        match (map.entries, map.fill_ratio()) {
+            (Some(entries), Some(ratio)) => debug!(
+                "Map {} ({}): {}/{} entries ({:.1}%)",
+                map.name,
+                map.map_type,
+                entries,
+                map.max_entries,
+                ratio * 100.0
+            ),
+            _ => debug!(
+                "Map {} ({}): {} max entries",
+                map.name, map.map_type, map.max_entries
+            ),
+        }
+    }
+}
+
+/// Serve Prometheus metrics (and `event_stream` on /ws/events) and refresh
+/// them from `source` until Ctrl+C; SIGUSR1 reloads its eBPF programs.
+/// `loader` is None when exporting from pinned maps, and so is
+/// `stack_profile`, served on /debug/stacks.
+async fn run_exporter(
+    source: &mut impl ConnectionSource,
+    args: &Args,
+    config: &Config,
+    event_stream: Option<EventStream>,
+    mut loader: Option<LoaderState>,
+    stack_profile: Option<StackProfile>,
+) -> Result<()> {
+    // Start Prometheus HTTP server
+    let metrics_addr: SocketAddr = ([0, 0, 0, 0], args.metrics_port).into();
+    let ui = args.ui;
+    let streaming = event_stream.is_some();
+    let view = ConnectionView::new();
+    let served_view = view.clone();
+    let (config_api, mut ports_requests) = match config_api(args)? {
+        Some((api, requests)) => (Some(api), Some(requests)),
+        None => (None, None),
+    };
+    let api_enabled = config_api.is_some();
+    let shards = args.metrics_shards;
+    let last_port = args.metrics_port as u32 + shards - 1;
+    if last_port > u16::MAX as u32 {
+        anyhow::bail!(
+            "--metrics-shards {} from port {} would need ports past 65535",
+            shards,
+            args.metrics_port
+        );
+    }
+    let cache_ttl = args.metrics_cache_ttl;
+    let first_shard = MetricsEndpoint::new(
+        Shard {
+            index: 0,
+            count: shards,
+        },
+        cache_ttl,
+    );
+    let maps = MapDirectory::new();
+    let debug = DebugEndpoints {
+        maps: args.debug.then(|| maps.clone()),
+        stacks: stack_profile,
+    };
+    tokio::spawn(async move {
+        let server = run_metrics_server(
+            metrics_addr,
+            ui,
+            event_stream,
+            served_view,
+            config_api,
+            first_shard,
+            debug,
+        );
+        if let Err(e) = server.await {
+            error!("Metrics server error: {}", e);
+        }
+    });
+    // The other shards only serve /metrics and /health
+    for index in 1..shards {
+        let addr: SocketAddr = ([0, 0, 0, 0], args.metrics_port + index as u16).into();
+        let shard = MetricsEndpoint::new(
+            Shard {
+                index,
+                count: shards,
+            },
+            cache_ttl,
+        );
+        let debug = DebugEndpoints::default();
+        let server = run_metrics_server(addr, false, None, view.clone(), None, shard, debug);
+        tokio::spawn(async move {
+            if let Err(e) = server.await {
+                error!("Metrics server error on shard {}: {}", index, e);
+            }
+        });
+    }
+    info!(
+        "Prometheus metrics available at http://0.0.0.0:{}/metrics",
+        args.metrics_port
+    );
+    if shards > 1 {
+        info!(
+            "Metrics sharded over ports {}-{}; scrape every one of them",
+            args.metrics_port, last_port
+        );
+    }
+    if ui {
+        info!(
+            "Dashboard available at http://0.0.0.0:{}/",
+            args.metrics_port
+        );
+    }
+    if streaming {
+        info!(
+            "Event stream available at ws://0.0.0.0:{}/ws/events",
+            args.metrics_port
+        );
+    }
+    if api_enabled {
+        info!(
+            "Config API available at http://0.0.0.0:{}/config/ports",
+            args.metrics_port
+        );
+    }
+    if args.debug {
+        info!(
+            "Map usage available at http://0.0.0.0:{}/debug/maps",
+            args.metrics_port
+        );
+    }
+
+    set_build_info(source);
+    maps.replace(source.map_ids());
+    log_map_usage(&maps);
+    let mut prev_failures = std::collections::HashMap::new();
+    let mut prev_interfaces = std::collections::HashMap::new();
+    let mut breaker = match breaker_thresholds(args) {
+        thresholds if thresholds.any() => {
+            CIRCUIT_BREAKER_TRIPPED.set(0);
+            info!(
+                "Circuit breaker armed: {:?}, sustained for {}",
+                thresholds,
+                humantime::format_duration(args.circuit_breaker_after)
+            );
+            let breaker = CircuitBreaker::new(thresholds, args.circuit_breaker_after);
+            Some((breaker, LoadSampler::new()?))
+        }
+        _ => None,
+    };
+    let udp_sizes = metrics::UdpSizeCollector::new()?;
+    prometheus::register(Box::new(udp_sizes.clone()))?;
+    let own_netns = sidecar::netns::netns_inode(std::process::id());
+    let mut conntrack = match args.conntrack {
+        true => Some(Conntrack::open()?),
+        false => None,
+    };
+    let mut health_endpoints = std::collections::HashSet::new();
+    if !(args.throughput_ewma_alpha > 0.0 && args.throughput_ewma_alpha <= 1.0) {
+        anyhow::bail!(
+            "--throughput-ewma-alpha must be in (0, 1], got {}",
+            args.throughput_ewma_alpha
+        );
+    }
+    let mut throughput = metrics::ThroughputEwma::new(args.throughput_ewma_alpha);
+    let mut throughput_endpoints = std::collections::HashSet::new();
+    let mut health_scores = metrics::HealthScores::new(config.metrics.health_score);
+    let mut scored_endpoints = std::collections::HashSet::new();
+    let mut mptcp = MptcpExport::default();
+
+    // Covers ports that overflowed the kernel filter; a no-op for the rest,
+    // which the kernel already filtered
+    let (mut export_ports, overflow_ports) = split_ports(
+        args.ports.as_deref().unwrap_or_default(),
+        args.priority_ports.as_deref().unwrap_or_default(),
+    );
+    export_ports.extend(overflow_ports);
+    let mut exes = metrics::ExeResolver::default();
+    let mut namespaces = NetnsResolver::new(args.resolve_netns);
+    let mut deltas = args.delta_mode.then(DeltaExport::default);
+    let mut disabled_metrics = args.disable_metrics.clone();
+    if !args.flow_label {
+        disabled_metrics.push(MetricGroup::FlowLabel);
+    }
+    let influx = spawn_influx_writer(args)?;
+
+    // Collect on the base interval; each backend flushes the latest
+    // collection on its own timer. A flush faster than collection would only
+    // republish the same data, so it is rejected.
+    let collect_secs = config.metrics.interval_secs;
+    let prometheus_flush = config.metrics.flush_secs.unwrap_or(collect_secs);
+    if prometheus_flush < collect_secs {
+        anyhow::bail!(
+            "Prometheus flush interval ({}s) is shorter than the collection interval ({}s)",
+            prometheus_flush,
+            collect_secs
+        );
+    }
+    let influx_flush = args.influx_flush_interval.unwrap_or(collect_secs);
+    if influx_flush < collect_secs {
+        anyhow::bail!(
+            "InfluxDB flush interval ({}s) is shorter than the collection interval ({}s)",
+            influx_flush,
+            collect_secs
+        );
+    }
+    let mut collect_interval = time::interval(Duration::from_secs(collect_secs));
+    let mut reload = signal::unix::signal(SignalKind::user_defined1())?;
+    let mut prometheus_interval = time::interval(Duration::from_secs(prometheus_flush));
+    let mut influx_interval = time::interval(Duration::from_secs(influx_flush));
+    // Influx points are per Influx flush in delta mode, whatever Prometheus does
+    let mut influx_deltas = args.delta_mode.then(metrics::CounterDeltas::default);
+    let mut latest: Option<metrics::Collection> = None;
+    // Highest kernel peak over the collections since the previous flush
+    let mut active_peak: Option<u64> = None;
+    let mut stalled_readers = std::collections::HashSet::new();
+    let mut source_changes = metrics::SourceChanges::new();
+
+    #[cfg(feature = "journald")]
+    let mut journal_opens = journald_open_tracker(args);
+
+    info!("Sidecar running. Press Ctrl+C to stop.");
+
+    let started = Instant::now();
+    let stop_after = async {
+        match args.duration {
+            Some(duration) => time::sleep(duration).await,
+            None => std::future::pending().await,
+        }
+    };
+    tokio::pin!(stop_after);
+    if let Some(duration) = args.duration {
+        info!("Stopping after {}", humantime::format_duration(duration));
+    }
+
+    loop {
+        tokio::select! {
+            _ = collect_interval.tick() => {
+                match metrics::Collection::read(source) {
+                    Ok(collection) => {
+                        let live = collection.connections.len() as u64;
+                        match source.take_active_peak(live) {
+                            Ok(peak) => active_peak = active_peak.max(peak),
+                            Err(e) => error!("Failed to read the active connection peak: {}", e),
+                        }
+                        #[cfg(feature = "journald")]
+                        if let Some(ref mut opens) = journal_opens {
+                            send_journal_opens(opens, &collection, &export_ports, &mut exes);
+                        }
+                        export_source_changes(&collection, &mut source_changes);
+                        latest = Some(collection);
+                    }
+                    Err(e) => error!("Failed to collect metrics: {}", e),
+                }
+                let readers = source.check_event_readers(args.restart_stalled_readers);
+                export_reader_health(&readers, &mut stalled_readers);
+                if let Some((ref mut breaker, ref mut sampler)) = breaker {
+                    check_circuit_breaker(source, breaker, sampler);
+                }
+            }
+            _ = prometheus_interval.tick() => {
+                if let Some(ref collection) = latest {
+                    let exported = export_connection_metrics(
+                        collection,
+                        &export_ports,
+                        args.group_by,
+                        &mut exes,
+                        &mut namespaces,
+                        &disabled_metrics,
+                        deltas.as_mut(),
+                    );
+                    if let Some(threshold) = args.keepalive_threshold {
+                        export_idle_beyond_keepalive(&exported, collection.read_ns, threshold);
+                    }
+                    if let Some(ref mut conntrack) = conntrack {
+                        export_nat_origins(&exported, conntrack, own_netns);
+                    }
+                    view.replace(exported);
+                    export_endpoint_health(
+                        collection,
+                        &export_ports,
+                        args.unhealthy_retransmit_ratio,
+                        &mut health_endpoints,
+                    );
+                    export_endpoint_throughput(
+                        collection,
+                        &export_ports,
+                        &mut throughput,
+                        &mut throughput_endpoints,
+                    );
+                    export_endpoint_health_score(
+                        collection,
+                        &export_ports,
+                        &mut health_scores,
+                        &mut scored_endpoints,
+                    );
+                    export_mptcp_subflows(collection, &export_ports, &mut mptcp);
+                    export_byte_profile(&collection.byte_profile);
+                    export_filter_decisions(&collection.filter_decisions);
+                    export_connect_failures(&collection.connect_failures, &mut prev_failures);
+                    export_interface_traffic(
+                        &collection.interfaces,
+                        own_netns,
+                        &mut namespaces,
+                        &mut prev_interfaces,
+                    );
+                    udp_sizes.set(collection.udp_sizes);
+                    // Registered only when counted here or by the loader
+                    if args.paws_drops || collection.paws_drops > 0 {
+                        export_paws_drops(collection.paws_drops);
+                    }
+                    export_arg_read_errors(collection.arg_read_errors);
+                }
+                if let Some(peak) = active_peak.take() {
+                    ACTIVE_CONNECTIONS_PEAK.set(peak as i64);
+                }
+            }
+            _ = influx_interval.tick(), if influx.is_some() => {
+                if let (Some(ref influx), Some(ref collection)) = (&influx, &latest) {
+                    let exported: Vec<_> = label_connections(
+                        select_connections(collection, &export_ports),
+                        collection,
+                        args.group_by,
+                        &mut exes,
+                        &mut namespaces,
+                        influx_deltas.as_mut(),
+                    )
+                    .into_iter()
+                    .map(|(labels, conn)| exported_connection(labels, &conn))
+                    .collect();
+                    send_influx_points(influx, &exported);
+                }
+            }
+            Some(request) = next_ports_request(&mut ports_requests) => {
+                let reply = change_ports(
+                    source,
+                    request.change,
+                    &mut export_ports,
+                    loader.as_mut(),
+                    args.clear_on_reconfigure,
+                );
+                let _ = request.reply.send(reply);
+            }
+            _ = reload.recv() => {
+                info!("Received SIGUSR1, reloading eBPF programs");
+                let old_sha = source.bpf_object_sha().unwrap_or_default().to_string();
+                match source.reload() {
+                    Ok(()) => {
+                        info!("eBPF programs reloaded");
+                        let audit_log = loader.as_mut().and_then(|l| l.audit_log.as_mut());
+                        if let Some(audit_log) = audit_log {
+                            let new_sha = source.bpf_object_sha().unwrap_or_default();
+                            if let Err(e) = audit_log.record_reload(&old_sha, new_sha) {
+                                error!("Failed to write the audit log: {}", e);
+                            }
+                        }
+                        set_build_info(source);
+                        maps.replace(source.map_ids());
+                        log_map_usage(&maps);
+                        // The reload attached every probe again
+                        if breaker.as_ref().is_some_and(|(breaker, _)| breaker.tripped()) {
+                            if let Err(e) = source.set_hot_paths_attached(false) {
+                                error!("Failed to detach the hot-path probes: {:#}", e);
+                            }
+                        }
+                    }
+                    Err(e) => error!("Reload failed, keeping the running programs: {:#}", e),
+                }
+            }
+            _ = signal::ctrl_c() => {
+                info!("Received shutdown signal");
+                break;
+            }
+            _ = &mut stop_after => {
+                info!("--duration elapsed, shutting down");
+                break;
+            }
+        }
+    }
+
+    let collection = metrics::Collection::read(source)?;
+    let closed = CLOSED_TOTALS.lock().unwrap().clone();
+    metrics::print_summary(&collection, &closed, started.elapsed());
+    info!("Sidecar stopped");
+    Ok(())
+}
+
+// ============================================================================
+// Metrics Collection and Export
+// ============================================================================
+
+/// `--circuit-breaker-*` thresholds; none set disables the breaker.
+fn breaker_thresholds(args: &Args) -> Thresholds {
+    Thresholds {
+        load_per_cpu: args.circuit_breaker_load,
+        own_cpu_percent: args.circuit_breaker_cpu,
+    }
+}
+
+/// Sample the load and detach or attach the hot-path probes when the
+/// breaker trips or resets.
+fn check_circuit_breaker(
+    source: &mut impl ConnectionSource,
+    breaker: &mut CircuitBreaker,
+    sampler: &mut LoadSampler,
+) {
+    let sample = match sampler.sample() {
+        Ok(sample) => sample,
+        Err(e) => {
+            log::warn!("Failed to sample the load for the circuit breaker: {:#}", e);
+            return;
+        }
+    };
+    match breaker.observe(sample, Instant::now()) {
+        Some(Transition::Trip) => {
+            error!(
+                "CRITICAL: circuit breaker tripped (load {:.2} per CPU, sidecar CPU {:.1}%): \
+                 detaching the tcp_sendmsg/tcp_recvmsg probes; byte, packet and L7 metrics \
+                 are paused",
+                sample.load_per_cpu, sample.own_cpu_percent
+            );
+            if let Err(e) = source.set_hot_paths_attached(false) {
+                error!("Failed to detach the hot-path probes: {:#}", e);
+            }
+        }
+        Some(Transition::Reset) => {
+            info!(
+                "Circuit breaker reset (load {:.2} per CPU, sidecar CPU {:.1}%): attaching the \
+                 tcp_sendmsg/tcp_recvmsg probes again",
+                sample.load_per_cpu, sample.own_cpu_percent
+            );
+            if let Err(e) = source.set_hot_paths_attached(true) {
+                error!("Failed to attach the hot-path probes again: {:#}", e);
+            }
+        }
+        None => {}
+    }
+    CIRCUIT_BREAKER_TRIPPED.set(breaker.tripped() as i64);
+}
+
+/// What the loader configured, kept to record changes made while running.
+struct LoaderState {
+    options: MonitorOptions,
+    audit_log: Option<AuditLog>,
+}
+
+/// `--delta-mode` state carried from one flush to the next.
+#[derive(Default)]
+pub(crate) struct DeltaExport {
+    deltas: metrics::CounterDeltas,
+    /// Label values (src_ip, dst_ip, dst_port, exe, cc_algo, netns) of the
+    /// delta series set by the previous flush
+    reported: std::collections::HashSet<[String; 6]>,
+}
+
+/// MPTCP series set by the previous flush, to drop those of connections
+/// and subflows that are gone.
+#[derive(Default)]
+struct MptcpExport {
+    /// (mptcp_token, src_ip, dst_ip, dst_port) of `sidecar_mptcp_subflows`
+    connections: std::collections::HashSet<[String; 4]>,
+    /// (mptcp_token, src_ip, src_port, dst_ip, dst_port) of
+    /// `sidecar_mptcp_subflow_retransmits`
+    subflows: std::collections::HashSet<[String; 5]>,
+}
+
+/// The tracked connections on one of `ports` (all when empty)
+fn select_connections(collection: &metrics::Collection, ports: &[u16]) -> Vec<Connection> {
+    collection
+        .connections
+        .iter()
+        .filter(|conn| ports.is_empty() || conn.matches_ports(ports))
+        .copied()
+        .collect()
+}
+
+/// Merge, group and relabel `selected` into the series they are exported
+/// as, with their counters since the previous call with `deltas`.
+fn label_connections(
+    mut selected: Vec<Connection>,
+    collection: &metrics::Collection,
+    group_by: GroupBy,
+    exes: &mut metrics::ExeResolver,
+    namespaces: &mut NetnsResolver,
+    deltas: Option<&mut metrics::CounterDeltas>,
+) -> Vec<([String; 6], Connection)> {
+    // Deltas are taken per connection, before grouping, so that connections
+    // joining or leaving an endpoint don't skew its sums
+    if let Some(deltas) = deltas {
+        selected = deltas.apply(selected);
+    }
+    let selected = metrics::group_by_mptcp(metrics::stitch_migrated(selected));
+    let connections = match group_by {
+        GroupBy::Connection => selected,
+        GroupBy::Endpoint => metrics::group_by_endpoint(selected),
+    };
+
+    let mut labelled = Vec::with_capacity(connections.len());
+    for conn in connections {
+        let src_ip = metrics::exported_ip(conn.src_ip()).to_string();
+        let dst_ip = metrics::exported_ip(conn.dst_ip()).to_string();
+        let dst_port = conn.key.dst_port.to_string();
+        let exe = exes.resolve(conn.metrics.pid, &collection.exe_paths);
+        let cc_algo = conn.cc_algo();
+        let netns = namespaces.resolve(conn.metrics.netns_inode);
+        let mut values = [src_ip, dst_ip, dst_port, exe, cc_algo, netns];
+        if relabel::apply(&mut values) {
+            labelled.push((values, conn));
+        }
+    }
+    if relabel::merges() {
+        labelled = metrics::group_by_labels(labelled);
+    }
+    labelled
+}
+
+fn exported_connection(labels: [String; 6], conn: &Connection) -> ExportedConnection {
+    ExportedConnection {
+        labels,
+        key: conn.key,
+        bytes: conn.metrics.bytes_sent + conn.metrics.bytes_recv,
+        metrics: conn.metrics,
+        duration_secs: conn.duration_secs(),
+    }
+}
+
+/// Export every tracked connection on one of `ports` (all when empty), or
+/// one merged series per endpoint with `GroupBy::Endpoint`. The subflows of
+/// an MPTCP connection, and the entries of a socket whose 4-tuple changed,
+/// are always merged into one connection first.
+///
+/// With `delta` the counter families are exported as their `*_delta`
+/// gauges instead, and the series of connections gone since the previous
+/// flush are removed.
+///
+/// Returns the exported connections, for filtered scrapes.
+fn export_connection_metrics(
+    collection: &metrics::Collection,
+    ports: &[u16],
+    group_by: GroupBy,
+    exes: &mut metrics::ExeResolver,
+    namespaces: &mut NetnsResolver,
+    disabled: &[MetricGroup],
+    mut delta: Option<&mut DeltaExport>,
+) -> Vec<ExportedConnection> {
+    let on = |group| !disabled.contains(&group);
+    let live = collection.connections.len() as u64;
+    let selected = select_connections(collection, ports);
+    // Counted once per socket, however many 4-tuples it was seen under
+    let sockets = metrics::stitch_migrated(selected.iter().copied());
+    let count = sockets.len() as i64;
+    let mut by_protocol = std::collections::HashMap::<&str, i64>::new();
+    for conn in &sockets {
+        for protocol in metrics::conn_protocols(conn.metrics.flags) {
+            *by_protocol.entry(protocol).or_default() += 1;
+        }
+    }
+    let labelled = label_connections(
+        selected,
+        collection,
+        group_by,
+        exes,
+        namespaces,
+        delta.as_mut().map(|delta| &mut delta.deltas),
+    );
+    let mut reported = std::collections::HashSet::new();
+    let mut exported = Vec::new();
+
+    for (values, conn) in labelled {
+        let metrics = conn.metrics;
+
+        // Update Prometheus metrics
+        let labels = values.each_ref().map(String::as_str);
+        exported.push(exported_connection(values.clone(), &conn));
+        if delta.is_some() {
+            export_counter_deltas(&labels, &metrics, &on);
+            reported.insert(labels.map(str::to_string));
+        } else {
+            if on(MetricGroup::Bytes) {
+                CONN_BYTES_SENT
+                    .with_label_values(&labels)
+                    .inc_by(metrics.bytes_sent as f64);
+
+                CONN_BYTES_RECV
+                    .with_label_values(&labels)
+                    .inc_by(metrics.bytes_recv as f64);
+            }
+
+            if on(MetricGroup::WireBytes) {
+                CONN_WIRE_BYTES_SENT
+                    .with_label_values(&labels)
+                    .inc_by(metrics.wire_bytes_sent as f64);
+            }
+
+            if on(MetricGroup::Packets) {
+                CONN_PACKETS_SENT
+                    .with_label_values(&labels)
+                    .inc_by(metrics.packets_sent as f64);
+
+                CONN_PACKETS_RECV
+                    .with_label_values(&labels)
+                    .inc_by(metrics.packets_recv as f64);
+            }
+
+            if on(MetricGroup::Retransmits) {
+                CONN_RETRANSMITS
+                    .with_label_values(&labels)
+                    .inc_by(metrics.retransmits as f64);
+            }
+
+            if on(MetricGroup::Sack) {
+                CONN_SACKS
+                    .with_label_values(&labels)
+                    .inc_by(metrics.sack_blocks as f64);
+
+                CONN_DSACKS
+                    .with_label_values(&labels)
+                    .inc_by(metrics.dsacks as f64);
+            }
+
+            if on(MetricGroup::Resets) {
+                CONN_RESETS
+                    .with_label_values(&with_direction(&labels, "sent"))
+                    .inc_by(metrics.rst_sent as f64);
+
+                CONN_RESETS
+                    .with_label_values(&with_direction(&labels, "received"))
+                    .inc_by(metrics.rst_recv as f64);
+            }
+        }
+
+        if on(MetricGroup::Duration) {
+            CONN_DURATION
+                .with_label_values(&labels)
+                .set(conn.duration_secs());
+        }
+
+        if on(MetricGroup::RttJitter) {
+            CONN_RTT_JITTER
+                .with_label_values(&labels)
+                .set(metrics::rtt_jitter_us(&metrics));
+        }
+
+        if on(MetricGroup::Mss) {
+            CONN_MSS.with_label_values(&labels).set(metrics.mss as f64);
+        }
+
+        if on(MetricGroup::Pmtu) {
+            CONN_PMTU
+                .with_label_values(&labels)
+                .set(metrics.pmtu as f64);
+        }
+
+        if on(MetricGroup::Idle) {
+            CONN_IDLE.observe(metrics::idle_secs(&conn, collection));
+        }
+
+        if on(MetricGroup::Timestamps) {
+            let enabled = metrics.flags & conn_flag::TCP_TIMESTAMPS != 0;
+            CONN_TIMESTAMPS
+                .with_label_values(&labels)
+                .set(if enabled { 1.0 } else { 0.0 });
+        }
+
+        if on(MetricGroup::Fd) && metrics.fd >= 0 {
+            let fd = metrics.fd.to_string();
+            let [src_ip, dst_ip, dst_port, exe, cc_algo, netns] = labels;
+            CONN_FD_INFO
+                .with_label_values(&[src_ip, dst_ip, dst_port, exe, cc_algo, netns, &fd])
+                .set(1.0);
+        }
+
+        if on(MetricGroup::Priority) && metrics.so_priority != 0 {
+            let priority = metrics.so_priority.to_string();
+            let [src_ip, dst_ip, dst_port, exe, cc_algo, netns] = labels;
+            CONN_PRIORITY_INFO
+                .with_label_values(&[src_ip, dst_ip, dst_port, exe, cc_algo, netns, &priority])
+                .set(1.0);
+        }
+
+        if on(MetricGroup::FlowLabel) && metrics.flow_label != 0 {
+            let flow_label = format!("0x{:05x}", metrics.flow_label);
+            let [src_ip, dst_ip, dst_port, exe, cc_algo, netns] = labels;
+            CONN_FLOW_LABEL_INFO
+                .with_label_values(&[src_ip, dst_ip, dst_port, exe, cc_algo, netns, &flow_label])
+                .set(1.0);
+        }
+
+        if let Some(version) = http_version::name(metrics.http_version) {
+            if on(MetricGroup::HttpVersion) {
+                let [src_ip, dst_ip, dst_port, exe, cc_algo, netns] = labels;
+                CONN_HTTP_VERSION_INFO
+                    .with_label_values(&[src_ip, dst_ip, dst_port, exe, cc_algo, netns, version])
+                    .set(1.0);
+            }
+        }
+    }
+
+    if let Some(delta) = delta {
+        for stale in delta.reported.difference(&reported) {
+            remove_counter_deltas(&stale.each_ref().map(String::as_str), &on);
+        }
+        delta.reported = reported;
+    }
+
+    ACTIVE_CONNECTIONS.set(count);
+    for (_, protocol) in conn_flag::NAMES {
+        let seen = by_protocol.get(protocol).copied().unwrap_or(0);
+        CONNECTIONS_BY_PROTOCOL
+            .with_label_values(&[protocol])
+            .set(seen);
+    }
+    exes.evict_unseen();
+    namespaces.next_collection();
+    debug!("Collected metrics for {} connections", count);
+
+    // Entries that were inserted but neither closed, cleared nor still live
+    // were evicted by the LRU. Concurrent inserts/closes make this
+    // approximate, so only ever move the counter forward.
+    let stats = collection.conn_stats;
+    let evicted = stats[conn_stat::INSERTED as usize]
+        .saturating_sub(stats[conn_stat::CLOSED as usize])
+        .saturating_sub(stats[conn_stat::CLEARED as usize])
+        .saturating_sub(live);
+    let reported = CONNECTIONS_EVICTED.get();
+    if evicted > reported {
+        CONNECTIONS_EVICTED.inc_by(evicted - reported);
+    }
+
+    // Cumulative in the kernel, so only the increase is added
+    let insert_failures = stats[conn_stat::INSERT_FAILED as usize];
+    let reported = CONNECTION_INSERT_FAILURES.get();
+    if insert_failures > reported {
+        CONNECTION_INSERT_FAILURES.inc_by(insert_failures - reported);
+    }
+
+    exported
+}
+
+/// Set the `*_delta` gauges of one connection (or endpoint) from its
+/// per-interval counters.
+fn export_counter_deltas(
+    labels: &[&str; 6],
+    metrics: &ConnMetrics,
+    on: &impl Fn(MetricGroup) -> bool,
+) {
+    if on(MetricGroup::Bytes) {
+        CONN_BYTES_SENT_DELTA
+            .with_label_values(labels)
+            .set(metrics.bytes_sent as f64);
+        CONN_BYTES_RECV_DELTA
+            .with_label_values(labels)
+            .set(metrics.bytes_recv as f64);
+    }
+    if on(MetricGroup::WireBytes) {
+        CONN_WIRE_BYTES_SENT_DELTA
+            .with_label_values(labels)
+            .set(metrics.wire_bytes_sent as f64);
+    }
+    if on(MetricGroup::Packets) {
+        CONN_PACKETS_SENT_DELTA
+            .with_label_values(labels)
+            .set(metrics.packets_sent as f64);
+        CONN_PACKETS_RECV_DELTA
+            .with_label_values(labels)
+            .set(metrics.packets_recv as f64);
+    }
+    if on(MetricGroup::Retransmits) {
+        CONN_RETRANSMITS_DELTA
+            .with_label_values(labels)
+            .set(metrics.retransmits as f64);
+    }
+    if on(MetricGroup::Sack) {
+        CONN_SACKS_DELTA
+            .with_label_values(labels)
+            .set(metrics.sack_blocks as f64);
+        CONN_DSACKS_DELTA
+            .with_label_values(labels)
+            .set(metrics.dsacks as f64);
+    }
+    if on(MetricGroup::Resets) {
+        for (direction, resets) in [("sent", metrics.rst_sent), ("received", metrics.rst_recv)] {
+            CONN_RESETS_DELTA
+                .with_label_values(&with_direction(labels, direction))
+                .set(resets as f64);
+        }
+    }
+}
+
+/// Drop the `*_delta` series of a connection (or endpoint) that is gone, so
+/// it doesn't keep reporting its last interval.
+fn remove_counter_deltas(labels: &[&str; 6], on: &impl Fn(MetricGroup) -> bool) {
+    if on(MetricGroup::Bytes) {
+        let _ = CONN_BYTES_SENT_DELTA.remove_label_values(labels);
+        let _ = CONN_BYTES_RECV_DELTA.remove_label_values(labels);
+    }
+    if on(MetricGroup::WireBytes) {
+        let _ = CONN_WIRE_BYTES_SENT_DELTA.remove_label_values(labels);
+    }
+    if on(MetricGroup::Packets) {
+        let _ = CONN_PACKETS_SENT_DELTA.remove_label_values(labels);
+        let _ = CONN_PACKETS_RECV_DELTA.remove_label_values(labels);
+    }
+    if on(MetricGroup::Retransmits) {
+        let _ = CONN_RETRANSMITS_DELTA.remove_label_values(labels);
+    }
+    if on(MetricGroup::Sack) {
+        let _ = CONN_SACKS_DELTA.remove_label_values(labels);
+        let _ = CONN_DSACKS_DELTA.remove_label_values(labels);
+    }
+    if on(MetricGroup::Resets) {
+        for direction in ["sent", "received"] {
+            let _ = CONN_RESETS_DELTA.remove_label_values(&with_direction(labels, direction));
+        }
+    }
+}
+
+fn with_direction<'a>(labels: &[&'a str; 6], direction: &'a str) -> [&'a str; 7] {
+    let [src_ip, dst_ip, dst_port, exe, cc_algo, netns] = *labels;
+    [src_ip, dst_ip, dst_port, exe, cc_algo, netns, direction]
+}
+
+/// Export the subflow count of each MPTCP connection on `ports` and the
+/// retransmits of each of its subflows, dropping the series of those that
+/// are gone.
+fn export_mptcp_subflows(
+    collection: &metrics::Collection,
+    ports: &[u16],
+    reported: &mut MptcpExport,
+) {
+    let subflows = metrics::mptcp_subflows(
+        collection
+            .connections
+            .iter()
+            .filter(|conn| ports.is_empty() || conn.matches_ports(ports)),
+    );
+
+    let mut connections = std::collections::HashSet::new();
+    let mut subflow_labels = std::collections::HashSet::new();
+    for (token, conns) in &subflows {
+        let token = format!("{:08x}", token);
+        // Labelled like the merged connection: its first subflow
+        let first = conns[0];
+        let labels = [
+            token.clone(),
+            metrics::exported_ip(first.src_ip()).to_string(),
+            metrics::exported_ip(first.dst_ip()).to_string(),
+            first.key.dst_port.to_string(),
+        ];
+        MPTCP_SUBFLOWS
+            .with_label_values(&labels.each_ref().map(String::as_str))
+            .set(conns.len() as f64);
+        connections.insert(labels);
+
+        for conn in conns {
+            let labels = [
+                token.clone(),
+                metrics::exported_ip(conn.src_ip()).to_string(),
+                conn.key.src_port.to_string(),
+                metrics::exported_ip(conn.dst_ip()).to_string(),
+                conn.key.dst_port.to_string(),
+            ];
+            MPTCP_SUBFLOW_RETRANSMITS
+                .with_label_values(&labels.each_ref().map(String::as_str))
+                .set(conn.metrics.retransmits as f64);
+            subflow_labels.insert(labels);
+        }
+    }
+
+    for stale in reported.connections.difference(&connections) {
+        let _ = MPTCP_SUBFLOWS.remove_label_values(&stale.each_ref().map(String::as_str));
+    }
+    for stale in reported.subflows.difference(&subflow_labels) {
+        let _ =
+            MPTCP_SUBFLOW_RETRANSMITS.remove_label_values(&stale.each_ref().map(String::as_str));
+    }
+    reported.connections = connections;
+    reported.subflows = subflow_labels;
+}
+
+/// Flag endpoints whose live connections on `ports` retransmit more than
+/// `retransmit_ratio` of the packets they send, and drop the series of
+/// endpoints with no live connections left.
+fn export_endpoint_health(
+    collection: &metrics::Collection,
+    ports: &[u16],
+    retransmit_ratio: f64,
+    reported: &mut std::collections::HashSet<(IpAddr, u16)>,
+) {
+    let endpoints = metrics::aggregate_by_destination(
+        collection
+            .connections
+            .iter()
+            .filter(|conn| ports.is_empty() || conn.matches_ports(ports))
+            .map(|conn| (conn.key, conn.metrics)),
+    );
+
+    for (dst_ip, dst_port) in reported.drain() {
+        if !endpoints.contains_key(&(dst_ip, dst_port)) {
+            let _ = CONN_UNHEALTHY.remove_label_values(&[
+                &dst_ip.to_string(),
+                &dst_port.to_string(),
+                metrics::UNHEALTHY_HIGH_RETRANSMITS,
+            ]);
+        }
+    }
+
+    for (&(dst_ip, dst_port), endpoint) in &endpoints {
+        let unhealthy = endpoint.has_high_retransmits(retransmit_ratio);
+        CONN_UNHEALTHY
+            .with_label_values(&[
+                &dst_ip.to_string(),
+                &dst_port.to_string(),
+                metrics::UNHEALTHY_HIGH_RETRANSMITS,
+            ])
+            .set(if unhealthy { 1.0 } else { 0.0 });
+        reported.insert((dst_ip, dst_port));
+    }
+}
+
+/// Publish the last hour of per-minute byte counts. The set of series is
+/// fixed, one per direction and minute, so nothing goes stale.
+fn export_byte_profile(profile: &metrics::ByteProfile) {
+    for (direction, minutes) in [("sent", &profile.sent), ("received", &profile.recv)] {
+        for (ago, &bytes) in minutes.iter().enumerate() {
+            BYTES_PER_MINUTE
+                .with_label_values(&[direction, &ago.to_string()])
+                .set(bytes as f64);
+        }
+    }
+}
+
+/// Count and log the connections whose socket was established from another
+/// source address (see `metrics::SourceChanges`).
+fn export_source_changes(
+    collection: &metrics::Collection,
+    source_changes: &mut metrics::SourceChanges,
+) {
+    for (established, conn) in source_changes.update(&collection.connections) {
+        CONN_ANOMALIES.with_label_values(&["src_change"]).inc();
+        log::warn!(
+            "Connection to {}:{} changed source address from {} to {}:{}",
+            metrics::exported_ip(conn.dst_ip()),
+            conn.key.dst_port,
+            metrics::exported_ip(established),
+            metrics::exported_ip(conn.src_ip()),
+            conn.key.src_port
+        );
+    }
+}
+
+/// Export the liveness of the perf buffer readers, logging each reader
+/// once when it stalls and once when it recovers. `stalled` carries the
+/// stalled readers over from the previous check.
+fn export_reader_health(
+    statuses: &[ReaderStatus],
+    stalled: &mut std::collections::HashSet<(&'static str, u32)>,
+) {
+    for status in statuses {
+        let reader = (status.events, status.cpu);
+        let cpu = status.cpu.to_string();
+        EVENT_READER_STALLED
+            .with_label_values(&[status.events, &cpu])
+            .set(status.stalled as i64);
+        if status.stalled && stalled.insert(reader) {
+            log::warn!(
+                "The {} event reader on CPU {} has not run for {}; its events are piling up \
+                 in the kernel buffer",
+                status.events,
+                status.cpu,
+                humantime::format_duration(Duration::from_secs(status.heartbeat_age.as_secs()))
+            );
+        } else if !status.stalled && stalled.remove(&reader) {
+            info!(
+                "The {} event reader on CPU {} is running again",
+                status.events, status.cpu
+            );
+        }
+        match status.restarted {
+            Some(Ok(())) => {
+                EVENT_READER_RESTARTS
+                    .with_label_values(&[status.events])
+                    .inc();
+                info!(
+                    "Restarted the {} event reader on CPU {}",
+                    status.events, status.cpu
+                );
+            }
+            Some(Err(ref e)) => error!(
+                "Failed to restart the {} event reader on CPU {}: {}",
+                status.events, status.cpu, e
+            ),
+            None => {}
+        }
+    }
+}
+
+/// Move `sidecar_paws_drops_total` up to the kernel's cumulative count.
+fn export_paws_drops(total: u64) {
+    let reported = PAWS_DROPS.get();
+    if total > reported {
+        PAWS_DROPS.inc_by(total - reported);
+    }
+}
+
+/// Set `sidecar_connection_idle_exceeds_keepalive` for each exported
+/// connection, as of the read at `read_ns`. Connections sharing their labels
+/// share the series, set if any of them is idle for longer than `threshold`.
+fn export_idle_beyond_keepalive(
+    exported: &[ExportedConnection],
+    read_ns: u64,
+    threshold: Duration,
+) {
+    let threshold_ns = threshold.as_nanos().min(u64::MAX as u128) as u64;
+    let mut idle = std::collections::HashMap::<&[String; 6], bool>::new();
+    for conn in exported {
+        let exceeds = read_ns.saturating_sub(conn.metrics.last_seen_ns) > threshold_ns;
+        *idle.entry(&conn.labels).or_default() |= exceeds;
+    }
+    for (labels, exceeds) in idle {
+        CONN_IDLE_EXCEEDS_KEEPALIVE
+            .with_label_values(&labels.each_ref().map(String::as_str))
+            .set(if exceeds { 1.0 } else { 0.0 });
+    }
+}
+
+/// Set `sidecar_connection_nat_info` for each exported connection accepted
+/// behind DNAT, and forget the lookups of connections no longer exported.
+/// Conntrack tables are per network namespace, so only connections in the
+/// sidecar's own, `own_netns`, are looked up; with `--group-by endpoint`
+/// the source port is gone and none are.
+fn export_nat_origins(
+    exported: &[ExportedConnection],
+    conntrack: &mut Conntrack,
+    own_netns: Option<u32>,
+) {
+    let mut lookup_failed = false;
+    for conn in exported {
+        if conn.key.src_port == 0 || own_netns != Some(conn.metrics.netns_inode) {
+            continue;
+        }
+        let origin = match conntrack.origin(&conn.key) {
+            Ok(Some(origin)) => origin,
+            Ok(None) => continue,
+            Err(e) => {
+                CONNTRACK_LOOKUP_ERRORS.inc();
+                if !lookup_failed {
+                    debug!("Conntrack lookup failed: {}", e);
+                    lookup_failed = true;
+                }
+                continue;
+            }
+        };
+        let original_dst_ip = metrics::exported_ip(origin.dst_ip).to_string();
+        let original_dst_port = origin.dst_port.to_string();
+        let [src_ip, dst_ip, dst_port, exe, cc_algo, netns] = conn.labels.each_ref();
+        CONN_NAT_INFO
+            .with_label_values(&[
+                src_ip,
+                dst_ip,
+                dst_port,
+                exe,
+                cc_algo,
+                netns,
+                &original_dst_ip,
+                &original_dst_port,
+            ])
+            .set(1.0);
+    }
+    let live: std::collections::HashSet<_> = exported.iter().map(|conn| conn.key).collect();
+    conntrack.retain(|key| live.contains(key));
+}
+
+/// Move `sidecar_arg_read_errors_total` up to the kernel's cumulative count.
+fn export_arg_read_errors(total: u64) {
+    let reported = ARG_READ_ERRORS.get();
+    if total > reported {
+        ARG_READ_ERRORS.inc_by(total - reported);
+    }
+}
+
+/// Move each filter outcome counter up to the kernel's cumulative count.
+fn export_filter_decisions(decisions: &sidecar::FilterDecisions) {
+    for (index, &total) in decisions.iter().enumerate() {
+        let counter =
+            FILTER_DECISIONS.with_label_values(&[metrics::filter_decision_outcome(index as u32)]);
+        let reported = counter.get();
+        if total > reported {
+            counter.inc_by(total - reported);
+        }
+    }
+}
+
+/// Update the smoothed per-endpoint throughput from `collection` and drop the
+/// series of endpoints that decayed away.
+fn export_endpoint_throughput(
+    collection: &metrics::Collection,
+    ports: &[u16],
+    throughput: &mut metrics::ThroughputEwma,
+    reported: &mut std::collections::HashSet<(IpAddr, u16)>,
+) {
+    let rates = throughput.update(collection, ports);
+
+    for (dst_ip, dst_port) in reported.drain() {
+        if !rates.contains_key(&(dst_ip, dst_port)) {
+            let _ = ENDPOINT_THROUGHPUT_EWMA
+                .remove_label_values(&[&dst_ip.to_string(), &dst_port.to_string()]);
+        }
+    }
+
+    for (&(dst_ip, dst_port), &rate) in rates {
+        ENDPOINT_THROUGHPUT_EWMA
+            .with_label_values(&[&dst_ip.to_string(), &dst_port.to_string()])
+            .set(rate);
+        reported.insert((dst_ip, dst_port));
+    }
+}
+
+/// Score the endpoints of `collection` and drop the series of endpoints that
+/// have neither live connections nor new connect failures.
+fn export_endpoint_health_score(
+    collection: &metrics::Collection,
+    ports: &[u16],
+    health_scores: &mut metrics::HealthScores,
+    reported: &mut std::collections::HashSet<(IpAddr, u16)>,
+) {
+    let scores = health_scores.update(collection, ports);
+
+    for (dst_ip, dst_port) in reported.drain() {
+        if !scores.contains_key(&(dst_ip, dst_port)) {
+            let _ = ENDPOINT_HEALTH_SCORE
+                .remove_label_values(&[&dst_ip.to_string(), &dst_port.to_string()]);
+        }
+    }
+
+    for (&(dst_ip, dst_port), &score) in scores {
+        ENDPOINT_HEALTH_SCORE
+            .with_label_values(&[&dst_ip.to_string(), &dst_port.to_string()])
+            .set(score);
+        reported.insert((dst_ip, dst_port));
+    }
+}
+
+/// Feed HTTP events into the per-protocol latency windows, SLO tracker and latency
+/// breakdown histograms, refreshing the percentile and per-endpoint SLO
+/// gauges every `refresh`. Path hashes are checked for collisions.
+async fn track_http_events(
+    mut events: mpsc::UnboundedReceiver<HttpEvent>,
+    window: Duration,
+    slo_latency: Duration,
+    refresh: Duration,
+    mut path_hashes: PathHashes,
+    sink: Option<mpsc::UnboundedSender<Value>>,
+) {
+    // One window per `protocol` label value, created on its first event
+    let mut latencies: BTreeMap<&'static str, metrics::LatencyWindow> = BTreeMap::new();
+    let mut slo = metrics::SloTracker::new(slo_latency);
+    let mut slo_endpoints = std::collections::HashSet::new();
+    let mut interval = time::interval(refresh);
+
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Some(event) => {
+                    latencies
+                        .entry(metrics::http_protocol(&event))
+                        .or_insert_with(|| metrics::LatencyWindow::new(window))
+                        .record(Instant::now(), event.latency_ns as f64 / 1e9);
+                    slo.record(&event);
+                    let breakdown = metrics::LatencyBreakdown::estimate(&event);
+                    HTTP_QUEUE_LATENCY.observe(breakdown.queue_secs);
+                    HTTP_NETWORK_LATENCY.observe(breakdown.network_secs);
+                    let path_hash = path_hashes.observe(event.path_hash, event.path_check);
+                    if let Some(PathHash { hash, collision: Some(first) }) = path_hash {
+                        PATH_HASH_COLLISIONS.inc();
+                        // Only paths differing in the full hash are kept
+                        // apart by more bits
+                        let hint = if first.0 != event.path_hash {
+                            " (raise --path-hash-bits)"
+                        } else {
+                            ""
+                        };
+                        log::warn!(
+                            "HTTP path hash collision: paths {:016x}/{:08x} and {:016x}/{:08x} \
+                             both export as {:x}; their requests can't be told apart{}",
+                            first.0,
+                            first.1,
+                            event.path_hash,
+                            event.path_check,
+                            hash,
+                            hint
+                        );
+                    }
+                    if let Some(ref tx) = sink {
+                        let hash = path_hash.map(|p| p.hash);
+                        let _ = tx.send(sidecar::fifo::http_event(&event, hash));
+                    }
+                }
+                None => return,
+            },
+            _ = interval.tick() => {
+                for (protocol, latencies) in latencies.iter_mut() {
+                    latencies.expire(Instant::now());
+                    let [p50, p95, p99] = match latencies.percentiles(&[0.50, 0.95, 0.99]) {
+                        Some(p) => [p[0], p[1], p[2]],
+                        None => [0.0; 3],
+                    };
+                    HTTP_LATENCY_P50.with_label_values(&[protocol]).set(p50);
+                    HTTP_LATENCY_P95.with_label_values(&[protocol]).set(p95);
+                    HTTP_LATENCY_P99.with_label_values(&[protocol]).set(p99);
+                }
+
+                let counts = slo.take();
+                export_slo_ratios(&counts, &mut slo_endpoints);
+            }
+        }
+    }
+}
+
+/// Set the SLO gauges for endpoints seen this interval and drop the series of
+/// endpoints that went quiet, so idle endpoints don't report stale ratios.
+fn export_slo_ratios(
+    counts: &std::collections::HashMap<(IpAddr, u16), metrics::SloCounts>,
+    reported: &mut std::collections::HashSet<(IpAddr, u16)>,
+) {
+    for (dst_ip, dst_port) in reported.drain() {
+        if !counts.contains_key(&(dst_ip, dst_port)) {
+            let (dst_ip, dst_port) = (dst_ip.to_string(), dst_port.to_string());
+            let _ = ENDPOINT_SUCCESS_RATIO.remove_label_values(&[&dst_ip, &dst_port]);
+            let _ = ENDPOINT_LATENCY_SLO_RATIO.remove_label_values(&[&dst_ip, &dst_port]);
+        }
+    }
+
+    for (&(dst_ip, dst_port), slo) in counts {
+        let dst_ip_label = dst_ip.to_string();
+        let dst_port_label = dst_port.to_string();
+        ENDPOINT_SUCCESS_RATIO
+            .with_label_values(&[&dst_ip_label, &dst_port_label])
+            .set(slo.success_ratio());
+        ENDPOINT_LATENCY_SLO_RATIO
+            .with_label_values(&[&dst_ip_label, &dst_port_label])
+            .set(slo.latency_ratio());
+        reported.insert((dst_ip, dst_port));
+    }
+}
+
+lazy_static::lazy_static! {
+    /// Lifetime totals of the connections closed since startup, for the
+    /// summary printed on exit.
+    static ref CLOSED_TOTALS: std::sync::Mutex<metrics::ClosedTotals> = Default::default();
+}
+
+/// Record a connection's lifetime totals. Called exactly once per connection.
+fn observe_close_event(event: &ConnCloseEvent) {
+    CLOSED_TOTALS.lock().unwrap().observe(event);
+
+    let dst_ip = metrics::exported_ip(event.conn.dst_addr()).to_string();
+    let dst_port = event.conn.dst_port.to_string();
+
+    CONN_TOTAL_BYTES
+        .with_label_values(&[&dst_ip, &dst_port])
+        .observe((event.bytes_sent + event.bytes_recv) as f64);
+
+    CONNECTIONS_BY_DURATION_TIER
+        .with_label_values(&[metrics::duration_tier(event.duration_ns)])
+        .inc();
+}
+
+fn observe_gap_event(gaps: &HistogramVec, event: &GapEvent) {
+    let dst_ip = metrics::exported_ip(event.conn.dst_addr()).to_string();
+    let dst_port = event.conn.dst_port.to_string();
+    let direction = if event.direction == direction::SENT {
+        "sent"
+    } else {
+        "received"
+    };
+
+    gaps.with_label_values(&[&dst_ip, &dst_port, direction])
+        .observe(event.gap_ns as f64 / 1e9);
+}
+
+fn observe_tls_hello(labels: &mut FingerprintLabels, event: &TlsHelloEvent) {
+    let data = &event.data[..(event.captured_len as usize).min(event.data.len())];
+    match ClientHello::parse(data) {
+        Some(hello) => {
+            let fingerprint = hello.ja4();
+            debug!(
+                "TLS ClientHello pid={} dst={} ja4={}",
+                event.pid,
+                SocketAddr::from((
+                    metrics::exported_ip(event.conn.dst_addr()),
+                    event.conn.dst_port
+                )),
+                fingerprint
+            );
+            TLS_FINGERPRINTS
+                .with_label_values(&[&labels.label(fingerprint)])
+                .inc();
+        }
+        None => TLS_HELLOS_UNPARSED.inc(),
+    }
+}
+
+/// Log a closed connection's final counters as `key=value` fields.
+fn log_close_event(event: &ConnCloseEvent) {
+    let addr = |ip: IpAddr, port: u16| SocketAddr::from((metrics::exported_ip(ip), port));
+    info!(
+        "Connection closed src={} dst={} bytes_sent={} bytes_recv={} packets_sent={} \
+         packets_recv={} retransmits={} duration_ms={}",
+        addr(event.conn.src_addr(), event.conn.src_port),
+        addr(event.conn.dst_addr(), event.conn.dst_port),
+        event.bytes_sent,
+        event.bytes_recv,
+        event.packets_sent,
+        event.packets_recv,
+        event.retransmits,
+        event.duration_ns / 1_000_000
+    );
+}
+
+/// The configuration this run uses: the `--config` file (or the defaults)
+/// with the settings given on the command line replaced by their CLI value.
+/// Settings left to their clap default are taken from the file instead, and
+/// written back to `args` so the run uses what is printed.
+/// `target.always_track` is the file's destinations followed by
+/// `--always-track`, validated and normalised.
+fn effective_config(args: &mut Args, matches: &ArgMatches) -> Result<Config> {
+    let mut config = match &args.config {
+        Some(path) => {
+            Config::load(path).with_context(|| format!("Failed to load config {}", path))?
+        }
+        None => Config::default_config(),
+    };
+    // Given on the command line (or by clap's env lookup), not defaulted
+    let passed = |id: &str| {
+        matches
+            .value_source(id)
+            .is_some_and(|source| source != ValueSource::DefaultValue)
+    };
+
+    let target = &mut config.target;
+    if passed("pid") {
+        target.pid = args.pid;
+    } else {
+        args.pid = target.pid;
+    }
+    if passed("pid_namespace") {
+        target.pid_namespace = args.pid_namespace.as_ref().map(ToString::to_string);
+    } else {
+        args.pid_namespace = target
+            .pid_namespace
+            .as_deref()
+            .map(str::parse)
+            .transpose()
+            .context("Invalid target.pid_namespace")?;
+    }
+    if passed("process_name") {
+        target.process_name = args.process_name.as_ref().map(ToString::to_string);
+    } else {
+        args.process_name = target
+            .process_name
+            .as_deref()
+            .map(sidecar::process::NamePattern::new);
+    }
+    if passed("cgroup") {
+        target.cgroup = args.cgroup.as_ref().map(|p| p.display().to_string());
+    } else {
+        args.cgroup = target.cgroup.as_ref().map(PathBuf::from);
+    }
+    if passed("ports") {
+        target.ports = args.ports.clone().unwrap_or_default();
+    } else {
+        args.ports = Some(target.ports.clone()).filter(|ports| !ports.is_empty());
+    }
+    if passed("priority_ports") {
+        target.priority_ports = args.priority_ports.clone().unwrap_or_default();
+    } else {
+        args.priority_ports = Some(target.priority_ports.clone()).filter(|ports| !ports.is_empty());
+    }
+    let mut always_track = Vec::new();
+    for rule in &target.always_track {
+        always_track.push(rule.parse::<PolicyRule>()?.to_string());
+    }
+    always_track.extend(
+        args.always_track
+            .iter()
+            .flatten()
+            .map(PolicyRule::to_string),
+    );
+    target.always_track = always_track;
+
+    let metrics = &mut config.metrics;
+    if passed("metrics_port") {
+        metrics.port = args.metrics_port;
+    } else {
+        args.metrics_port = metrics.port;
+    }
+    if passed("interval") {
+        metrics.interval_secs = args.interval;
+    } else {
+        args.interval = metrics.interval_secs;
+    }
+    if passed("prometheus_flush_interval") {
+        metrics.flush_secs = args.prometheus_flush_interval;
+    } else {
+        args.prometheus_flush_interval = metrics.flush_secs;
+    }
+    if passed("unhealthy_retransmit_ratio") {
+        metrics.unhealthy_retransmit_ratio = args.unhealthy_retransmit_ratio;
+    } else {
+        args.unhealthy_retransmit_ratio = metrics.unhealthy_retransmit_ratio;
+    }
+    if passed("throughput_ewma_alpha") {
+        metrics.throughput_ewma_alpha = args.throughput_ewma_alpha;
+    } else {
+        args.throughput_ewma_alpha = metrics.throughput_ewma_alpha;
+    }
+    if passed("delta_mode") {
+        metrics.delta_mode = args.delta_mode;
+    } else {
+        args.delta_mode = metrics.delta_mode;
+    }
+    if passed("l7_parsers") {
+        metrics.enable_http = args
+            .l7_parsers
+            .as_ref()
+            .is_some_and(|parsers| parsers.contains(&L7Protocol::Http));
+    } else if metrics.enable_http {
+        args.l7_parsers = Some(vec![L7Protocol::Http]);
+    }
+
+    // RUST_LOG wins over --debug, as in the logger setup
+    config.logging.level = std::env::var("RUST_LOG")
+        .unwrap_or_else(|_| if args.debug { "debug" } else { "info" }.to_string());
+    config.logging.ebpf_debug = args.debug;
+    config.sample_rates.validate()?;
+    config.metrics.health_score.validate()?;
+
+    Ok(config)
+}
+
+/// Count and log a connect attempt to a denylisted destination.
+fn observe_policy_event(event: &PolicyEvent, rules: &[PolicyRule]) {
+    let rule = match rules.get(event.rule as usize) {
+        Some(rule) => rule.to_string(),
+        None => return,
+    };
+    let dst_ip = metrics::exported_ip(policy::dst_ip(event)).to_string();
+    let dst_port = event.dst_port.to_string();
+
+    log::warn!(
+        "Policy violation: {} (pid {}) connecting to {}:{}, denied by {}",
+        policy::comm(event),
+        event.pid,
+        dst_ip,
+        dst_port,
+        rule
+    );
+    POLICY_VIOLATIONS
+        .with_label_values(&[&rule, &dst_ip, &dst_port])
+        .inc();
+}
+
+/// Rescan the monitored cgroup subtree every `refresh` so containers started
+/// after the sidecar are traced too.
+fn spawn_cgroup_refresh(mut targets: CgroupTargets, refresh: Duration) {
+    let mut interval = time::interval(refresh);
+    tokio::spawn(async move {
+        // The first tick fires immediately and configure() just seeded the set
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            match targets.refresh() {
+                Ok(count) => debug!(
+                    "Monitoring {} cgroups under {}",
+                    count,
+                    targets.dir().display()
+                ),
+                Err(e) => log::warn!(
+                    "Failed to refresh cgroups under {}: {}",
+                    targets.dir().display(),
+                    e
+                ),
+            }
+        }
+    });
+}
+
+/// Write the events sent to the returned channel to the FIFO at `fifo`,
+/// counting those dropped, and publish them on `stream`. None when there is
+/// neither.
+fn spawn_event_sinks(
+    fifo: Option<&std::path::Path>,
+    stream: Option<EventStream>,
+) -> Result<Option<mpsc::UnboundedSender<Value>>> {
+    let mut writer = match fifo {
+        Some(path) => {
+            let writer = sidecar::fifo::FifoWriter::create(path)?;
+            info!("Streaming events to FIFO {}", path.display());
+            Some(writer)
+        }
+        None if stream.is_none() => return Ok(None),
+        None => None,
+    };
+
+    let (tx, mut rx) = mpsc::unbounded_channel::<Value>();
+    tokio::spawn(async move {
+        while let Some(event) = rx.recv().await {
+            if let Some(ref mut writer) = writer {
+                match writer.write(&event) {
+                    Ok(()) => FIFO_EVENTS_WRITTEN.inc(),
+                    Err(dropped) => FIFO_EVENTS_DROPPED
+                        .with_label_values(&[dropped.reason()])
+                        .inc(),
+                }
+            }
+            if let Some(ref stream) = stream {
+                stream.publish(&event);
+            }
+        }
+    });
+
+    Ok(Some(tx))
+}
+
+/// Write the points sent to the returned channel to `--influx-url`. None
+/// without it.
+///
+/// The channel holds one flush: a flush arriving while the previous one is
+/// still being written is skipped and counted, so a slow or unreachable
+/// server never delays collection or piles up points.
+fn spawn_influx_writer(args: &Args) -> Result<Option<mpsc::Sender<Vec<String>>>> {
+    let (Some(url), Some(db)) = (&args.influx_url, &args.influx_db) else {
+        return Ok(None);
+    };
+    let writer = sidecar::influx::InfluxWriter::new(url, db)?;
+    info!("Writing connection metrics to {}", writer.uri());
+    // Registered up front, so that a clean run exports 0
+    INFLUX_ERRORS.reset();
+
+    let (tx, mut rx) = mpsc::channel::<Vec<String>>(1);
+    tokio::spawn(async move {
+        while let Some(lines) = rx.recv().await {
+            let failed = writer.write(&lines).await;
+            INFLUX_ERRORS.inc_by(failed as u64);
+        }
+    });
+
+    Ok(Some(tx))
+}
+
+/// Queue the connections of a flush for the InfluxDB writer, as points
+/// timestamped now.
+fn send_influx_points(influx: &mpsc::Sender<Vec<String>>, exported: &[ExportedConnection]) {
+    if exported.is_empty() {
+        return;
+    }
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64;
+    let lines = exported
+        .iter()
+        .map(|conn| sidecar::influx::connection_line(conn, now))
+        .collect();
+    if influx.try_send(lines).is_err() {
+        log::warn!("InfluxDB writer is still busy with the previous flush, skipping this one");
+        INFLUX_ERRORS.inc();
+    }
+}
+
+/// Archive close events to Parquet files in `dir`, flushing every interval.
+///
+/// The sink finishes its current file when the task is dropped at shutdown.
+#[cfg(feature = "parquet")]
+fn spawn_parquet_sink(
+    dir: &std::path::Path,
+    args: &Args,
+) -> Result<mpsc::UnboundedSender<ConnCloseEvent>> {
+    use sidecar::columnar::{ParquetSink, Rotation};
+
+    let mut sink = ParquetSink::new(
+        dir,
+        Rotation {
+            max_rows: args.parquet_rotate_rows,
+            max_age: Duration::from_secs(args.parquet_rotate_secs),
+        },
+    )?;
+    info!("Archiving closed connections to {}", dir.display());
+
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let mut interval = time::interval(Duration::from_secs(args.interval));
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                event = rx.recv() => match event {
+                    Some(event) => sink.record(&event),
+                    None => return,
+                },
+                _ = interval.tick() => {
+                    if let Err(e) = sink.flush() {
+                        error!("Failed to write parquet: {}", e);
+                    }
+                }
+            }
+        }
+    });
+
+    Ok(tx)
+}
+
+/// Tracker for `--journald` open events, or `None` (with a warning if the
+/// flag was given) when there is no journal to write to.
+#[cfg(feature = "journald")]
+fn journald_open_tracker(args: &Args) -> Option<sidecar::journal::OpenTracker> {
+    if !args.journald {
+        return None;
+    }
+    if !sidecar::journal::available() {
+        log::warn!("--journald: no systemd journal on this host, not sending events");
+        return None;
+    }
+    info!("Sending connection events to the systemd journal");
+    Some(Default::default())
+}
+
+/// Log connections on `ports` (all when empty) that are new since the
+/// previous collection.
+#[cfg(feature = "journald")]
+fn send_journal_opens(
+    opens: &mut sidecar::journal::OpenTracker,
+    collection: &metrics::Collection,
+    ports: &[u16],
+    exes: &mut metrics::ExeResolver,
+) {
+    for conn in opens.new_connections(&collection.connections) {
+        if !ports.is_empty() && !conn.matches_ports(ports) {
+            continue;
+        }
+        let exe = exes.resolve(conn.metrics.pid, &collection.exe_paths);
+        if let Err(e) = sidecar::journal::send_open(conn, &exe) {
+            debug!("Failed to write open event to the journal: {}", e);
+        }
+    }
+}
+
+/// Export connect failures. The kernel keeps cumulative counts, so only the
+/// increase since the previous scan is added to the Prometheus counter.
+fn export_connect_failures(
+    failures: &[(ConnFailKey, u64)],
+    prev: &mut std::collections::HashMap<ConnFailKey, u64>,
+) {
+    for &(key, count) in failures {
+        let last = prev.insert(key, count).unwrap_or(0);
+        if count <= last {
+            continue;
+        }
+
+        let dst_ip = metrics::exported_ip(Ipv4Addr::from(key.dst_ip.to_be())).to_string();
+        let dst_port = key.dst_port.to_string();
+        CONN_FAILURES
+            .with_label_values(&[
+                &dst_ip,
+                &dst_port,
+                metrics::connect_failure_reason(key.reason),
+            ])
+            .inc_by((count - last) as f64);
+    }
+}
+
+/// Add the bytes each interface moved since the previous flush. Names are
+/// looked up in our own namespace; interfaces of other namespaces are
+/// labelled `if<index>`.
+fn export_interface_traffic(
+    interfaces: &[(IfaceKey, IfaceTraffic)],
+    own_netns: Option<u32>,
+    namespaces: &mut NetnsResolver,
+    prev: &mut std::collections::HashMap<IfaceKey, IfaceTraffic>,
+) {
+    for &(key, traffic) in interfaces {
+        let last = prev.insert(key, traffic).unwrap_or_default();
+        let name = match own_netns {
+            Some(inode) if inode == key.netns_inode => metrics::interface_name(key.ifindex),
+            _ => format!("if{}", key.ifindex),
+        };
+        let netns = namespaces.resolve(key.netns_inode);
+        for (direction, now, last) in [
+            ("rx", traffic.rx_bytes, last.rx_bytes),
+            ("tx", traffic.tx_bytes, last.tx_bytes),
+        ] {
+            // An evicted entry starts over from zero: count it all again
+            let added = now.checked_sub(last).unwrap_or(now);
+            if added > 0 {
+                INTERFACE_BYTES
+                    .with_label_values(&[&name, &netns, direction])
+                    .inc_by(added as f64);
+            }
+        }
+    }
+}
+
+// ============================================================================
+// Prometheus HTTP Server
+// ============================================================================
+
+/// Self-contained dashboard page served at `/` with `--ui`
+const DASHBOARD_HTML: &str = include_str!("dashboard.html");
+
+/// Endpoints under /debug/ and the state they read; None = not served.
+#[derive(Clone, Default)]
+struct DebugEndpoints {
+    /// /debug/maps, with --debug
+    maps: Option<MapDirectory>,
+    /// /debug/stacks, with --capture-stacks
+    stacks: Option<StackProfile>,
+}
+
+async fn run_metrics_server(
+    addr: SocketAddr,
+    ui: bool,
+    event_stream: Option<EventStream>,
+    view: ConnectionView,
+    config_api: Option<ConfigApi>,
+    endpoint: MetricsEndpoint,
+    debug: DebugEndpoints,
+) -> Result<()> {
+    use hyper::server::conn::AddrStream;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Request, Server};
+
+    let make_svc = make_service_fn(move |conn: &AddrStream| {
+        let peer = conn.remote_addr();
+        let event_stream = event_stream.clone();
+        let view = view.clone();
+        let config_api = config_api.clone();
+        let debug = debug.clone();
+        let endpoint = endpoint.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                let event_stream = event_stream.clone();
+                let view = view.clone();
+                let config_api = config_api.clone();
+                let debug = debug.clone();
+                let endpoint = endpoint.clone();
+                async move {
+                    // Served outside `route`, only when enabled
+                    let response = match (req.uri().path(), debug) {
+                        (
+                            "/debug/maps",
+                            DebugEndpoints {
+                                maps: Some(maps), ..
+                            },
+                        ) => map_usage_json(&maps),
+                        (
+                            "/debug/stacks",
+                            DebugEndpoints {
+                                stacks: Some(stacks),
+                                ..
+                            },
+                        ) => hyper::Response::builder()
+                            .header("Content-Type", "text/plain; charset=utf-8")
+                            .body(Body::from(stacks.folded()))
+                            .unwrap(),
+                        _ => route(req, ui, event_stream, &view, config_api, &endpoint, peer).await,
+                    };
+                    Ok::<_, Infallible>(response)
+                }
+            }))
+        }
+    });
+
+    Server::bind(&addr).serve(make_svc).await?;
+    Ok(())
+}
+
+/// `/debug/maps`: every eBPF map's type, capacity and, for hash-type maps,
+/// entries, read when asked.
+fn map_usage_json(maps: &MapDirectory) -> hyper::Response<hyper::Body> {
+    let maps = maps.usage();
+    hyper::Response::builder()
+        .header("Content-Type", "application/json")
+        .body(hyper::Body::from(json!({ "maps": maps }).to_string()))
+        .unwrap()
+}
+
+/// A change to the monitored ports, requested through the config API.
+#[derive(Debug)]
+enum PortsChange {
+    Add(Vec<u16>),
+    /// Remove a port; `all_ports` allows removing the last one, which
+    /// monitors all ports.
+    Remove {
+        port: u16,
+        all_ports: bool,
+    },
+}
+
+/// Outcome of a `PortsChange`: the monitored ports, or the status and
+/// message to answer with.
+type PortsReply = std::result::Result<Vec<u16>, (hyper::StatusCode, String)>;
+
+/// A `PortsChange` for the exporter loop to apply.
+struct PortsRequest {
+    change: PortsChange,
+    reply: oneshot::Sender<PortsReply>,
+}
+
+/// `--allow-config-api` endpoints, as seen by the metrics server.
+#[derive(Clone)]
+struct ConfigApi {
+    token: Arc<str>,
+    requests: mpsc::Sender<PortsRequest>,
+}
+
+impl ConfigApi {
+    /// Whether `req` carries the bearer token. Compared in constant time.
+    fn authorized(&self, req: &hyper::Request<hyper::Body>) -> bool {
+        let given = req
+            .headers()
+            .get(hyper::header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        match given {
+            Some(given) if given.len() == self.token.len() => {
+                given
+                    .bytes()
+                    .zip(self.token.bytes())
+                    .fold(0, |diff, (a, b)| diff | (a ^ b))
+                    == 0
+            }
+            _ => false,
+        }
+    }
+}
+
+/// The config API and the receiving end of its requests, with
+/// `--allow-config-api`.
+fn config_api(args: &Args) -> Result<Option<(ConfigApi, mpsc::Receiver<PortsRequest>)>> {
+    let Some(path) = args
+        .config_api_token_file
+        .as_ref()
+        .filter(|_| args.allow_config_api)
+    else {
+        return Ok(None);
+    };
+    let token = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    let token = token.trim();
+    if token.is_empty() {
+        anyhow::bail!("{} holds no token", path.display());
+    }
+    let (requests, rx) = mpsc::channel(16);
+    Ok(Some((
+        ConfigApi {
+            token: token.into(),
+            requests,
+        },
+        rx,
+    )))
+}
+
+/// Next request for the exporter loop; never resolves without the API.
+async fn next_ports_request(
+    requests: &mut Option<mpsc::Receiver<PortsRequest>>,
+) -> Option<PortsRequest> {
+    match requests {
+        Some(requests) => requests.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Apply `change` to the monitored ports, `export_ports` (empty = all), in
+/// the kernel filter and at export, and record it in the audit log. With
+/// `clear_stale`, connections on none of the new ports stop being tracked.
+fn change_ports(
+    source: &mut impl ConnectionSource,
+    change: PortsChange,
+    export_ports: &mut Vec<u16>,
+    loader: Option<&mut LoaderState>,
+    clear_stale: bool,
+) -> PortsReply {
+    use hyper::StatusCode;
+
+    let mut ports = export_ports.clone();
+    match change {
+        PortsChange::Add(added) => {
+            if added.contains(&0) {
+                return Err((StatusCode::BAD_REQUEST, "Port 0 can't be monitored".into()));
+            }
+            for port in added {
+                if !ports.contains(&port) {
+                    ports.push(port);
+                }
+            }
+        }
+        PortsChange::Remove { port, all_ports } => {
+            if !ports.contains(&port) {
+                return Err((
+                    StatusCode::NOT_FOUND,
+                    format!("Port {} is not monitored", port),
+                ));
+            }
+            ports.retain(|&p| p != port);
+            if ports.is_empty() && !all_ports {
+                let message = format!(
+                    "Port {} is the last monitored port; removing it monitors all ports, \
+                     which needs ?all_ports=true",
+                    port
+                );
+                return Err((StatusCode::CONFLICT, message));
+            }
+        }
+    }
+    if ports.len() > MAX_TARGET_PORTS {
+        let message = format!("At most {} ports can be monitored", MAX_TARGET_PORTS);
+        return Err((StatusCode::UNPROCESSABLE_ENTITY, message));
+    }
+    if ports == *export_ports {
+        return Ok(ports);
+    }
+
+    if let Err(e) = source.set_ports(&ports) {
+        error!("Failed to change the monitored ports: {:#}", e);
+        return Err((StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", e)));
+    }
+    info!(
+        "Monitored ports changed through the config API: {:?}",
+        ports
+    );
+    if clear_stale && !ports.is_empty() {
+        clear_unmonitored_connections(source, &ports);
+    }
+    if let Some(loader) = loader {
+        let old = loader.options.clone();
+        loader.options.ports = ports.clone();
+        loader
+            .options
+            .priority_ports
+            .retain(|port| ports.contains(port));
+        if let Some(ref mut audit_log) = loader.audit_log {
+            if let Err(e) = audit_log.record(AuditTrigger::Http, Some(&old), &loader.options) {
+                error!("Failed to write the audit log: {}", e);
+            }
+        }
+    }
+    *export_ports = ports.clone();
+    Ok(ports)
+}
+
+/// Stop tracking the connections on none of `ports` (`--clear-on-reconfigure`).
+/// The port change stands if this fails; the connections are then kept until
+/// they close, as without the option.
+fn clear_unmonitored_connections(source: &mut impl ConnectionSource, ports: &[u16]) {
+    let stale: Vec<ConnKey> = match source.snapshot() {
+        Ok(connections) => connections
+            .iter()
+            .filter(|conn| !conn.matches_ports(ports))
+            .map(|conn| conn.key)
+            .collect(),
+        Err(e) => {
+            error!("Failed to read the connections to clear: {:#}", e);
+            return;
+        }
+    };
+    match source.clear_connections(&stale) {
+        Ok(cleared) => info!("Cleared {} connections on no monitored port", cleared),
+        Err(e) => error!(
+            "Failed to clear the connections on no monitored port: {:#}",
+            e
+        ),
+    }
+}
+
+/// Answer `POST /config/ports` (body `{"ports": [8080]}`) and
+/// `DELETE /config/ports/<port>` with the monitored ports after the change.
+/// Removing the last port needs `?all_ports=true`.
+async fn config_ports(
+    req: hyper::Request<hyper::Body>,
+    api: &ConfigApi,
+) -> hyper::Response<hyper::Body> {
+    use hyper::{Body, Method, Response, StatusCode};
+
+    let respond = |status: StatusCode, body: Value| {
+        Response::builder()
+            .status(status)
+            .header("Content-Type", "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    };
+    if !api.authorized(&req) {
+        let mut response = respond(StatusCode::UNAUTHORIZED, json!({"error": "Unauthorized"}));
+        response
+            .headers_mut()
+            .insert(hyper::header::WWW_AUTHENTICATE, "Bearer".parse().unwrap());
+        return response;
+    }
+
+    #[derive(serde::Deserialize)]
+    struct AddPorts {
+        ports: Vec<u16>,
+    }
+
+    let path = req.uri().path().to_string();
+    let change = match (req.method().clone(), path.strip_prefix("/config/ports")) {
+        (Method::POST, Some("")) => {
+            let body = hyper::body::to_bytes(req.into_body())
+                .await
+                .unwrap_or_default();
+            match serde_json::from_slice::<AddPorts>(&body) {
+                Ok(add) => PortsChange::Add(add.ports),
+                Err(e) => return respond(StatusCode::BAD_REQUEST, json!({"error": e.to_string()})),
+            }
+        }
+        (Method::DELETE, Some(port)) => {
+            let all_ports = match req.uri().query() {
+                None | Some("all_ports=false") => false,
+                Some("all_ports=true") => true,
+                Some(query) => {
+                    let error = format!("Invalid query {:?}, expected all_ports=true", query);
+                    return respond(StatusCode::BAD_REQUEST, json!({"error": error}));
+                }
+            };
+            match port.strip_prefix('/').map(str::parse::<u16>) {
+                Some(Ok(port)) => PortsChange::Remove { port, all_ports },
+                _ => {
+                    let error = format!("Invalid port in {}", path);
+                    return respond(StatusCode::BAD_REQUEST, json!({"error": error}));
+                }
+            }
+        }
+        (_, Some(_)) => {
+            let error = "Use POST /config/ports or DELETE /config/ports/<port>";
+            return respond(StatusCode::METHOD_NOT_ALLOWED, json!({"error": error}));
+        }
+        (_, None) => return respond(StatusCode::NOT_FOUND, json!({"error": "Not Found"})),
+    };
+
+    let (reply, response) = oneshot::channel();
+    if api
+        .requests
+        .send(PortsRequest { change, reply })
+        .await
+        .is_err()
+    {
+        return respond(
+            StatusCode::SERVICE_UNAVAILABLE,
+            json!({"error": "Shutting down"}),
+        );
+    }
+    match response.await {
+        Ok(Ok(ports)) => respond(StatusCode::OK, json!({"ports": ports})),
+        Ok(Err((status, error))) => respond(status, json!({"error": error})),
+        Err(_) => respond(
+            StatusCode::SERVICE_UNAVAILABLE,
+            json!({"error": "Shutting down"}),
+        ),
+    }
+}
+
+/// Response to a request from `peer` to the metrics server.
+async fn route(
+    req: hyper::Request<hyper::Body>,
+    ui: bool,
+    event_stream: Option<EventStream>,
+    view: &ConnectionView,
+    config_api: Option<ConfigApi>,
+    endpoint: &MetricsEndpoint,
+    peer: SocketAddr,
+) -> hyper::Response<hyper::Body> {
+    use hyper::{Body, Response};
+
+    let path = req.uri().path();
+    if let Some(ref api) = config_api {
+        if path == "/config/ports" || path.starts_with("/config/ports/") {
+            return config_ports(req, api).await;
+        }
+    }
+
+    match (path, event_stream) {
+        ("/", _) if ui => Response::builder()
+            .header("Content-Type", "text/html; charset=utf-8")
+            .body(Body::from(DASHBOARD_HTML))
+            .unwrap(),
+        ("/metrics.json", _) if ui => Response::builder()
+            .header("Content-Type", "application/json")
+            .body(Body::from(metrics_json().to_string()))
+            .unwrap(),
+        ("/metrics", _) => {
+            let filter = match req.uri().query().map(ScrapeFilter::parse) {
+                Some(Ok(filter)) => Some(filter),
+                Some(Err(e)) => {
+                    return Response::builder().status(400).body(Body::from(e)).unwrap()
+                }
+                None => None,
+            };
+            let shard = endpoint.shard;
+            let encode = || {
+                let mut metric_families = prometheus::gather();
+                if shard != Shard::WHOLE {
+                    metric_families = scrape::shard_families(metric_families, shard);
+                }
+                if let Some(ref filter) = filter {
+                    let keep = view.select(filter);
+                    metric_families = scrape::filter_families(metric_families, &keep);
+                }
+                let mut buffer = Vec::new();
+                TextEncoder::new()
+                    .encode(&metric_families, &mut buffer)
+                    .unwrap();
+                buffer
+            };
+            // Filtered scrapes differ per query, so only the whole shard is cached
+            match filter {
+                Some(_) => Response::new(Body::from(encode())),
+                None => Response::new(Body::from(endpoint.encoded(encode))),
+            }
+        }
+        ("/health", _) => Response::new(Body::from("OK")),
+        ("/ws/events", Some(event_stream)) => upgrade_event_stream(req, event_stream, peer),
+        _ => Response::builder()
+            .status(404)
+            .body(Body::from("Not Found"))
+            .unwrap(),
+    }
+}
+
+/// Answer a WebSocket upgrade request on /ws/events and forward the events
+/// of `event_stream` to the client at `peer` until it goes away.
+fn upgrade_event_stream(
+    req: hyper::Request<hyper::Body>,
+    event_stream: EventStream,
+    peer: SocketAddr,
+) -> hyper::Response<hyper::Body> {
+    use hyper::header::{CONNECTION, SEC_WEBSOCKET_ACCEPT, UPGRADE};
+    use tokio_tungstenite::tungstenite::protocol::Role;
+
+    let Some(accept) = sidecar::event_stream::accept_key(req.headers()) else {
+        return hyper::Response::builder()
+            .status(400)
+            .body(hyper::Body::from("Expected a WebSocket upgrade"))
+            .unwrap();
+    };
+
+    // Subscribe now so no event is missed while the upgrade completes
+    let events = event_stream.subscribe();
+    tokio::spawn(async move {
+        match hyper::upgrade::on(req).await {
+            Ok(upgraded) => {
+                let socket = tokio_tungstenite::WebSocketStream::from_raw_socket(
+                    upgraded,
+                    Role::Server,
+                    None,
+                )
+                .await;
+                forward_events(socket, events, peer).await;
+            }
+            Err(e) => debug!("WebSocket upgrade from {} failed: {}", peer, e),
+        }
+    });
+
+    hyper::Response::builder()
+        .status(101)
+        .header(UPGRADE, "websocket")
+        .header(CONNECTION, "Upgrade")
+        .header(SEC_WEBSOCKET_ACCEPT, accept)
+        .body(hyper::Body::empty())
+        .unwrap()
+}
+
+/// Send each event to the WebSocket client at `peer` as a text message,
+/// counting the events dropped for it while it lagged behind.
+async fn forward_events(
+    mut socket: tokio_tungstenite::WebSocketStream<hyper::upgrade::Upgraded>,
+    mut events: Subscription,
+    peer: SocketAddr,
+) {
+    use futures_util::{SinkExt, StreamExt};
+    use tokio_tungstenite::tungstenite::Message;
+
+    let client = peer.to_string();
+    let dropped = EVENT_STREAM_DROPPED.with_label_values(&[&client]);
+    EVENT_STREAM_CLIENTS.inc();
+    info!("Event stream client {} connected", client);
+
+    loop {
+        tokio::select! {
+            event = events.recv() => {
+                let Some(event) = event else { break };
+                dropped.inc_by(events.take_dropped());
+                if socket.send(Message::Text(event.to_string())).await.is_err() {
+                    break;
+                }
+            }
+            // Pings are answered while reading; other client messages are
+            // ignored
+            message = socket.next() => match message {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+
+    dropped.inc_by(events.take_dropped());
+    info!(
+        "Event stream client {} disconnected ({} events dropped)",
+        client,
+        dropped.get()
+    );
+    let _ = EVENT_STREAM_DROPPED.remove_label_values(&[&client]);
+    EVENT_STREAM_CLIENTS.dec();
+}
+
+/// Per-connection series from the Prometheus registry, one JSON object per
+/// connection with a field per metric (e.g. `bytes_sent_total`).
+fn metrics_json() -> serde_json::Value {
+    use prometheus::proto::MetricType;
+
+    let mut connections: BTreeMap<(String, String, String), serde_json::Map<String, Value>> =
+        BTreeMap::new();
+
+    for family in prometheus::gather() {
+        let field = match family.get_name().strip_prefix("sidecar_connection_") {
+            Some(field) => field,
+            None => continue,
+        };
+
+        for metric in family.get_metric() {
+            let label = |name: &str| {
+                metric
+                    .get_label()
+                    .iter()
+                    .find(|l| l.get_name() == name)
+                    .map(|l| l.get_value().to_string())
+            };
+            // Per-destination series (failures, lifetime histogram) have no src_ip
+            let key = match (label("src_ip"), label("dst_ip"), label("dst_port")) {
+                (Some(src_ip), Some(dst_ip), Some(dst_port)) => (src_ip, dst_ip, dst_port),
+                _ => continue,
+            };
+            let value = match family.get_field_type() {
+                MetricType::COUNTER => metric.get_counter().get_value(),
+                MetricType::GAUGE => metric.get_gauge().get_value(),
+                _ => continue,
+            };
+
+            connections
+                .entry(key)
+                .or_default()
+                .insert(field.to_string(), json!(value));
+        }
+    }
+
+    let connections: Vec<Value> = connections
+        .into_iter()
+        .map(|((src_ip, dst_ip, dst_port), mut fields)| {
+            fields.insert("src_ip".to_string(), json!(src_ip));
+            fields.insert("dst_ip".to_string(), json!(dst_ip));
+            fields.insert(
+                "dst_port".to_string(),
+                json!(dst_port.parse::<u16>().unwrap_or(0)),
+            );
+            Value::Object(fields)
+        })
+        .collect();
+
+    json!({
+        "active_connections": ACTIVE_CONNECTIONS.get(),
+        "connections": connections,
+    })
 }
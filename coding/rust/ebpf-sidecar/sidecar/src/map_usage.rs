@@ -0,0 +1,192 @@
+//! Sizing and population of the eBPF maps, for `/debug/maps` and the
+//! startup log.
+//!
+//! Maps are looked up by their kernel id, recorded when the object is
+//! loaded: `attach` takes some of them out of the `Bpf` handle, so
+//! `Bpf::maps` no longer lists every one. Hash-type maps are walked key by
+//! key to count their entries; arrays always hold `max_entries` and other
+//! types can't be walked, so they have no count.
+
+use anyhow::{Context, Result};
+use aya::maps::MapInfo;
+use serde::Serialize;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd};
+use std::sync::{Arc, Mutex};
+
+/// Kernel `bpf_map_type` values of the types the sidecar uses.
+const BPF_MAP_TYPE_HASH: u32 = 1;
+const BPF_MAP_TYPE_ARRAY: u32 = 2;
+const BPF_MAP_TYPE_PROG_ARRAY: u32 = 3;
+const BPF_MAP_TYPE_PERF_EVENT_ARRAY: u32 = 4;
+const BPF_MAP_TYPE_PERCPU_HASH: u32 = 5;
+const BPF_MAP_TYPE_PERCPU_ARRAY: u32 = 6;
+const BPF_MAP_TYPE_STACK_TRACE: u32 = 7;
+const BPF_MAP_TYPE_LRU_HASH: u32 = 9;
+const BPF_MAP_TYPE_LRU_PERCPU_HASH: u32 = 10;
+const BPF_MAP_TYPE_LPM_TRIE: u32 = 11;
+
+/// `BPF_MAP_GET_NEXT_KEY` command of the `bpf` system call
+const BPF_MAP_GET_NEXT_KEY: libc::c_int = 4;
+
+/// One eBPF map's sizing, as the kernel reports it.
+#[derive(Debug, Clone, Serialize)]
+pub struct MapUsage {
+    /// Name in the eBPF object
+    pub name: String,
+    /// Map type, e.g. `lru_hash`; see [`type_name`]
+    pub map_type: &'static str,
+    /// Capacity the map was created with
+    pub max_entries: u32,
+    /// Key size in bytes
+    pub key_size: u32,
+    /// Value size in bytes, per CPU for per-CPU maps
+    pub value_size: u32,
+    /// Entries now in the map; None for types that can't be walked
+    pub entries: Option<u32>,
+}
+
+impl MapUsage {
+    /// Read the sizing of map `id`, and its population if it is a hash-type
+    /// map.
+    pub fn read(name: &str, id: u32) -> Result<Self> {
+        let info = MapInfo::from_id(id).with_context(|| format!("Failed to open map {}", name))?;
+        let entries = match info.map_type() {
+            BPF_MAP_TYPE_HASH
+            | BPF_MAP_TYPE_PERCPU_HASH
+            | BPF_MAP_TYPE_LRU_HASH
+            | BPF_MAP_TYPE_LRU_PERCPU_HASH
+            | BPF_MAP_TYPE_LPM_TRIE => {
+                let fd = info
+                    .fd()
+                    .with_context(|| format!("Failed to open map {}", name))?;
+                let count = count_keys(fd.as_fd(), info.key_size(), info.max_entries())
+                    .with_context(|| format!("Failed to walk map {}", name))?;
+                Some(count)
+            }
+            _ => None,
+        };
+        Ok(Self {
+            name: name.to_string(),
+            map_type: type_name(info.map_type()),
+            max_entries: info.max_entries(),
+            key_size: info.key_size(),
+            value_size: info.value_size(),
+            entries,
+        })
+    }
+
+    /// Share of `max_entries` in use, if the entries are counted.
+    pub fn fill_ratio(&self) -> Option<f64> {
+        let entries = self.entries?;
+        (self.max_entries > 0).then(|| entries as f64 / self.max_entries as f64)
+    }
+}
+
+/// The ids of the eBPF object's maps, shared between the exporter, which
+/// replaces them on every (re)load, and the metrics server. Cloning shares
+/// the directory.
+#[derive(Clone, Default)]
+pub struct MapDirectory {
+    maps: Arc<Mutex<Vec<(String, u32)>>>,
+}
+
+impl MapDirectory {
+    /// An empty directory, until the first load.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replace the maps with those of a new load.
+    pub fn replace(&self, maps: Vec<(String, u32)>) {
+        *self.maps.lock().unwrap() = maps;
+    }
+
+    /// Read every map's usage, by name. A map that can't be read (closed by
+    /// a reload in between) is logged and left out.
+    pub fn usage(&self) -> Vec<MapUsage> {
+        let maps = self.maps.lock().unwrap().clone();
+        maps.iter()
+            .filter_map(|(name, id)| match MapUsage::read(name, *id) {
+                Ok(usage) => Some(usage),
+                Err(e) => {
+                    log::debug!("{:#}", e);
+                    None
+                }
+            })
+            .collect()
+    }
+}
+
+/// Name of a kernel `bpf_map_type`, `other` for types the sidecar doesn't
+/// use.
+///
+/// ```
+/// use sidecar::map_usage::type_name;
+///
+/// assert_eq!(type_name(9), "lru_hash");
+/// assert_eq!(type_name(6), "percpu_array");
+/// assert_eq!(type_name(27), "other");
+/// ```
+pub fn type_name(map_type: u32) -> &'static str {
+    match map_type {
+        BPF_MAP_TYPE_HASH => "hash",
+        BPF_MAP_TYPE_ARRAY => "array",
+        BPF_MAP_TYPE_PROG_ARRAY => "prog_array",
+        BPF_MAP_TYPE_PERF_EVENT_ARRAY => "perf_event_array",
+        BPF_MAP_TYPE_PERCPU_HASH => "percpu_hash",
+        BPF_MAP_TYPE_PERCPU_ARRAY => "percpu_array",
+        BPF_MAP_TYPE_STACK_TRACE => "stack_trace",
+        BPF_MAP_TYPE_LRU_HASH => "lru_hash",
+        BPF_MAP_TYPE_LRU_PERCPU_HASH => "lru_percpu_hash",
+        BPF_MAP_TYPE_LPM_TRIE => "lpm_trie",
+        _ => "other",
+    }
+}
+
+/// `bpf_attr` of `BPF_MAP_GET_NEXT_KEY`
+#[repr(C)]
+struct NextKeyAttr {
+    map_fd: u32,
+    _padding: u32,
+    key: u64,
+    next_key: u64,
+}
+
+/// Count the keys of a hash-type map with `BPF_MAP_GET_NEXT_KEY`, at most
+/// `limit` of them.
+///
+/// The walk isn't atomic: entries inserted or deleted meanwhile may or may
+/// not be counted, and a deleted current key restarts the walk at the first
+/// one, hence the limit.
+fn count_keys(fd: BorrowedFd<'_>, key_size: u32, limit: u32) -> std::io::Result<u32> {
+    let mut key = vec![0u8; key_size as usize];
+    let mut next_key = vec![0u8; key_size as usize];
+    let mut count = 0;
+    while count < limit {
+        let attr = NextKeyAttr {
+            map_fd: fd.as_raw_fd() as u32,
+            _padding: 0,
+            // No key yet asks for the first one
+            key: if count == 0 { 0 } else { key.as_ptr() as u64 },
+            next_key: next_key.as_mut_ptr() as u64,
+        };
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_bpf,
+                BPF_MAP_GET_NEXT_KEY,
+                &attr as *const NextKeyAttr,
+                std::mem::size_of::<NextKeyAttr>(),
+            )
+        };
+        if ret < 0 {
+            let error = std::io::Error::last_os_error();
+            if error.raw_os_error() == Some(libc::ENOENT) {
+                break;
+            }
+            return Err(error);
+        }
+        std::mem::swap(&mut key, &mut next_key);
+        count += 1;
+    }
+    Ok(count)
+}
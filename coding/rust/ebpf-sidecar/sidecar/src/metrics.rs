@@ -1,8 +1,223 @@
 //! Metrics collection and aggregation utilities.
 
-use sidecar_common::{ConnKey, ConnMetrics};
-use std::collections::HashMap;
-use std::net::Ipv4Addr;
+use crate::config::HealthScoreConfig;
+use crate::{ConnStats, Connection, ConnectionSource, FilterDecisions};
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::{self, MetricFamily, MetricType};
+use sidecar_common::{
+    conn_flag, connect_failure, direction, filter_decision, ByteHistory, ConnCloseEvent,
+    ConnFailKey, ConnKey, ConnMetrics, EndpointKey, HttpEvent, IfaceKey, IfaceTraffic,
+    UdpSizeHistogram, HISTORY_BUCKET_NS, HISTORY_MINUTES, UDP_SIZE_BUCKETS,
+};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Bucket boundaries (bytes) for the per-connection lifetime bytes histogram:
+/// log-scale, x4 per step from 1 KiB to 1 GiB.
+pub const CONN_TOTAL_BYTES_BUCKETS: [f64; 11] = [
+    1024.0,       // 1 KiB
+    4096.0,       // 4 KiB
+    16384.0,      // 16 KiB
+    65536.0,      // 64 KiB
+    262144.0,     // 256 KiB
+    1048576.0,    // 1 MiB
+    4194304.0,    // 4 MiB
+    16777216.0,   // 16 MiB
+    67108864.0,   // 64 MiB
+    268435456.0,  // 256 MiB
+    1073741824.0, // 1 GiB
+];
+
+/// Upper bounds (seconds, exclusive) of the connection duration tiers but
+/// the last, which is open-ended.
+pub const DURATION_TIER_BOUNDS_SECS: [f64; 3] = [1.0, 10.0, 60.0];
+
+/// `tier` label values of the duration tiers, shortest first.
+pub const DURATION_TIERS: [&str; 4] = ["under_1s", "1s_to_10s", "10s_to_1m", "over_1m"];
+
+/// Duration tier of a connection that lasted `duration_ns`.
+///
+/// ```
+/// use sidecar::metrics::duration_tier;
+///
+/// const SEC: u64 = 1_000_000_000;
+/// assert_eq!(duration_tier(0), "under_1s");
+/// assert_eq!(duration_tier(SEC - 1), "under_1s");
+/// assert_eq!(duration_tier(SEC), "1s_to_10s");
+/// assert_eq!(duration_tier(10 * SEC - 1), "1s_to_10s");
+/// assert_eq!(duration_tier(10 * SEC), "10s_to_1m");
+/// assert_eq!(duration_tier(60 * SEC - 1), "10s_to_1m");
+/// assert_eq!(duration_tier(60 * SEC), "over_1m");
+/// assert_eq!(duration_tier(u64::MAX), "over_1m");
+/// ```
+pub fn duration_tier(duration_ns: u64) -> &'static str {
+    let secs = duration_ns as f64 / 1e9;
+    let tier = DURATION_TIER_BOUNDS_SECS
+        .iter()
+        .position(|&bound| secs < bound)
+        .unwrap_or(DURATION_TIER_BOUNDS_SECS.len());
+    DURATION_TIERS[tier]
+}
+
+/// Whether exported addresses are masked; see `set_anonymize_ips`.
+static ANONYMIZE_IPS: AtomicBool = AtomicBool::new(false);
+
+/// Mask the host part of every address this module hands out for export
+/// (`exported_ip`, endpoint aggregation, SLO tracking). Set once at startup,
+/// before any collection.
+pub fn set_anonymize_ips(enabled: bool) {
+    ANONYMIZE_IPS.store(enabled, Ordering::Relaxed);
+}
+
+/// Zero the low bits of an address: the last octet of IPv4 (keeping the
+/// /24) and the last 80 bits of IPv6 (keeping the /48).
+///
+/// Deterministic, so one network always maps to the same value and series
+/// still group by it; irreversible, since the masked bits are discarded.
+///
+/// ```
+/// use sidecar::metrics::mask_ip;
+/// use std::net::IpAddr;
+///
+/// let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+/// assert_eq!(mask_ip(ip("10.1.2.3")), ip("10.1.2.0"));
+/// // Every host of the /24 maps to the same value
+/// assert_eq!(mask_ip(ip("10.1.2.254")), mask_ip(ip("10.1.2.3")));
+/// assert_ne!(mask_ip(ip("10.1.3.3")), mask_ip(ip("10.1.2.3")));
+/// // IPv6 keeps the /48
+/// assert_eq!(mask_ip(ip("2001:db8:abcd:12::1")), ip("2001:db8:abcd::"));
+/// ```
+pub fn mask_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(v4) => IpAddr::V4(Ipv4Addr::from(u32::from(v4) & !0xff)),
+        IpAddr::V6(v6) => IpAddr::V6(Ipv6Addr::from(u128::from(v6) & !((1u128 << 80) - 1))),
+    }
+}
+
+/// An address as it should appear in exported labels: masked with
+/// `mask_ip` when anonymization is on, unchanged otherwise.
+///
+/// ```
+/// use sidecar::metrics::{exported_ip, set_anonymize_ips};
+/// use std::net::Ipv4Addr;
+///
+/// let host = Ipv4Addr::new(192, 168, 7, 42);
+/// assert_eq!(exported_ip(host).to_string(), "192.168.7.42");
+/// set_anonymize_ips(true);
+/// assert_eq!(exported_ip(host).to_string(), "192.168.7.0");
+/// assert_eq!(exported_ip(host), exported_ip(host));
+/// ```
+pub fn exported_ip(ip: impl Into<IpAddr>) -> IpAddr {
+    let ip = ip.into();
+    if !ANONYMIZE_IPS.load(Ordering::Relaxed) {
+        return ip;
+    }
+    mask_ip(ip)
+}
+
+/// Name of the interface with index `ifindex` in this process's network
+/// namespace, or `if<ifindex>` if there is none (it was removed).
+pub fn interface_name(ifindex: u32) -> String {
+    let mut buf = [0 as libc::c_char; libc::IF_NAMESIZE];
+    let name = unsafe { libc::if_indextoname(ifindex, buf.as_mut_ptr()) };
+    if name.is_null() {
+        return format!("if{}", ifindex);
+    }
+    unsafe { std::ffi::CStr::from_ptr(name) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Bucket boundaries (seconds) for the connection idle-time histogram, from
+/// 1s to 1h.
+pub const CONN_IDLE_SECONDS_BUCKETS: [f64; 10] = [
+    1.0, 5.0, 15.0, 30.0, 60.0, 120.0, 300.0, 600.0, 1800.0, 3600.0,
+];
+
+/// Default bucket boundaries (seconds) for the inter-packet gap histogram;
+/// defined with the config default so the config parser builds on its own.
+pub use crate::config::INTERPACKET_GAP_SECONDS_BUCKETS;
+
+/// One read of the kernel maps, shared by every export backend.
+///
+/// Collection runs on the base interval; each backend publishes the latest
+/// collection on its own cadence. The kernel counters are cumulative, so a
+/// backend flushing less often than collection happens loses nothing.
+#[derive(Debug, Clone, Default)]
+pub struct Collection {
+    pub connections: Vec<Connection>,
+    pub exe_paths: HashMap<u32, String>,
+    pub conn_stats: ConnStats,
+    pub filter_decisions: FilterDecisions,
+    pub connect_failures: Vec<(ConnFailKey, u64)>,
+    pub byte_profile: ByteProfile,
+    pub interfaces: Vec<(IfaceKey, IfaceTraffic)>,
+    /// UDP datagram sizes, indexed by `direction`
+    pub udp_sizes: [UdpSizeHistogram; 2],
+    /// TCP segments dropped by the PAWS check, host-wide
+    pub paws_drops: u64,
+    /// kprobe arguments the probes couldn't read
+    pub arg_read_errors: u64,
+    /// Kernel-clock time of the read (see `time::monotonic_ns`)
+    pub read_ns: u64,
+}
+
+impl Collection {
+    pub fn read(source: &impl ConnectionSource) -> anyhow::Result<Self> {
+        let read_ns = crate::time::monotonic_ns();
+        Ok(Self {
+            connections: source.snapshot()?,
+            exe_paths: source.exe_paths()?,
+            conn_stats: source.conn_stats()?,
+            filter_decisions: source.filter_decisions()?,
+            connect_failures: source.connect_failures()?,
+            byte_profile: ByteProfile::from_rings(&source.byte_history()?, read_ns),
+            interfaces: source.interface_traffic()?,
+            udp_sizes: source.udp_datagram_sizes()?,
+            paws_drops: source.paws_drops()?,
+            arg_read_errors: source.arg_read_errors()?,
+            read_ns,
+        })
+    }
+}
+
+/// Bytes sent and received per minute over the last hour, across all CPUs.
+///
+/// Index 0 is the current, still filling minute, index 1 the one before, and
+/// so on. Each CPU's ring bucket only counts if it is stamped with the minute
+/// expected at its position; anything else was last written an hour or more
+/// ago (the ring wrapped without traffic on that CPU), so it reads as 0.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ByteProfile {
+    pub sent: Vec<u64>,
+    pub recv: Vec<u64>,
+}
+
+impl ByteProfile {
+    /// Combine per-CPU rings as of kernel-clock time `now_ns`.
+    pub fn from_rings(rings: &[ByteHistory], now_ns: u64) -> Self {
+        let now = now_ns / HISTORY_BUCKET_NS;
+        let mut profile = Self {
+            sent: vec![0; HISTORY_MINUTES],
+            recv: vec![0; HISTORY_MINUTES],
+        };
+
+        // Shortly after boot there are fewer minutes than buckets
+        for ago in 0..HISTORY_MINUTES.min(now as usize + 1) {
+            let minute = now - ago as u64;
+            let slot = (minute % HISTORY_MINUTES as u64) as usize;
+            for ring in rings.iter().filter(|ring| ring.minute[slot] == minute) {
+                profile.sent[ago] += ring.bytes_sent[slot];
+                profile.recv[ago] += ring.bytes_recv[slot];
+            }
+        }
+
+        profile
+    }
+}
 
 /// Aggregated metrics for a destination endpoint.
 #[derive(Debug, Default, Clone)]
@@ -14,17 +229,450 @@ pub struct EndpointMetrics {
     pub total_retransmits: u64,
     pub connection_count: u64,
     pub avg_duration_ms: f64,
+    pub rtt_samples: u64,
+    pub rtt_sum_us: u64,
+}
+
+impl EndpointMetrics {
+    /// Retransmissions per packet sent (0 when nothing was sent).
+    pub fn retransmit_ratio(&self) -> f64 {
+        if self.total_packets_sent == 0 {
+            return 0.0;
+        }
+        self.total_retransmits as f64 / self.total_packets_sent as f64
+    }
+
+    /// Mean smoothed RTT over every sample of every connection, in
+    /// milliseconds (0 before the first sample).
+    pub fn avg_rtt_ms(&self) -> f64 {
+        if self.rtt_samples == 0 {
+            return 0.0;
+        }
+        self.rtt_sum_us as f64 / self.rtt_samples as f64 / 1000.0
+    }
+
+    /// Whether the retransmit ratio is above `threshold`. Endpoints that sent
+    /// fewer than `UNHEALTHY_MIN_PACKETS_SENT` packets are never flagged, so a
+    /// single early retransmit on a fresh connection doesn't raise an alert.
+    ///
+    /// ```
+    /// use sidecar::metrics::{EndpointMetrics, UNHEALTHY_MIN_PACKETS_SENT};
+    ///
+    /// let endpoint = |sent, retransmits| EndpointMetrics {
+    ///     total_packets_sent: sent,
+    ///     total_retransmits: retransmits,
+    ///     ..Default::default()
+    /// };
+    /// // Below the minimum: never flagged, whatever the ratio
+    /// assert!(!endpoint(UNHEALTHY_MIN_PACKETS_SENT - 1, 50).has_high_retransmits(0.05));
+    /// // Exactly at the minimum: judged, and 6% is above 5%
+    /// assert!(endpoint(UNHEALTHY_MIN_PACKETS_SENT, 6).has_high_retransmits(0.05));
+    /// // A ratio equal to the threshold is not above it
+    /// assert!(!endpoint(UNHEALTHY_MIN_PACKETS_SENT, 5).has_high_retransmits(0.05));
+    /// // Above the minimum
+    /// assert!(endpoint(1000, 60).has_high_retransmits(0.05));
+    /// assert!(!endpoint(1000, 40).has_high_retransmits(0.05));
+    /// ```
+    pub fn has_high_retransmits(&self, threshold: f64) -> bool {
+        self.total_packets_sent >= UNHEALTHY_MIN_PACKETS_SENT && self.retransmit_ratio() > threshold
+    }
+}
+
+/// Packets an endpoint must have sent before its retransmit ratio is judged.
+pub const UNHEALTHY_MIN_PACKETS_SENT: u64 = 100;
+
+/// `reason` label of `sidecar_connection_unhealthy` for a retransmit ratio
+/// over the threshold.
+pub const UNHEALTHY_HIGH_RETRANSMITS: &str = "high_retransmits";
+
+/// Endpoints whose smoothed throughput decays below this (bytes/second) are
+/// forgotten by `ThroughputEwma`.
+pub const THROUGHPUT_EWMA_FLOOR: f64 = 1.0;
+
+/// One EWMA step: move `average` towards `sample` by `alpha` (0 < alpha <= 1;
+/// 1 follows the samples exactly, smaller values smooth more).
+///
+/// ```
+/// use sidecar::metrics::ewma;
+///
+/// assert_eq!(ewma(100.0, 200.0, 0.5), 150.0);
+/// assert_eq!(ewma(100.0, 200.0, 0.25), 125.0);
+/// assert_eq!(ewma(100.0, 200.0, 1.0), 200.0);
+/// assert_eq!(ewma(100.0, 0.0, 0.5), 50.0);
+/// ```
+pub fn ewma(average: f64, sample: f64, alpha: f64) -> f64 {
+    average + alpha * (sample - average)
+}
+
+/// Per-connection counters turned into per-interval deltas, for
+/// `--delta-mode`.
+///
+/// Each `apply` replaces the bytes, wire bytes, packets, retransmits and
+/// resets of the given connections with how much they grew since the
+/// previous call. A connection first seen counts in full, as does a new
+/// connection reusing a known key (different start time). Connections
+/// missing from a call are forgotten, so whatever they moved between the
+/// last call and their close is not seen.
+#[derive(Debug, Default)]
+pub struct CounterDeltas {
+    prev: HashMap<ConnKey, ConnMetrics>,
+}
+
+impl CounterDeltas {
+    /// Replace the counters of `connections` with their deltas since the
+    /// previous call and remember the current totals for the next one.
+    pub fn apply(&mut self, connections: Vec<Connection>) -> Vec<Connection> {
+        let mut current = HashMap::with_capacity(connections.len());
+        let deltas = connections
+            .into_iter()
+            .map(|mut conn| {
+                let totals = conn.metrics;
+                if let Some(prev) = self.prev.get(&conn.key) {
+                    if prev.start_ns == totals.start_ns {
+                        let m = &mut conn.metrics;
+                        m.bytes_sent = totals.bytes_sent.saturating_sub(prev.bytes_sent);
+                        m.bytes_recv = totals.bytes_recv.saturating_sub(prev.bytes_recv);
+                        m.wire_bytes_sent =
+                            totals.wire_bytes_sent.saturating_sub(prev.wire_bytes_sent);
+                        m.packets_sent = totals.packets_sent.saturating_sub(prev.packets_sent);
+                        m.packets_recv = totals.packets_recv.saturating_sub(prev.packets_recv);
+                        m.retransmits = totals.retransmits.saturating_sub(prev.retransmits);
+                        m.rst_sent = totals.rst_sent.saturating_sub(prev.rst_sent);
+                        m.rst_recv = totals.rst_recv.saturating_sub(prev.rst_recv);
+                        m.sack_blocks = totals.sack_blocks.saturating_sub(prev.sack_blocks);
+                        m.dsacks = totals.dsacks.saturating_sub(prev.dsacks);
+                    }
+                }
+                current.insert(conn.key, totals);
+                conn
+            })
+            .collect();
+        self.prev = current;
+        deltas
+    }
+}
+
+/// Exponentially weighted moving average of bytes/second (sent + received)
+/// per destination endpoint, updated once per collection.
+///
+/// Each update's sample is the bytes moved since the previous update by the
+/// connections in the collection, over the time between the two reads.
+/// Connections first seen count in full, except in the first update, which
+/// only sets the baseline. Bytes a connection moved between the last read
+/// and its close are not seen.
+///
+/// An endpoint with no traffic in an update gets a sample of 0, so its
+/// average decays; once it is below `THROUGHPUT_EWMA_FLOOR` it is dropped.
+#[derive(Debug)]
+pub struct ThroughputEwma {
+    alpha: f64,
+    /// Bytes per connection as of the previous update
+    prev_bytes: HashMap<ConnKey, u64>,
+    /// `Collection::read_ns` of the previous update
+    prev_read_ns: Option<u64>,
+    rates: HashMap<(IpAddr, u16), f64>,
+}
+
+impl ThroughputEwma {
+    /// A tracker smoothing with `alpha`, see [`ewma`].
+    pub fn new(alpha: f64) -> Self {
+        Self {
+            alpha,
+            prev_bytes: HashMap::new(),
+            prev_read_ns: None,
+            rates: HashMap::new(),
+        }
+    }
+
+    /// Fold in the connections of `collection` on `ports` (all when empty)
+    /// and return the averages. A collection not newer than the previous
+    /// one is ignored.
+    ///
+    /// ```
+    /// use sidecar::metrics::{Collection, ThroughputEwma};
+    /// use sidecar::Connection;
+    /// use sidecar_common::{ConnKey, ConnMetrics};
+    ///
+    /// let key = ConnKey::ipv4(
+    ///     u32::from_ne_bytes([10, 0, 0, 1]),
+    ///     u32::from_ne_bytes([10, 0, 0, 5]),
+    ///     51234,
+    ///     443,
+    /// );
+    /// let read = |secs: u64, bytes_sent| Collection {
+    ///     connections: vec![Connection {
+    ///         key,
+    ///         metrics: ConnMetrics { bytes_sent, ..Default::default() },
+    ///     }],
+    ///     read_ns: secs * 1_000_000_000,
+    ///     ..Default::default()
+    /// };
+    /// let endpoint = ("10.0.0.5".parse().unwrap(), 443);
+    ///
+    /// let mut throughput = ThroughputEwma::new(0.5);
+    /// // The first update only sets the baseline
+    /// assert!(throughput.update(&read(10, 1_000), &[]).is_empty());
+    /// // The first sample, 2000 bytes over 1s, is taken as is
+    /// assert_eq!(throughput.update(&read(11, 3_000), &[])[&endpoint], 2000.0);
+    /// // Later ones move halfway towards the sample: 1000 B/s
+    /// assert_eq!(throughput.update(&read(12, 4_000), &[])[&endpoint], 1500.0);
+    /// // A collection that isn't newer changes nothing
+    /// assert_eq!(throughput.update(&read(12, 9_000), &[])[&endpoint], 1500.0);
+    /// ```
+    pub fn update(
+        &mut self,
+        collection: &Collection,
+        ports: &[u16],
+    ) -> &HashMap<(IpAddr, u16), f64> {
+        let elapsed_ns = match self.prev_read_ns {
+            Some(prev) if collection.read_ns <= prev => return &self.rates,
+            Some(prev) => Some(collection.read_ns - prev),
+            None => None,
+        };
+
+        let mut moved: HashMap<(IpAddr, u16), u64> = HashMap::new();
+        let mut bytes = HashMap::with_capacity(collection.connections.len());
+        for conn in &collection.connections {
+            if !ports.is_empty() && !conn.matches_ports(ports) {
+                continue;
+            }
+            let total = conn.metrics.bytes_sent + conn.metrics.bytes_recv;
+            let prev = self.prev_bytes.get(&conn.key).copied().unwrap_or(0);
+            let endpoint = (exported_ip(conn.dst_ip()), conn.key.dst_port);
+            *moved.entry(endpoint).or_default() += total.saturating_sub(prev);
+            bytes.insert(conn.key, total);
+        }
+        self.prev_bytes = bytes;
+        self.prev_read_ns = Some(collection.read_ns);
+
+        let Some(elapsed_ns) = elapsed_ns else {
+            return &self.rates;
+        };
+        let elapsed_secs = elapsed_ns as f64 / 1_000_000_000.0;
+
+        for (endpoint, rate) in self.rates.iter_mut() {
+            let sample = moved.remove(endpoint).unwrap_or(0) as f64 / elapsed_secs;
+            *rate = ewma(*rate, sample, self.alpha);
+        }
+        // New endpoints start from their first sample rather than ramping up
+        // from 0
+        for (endpoint, bytes) in moved {
+            self.rates.insert(endpoint, bytes as f64 / elapsed_secs);
+        }
+        self.rates.retain(|_, rate| *rate >= THROUGHPUT_EWMA_FLOOR);
+
+        &self.rates
+    }
+}
+
+/// Per-endpoint inputs of [`health_score`].
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct HealthInputs {
+    /// Retransmits per packet sent
+    pub retransmit_ratio: f64,
+    /// Mean smoothed RTT in milliseconds
+    pub rtt_ms: f64,
+    /// Failed connects per connect attempt
+    pub failure_ratio: f64,
+}
+
+/// Health of an endpoint from 0 (worst) to 100 (no retransmits, no latency,
+/// no failed connects).
+///
+/// Each input is divided by its `max_*` scale in `config` and capped at 1,
+/// giving penalties `p`; the score is 100 minus the weighted mean penalty:
+///
+/// ```text
+/// p_retransmit = min(retransmit_ratio / max_retransmit_ratio, 1)
+/// p_rtt        = min(rtt_ms / max_rtt_ms, 1)
+/// p_failure    = min(failure_ratio / max_failure_ratio, 1)
+///
+/// score = 100 * (1 - (w_retransmit * p_retransmit + w_rtt * p_rtt + w_failure * p_failure)
+///                    / (w_retransmit + w_rtt + w_failure))
+/// ```
+///
+/// With the default config (weights 0.4/0.3/0.3, scales 0.05 / 200 ms / 0.2):
+///
+/// ```
+/// use sidecar::config::HealthScoreConfig;
+/// use sidecar::metrics::{health_score, HealthInputs};
+///
+/// let config = HealthScoreConfig::default();
+/// let score = |retransmit_ratio, rtt_ms, failure_ratio| {
+///     let inputs = HealthInputs { retransmit_ratio, rtt_ms, failure_ratio };
+///     (health_score(&inputs, &config) * 100.0).round() / 100.0
+/// };
+///
+/// assert_eq!(score(0.0, 0.0, 0.0), 100.0);
+/// // Half of each scale costs half of every weight
+/// assert_eq!(score(0.025, 100.0, 0.1), 50.0);
+/// // 1% retransmits (0.4 * 0.2), 50 ms (0.3 * 0.25), no failures
+/// assert_eq!(score(0.01, 50.0, 0.0), 84.5);
+/// // Inputs past their scale are capped
+/// assert_eq!(score(0.5, 0.0, 0.0), 60.0);
+/// assert_eq!(score(1.0, 10_000.0, 1.0), 0.0);
+/// ```
+pub fn health_score(inputs: &HealthInputs, config: &HealthScoreConfig) -> f64 {
+    let penalty = |value: f64, max: f64| (value / max).clamp(0.0, 1.0);
+    let weights = config.retransmit_weight + config.rtt_weight + config.failure_weight;
+    let weighted = config.retransmit_weight
+        * penalty(inputs.retransmit_ratio, config.max_retransmit_ratio)
+        + config.rtt_weight * penalty(inputs.rtt_ms, config.max_rtt_ms)
+        + config.failure_weight * penalty(inputs.failure_ratio, config.max_failure_ratio);
+    100.0 * (1.0 - weighted / weights)
+}
+
+/// Per-endpoint [`health_score`]s across collections.
+///
+/// Retransmits and RTT come from the live connections. The failure ratio
+/// covers the time since the previous update: connect failures added since
+/// then, over those plus the connections that started since then (on the
+/// first update, every live connection). Connections that opened and closed
+/// between two updates are not seen, so a busy endpoint with short
+/// connections reads a higher failure ratio than it has.
+#[derive(Debug)]
+pub struct HealthScores {
+    config: HealthScoreConfig,
+    /// Cumulative kernel failure counts as of the previous update
+    prev_failures: HashMap<ConnFailKey, u64>,
+    /// `Collection::read_ns` of the previous update
+    prev_read_ns: Option<u64>,
+    scores: HashMap<(IpAddr, u16), f64>,
 }
 
-/// Aggregate per-connection metrics by destination.
+impl HealthScores {
+    /// A tracker scoring with `config`, which must be valid.
+    pub fn new(config: HealthScoreConfig) -> Self {
+        Self {
+            config,
+            prev_failures: HashMap::new(),
+            prev_read_ns: None,
+            scores: HashMap::new(),
+        }
+    }
+
+    /// Score the endpoints of `collection` on `ports` (all when empty): those
+    /// with live connections or with failed connects since the previous
+    /// update. A collection not newer than the previous one is ignored.
+    pub fn update(
+        &mut self,
+        collection: &Collection,
+        ports: &[u16],
+    ) -> &HashMap<(IpAddr, u16), f64> {
+        if self
+            .prev_read_ns
+            .is_some_and(|prev| collection.read_ns <= prev)
+        {
+            return &self.scores;
+        }
+
+        let mut failed: HashMap<(IpAddr, u16), u64> = HashMap::new();
+        for &(key, count) in &collection.connect_failures {
+            let last = self.prev_failures.insert(key, count).unwrap_or(0);
+            if count <= last || !(ports.is_empty() || ports.contains(&key.dst_port)) {
+                continue;
+            }
+            let dst_ip = exported_ip(Ipv4Addr::from(key.dst_ip.to_be()));
+            *failed.entry((dst_ip, key.dst_port)).or_default() += count - last;
+        }
+
+        let live: Vec<&Connection> = collection
+            .connections
+            .iter()
+            .filter(|conn| ports.is_empty() || conn.matches_ports(ports))
+            .collect();
+        let mut opened: HashMap<(IpAddr, u16), u64> = HashMap::new();
+        for conn in &live {
+            if self
+                .prev_read_ns
+                .is_none_or(|prev| conn.metrics.start_ns > prev)
+            {
+                let endpoint = (exported_ip(conn.dst_ip()), conn.key.dst_port);
+                *opened.entry(endpoint).or_default() += 1;
+            }
+        }
+        self.prev_read_ns = Some(collection.read_ns);
+
+        let mut endpoints =
+            aggregate_by_destination(live.iter().map(|conn| (conn.key, conn.metrics)));
+        for &endpoint in failed.keys() {
+            endpoints.entry(endpoint).or_default();
+        }
+
+        self.scores.clear();
+        for (endpoint, metrics) in endpoints {
+            let failures = failed.get(&endpoint).copied().unwrap_or(0);
+            let attempts = failures + opened.get(&endpoint).copied().unwrap_or(0);
+            let inputs = HealthInputs {
+                retransmit_ratio: metrics.retransmit_ratio(),
+                rtt_ms: metrics.avg_rtt_ms(),
+                failure_ratio: if attempts == 0 {
+                    0.0
+                } else {
+                    failures as f64 / attempts as f64
+                },
+            };
+            self.scores
+                .insert(endpoint, health_score(&inputs, &self.config));
+        }
+
+        &self.scores
+    }
+}
+
+/// Aggregate per-connection metrics by destination (as `exported_ip`, so
+/// anonymized destinations in the same network are one endpoint).
+///
+/// Counters add up; `avg_duration_ms` is the mean duration of the
+/// connections, kept as a running average.
+///
+/// ```
+/// use sidecar::metrics::aggregate_by_destination;
+/// use sidecar_common::{ConnKey, ConnMetrics};
+/// use std::net::{IpAddr, Ipv4Addr};
+///
+/// // Addresses are kept as read from the kernel: network byte order
+/// let src = u32::from_ne_bytes([10, 0, 0, 1]);
+/// let key = |src_port, dst: [u8; 4], dst_port| {
+///     ConnKey::ipv4(src, u32::from_ne_bytes(dst), src_port, dst_port)
+/// };
+/// let conn = |sent, duration_ms: u64| ConnMetrics {
+///     bytes_sent: sent,
+///     packets_sent: 10,
+///     retransmits: 1,
+///     start_ns: 1_000_000_000,
+///     last_seen_ns: 1_000_000_000 + duration_ms * 1_000_000,
+///     ..Default::default()
+/// };
+/// let endpoints = aggregate_by_destination(
+///     [
+///         (key(40001, [10, 0, 0, 5], 443), conn(100, 1000)),
+///         (key(40002, [10, 0, 0, 5], 443), conn(200, 2000)),
+///         (key(40003, [10, 0, 0, 5], 443), conn(300, 6000)),
+///         (key(40004, [10, 0, 0, 5], 80), conn(400, 500)),
+///     ]
+///     .into_iter(),
+/// );
+///
+/// assert_eq!(endpoints.len(), 2);
+/// let https = &endpoints[&(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 5)), 443)];
+/// assert_eq!(https.connection_count, 3);
+/// assert_eq!(https.total_bytes_sent, 600);
+/// assert_eq!(https.total_packets_sent, 30);
+/// assert_eq!(https.total_retransmits, 3);
+/// // The running average ends at the plain mean: (1000 + 2000 + 6000) / 3
+/// assert!((https.avg_duration_ms - 3000.0).abs() < 1e-9);
+///
+/// let http = &endpoints[&(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 5)), 80)];
+/// assert_eq!((http.connection_count, http.avg_duration_ms), (1, 500.0));
+/// ```
 pub fn aggregate_by_destination(
     connections: impl Iterator<Item = (ConnKey, ConnMetrics)>,
-) -> HashMap<(Ipv4Addr, u16), EndpointMetrics> {
-    let mut aggregated: HashMap<(Ipv4Addr, u16), EndpointMetrics> = HashMap::new();
+) -> HashMap<(IpAddr, u16), EndpointMetrics> {
+    let mut aggregated: HashMap<(IpAddr, u16), EndpointMetrics> = HashMap::new();
 
     for (key, metrics) in connections {
-        let dst_ip = Ipv4Addr::from(key.dst_ip.to_be());
-        let endpoint = (dst_ip, key.dst_port);
+        let endpoint = (exported_ip(key.dst_addr()), key.dst_port);
 
         let entry = aggregated.entry(endpoint).or_default();
         entry.total_bytes_sent += metrics.bytes_sent;
@@ -33,8 +681,10 @@ pub fn aggregate_by_destination(
         entry.total_packets_recv += metrics.packets_recv;
         entry.total_retransmits += metrics.retransmits as u64;
         entry.connection_count += 1;
+        entry.rtt_samples += metrics.rtt_samples;
+        entry.rtt_sum_us += metrics.rtt_sum_us;
 
-        let duration_ms = (metrics.last_seen_ns - metrics.start_ns) as f64 / 1_000_000.0;
+        let duration_ms = metrics.duration_ns() as f64 / 1_000_000.0;
         // Running average
         let n = entry.connection_count as f64;
         entry.avg_duration_ms = entry.avg_duration_ms * ((n - 1.0) / n) + duration_ms / n;
@@ -43,7 +693,717 @@ pub fn aggregate_by_destination(
     aggregated
 }
 
+/// Merge connections sharing an `EndpointKey` into one per endpoint, keyed
+/// with source port 0.
+///
+/// Counters and RTT sums add up, so rates and jitter cover every merged
+/// connection. The span runs from the earliest start to the latest activity;
+/// MSS and path MTU are the smallest known (non-zero) values; the PID and
+/// congestion control algorithm are those of the first connection seen.
+///
+/// ```
+/// use sidecar::metrics::group_by_endpoint;
+/// use sidecar::Connection;
+/// use sidecar_common::{ConnKey, ConnMetrics};
+///
+/// let (src, dst) = (u32::from_ne_bytes([10, 0, 0, 1]), u32::from_ne_bytes([10, 0, 0, 5]));
+/// let conn = |src_port, dst_port, bytes_sent| Connection {
+///     key: ConnKey::ipv4(src, dst, src_port, dst_port),
+///     metrics: ConnMetrics { bytes_sent, ..Default::default() },
+/// };
+/// let (a, b) = (conn(40001, 443, 100), conn(40002, 443, 250));
+/// // Only the source port differs, so both have the same endpoint
+/// assert_eq!(a.key.endpoint_key(), b.key.endpoint_key());
+///
+/// let mut endpoints = group_by_endpoint([a, b, conn(40003, 8443, 7)]);
+/// endpoints.sort_by_key(|c| c.key.dst_port);
+/// assert_eq!(endpoints.len(), 2);
+/// assert_eq!(endpoints[0].key, ConnKey::ipv4(src, dst, 0, 443));
+/// assert_eq!(endpoints[0].metrics.bytes_sent, 350);
+/// assert_eq!(endpoints[1].metrics.bytes_sent, 7);
+/// ```
+pub fn group_by_endpoint(connections: impl IntoIterator<Item = Connection>) -> Vec<Connection> {
+    let mut endpoints: HashMap<EndpointKey, Connection> = HashMap::new();
+
+    for conn in connections {
+        let endpoint = conn.key.endpoint_key();
+        let Some(merged) = endpoints.get_mut(&endpoint) else {
+            let key = ConnKey {
+                src_port: 0,
+                ..conn.key
+            };
+            endpoints.insert(endpoint, Connection { key, ..conn });
+            continue;
+        };
+
+        merge_metrics(&mut merged.metrics, &conn.metrics);
+    }
+
+    endpoints.into_values().collect()
+}
+
+/// Merge the connections exported with the same labels (`metrics.group_by`)
+/// into one, in the order their labels were first seen. Metrics add up as in
+/// [`group_by_endpoint`]; the merged connection keeps the first one's key.
+pub fn group_by_labels(
+    connections: impl IntoIterator<Item = ([String; 6], Connection)>,
+) -> Vec<([String; 6], Connection)> {
+    let mut grouped: Vec<([String; 6], Connection)> = Vec::new();
+    let mut index: HashMap<[String; 6], usize> = HashMap::new();
+    for (labels, conn) in connections {
+        match index.get(&labels) {
+            Some(&i) => merge_metrics(&mut grouped[i].1.metrics, &conn.metrics),
+            None => {
+                index.insert(labels.clone(), grouped.len());
+                grouped.push((labels, conn));
+            }
+        }
+    }
+    grouped
+}
+
+/// Merge the entries of each socket whose 4-tuple changed (same non-zero
+/// `cookie`) into one connection under its latest key, the one it is seen
+/// under now. Other connections pass through unchanged.
+///
+/// The kernel starts a new entry under the new 4-tuple, so counters add up
+/// as in [`group_by_endpoint`] to the socket's totals.
+pub fn stitch_migrated(connections: impl IntoIterator<Item = Connection>) -> Vec<Connection> {
+    let mut stitched = Vec::new();
+    let mut sockets: HashMap<u64, Connection> = HashMap::new();
+    for conn in connections {
+        if conn.metrics.cookie == 0 {
+            stitched.push(conn);
+            continue;
+        }
+        let Some(merged) = sockets.get_mut(&conn.metrics.cookie) else {
+            sockets.insert(conn.metrics.cookie, conn);
+            continue;
+        };
+
+        if conn.metrics.last_seen_ns > merged.metrics.last_seen_ns {
+            merged.key = conn.key;
+        }
+        merge_metrics(&mut merged.metrics, &conn.metrics);
+    }
+    stitched.extend(sockets.into_values());
+    stitched
+}
+
+/// Tracks the source address each socket was established with (by
+/// `ConnMetrics::cookie`), to spot sockets whose traffic later shows up
+/// from another one. The map is keyed by 4-tuple, so a different source
+/// address always means a new entry; the cookie ties it to the old one.
+#[derive(Debug, Default)]
+pub struct SourceChanges {
+    /// Per socket: the source address of its earliest entry, and every
+    /// source address seen on it so far
+    sockets: HashMap<u64, (IpAddr, HashSet<IpAddr>)>,
+}
+
+impl SourceChanges {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The connections of `connections` whose socket was established from
+    /// another source address, each with that address. A socket and
+    /// address are reported once, in the first collection that has them;
+    /// sockets no longer tracked are forgotten.
+    pub fn update(&mut self, connections: &[Connection]) -> Vec<(IpAddr, Connection)> {
+        let mut sockets: HashMap<u64, Vec<&Connection>> = HashMap::new();
+        for conn in connections.iter().filter(|conn| conn.metrics.cookie != 0) {
+            sockets.entry(conn.metrics.cookie).or_default().push(conn);
+        }
+
+        let mut changes = Vec::new();
+        for (&cookie, conns) in sockets.iter_mut() {
+            conns.sort_by_key(|conn| conn.metrics.start_ns);
+            let first = conns[0].src_ip();
+            let (established, seen) = self
+                .sockets
+                .entry(cookie)
+                .or_insert_with(|| (first, HashSet::from([first])));
+            for conn in conns.iter() {
+                if seen.insert(conn.src_ip()) {
+                    changes.push((*established, **conn));
+                }
+            }
+        }
+        self.sockets
+            .retain(|cookie, _| sockets.contains_key(cookie));
+        changes
+    }
+}
+
+/// Merge the subflows of each MPTCP connection (same non-zero
+/// `mptcp_token`) into one connection under the key of its earliest
+/// subflow, which also carries the MPTCP socket's application bytes. Plain
+/// TCP connections pass through unchanged.
+///
+/// Counters and RTT sums add up across subflows as in [`group_by_endpoint`];
+/// per-subflow detail is still in the input (see `mptcp_subflows`).
+pub fn group_by_mptcp(connections: impl IntoIterator<Item = Connection>) -> Vec<Connection> {
+    let mut grouped = Vec::new();
+    let mut mptcp: HashMap<u32, Vec<Connection>> = HashMap::new();
+    for conn in connections {
+        match conn.metrics.mptcp_token {
+            0 => grouped.push(conn),
+            token => mptcp.entry(token).or_default().push(conn),
+        }
+    }
+
+    for mut subflows in mptcp.into_values() {
+        subflows.sort_by_key(|conn| conn.metrics.start_ns);
+        let mut subflows = subflows.into_iter();
+        let Some(mut merged) = subflows.next() else {
+            continue;
+        };
+        for conn in subflows {
+            merge_metrics(&mut merged.metrics, &conn.metrics);
+        }
+        grouped.push(merged);
+    }
+
+    grouped
+}
+
+/// MPTCP subflows by connection token, each connection's subflows in start
+/// order.
+pub fn mptcp_subflows<'a>(
+    connections: impl IntoIterator<Item = &'a Connection>,
+) -> HashMap<u32, Vec<&'a Connection>> {
+    let mut subflows: HashMap<u32, Vec<&Connection>> = HashMap::new();
+    for conn in connections {
+        if conn.metrics.mptcp_token != 0 {
+            subflows
+                .entry(conn.metrics.mptcp_token)
+                .or_default()
+                .push(conn);
+        }
+    }
+    for conns in subflows.values_mut() {
+        conns.sort_by_key(|conn| conn.metrics.start_ns);
+    }
+    subflows
+}
+
+/// Fold the counters of `c` into `m`: sums for counters and RTT, the widest
+/// span of activity, the smallest known MSS and path MTU. The descriptor,
+/// socket priority and flow label stay known only if the two don't disagree.
+fn merge_metrics(m: &mut ConnMetrics, c: &ConnMetrics) {
+    m.bytes_sent += c.bytes_sent;
+    m.bytes_recv += c.bytes_recv;
+    m.packets_sent += c.packets_sent;
+    m.packets_recv += c.packets_recv;
+    m.wire_bytes_sent += c.wire_bytes_sent;
+    m.retransmits = m.retransmits.saturating_add(c.retransmits);
+    m.rst_sent = m.rst_sent.saturating_add(c.rst_sent);
+    m.rst_recv = m.rst_recv.saturating_add(c.rst_recv);
+    m.sack_blocks = m.sack_blocks.saturating_add(c.sack_blocks);
+    m.dsacks = m.dsacks.saturating_add(c.dsacks);
+    m.rtt_samples += c.rtt_samples;
+    m.rtt_sum_us += c.rtt_sum_us;
+    m.rtt_sum_sq_us += c.rtt_sum_sq_us;
+    m.start_ns = m.start_ns.min(c.start_ns);
+    m.last_seen_ns = m.last_seen_ns.max(c.last_seen_ns);
+    m.mss = min_known(m.mss, c.mss);
+    m.pmtu = min_known(m.pmtu, c.pmtu);
+    m.flags |= c.flags;
+    m.fd = match (m.fd, c.fd) {
+        (-1, fd) | (fd, -1) => fd,
+        (a, b) if a == b => a,
+        _ => -1,
+    };
+    m.so_priority = match (m.so_priority, c.so_priority) {
+        (0, p) | (p, 0) => p,
+        (a, b) if a == b => a,
+        _ => 0,
+    };
+    m.flow_label = match (m.flow_label, c.flow_label) {
+        (0, l) | (l, 0) => l,
+        (a, b) if a == b => a,
+        _ => 0,
+    };
+    m.http_version = match (m.http_version, c.http_version) {
+        (0, v) | (v, 0) => v,
+        (a, b) if a == b => a,
+        _ => 0,
+    };
+}
+
+/// Smaller of two values where 0 means "unknown".
+fn min_known(a: u32, b: u32) -> u32 {
+    match (a, b) {
+        (0, x) | (x, 0) => x,
+        (a, b) => a.min(b),
+    }
+}
+
+/// RTT jitter (standard deviation of sampled RTTs) in microseconds.
+///
+/// Derived from the kernel's running sum and sum-of-squares:
+/// `sqrt(E[x^2] - E[x]^2)`. Returns 0 with fewer than two samples.
+pub fn rtt_jitter_us(metrics: &ConnMetrics) -> f64 {
+    if metrics.rtt_samples < 2 {
+        return 0.0;
+    }
+    let n = metrics.rtt_samples as f64;
+    let mean = metrics.rtt_sum_us as f64 / n;
+    let variance = metrics.rtt_sum_sq_us as f64 / n - mean * mean;
+    // Integer halving in the kernel can push this slightly negative
+    variance.max(0.0).sqrt()
+}
+
+/// Label value for a `connect_failure` reason code.
+pub fn connect_failure_reason(reason: u8) -> &'static str {
+    match reason {
+        connect_failure::REFUSED => "refused",
+        connect_failure::TIMEOUT => "timeout",
+        connect_failure::UNREACHABLE => "unreachable",
+        _ => "other",
+    }
+}
+
+/// `outcome` label value for a `filter_decision` counter index.
+pub fn filter_decision_outcome(index: u32) -> &'static str {
+    match index {
+        filter_decision::MATCHED_PID => "matched_pid",
+        filter_decision::FILTERED_PID => "filtered_pid",
+        filter_decision::MATCHED_CGROUP => "matched_cgroup",
+        filter_decision::FILTERED_CGROUP => "filtered_cgroup",
+        filter_decision::FILTERED_PORT => "filtered_port",
+        filter_decision::FILTERED_LOOPBACK => "filtered_loopback",
+        _ => "no_filter",
+    }
+}
+
+/// Label value for the HTTP version of an event: HTTP/2 requests carry their
+/// stream id, HTTP/1.x ones 0.
+pub fn http_protocol(event: &HttpEvent) -> &'static str {
+    if event.stream_id == 0 {
+        "http1"
+    } else {
+        "http2"
+    }
+}
+
+/// `protocol` label values of the `conn_flag` bits set in `flags`.
+pub fn conn_protocols(flags: u8) -> impl Iterator<Item = &'static str> {
+    conn_flag::NAMES
+        .into_iter()
+        .filter(move |(flag, _)| flags & flag != 0)
+        .map(|(_, name)| name)
+}
+
+/// Upper bound on samples kept by a `LatencyWindow`; oldest are dropped first.
+pub const LATENCY_WINDOW_MAX_SAMPLES: usize = 100_000;
+
+/// Sliding time window of latency samples with exact percentiles.
+///
+/// Complements bucketed histograms: percentiles are computed from the raw
+/// samples of the last `window`, so they are exact rather than interpolated.
+#[derive(Debug)]
+pub struct LatencyWindow {
+    window: Duration,
+    samples: VecDeque<(Instant, f64)>,
+}
+
+impl LatencyWindow {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            samples: VecDeque::new(),
+        }
+    }
+
+    /// Add a latency sample (seconds) observed at `at`.
+    pub fn record(&mut self, at: Instant, latency_secs: f64) {
+        if self.samples.len() == LATENCY_WINDOW_MAX_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back((at, latency_secs));
+    }
+
+    /// Drop samples older than the window, relative to `now`.
+    pub fn expire(&mut self, now: Instant) {
+        while let Some(&(at, _)) = self.samples.front() {
+            if now.duration_since(at) <= self.window {
+                break;
+            }
+            self.samples.pop_front();
+        }
+    }
+
+    /// Nearest-rank percentiles (each in 0.0..=1.0) over the current samples.
+    /// Returns `None` when the window is empty.
+    pub fn percentiles(&self, quantiles: &[f64]) -> Option<Vec<f64>> {
+        if self.samples.is_empty() {
+            return None;
+        }
+
+        let mut sorted: Vec<f64> = self.samples.iter().map(|&(_, v)| v).collect();
+        sorted.sort_by(|a, b| a.total_cmp(b));
+
+        let n = sorted.len();
+        Some(
+            quantiles
+                .iter()
+                .map(|q| {
+                    let rank = (q * n as f64).ceil() as usize;
+                    sorted[rank.clamp(1, n) - 1]
+                })
+                .collect(),
+        )
+    }
+}
+
+/// Estimated split of an HTTP request's latency into time queued behind
+/// earlier data in the local send buffer and time on the network.
+///
+/// Both parts are estimates from the socket state when the request was sent;
+/// whatever they don't cover (server processing, mostly) is the remainder of
+/// the total latency:
+///
+/// - network time is one smoothed RTT: the request going out and the first
+///   response bytes coming back
+/// - queue time is the bytes ahead of the request divided by the rate one
+///   congestion window drains at, `snd_cwnd * mss` per RTT
+///
+/// This assumes the window stays constant while the queue drains and that
+/// the connection is limited by its window rather than by the receiver or
+/// the application. Both parts are capped so they never add up to more than
+/// the measured latency.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct LatencyBreakdown {
+    pub queue_secs: f64,
+    pub network_secs: f64,
+}
+
+impl LatencyBreakdown {
+    pub fn estimate(event: &HttpEvent) -> Self {
+        let total = event.latency_ns as f64 / 1e9;
+        let rtt = event.srtt_us as f64 / 1e6;
+        let window_bytes = event.snd_cwnd as f64 * event.mss as f64;
+
+        let queue = if window_bytes > 0.0 {
+            event.queued_bytes as f64 / window_bytes * rtt
+        } else {
+            0.0
+        };
+
+        let network_secs = rtt.min(total);
+        Self {
+            queue_secs: queue.min(total - network_secs),
+            network_secs,
+        }
+    }
+}
+
+/// SLO counts for one endpoint over a collection interval.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SloCounts {
+    pub requests: u64,
+    /// Requests that did not fail with a 5xx status
+    pub successes: u64,
+    /// Requests that completed within the latency threshold
+    pub within_latency: u64,
+}
+
+impl SloCounts {
+    /// Fraction of requests that succeeded (1.0 when there were none).
+    pub fn success_ratio(&self) -> f64 {
+        ratio(self.successes, self.requests)
+    }
+
+    /// Fraction of requests within the latency threshold (1.0 when there
+    /// were none).
+    pub fn latency_ratio(&self) -> f64 {
+        ratio(self.within_latency, self.requests)
+    }
+}
+
+fn ratio(good: u64, total: u64) -> f64 {
+    if total == 0 {
+        return 1.0;
+    }
+    good as f64 / total as f64
+}
+
+/// Per-endpoint success and latency SLO counts, reset every interval.
+///
+/// Client errors (4xx) count as successes: they are the caller's fault, not
+/// the endpoint's, and would otherwise burn its error budget.
+#[derive(Debug)]
+pub struct SloTracker {
+    latency_threshold: Duration,
+    endpoints: HashMap<(IpAddr, u16), SloCounts>,
+}
+
+impl SloTracker {
+    pub fn new(latency_threshold: Duration) -> Self {
+        Self {
+            latency_threshold,
+            endpoints: HashMap::new(),
+        }
+    }
+
+    /// Count one HTTP request/response against its destination endpoint
+    /// (as `exported_ip`).
+    pub fn record(&mut self, event: &HttpEvent) {
+        let dst_ip = exported_ip(event.conn.dst_addr());
+        let counts = self
+            .endpoints
+            .entry((dst_ip, event.conn.dst_port))
+            .or_default();
+
+        counts.requests += 1;
+        if event.status_code < 500 {
+            counts.successes += 1;
+        }
+        if Duration::from_nanos(event.latency_ns) <= self.latency_threshold {
+            counts.within_latency += 1;
+        }
+    }
+
+    /// Counts gathered since the previous call, leaving the tracker empty.
+    pub fn take(&mut self) -> HashMap<(IpAddr, u16), SloCounts> {
+        std::mem::take(&mut self.endpoints)
+    }
+}
+
+/// Executable path per PID, for the `exe` label.
+///
+/// Paths the kernel captured at exec win. Processes that exec'd before the
+/// sidecar started (or forked without exec'ing) fall back to
+/// `/proc/<pid>/exe`; those lookups are cached until the PID stops showing up
+/// in a collection, so a reused PID is looked up afresh.
+#[derive(Debug, Default)]
+pub struct ExeResolver {
+    proc_cache: HashMap<u32, String>,
+    seen: HashSet<u32>,
+}
+
+impl ExeResolver {
+    /// Path for `pid`, or an empty string if it is unknown.
+    pub fn resolve(&mut self, pid: u32, captured: &HashMap<u32, String>) -> String {
+        if pid == 0 {
+            return String::new();
+        }
+        if let Some(path) = captured.get(&pid) {
+            return path.clone();
+        }
+
+        self.seen.insert(pid);
+        self.proc_cache
+            .entry(pid)
+            .or_insert_with(|| {
+                std::fs::read_link(format!("/proc/{}/exe", pid))
+                    .map(|p| p.to_string_lossy().into_owned())
+                    .unwrap_or_default()
+            })
+            .clone()
+    }
+
+    /// Drop cached paths of PIDs not resolved since the previous call.
+    pub fn evict_unseen(&mut self) {
+        let seen = std::mem::take(&mut self.seen);
+        self.proc_cache.retain(|pid, _| seen.contains(pid));
+    }
+}
+
+/// Time since the connection's last observed activity, as of the read that
+/// produced `collection`.
+pub fn idle_secs(conn: &Connection, collection: &Collection) -> f64 {
+    collection.read_ns.saturating_sub(conn.metrics.last_seen_ns) as f64 / 1_000_000_000.0
+}
+
+/// Lifetime totals of the connections closed since startup, for
+/// [`print_summary`]. Only the loader sees close events, so these stay
+/// empty when exporting from pinned maps.
+#[derive(Debug, Default, Clone)]
+pub struct ClosedTotals {
+    pub connections: u64,
+    pub bytes_sent: u64,
+    pub bytes_recv: u64,
+    pub retransmits: u64,
+    pub duration_ns: u64,
+    /// Bytes sent plus received by destination, as `exported_ip`
+    pub endpoint_bytes: HashMap<(IpAddr, u16), u64>,
+}
+
+impl ClosedTotals {
+    /// Add a closed connection's totals. Call exactly once per connection.
+    pub fn observe(&mut self, event: &ConnCloseEvent) {
+        self.connections += 1;
+        self.bytes_sent += event.bytes_sent;
+        self.bytes_recv += event.bytes_recv;
+        self.retransmits += event.retransmits as u64;
+        self.duration_ns += event.duration_ns;
+
+        let dst_ip = exported_ip(event.conn.dst_addr());
+        *self
+            .endpoint_bytes
+            .entry((dst_ip, event.conn.dst_port))
+            .or_default() += event.bytes_sent + event.bytes_recv;
+    }
+}
+
+/// `sidecar_udp_datagram_size_bytes`, a histogram counted in the kernel
+/// (`UDP_SIZES`) rather than through `observe`. Register it once and `set`
+/// the latest totals on every flush; scrapes read them. It has no series
+/// until a datagram is counted, so without UDP metrics on it stays out of
+/// scrapes. Cloning shares the totals.
+#[derive(Clone)]
+pub struct UdpSizeCollector {
+    desc: Desc,
+    sizes: Arc<Mutex<[UdpSizeHistogram; 2]>>,
+}
+
+impl UdpSizeCollector {
+    pub fn new() -> prometheus::Result<Self> {
+        let desc = Desc::new(
+            "sidecar_udp_datagram_size_bytes".to_string(),
+            "Sizes of the UDP datagrams the monitored processes sent and received".to_string(),
+            vec!["direction".to_string()],
+            HashMap::new(),
+        )?;
+        Ok(Self {
+            desc,
+            sizes: Arc::default(),
+        })
+    }
+
+    /// Publish the totals of a new collection.
+    pub fn set(&self, sizes: [UdpSizeHistogram; 2]) {
+        *self.sizes.lock().unwrap() = sizes;
+    }
+}
+
+impl Collector for UdpSizeCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        vec![&self.desc]
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let sizes = *self.sizes.lock().unwrap();
+        if sizes
+            .iter()
+            .all(|sizes| sizes.counts.iter().all(|&count| count == 0))
+        {
+            return Vec::new();
+        }
+        let directions = [("sent", direction::SENT), ("received", direction::RECEIVED)];
+        let metrics: Vec<proto::Metric> = directions
+            .iter()
+            .map(|&(name, dir)| {
+                let sizes = &sizes[dir as usize];
+                let mut cumulative = 0;
+                let buckets: Vec<proto::Bucket> = UDP_SIZE_BUCKETS
+                    .iter()
+                    .zip(sizes.counts)
+                    .map(|(&bound, count)| {
+                        cumulative += count;
+                        let mut bucket = proto::Bucket::default();
+                        bucket.set_upper_bound(bound as f64);
+                        bucket.set_cumulative_count(cumulative);
+                        bucket
+                    })
+                    .collect();
+                // The encoder adds the +Inf bucket from the sample count
+                let mut histogram = proto::Histogram::default();
+                histogram.set_sample_count(sizes.counts.iter().sum());
+                histogram.set_sample_sum(sizes.sum as f64);
+                histogram.set_bucket(buckets.into());
+
+                let mut label = proto::LabelPair::default();
+                label.set_name("direction".to_string());
+                label.set_value(name.to_string());
+                let mut metric = proto::Metric::default();
+                metric.set_label(vec![label].into());
+                metric.set_histogram(histogram);
+                metric
+            })
+            .collect();
+
+        let mut family = MetricFamily::default();
+        family.set_name(self.desc.fq_name.clone());
+        family.set_help(self.desc.help.clone());
+        family.set_field_type(MetricType::HISTOGRAM);
+        family.set_metric(metrics.into());
+        vec![family]
+    }
+}
+
+/// Endpoints listed in [`print_summary`].
+const SUMMARY_TOP_ENDPOINTS: usize = 10;
+
+/// Print a report of everything seen over a run of `elapsed`: the
+/// connections still open in `collection`, the final read, plus those in
+/// `closed`. A socket whose 4-tuple changed counts once.
+pub fn print_summary(collection: &Collection, closed: &ClosedTotals, elapsed: Duration) {
+    let live = stitch_migrated(collection.connections.iter().copied());
+    let sum = |field: fn(&ConnMetrics) -> u64| live.iter().map(|c| field(&c.metrics)).sum::<u64>();
+    let seen = live.len() as u64 + closed.connections;
+    let duration_ns = sum(ConnMetrics::duration_ns) + closed.duration_ns;
+
+    let mut endpoints = closed.endpoint_bytes.clone();
+    let open = aggregate_by_destination(live.iter().map(|conn| (conn.key, conn.metrics)));
+    for (endpoint, metrics) in open {
+        *endpoints.entry(endpoint).or_default() +=
+            metrics.total_bytes_sent + metrics.total_bytes_recv;
+    }
+    let mut endpoints: Vec<_> = endpoints.into_iter().collect();
+    endpoints.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+
+    println!(
+        "Summary after {}:",
+        format_duration(elapsed.as_millis() as f64)
+    );
+    println!(
+        "  Connections:  {} seen ({} open, {} closed)",
+        seen,
+        live.len(),
+        closed.connections
+    );
+    println!(
+        "  Bytes sent:   {}",
+        format_bytes(sum(|m| m.bytes_sent) + closed.bytes_sent)
+    );
+    println!(
+        "  Bytes recv:   {}",
+        format_bytes(sum(|m| m.bytes_recv) + closed.bytes_recv)
+    );
+    println!(
+        "  Retransmits:  {}",
+        sum(|m| m.retransmits as u64) + closed.retransmits
+    );
+    if seen > 0 {
+        let avg_ms = duration_ns as f64 / seen as f64 / 1_000_000.0;
+        println!("  Avg duration: {}", format_duration(avg_ms));
+    }
+    if !endpoints.is_empty() {
+        println!("  Top endpoints by bytes:");
+    }
+    for ((ip, port), bytes) in endpoints.into_iter().take(SUMMARY_TOP_ENDPOINTS) {
+        println!(
+            "    {:<24} {}",
+            SocketAddr::from((ip, port)),
+            format_bytes(bytes)
+        );
+    }
+}
+
 /// Format bytes as human-readable string.
+///
+/// ```
+/// use sidecar::metrics::format_bytes;
+///
+/// assert_eq!(format_bytes(1023), "1023 B");
+/// assert_eq!(format_bytes(1024), "1.00 KB");
+/// assert_eq!(format_bytes(1_048_576), "1.00 MB");
+/// assert_eq!(format_bytes(1_073_741_824), "1.00 GB");
+/// ```
 pub fn format_bytes(bytes: u64) -> String {
     if bytes >= 1_073_741_824 {
         format!("{:.2} GB", bytes as f64 / 1_073_741_824.0)
@@ -57,6 +1417,14 @@ pub fn format_bytes(bytes: u64) -> String {
 }
 
 /// Format duration in milliseconds as human-readable string.
+///
+/// ```
+/// use sidecar::metrics::format_duration;
+///
+/// assert_eq!(format_duration(999.0), "999 ms");
+/// assert_eq!(format_duration(1000.0), "1.00 s");
+/// assert_eq!(format_duration(60_000.0), "1.0 min");
+/// ```
 pub fn format_duration(ms: f64) -> String {
     if ms >= 60_000.0 {
         format!("{:.1} min", ms / 60_000.0)
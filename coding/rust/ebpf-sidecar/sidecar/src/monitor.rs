@@ -0,0 +1,2289 @@
+//! Loading, attaching and reading the eBPF programs and maps.
+
+use crate::cgroup::{self, CgroupTargets};
+use crate::config::SampleRates;
+use crate::policy::PolicyRule;
+use anyhow::{Context, Result};
+use aya::util::{online_cpus, KernelVersion};
+use aya::{
+    include_bytes_aligned,
+    maps::{
+        perf::{AsyncPerfEventArray, AsyncPerfEventArrayBuffer},
+        Array, HashMap, LpmTrie, Map, MapData, PerCpuArray, PerCpuHashMap, PerCpuValues,
+        ProgramArray, StackTraceMap,
+    },
+    programs::{
+        fentry::FEntryLinkId, fexit::FExitLinkId, kprobe::KProbeLinkId, CgroupSockAddr, FEntry,
+        FExit, KProbe, TracePoint,
+    },
+    Bpf, BpfLoader, Btf, VerifierLogLevel,
+};
+use aya_log::BpfLogger;
+use bytes::BytesMut;
+use log::{debug, error, info, warn};
+use serde::Serialize;
+use sidecar_common::{
+    active_conn, conn_stat, filter_decision, l7_proto, probe, ByteHistory, ConnCloseEvent,
+    ConnFailKey, ConnKey, ConnMetrics, ConnectStackEvent, GapEvent, HttpEvent, IfaceKey,
+    IfaceTraffic, LayoutInfo, PayloadEvent, PolicyEvent, PolicyTarget, SidecarConfig,
+    TlsHelloEvent, UdpSizeHistogram, BPF_ARCH_LEN, CONFIG_VERSION, EXE_PATH_LEN,
+    PAYLOAD_CAPTURE_MAX,
+};
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+use tokio::time;
+
+/// An fentry/fexit program replacing the kprobes on a kernel function.
+#[derive(Debug, Clone, Copy)]
+enum Tracing {
+    FEntry(&'static str),
+    FExit(&'static str),
+}
+
+impl Tracing {
+    fn program(self) -> &'static str {
+        match self {
+            Tracing::FEntry(name) | Tracing::FExit(name) => name,
+        }
+    }
+}
+
+/// Link of an attached kprobe or fentry/fexit program, to detach it again.
+#[derive(Debug)]
+enum ProgramLink {
+    KProbe(KProbeLinkId),
+    FEntry(FEntryLinkId),
+    FExit(FExitLinkId),
+}
+
+/// Kernel functions called for every message sent or received: the probes
+/// [`Monitor::set_hot_paths_attached`] detaches.
+const HOT_PATH_FUNCTIONS: [&str; 2] = ["tcp_sendmsg", "tcp_recvmsg"];
+
+/// A program attached to one of `HOT_PATH_FUNCTIONS`.
+#[derive(Debug)]
+struct HotPathProgram {
+    program: &'static str,
+    fn_name: &'static str,
+    /// None for a kprobe; the program name is `program` either way
+    tracing: Option<Tracing>,
+    /// None while detached
+    link: Option<ProgramLink>,
+}
+
+/// Maximum number of ports the kernel-side filter can hold.
+pub const MAX_TARGET_PORTS: usize = 8;
+
+/// bpffs mount holding the private pin directories
+const BPFFS: &str = "/sys/fs/bpf";
+
+/// Maps the eBPF object declares `pinned`: created in (or taken over from)
+/// the monitor's pin directory, so [`Monitor::reload`] keeps them. Must list
+/// every `pinned` map in sidecar-ebpf.
+const STATE_MAPS: [&str; 28] = [
+    "CONNECTIONS",
+    "CONN_STATS",
+    "ACTIVE_CONNS",
+    "FILTER_DECISIONS",
+    "CONFIG",
+    "TARGET_PIDS",
+    "TARGET_CGROUPS",
+    "PROCESS_EXES",
+    "CONNECT_FAILURES",
+    "IFACE_TRAFFIC",
+    "UDP_SIZES",
+    "PAWS_DROPS",
+    "ARG_READ_ERRORS",
+    "EVENTS",
+    "CLOSE_EVENTS",
+    "ALWAYS_TRACK",
+    "POLICY_DENYLIST",
+    "POLICY_EVENTS",
+    "RECV_ARGS",
+    "NEW_SOCKS",
+    "SOCK_COOKIES",
+    "HTTP_STREAMS",
+    "BYTE_HISTORY",
+    "PAYLOAD_EVENTS",
+    "GAP_EVENTS",
+    "TLS_HELLO_EVENTS",
+    "STACK_TRACES",
+    "STACK_EVENTS",
+];
+
+/// Totals of the kernel's per-CPU `CONN_STATS` counters, indexed by `conn_stat`.
+pub type ConnStats = [u64; conn_stat::COUNT as usize];
+
+/// Totals of the kernel's per-CPU `FILTER_DECISIONS` counters, indexed by
+/// `filter_decision`.
+pub type FilterDecisions = [u64; filter_decision::COUNT as usize];
+
+/// Anything connection metrics can be read from.
+pub trait ConnectionSource {
+    /// Read every tracked connection from the kernel.
+    fn snapshot(&self) -> Result<Vec<Connection>>;
+
+    /// Read cumulative failed-connect counts per destination and reason.
+    fn connect_failures(&self) -> Result<Vec<(ConnFailKey, u64)>>;
+
+    /// Read the connection insert/close counters, summed over all CPUs.
+    fn conn_stats(&self) -> Result<ConnStats>;
+
+    /// Read the filter check outcome counters, summed over all CPUs.
+    fn filter_decisions(&self) -> Result<FilterDecisions>;
+
+    /// Read the executable paths recorded at exec, by PID.
+    fn exe_paths(&self) -> Result<std::collections::HashMap<u32, String>>;
+
+    /// Read every CPU's per-minute byte ring; see `metrics::ByteProfile` to
+    /// combine them.
+    fn byte_history(&self) -> Result<Vec<ByteHistory>>;
+
+    /// Read the bytes received and sent per network interface, summed over
+    /// all CPUs. Empty unless interface metrics are on.
+    fn interface_traffic(&self) -> Result<Vec<(IfaceKey, IfaceTraffic)>>;
+
+    /// Read the sizes of the UDP datagrams sent and received, indexed by
+    /// `direction` and summed over all CPUs. All zero unless UDP metrics are
+    /// on.
+    fn udp_datagram_sizes(&self) -> Result<[UdpSizeHistogram; 2]>;
+
+    /// Read the TCP segments dropped by the PAWS check on the whole host,
+    /// summed over all CPUs. 0 unless PAWS drop counting is on.
+    fn paws_drops(&self) -> Result<u64>;
+
+    /// Read the kprobe arguments and return values the probes couldn't
+    /// read, summed over all CPUs.
+    fn arg_read_errors(&self) -> Result<u64>;
+
+    /// Replace the eBPF programs feeding the maps, keeping their state.
+    /// Only a loader has programs to replace.
+    fn reload(&mut self) -> Result<()> {
+        anyhow::bail!("No eBPF programs are loaded by this process")
+    }
+
+    /// Detach the probes on the per-message send and receive paths, or
+    /// attach them again. Only a loader has probes to detach.
+    fn set_hot_paths_attached(&mut self, _attached: bool) -> Result<()> {
+        anyhow::bail!("No eBPF programs are loaded by this process")
+    }
+
+    /// Replace the ports the kernel filters on (empty = all). Only the
+    /// loader owns the config map.
+    fn set_ports(&mut self, _ports: &[u16]) -> Result<()> {
+        anyhow::bail!("The monitored ports can only be changed by the loader")
+    }
+
+    /// Stop tracking `keys`, returning how many were tracked. Only the
+    /// loader deletes map entries.
+    fn clear_connections(&mut self, _keys: &[ConnKey]) -> Result<u64> {
+        anyhow::bail!("Connections can only be cleared by the loader")
+    }
+
+    /// Highest number of concurrently tracked connections since the
+    /// previous call, then start over from `live`, the entries just read
+    /// from the connections map. None if this source can't reset the
+    /// kernel counters: only the loader does, so that two readers don't
+    /// split the peaks between them.
+    fn take_active_peak(&mut self, _live: u64) -> Result<Option<u64>> {
+        Ok(None)
+    }
+
+    /// Check the heartbeat of every perf buffer reader task, restarting the
+    /// stalled ones if `restart` is set. Empty for sources without readers.
+    fn check_event_readers(&mut self, _restart: bool) -> Vec<ReaderStatus> {
+        Vec::new()
+    }
+
+    /// SHA-256 of the eBPF object running, hex-encoded. None for sources
+    /// that didn't load it.
+    fn bpf_object_sha(&self) -> Option<&str> {
+        None
+    }
+
+    /// Whether a retransmit counter is attached, so retransmit counts of 0
+    /// can be trusted. None for sources that didn't attach the programs.
+    fn retransmit_tracking(&self) -> Option<bool> {
+        None
+    }
+
+    /// Kernel ids of the eBPF object's maps, by name, for
+    /// [`crate::map_usage::MapDirectory`]. Empty for sources that didn't
+    /// load the object.
+    fn map_ids(&self) -> Vec<(String, u32)> {
+        Vec::new()
+    }
+}
+
+/// How often an idle perf buffer reader wakes up to refresh its heartbeat.
+const READER_HEARTBEAT: Duration = Duration::from_secs(5);
+
+/// Heartbeat age after which a perf buffer reader counts as stalled.
+pub const READER_STALL_AFTER: Duration = Duration::from_secs(15);
+
+/// Liveness of one perf buffer reader task, from
+/// [`ConnectionSource::check_event_readers`].
+#[derive(Debug, Clone)]
+pub struct ReaderStatus {
+    /// Which events the reader forwards (`close`, `http`, ...)
+    pub events: &'static str,
+    /// CPU whose buffer it reads
+    pub cpu: u32,
+    /// Time since its last heartbeat
+    pub heartbeat_age: Duration,
+    /// Whether the heartbeat is older than [`READER_STALL_AFTER`]
+    pub stalled: bool,
+    /// A new reader was started for the buffer. With `Err`, restarting was
+    /// asked for but failed.
+    pub restarted: Option<Result<(), String>>,
+}
+
+/// A perf buffer reader task, and how to start it again.
+struct PerfReader {
+    events: &'static str,
+    cpu: u32,
+    liveness: Arc<ReaderLiveness>,
+    start: Box<dyn Fn() -> Result<()> + Send>,
+}
+
+/// Shared between a perf buffer's reader task and its watchdog.
+#[derive(Default)]
+struct ReaderLiveness {
+    /// Milliseconds since the Unix epoch of the reader's latest pass
+    heartbeat: AtomicU64,
+    /// Bumped by every start; a reader of an older generation was replaced
+    /// and exits
+    generation: AtomicU64,
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// Forward the events of one CPU's perf buffer into `tx` until reading
+/// fails, the receiver is dropped or a newer reader replaces this one
+/// (`generation`), storing a heartbeat on every pass.
+async fn read_perf_buffer<T: Copy + Send + 'static>(
+    mut buf: AsyncPerfEventArrayBuffer<MapData>,
+    tx: mpsc::UnboundedSender<T>,
+    events_name: &'static str,
+    cpu_id: u32,
+    liveness: Arc<ReaderLiveness>,
+    generation: u64,
+) {
+    let mut buffers = (0..16)
+        .map(|_| BytesMut::with_capacity(std::mem::size_of::<T>()))
+        .collect::<Vec<_>>();
+
+    while liveness.generation.load(Ordering::Relaxed) == generation {
+        liveness.heartbeat.store(now_ms(), Ordering::Relaxed);
+        // Wake up now and then when idle, so that quiet isn't mistaken for
+        // a stall; dropping the read mid-wait loses nothing
+        let events = match time::timeout(READER_HEARTBEAT, buf.read_events(&mut buffers)).await {
+            Err(_) => continue,
+            Ok(Ok(events)) => events,
+            Ok(Err(e)) => {
+                error!(
+                    "{} event reader on CPU {} failed: {}",
+                    events_name, cpu_id, e
+                );
+                return;
+            }
+        };
+        if events.lost > 0 {
+            warn!(
+                "Lost {} {} events on CPU {}",
+                events.lost, events_name, cpu_id
+            );
+        }
+
+        for buf in buffers.iter().take(events.read) {
+            let event = unsafe { std::ptr::read_unaligned(buf.as_ptr() as *const T) };
+            if tx.send(event).is_err() {
+                return; // Receiver dropped
+            }
+        }
+    }
+}
+
+/// What the eBPF programs should monitor.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct MonitorOptions {
+    /// Target PID to monitor (0 = all processes); forked children follow
+    pub target_pid: u32,
+    /// More PIDs to monitor alongside `target_pid`, e.g. every match of a
+    /// process-name pattern; forked children follow
+    pub extra_pids: Vec<u32>,
+    /// cgroup whose whole subtree to monitor (relative paths are under
+    /// `/sys/fs/cgroup`); see [`Monitor::cgroup_targets`] to keep it current
+    pub cgroup: Option<PathBuf>,
+    /// Ports to monitor (empty = all); see [`split_ports`] for more than 8
+    pub ports: Vec<u16>,
+    /// Ports that get first claim on the kernel's filter slots
+    pub priority_ports: Vec<u16>,
+    /// Enable detailed per-packet logging from the eBPF programs
+    pub debug: bool,
+    /// Don't track connections between two loopback addresses
+    pub exclude_loopback: bool,
+    /// Only track connections opened (connected or accepted) after the
+    /// programs are attached; ones already open are never reported
+    pub new_connections_only: bool,
+    /// Share of calls each probe counts
+    pub sample_rates: SampleRates,
+    /// L7 parsers to run on the send path (empty = none)
+    pub l7_parsers: Vec<L7Protocol>,
+    /// Destinations whose connect attempts are reported through
+    /// [`Monitor::policy_events`] (empty = no policy hook). Checked for
+    /// processes in `cgroup`, or on the whole host without one; the
+    /// connects are not blocked.
+    pub denylist: Vec<PolicyRule>,
+    /// Destinations whose connections are always tracked, whatever the PID,
+    /// cgroup, port and loopback filters say
+    pub always_track: Vec<PolicyRule>,
+    /// Copy the start of sampled payloads to [`Monitor::payload_events`]
+    /// (None = off)
+    pub payload_capture: Option<PayloadCapture>,
+    /// Report the time between a sample of the sends and receives on each
+    /// tracked connection to [`Monitor::gap_events`] (None = off)
+    pub gap_sampling: Option<GapSampling>,
+    /// Send the ClientHellos the TLS parser sees to [`Monitor::tls_hellos`],
+    /// for fingerprinting; needs [`L7Protocol::Tls`] in `l7_parsers`
+    pub tls_fingerprints: bool,
+    /// Capture the kernel and user call stacks of a sample of tracked
+    /// connects, for [`Monitor::connect_stacks`] (None = off)
+    pub stack_sampling: Option<StackSampling>,
+    /// Count the bytes through each network interface, whatever the
+    /// filters; see [`ConnectionSource::interface_traffic`]
+    pub interface_metrics: bool,
+    /// Count the sizes of UDP datagrams sent and received by the monitored
+    /// processes; see [`ConnectionSource::udp_datagram_sizes`]
+    pub udp_metrics: bool,
+    /// Count the TCP segments the kernel drops in its PAWS check; see
+    /// [`ConnectionSource::paws_drops`]
+    pub paws_drops: bool,
+    /// Record the descriptor each connection has in its process
+    /// (`ConnMetrics::fd`), from the socket I/O system calls
+    pub fd_tracking: bool,
+}
+
+/// System calls whose descriptor argument `fd_tracking` records: the ones
+/// applications send and receive on sockets with.
+const FD_SYSCALLS: [&str; 8] = [
+    "read", "write", "readv", "writev", "recvfrom", "sendto", "recvmsg", "sendmsg",
+];
+
+/// How much payload the kernel copies out, and how often.
+///
+/// Captures hold raw application data: secrets and personal data leave the
+/// process they belong to. Keep them rare and short.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct PayloadCapture {
+    /// Capture one in this many sends and receives on tracked connections
+    pub sample_every: u32,
+    /// Payload bytes per capture, at most `PAYLOAD_CAPTURE_MAX`
+    pub max_bytes: u16,
+    /// Captures per second over all CPUs, counted in one budget they share
+    pub max_per_sec: u32,
+}
+
+/// How often the kernel reports the gap before a send or receive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct GapSampling {
+    /// Report one in this many sends and receives on tracked connections
+    pub sample_every: u32,
+    /// Reports per second over all CPUs, counted in one budget they share
+    pub max_per_sec: u32,
+}
+
+/// How often the kernel captures the call stacks of a connect.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct StackSampling {
+    /// Capture one in this many tracked connects
+    pub sample_every: u32,
+    /// Captures per second over all CPUs, counted in one budget they share
+    pub max_per_sec: u32,
+}
+
+/// An L7 protocol with its own parser program in the eBPF object.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum L7Protocol {
+    Http,
+    Tls,
+    Dns,
+}
+
+impl L7Protocol {
+    pub const ALL: [L7Protocol; 3] = [L7Protocol::Http, L7Protocol::Tls, L7Protocol::Dns];
+
+    /// Slot in the `L7_PARSERS` program array.
+    fn slot(self) -> u32 {
+        match self {
+            L7Protocol::Http => l7_proto::HTTP,
+            L7Protocol::Tls => l7_proto::TLS,
+            L7Protocol::Dns => l7_proto::DNS,
+        }
+    }
+
+    /// Name of the parser program tail-called for this protocol.
+    fn program(self) -> &'static str {
+        match self {
+            L7Protocol::Http => "parse_http",
+            L7Protocol::Tls => "parse_tls",
+            L7Protocol::Dns => "parse_dns",
+        }
+    }
+}
+
+impl FromStr for L7Protocol {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "http" => Ok(L7Protocol::Http),
+            "tls" => Ok(L7Protocol::Tls),
+            "dns" => Ok(L7Protocol::Dns),
+            other => anyhow::bail!(
+                "Unknown L7 protocol {:?} (expected http, tls or dns)",
+                other
+            ),
+        }
+    }
+}
+
+/// How much of the kernel verifier's log to request when loading programs.
+///
+/// The log is only returned when a program is rejected, and is then part of
+/// the load error. aya grows the log buffer until the whole log fits.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum VerifierLog {
+    /// No log; rejections only report the errno
+    Off,
+    /// The instructions leading to the rejection, plus verifier stats
+    #[default]
+    Debug,
+    /// Every instruction and register state the verifier walked, plus stats
+    Verbose,
+}
+
+impl VerifierLog {
+    fn level(self) -> VerifierLogLevel {
+        match self {
+            VerifierLog::Off => VerifierLogLevel::DISABLE,
+            VerifierLog::Debug => VerifierLogLevel::DEBUG | VerifierLogLevel::STATS,
+            VerifierLog::Verbose => VerifierLogLevel::VERBOSE | VerifierLogLevel::STATS,
+        }
+    }
+}
+
+impl FromStr for VerifierLog {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "off" => Ok(VerifierLog::Off),
+            "debug" => Ok(VerifierLog::Debug),
+            "verbose" => Ok(VerifierLog::Verbose),
+            other => anyhow::bail!(
+                "Unknown verifier log level {:?} (expected off, debug or verbose)",
+                other
+            ),
+        }
+    }
+}
+
+/// The rates as `SidecarConfig::sample_rates`, indexed by `probe`.
+fn kernel_sample_rates(rates: &SampleRates) -> [u8; probe::COUNT as usize] {
+    let mut kernel = [100; probe::COUNT as usize];
+    kernel[probe::CONNECT as usize] = rates.connect;
+    kernel[probe::SENDMSG as usize] = rates.sendmsg;
+    kernel[probe::RECVMSG as usize] = rates.recvmsg;
+    kernel[probe::QUEUE_XMIT as usize] = rates.queue_xmit;
+    kernel[probe::RETRANSMIT as usize] = rates.retransmit;
+    kernel[probe::RESET as usize] = rates.reset;
+    kernel
+}
+
+/// Split the wanted ports into the kernel's filter slots and a userspace-only
+/// overflow.
+///
+/// Priority ports claim slots first (and are wanted even if missing from
+/// `ports`), then the remaining ports in order. Returns `(kernel, overflow)`;
+/// while `overflow` is non-empty the kernel can't filter by port exactly, so
+/// it tracks all connections and only runs L7 parsing for the kernel ports.
+pub fn split_ports(ports: &[u16], priority: &[u16]) -> (Vec<u16>, Vec<u16>) {
+    let mut wanted: Vec<u16> = Vec::new();
+    for &port in priority.iter().chain(ports) {
+        if !wanted.contains(&port) {
+            wanted.push(port);
+        }
+    }
+
+    let overflow = wanted.split_off(wanted.len().min(MAX_TARGET_PORTS));
+    (wanted, overflow)
+}
+
+/// A tracked connection as read from the `CONNECTIONS` map.
+#[derive(Debug, Clone, Copy)]
+pub struct Connection {
+    pub key: ConnKey,
+    pub metrics: ConnMetrics,
+}
+
+impl Connection {
+    /// Source address of the connection.
+    pub fn src_ip(&self) -> IpAddr {
+        self.key.src_addr()
+    }
+
+    /// Destination address of the connection.
+    pub fn dst_ip(&self) -> IpAddr {
+        self.key.dst_addr()
+    }
+
+    /// Whether either end of the connection uses one of `ports`.
+    pub fn matches_ports(&self, ports: &[u16]) -> bool {
+        ports.contains(&self.key.src_port) || ports.contains(&self.key.dst_port)
+    }
+
+    /// Congestion control algorithm, or an empty string if it couldn't be
+    /// read.
+    pub fn cc_algo(&self) -> String {
+        let name = &self.metrics.cc_algo;
+        let len = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+        String::from_utf8_lossy(&name[..len]).into_owned()
+    }
+
+    /// Time between the first and the most recent observed activity.
+    pub fn duration_secs(&self) -> f64 {
+        self.metrics.duration_ns() as f64 / 1_000_000_000.0
+    }
+}
+
+/// How [`Monitor::load_with`] loads the eBPF object.
+#[derive(Debug, Clone, Default)]
+pub struct LoadOptions {
+    /// Verifier log detail for rejected programs
+    pub verifier_log: VerifierLog,
+    /// eBPF object file to load instead of the one embedded at build time;
+    /// read again by every [`Monitor::reload`]
+    pub object: Option<PathBuf>,
+    /// bpffs directory to pin the state maps in, kept after exit for
+    /// [`PinnedMaps::open`]. Loading fails if they can't be pinned there.
+    pub pin_dir: Option<PathBuf>,
+    /// Without `pin_dir`, pin the state maps in a private directory removed
+    /// on drop, so [`Monitor::reload`] can keep them. Maps are left unpinned
+    /// (with a warning) if there is no bpffs to pin them in.
+    pub reloadable: bool,
+}
+
+/// Handle to the loaded eBPF programs and their maps.
+pub struct Monitor {
+    bpf: Bpf,
+    /// SHA-256 of the object `bpf` was loaded from, hex-encoded
+    bpf_object_sha: String,
+    /// How `bpf` was loaded; `reload` loads the same way
+    load: LoadOptions,
+    /// bpffs directory the state maps are pinned in, if they are
+    pin_dir: Option<PathBuf>,
+    /// `pin_dir` is private to this monitor and removed on drop
+    private_pin_dir: bool,
+    /// Parsers to enable, from the last `configure`
+    l7_parsers: Vec<L7Protocol>,
+    /// Payload capture is on, from the last `configure`
+    payload_capture: bool,
+    /// Interface metrics are on, from the last `configure`
+    interface_metrics: bool,
+    /// UDP datagram sizes are counted, from the last `configure`
+    udp_metrics: bool,
+    /// PAWS drops are counted, from the last `configure`
+    paws_drops: bool,
+    /// Descriptor tracking is on, from the last `configure`
+    fd_tracking: bool,
+    /// `attach` attached a retransmit counter
+    retransmit_tracking: bool,
+    /// Kernel id of every map of `bpf`, taken at load before `attach` and
+    /// the stream readers take some of them out
+    map_ids: Vec<(String, u32)>,
+    /// `L7_PARSERS` program array, taken out of `bpf` by `attach`. Held for
+    /// the monitor's lifetime: the kernel empties a program array once no
+    /// userspace fd refers to it.
+    l7_slots: Option<ProgramArray<MapData>>,
+    /// Monitored cgroup directory and the ids `configure` seeded for it
+    cgroup_seed: Option<(PathBuf, HashSet<u64>)>,
+    /// cgroup to attach the policy hooks to, if a denylist is configured
+    policy_cgroup: Option<PathBuf>,
+    /// Kernel functions `attach` probed with fentry/fexit instead of kprobes
+    tracing_functions: Vec<&'static str>,
+    /// Programs `attach` attached to the send/receive hot path
+    hot_paths: Vec<HotPathProgram>,
+    /// Reader tasks of the perf buffers streamed so far
+    readers: Vec<PerfReader>,
+}
+
+impl Monitor {
+    /// Load the embedded eBPF object into the kernel.
+    ///
+    /// Fails if the object was built against a different `sidecar-common`
+    /// layout than this library.
+    pub fn load() -> Result<Self> {
+        Self::load_with_verifier_log(VerifierLog::default())
+    }
+
+    /// Like [`Monitor::load`], requesting `verifier_log` detail for
+    /// rejected programs.
+    pub fn load_with_verifier_log(verifier_log: VerifierLog) -> Result<Self> {
+        Self::load_with(LoadOptions {
+            verifier_log,
+            ..Default::default()
+        })
+    }
+
+    /// Load the eBPF object as `options` say.
+    ///
+    /// The state maps start empty: pins left in the pin directory by an
+    /// earlier run are replaced, never taken over. They are only pinned with
+    /// [`LoadOptions::pin_dir`] or [`LoadOptions::reloadable`].
+    pub fn load_with(options: LoadOptions) -> Result<Self> {
+        bump_memlock_rlimit()?;
+        remove_stale_pin_dirs();
+
+        let (pin_dir, private_pin_dir) = match options.pin_dir {
+            Some(ref dir) => {
+                std::fs::create_dir_all(dir).with_context(|| {
+                    format!(
+                        "Failed to create pin directory {} (is bpffs mounted?)",
+                        dir.display()
+                    )
+                })?;
+                (Some(dir.clone()), false)
+            }
+            None if options.reloadable => {
+                let dir = Path::new(BPFFS).join(format!("sidecar-{}", std::process::id()));
+                match std::fs::create_dir_all(&dir) {
+                    Ok(()) => (Some(dir), true),
+                    Err(e) => {
+                        warn!(
+                            "Failed to create pin directory {} (is bpffs mounted?): {}; \
+                             the maps are not pinned and SIGUSR1 can't reload the programs",
+                            dir.display(),
+                            e
+                        );
+                        (None, false)
+                    }
+                }
+            }
+            None => (None, false),
+        };
+        if let Some(ref dir) = pin_dir {
+            unpin_state_maps(dir);
+        }
+
+        let loaded = load_ebpf_program(&options, pin_dir.as_deref())
+            .and_then(|(bpf, sha)| verify_layout(&bpf).map(|()| (bpf, sha)))
+            .and_then(|(bpf, sha)| verify_arch(&bpf).map(|()| (bpf, sha)));
+        let (mut bpf, bpf_object_sha) = match loaded {
+            Ok(loaded) => loaded,
+            Err(e) => {
+                if let (true, Some(dir)) = (private_pin_dir, &pin_dir) {
+                    unpin_state_maps(dir);
+                    let _ = std::fs::remove_dir(dir);
+                }
+                return Err(e);
+            }
+        };
+
+        if let Err(e) = BpfLogger::init(&mut bpf) {
+            warn!("Failed to initialize eBPF logger: {}", e);
+        }
+        let map_ids = map_ids(&bpf);
+
+        Ok(Self {
+            bpf,
+            bpf_object_sha,
+            load: options,
+            pin_dir,
+            private_pin_dir,
+            l7_parsers: Vec::new(),
+            payload_capture: false,
+            interface_metrics: false,
+            udp_metrics: false,
+            paws_drops: false,
+            fd_tracking: false,
+            retransmit_tracking: false,
+            map_ids,
+            l7_slots: None,
+            cgroup_seed: None,
+            policy_cgroup: None,
+            tracing_functions: Vec::new(),
+            hot_paths: Vec::new(),
+            readers: Vec::new(),
+        })
+    }
+
+    /// Write the monitoring options into the kernel-side config maps.
+    pub fn configure(&mut self, options: &MonitorOptions) -> Result<()> {
+        let target_pids: Vec<u32> = std::iter::once(options.target_pid)
+            .chain(options.extra_pids.iter().copied())
+            .filter(|&pid| pid != 0)
+            .collect();
+        // The kernel only needs a non-zero target_pid to switch PID filtering
+        // on; every seeded PID is matched through TARGET_PIDS
+        let cgroup_dir = options.cgroup.as_deref().map(cgroup::cgroup_dir);
+        let target_cgroup = match cgroup_dir {
+            Some(ref dir) => cgroup::cgroup_id(dir)?,
+            None => 0,
+        };
+        let mut config = SidecarConfig {
+            target_pid: target_pids.first().copied().unwrap_or(0),
+            target_cgroup,
+            debug_mode: options.debug as u8,
+            exclude_loopback: options.exclude_loopback as u8,
+            new_connections_only: options.new_connections_only as u8,
+            sample_rates: kernel_sample_rates(&options.sample_rates),
+            always_track: !options.always_track.is_empty() as u8,
+            enable_http: options.l7_parsers.contains(&L7Protocol::Http) as u8,
+            tls_fingerprints: options.tls_fingerprints as u32,
+            version: CONFIG_VERSION,
+            ..Default::default()
+        };
+        if let Some(capture) = options.payload_capture {
+            config.capture_every = capture.sample_every.max(1);
+            config.capture_max_bytes = capture.max_bytes.min(PAYLOAD_CAPTURE_MAX as u16);
+            config.capture_max_per_sec = capture.max_per_sec.clamp(1, u16::MAX as u32) as u16;
+            warn!(
+                "Payload capture is on: copying up to {} bytes of 1 in {} sends/receives, \
+                 at most {}/s. Captures may contain secrets and personal data",
+                config.capture_max_bytes, config.capture_every, config.capture_max_per_sec
+            );
+        }
+
+        if let Some(gaps) = options.gap_sampling {
+            config.gap_every = gaps.sample_every.max(1);
+            config.gap_max_per_sec = gaps.max_per_sec.max(1);
+        }
+
+        if let Some(stacks) = options.stack_sampling {
+            config.stack_every = stacks.sample_every.max(1);
+            config.stack_max_per_sec = stacks.max_per_sec.max(1);
+        }
+
+        let mut paws_drops = false;
+        if options.paws_drops {
+            match paws_drop_reason() {
+                Ok((reason, offset)) => {
+                    config.paws_drop_reason = reason;
+                    config.kfree_skb_reason_offset = offset;
+                    paws_drops = true;
+                }
+                Err(e) => warn!("Not counting PAWS drops: {:#}", e),
+            }
+        }
+
+        let (kernel_ports, overflow_ports) = split_ports(&options.ports, &options.priority_ports);
+        for (i, port) in kernel_ports.iter().enumerate() {
+            config.target_ports[i] = *port;
+        }
+        config.num_target_ports = kernel_ports.len() as u8;
+        if !overflow_ports.is_empty() {
+            config.ports_overflow = 1;
+            warn!(
+                "Only {} ports fit the kernel filter; filtering {:?} in userspace at export",
+                MAX_TARGET_PORTS, overflow_ports
+            );
+        }
+
+        for (probe, rate) in options.sample_rates.named() {
+            if rate < 100 {
+                info!("Sampling {}% of {} calls", rate, probe);
+            }
+        }
+
+        self.update_always_track(&options.always_track)?;
+
+        let mut config_map: Array<_, SidecarConfig> = Array::try_from(
+            self.bpf
+                .map_mut("CONFIG")
+                .context("Failed to get CONFIG map")?,
+        )?;
+        config_map.set(0, config, 0)?;
+
+        // Seed the process tree; forked children are added by the kernel side
+        if !target_pids.is_empty() {
+            let mut pid_map: HashMap<_, u32, u8> = HashMap::try_from(
+                self.bpf
+                    .map_mut("TARGET_PIDS")
+                    .context("Failed to get TARGET_PIDS map")?,
+            )?;
+            for pid in target_pids {
+                pid_map.insert(pid, 0, 0)?;
+            }
+        }
+
+        // Seed the cgroup subtree; cgroup_targets() keeps it current
+        if let Some(ref dir) = cgroup_dir {
+            let mut cgroup_map: HashMap<_, u64, u8> = HashMap::try_from(
+                self.bpf
+                    .map_mut("TARGET_CGROUPS")
+                    .context("Failed to get TARGET_CGROUPS map")?,
+            )?;
+            let ids = cgroup::seed(&mut cgroup_map, dir)?;
+            info!("Monitoring {} cgroups under {}", ids.len(), dir.display());
+            self.cgroup_seed = Some((dir.clone(), ids));
+        }
+
+        self.update_denylist(&options.denylist)?;
+        self.policy_cgroup = if options.denylist.is_empty() {
+            None
+        } else {
+            Some(cgroup_dir.unwrap_or_else(|| PathBuf::from(cgroup::CGROUP_ROOT)))
+        };
+
+        self.l7_parsers = options.l7_parsers.clone();
+        self.payload_capture = options.payload_capture.is_some();
+        self.interface_metrics = options.interface_metrics;
+        self.udp_metrics = options.udp_metrics;
+        self.paws_drops = paws_drops;
+        self.fd_tracking = options.fd_tracking;
+        self.update_l7_parsers()?;
+
+        debug!("Configuration applied: {:?}", config);
+        Ok(())
+    }
+
+    /// Replace the rules in `POLICY_DENYLIST`; the value of each is its index
+    /// in `rules`, reported back in [`PolicyEvent::rule`].
+    fn update_denylist(&mut self, rules: &[PolicyRule]) -> Result<()> {
+        let mut denylist: LpmTrie<_, PolicyTarget, u32> = LpmTrie::try_from(
+            self.bpf
+                .map_mut("POLICY_DENYLIST")
+                .context("Failed to get POLICY_DENYLIST map")?,
+        )?;
+
+        let stale = denylist.keys().collect::<Result<Vec<_>, _>>()?;
+        for key in stale {
+            denylist.remove(&key)?;
+        }
+        for (i, rule) in rules.iter().enumerate() {
+            denylist.insert(&rule.key(), i as u32, 0)?;
+        }
+
+        Ok(())
+    }
+
+    /// Replace the destinations in `ALWAYS_TRACK`.
+    fn update_always_track(&mut self, rules: &[PolicyRule]) -> Result<()> {
+        let mut always_track: LpmTrie<_, PolicyTarget, u8> = LpmTrie::try_from(
+            self.bpf
+                .map_mut("ALWAYS_TRACK")
+                .context("Failed to get ALWAYS_TRACK map")?,
+        )?;
+
+        let stale = always_track.keys().collect::<Result<Vec<_>, _>>()?;
+        for key in stale {
+            always_track.remove(&key)?;
+        }
+        for rule in rules {
+            always_track.insert(&rule.key(), 0, 0)?;
+        }
+
+        Ok(())
+    }
+
+    /// Hand over the configured cgroup subtree for periodic
+    /// [`CgroupTargets::refresh`], or `None` if no cgroup is monitored.
+    ///
+    /// Takes the `TARGET_CGROUPS` map out of the monitor, so call it once,
+    /// after the final `configure`.
+    pub fn cgroup_targets(&mut self) -> Result<Option<CgroupTargets>> {
+        let Some((dir, ids)) = self.cgroup_seed.take() else {
+            return Ok(None);
+        };
+        let map = HashMap::try_from(
+            self.bpf
+                .take_map("TARGET_CGROUPS")
+                .context("Failed to get TARGET_CGROUPS map")?,
+        )?;
+        Ok(Some(CgroupTargets::new(dir, map, ids)))
+    }
+
+    /// Attach the retransmit counter: the `tcp:tcp_retransmit_skb`
+    /// tracepoint, or a kprobe on the function on kernels without it. Only
+    /// one of them, or every retransmit counts twice. Returns whether either
+    /// is attached; without them retransmit counts stay 0.
+    fn attach_retransmits(&mut self) -> Result<bool> {
+        let tp: &mut TracePoint = self
+            .bpf
+            .program_mut("trace_tcp_retransmit")
+            .context("Failed to get program trace_tcp_retransmit")?
+            .try_into()?;
+        tp.load()
+            .context("Failed to load program trace_tcp_retransmit")?;
+        let tracepoint_error = match tp.attach("tcp", "tcp_retransmit_skb") {
+            Ok(_) => {
+                info!("Attached trace_tcp_retransmit to tcp:tcp_retransmit_skb");
+                return Ok(true);
+            }
+            Err(e) => e,
+        };
+
+        let program: &mut KProbe = self
+            .bpf
+            .program_mut("trace_tcp_retransmit_skb")
+            .context("Failed to get program trace_tcp_retransmit_skb")?
+            .try_into()?;
+        program
+            .load()
+            .context("Failed to load program trace_tcp_retransmit_skb")?;
+        match program.attach("tcp_retransmit_skb", 0) {
+            Ok(_) => {
+                warn!(
+                    "Counting retransmits by kprobe (tcp:tcp_retransmit_skb unavailable: {})",
+                    tracepoint_error
+                );
+                Ok(true)
+            }
+            Err(e) => {
+                warn!(
+                    "Not counting retransmits (tcp:tcp_retransmit_skb unavailable: {}; \
+                     tcp_retransmit_skb unavailable: {})",
+                    tracepoint_error, e
+                );
+                Ok(false)
+            }
+        }
+    }
+
+    /// Load and attach all probes and tracepoints.
+    ///
+    /// Kernel functions are probed with fentry/fexit programs when the
+    /// kernel has BTF (5.5+), and with kprobes otherwise or when an
+    /// fentry/fexit program fails to load. The send path stays on kprobes
+    /// when L7 parsers are enabled, and the receive path when HTTP is:
+    /// the parsers are kprobe programs, which tracing programs can't
+    /// tail-call. Both stay on kprobes with payload capture on, which only
+    /// the kprobes do.
+    pub fn attach(&mut self) -> Result<()> {
+        let btf = tracing_btf();
+        let kernel = KernelVersion::current().ok();
+        // fexit finds the return value after the arguments, and 5.19
+        // dropped one from tcp_recvmsg
+        let recvmsg_fexit = kernel.is_some_and(|v| v >= KernelVersion::new(5, 19, 0))
+            && !self.l7_parsers.contains(&L7Protocol::Http)
+            && !self.payload_capture;
+        let send_fentry = self.l7_parsers.is_empty() && !self.payload_capture;
+
+        let programs = [
+            (
+                "tcp_connect",
+                &["trace_tcp_connect"][..],
+                Some(Tracing::FEntry("fentry_tcp_connect")),
+            ),
+            (
+                "tcp_sendmsg",
+                &["trace_tcp_sendmsg"],
+                send_fentry.then_some(Tracing::FEntry("fentry_tcp_sendmsg")),
+            ),
+            (
+                "tcp_recvmsg",
+                &["trace_tcp_recvmsg", "trace_tcp_recvmsg_ret"],
+                recvmsg_fexit.then_some(Tracing::FExit("fexit_tcp_recvmsg")),
+            ),
+            (
+                "tcp_close",
+                &["trace_tcp_close"],
+                Some(Tracing::FEntry("fentry_tcp_close")),
+            ),
+            (
+                "ip_queue_xmit",
+                &["trace_ip_queue_xmit"],
+                Some(Tracing::FEntry("fentry_ip_queue_xmit")),
+            ),
+            (
+                "tcp_reset",
+                &["trace_tcp_reset"],
+                Some(Tracing::FEntry("fentry_tcp_reset")),
+            ),
+            (
+                "tcp_send_active_reset",
+                &["trace_tcp_send_active_reset"],
+                Some(Tracing::FEntry("fentry_tcp_send_active_reset")),
+            ),
+            ("inet_csk_accept", &["trace_inet_csk_accept"], None),
+        ];
+
+        for (fn_name, kprobes, tracing) in programs {
+            let hot_path = HOT_PATH_FUNCTIONS.contains(&fn_name);
+            if let (Some(btf), Some(tracing)) = (btf.as_ref(), tracing) {
+                match self.attach_tracing(tracing, fn_name, btf) {
+                    Ok(link) => {
+                        info!("Attached {} to {}", tracing.program(), fn_name);
+                        self.tracing_functions.push(fn_name);
+                        if hot_path {
+                            self.hot_paths.push(HotPathProgram {
+                                program: tracing.program(),
+                                fn_name,
+                                tracing: Some(tracing),
+                                link: Some(link),
+                            });
+                        }
+                        continue;
+                    }
+                    Err(e) => warn!(
+                        "Failed to attach {} to {}, falling back to kprobes: {:#}",
+                        tracing.program(),
+                        fn_name,
+                        e
+                    ),
+                }
+            }
+
+            for &prog_name in kprobes {
+                let program: &mut KProbe = self
+                    .bpf
+                    .program_mut(prog_name)
+                    .context(format!("Failed to get program {}", prog_name))?
+                    .try_into()?;
+                program
+                    .load()
+                    .with_context(|| format!("Failed to load program {}", prog_name))?;
+                let link = program.attach(fn_name, 0)?;
+                info!("Attached {} to {}", prog_name, fn_name);
+                if hot_path {
+                    self.hot_paths.push(HotPathProgram {
+                        program: prog_name,
+                        fn_name,
+                        tracing: None,
+                        link: Some(ProgramLink::KProbe(link)),
+                    });
+                }
+            }
+        }
+
+        // MPTCP sockets move data to and from their subflows without
+        // tcp_sendmsg/tcp_recvmsg. Kernels built without MPTCP lack these
+        // functions, which only loses MPTCP byte counts.
+        let mptcp_programs = [
+            ("mptcp_sendmsg", &["trace_mptcp_sendmsg"][..]),
+            (
+                "mptcp_recvmsg",
+                &["trace_mptcp_recvmsg", "trace_mptcp_recvmsg_ret"],
+            ),
+        ];
+        for (fn_name, kprobes) in mptcp_programs {
+            for &prog_name in kprobes {
+                let program: &mut KProbe = self
+                    .bpf
+                    .program_mut(prog_name)
+                    .context(format!("Failed to get program {}", prog_name))?
+                    .try_into()?;
+                program
+                    .load()
+                    .with_context(|| format!("Failed to load program {}", prog_name))?;
+                match program.attach(fn_name, 0) {
+                    Ok(_) => info!("Attached {} to {}", prog_name, fn_name),
+                    Err(e) => {
+                        info!(
+                            "Not tracking MPTCP sockets ({} unavailable: {})",
+                            fn_name, e
+                        );
+                        break;
+                    }
+                }
+            }
+        }
+
+        // A static function: kernels that inline it lose SACK counts only
+        let program: &mut KProbe = self
+            .bpf
+            .program_mut("trace_tcp_check_dsack")
+            .context("Failed to get program trace_tcp_check_dsack")?
+            .try_into()?;
+        program
+            .load()
+            .context("Failed to load program trace_tcp_check_dsack")?;
+        match program.attach("tcp_check_dsack", 0) {
+            Ok(_) => info!("Attached trace_tcp_check_dsack to tcp_check_dsack"),
+            Err(e) => info!(
+                "Not counting SACK blocks (tcp_check_dsack unavailable: {})",
+                e
+            ),
+        }
+
+        self.retransmit_tracking = self.attach_retransmits()?;
+
+        let tracepoints = [
+            ("trace_sched_process_fork", "sched", "sched_process_fork"),
+            ("trace_sched_process_exit", "sched", "sched_process_exit"),
+            ("trace_inet_sock_set_state", "sock", "inet_sock_set_state"),
+            ("trace_sched_process_exec", "sched", "sched_process_exec"),
+        ];
+        // Fire for every packet on the host, so only when asked for
+        let interface_tracepoints = [
+            ("trace_net_dev_xmit", "net", "net_dev_xmit"),
+            ("trace_netif_receive_skb", "net", "netif_receive_skb"),
+        ];
+        let interface_tracepoints = match self.interface_metrics {
+            true => &interface_tracepoints[..],
+            false => &[],
+        };
+        // Fires for every dropped packet on the host
+        let paws_tracepoints = [("trace_kfree_skb", "skb", "kfree_skb")];
+        let paws_tracepoints = match self.paws_drops {
+            true => &paws_tracepoints[..],
+            false => &[],
+        };
+
+        let tracepoints = tracepoints
+            .iter()
+            .chain(interface_tracepoints)
+            .chain(paws_tracepoints);
+        for &(prog_name, category, name) in tracepoints {
+            let tp: &mut TracePoint = self
+                .bpf
+                .program_mut(prog_name)
+                .context(format!("Failed to get program {}", prog_name))?
+                .try_into()?;
+            tp.load()
+                .with_context(|| format!("Failed to load program {}", prog_name))?;
+            tp.attach(category, name)?;
+            info!("Attached {} to {}:{}", prog_name, category, name);
+        }
+
+        // Every UDP send and receive of the monitored processes, so only
+        // when asked for. The IPv6 functions are missing without IPv6.
+        let udp_programs = [
+            ("trace_udp_sendmsg", ["udp_sendmsg", "udpv6_sendmsg"]),
+            ("trace_udp_recvmsg_ret", ["udp_recvmsg", "udpv6_recvmsg"]),
+        ];
+        let udp_programs = match self.udp_metrics {
+            true => &udp_programs[..],
+            false => &[],
+        };
+        for (prog_name, fn_names) in udp_programs {
+            let program: &mut KProbe = self
+                .bpf
+                .program_mut(prog_name)
+                .context(format!("Failed to get program {}", prog_name))?
+                .try_into()?;
+            program
+                .load()
+                .with_context(|| format!("Failed to load program {}", prog_name))?;
+            for fn_name in fn_names {
+                match program.attach(fn_name, 0) {
+                    Ok(_) => info!("Attached {} to {}", prog_name, fn_name),
+                    Err(e) => warn!("Not counting UDP datagrams of {}: {}", fn_name, e),
+                }
+            }
+        }
+
+        if self.fd_tracking {
+            let tp: &mut TracePoint = self
+                .bpf
+                .program_mut("trace_sys_enter_fd")
+                .context("Failed to get program trace_sys_enter_fd")?
+                .try_into()?;
+            tp.load()
+                .context("Failed to load program trace_sys_enter_fd")?;
+            for name in FD_SYSCALLS {
+                let event = format!("sys_enter_{}", name);
+                match tp.attach("syscalls", &event) {
+                    Ok(_) => info!("Attached trace_sys_enter_fd to syscalls:{}", event),
+                    Err(e) => warn!("Not recording descriptors of {}: {}", name, e),
+                }
+            }
+        }
+
+        // Parsers are only ever reached by tail call from the send/receive path.
+        // Load all of them so enabling one later needs no reload.
+        for proto in L7Protocol::ALL {
+            let program: &mut KProbe = self
+                .bpf
+                .program_mut(proto.program())
+                .context(format!("Failed to get program {}", proto.program()))?
+                .try_into()?;
+            program
+                .load()
+                .with_context(|| format!("Failed to load program {}", proto.program()))?;
+        }
+        self.l7_slots = Some(ProgramArray::try_from(
+            self.bpf
+                .take_map("L7_PARSERS")
+                .context("Failed to get L7_PARSERS map")?,
+        )?);
+        self.update_l7_parsers()?;
+
+        if let Some(dir) = self.policy_cgroup.clone() {
+            self.attach_policy(&dir)?;
+        }
+
+        Ok(())
+    }
+
+    /// Load `tracing` against the kernel's BTF and attach it to `fn_name`.
+    fn attach_tracing(
+        &mut self,
+        tracing: Tracing,
+        fn_name: &str,
+        btf: &Btf,
+    ) -> Result<ProgramLink> {
+        let program = self
+            .bpf
+            .program_mut(tracing.program())
+            .context(format!("Failed to get program {}", tracing.program()))?;
+        let link = match tracing {
+            Tracing::FEntry(_) => {
+                let program: &mut FEntry = program.try_into()?;
+                program.load(fn_name, btf)?;
+                ProgramLink::FEntry(program.attach()?)
+            }
+            Tracing::FExit(_) => {
+                let program: &mut FExit = program.try_into()?;
+                program.load(fn_name, btf)?;
+                ProgramLink::FExit(program.attach()?)
+            }
+        };
+        Ok(link)
+    }
+
+    /// Detach the programs on `tcp_sendmsg` and `tcp_recvmsg`, or attach
+    /// them again. Detached, the send and receive paths run without any
+    /// probe: byte and packet counts of tracked connections stop, and so do
+    /// L7 parsing, payload capture and inter-packet gaps. Connection
+    /// lifecycle, retransmits and resets keep being recorded.
+    ///
+    /// A no-op for programs already in the requested state. Reloading the
+    /// programs attaches them all again.
+    pub fn set_hot_paths_attached(&mut self, attached: bool) -> Result<()> {
+        for hot in &mut self.hot_paths {
+            let program = self
+                .bpf
+                .program_mut(hot.program)
+                .context(format!("Failed to get program {}", hot.program))?;
+            match (attached, hot.link.take()) {
+                (false, Some(ProgramLink::KProbe(link))) => {
+                    <&mut KProbe>::try_from(program)?.detach(link)?
+                }
+                (false, Some(ProgramLink::FEntry(link))) => {
+                    <&mut FEntry>::try_from(program)?.detach(link)?
+                }
+                (false, Some(ProgramLink::FExit(link))) => {
+                    <&mut FExit>::try_from(program)?.detach(link)?
+                }
+                (true, None) => {
+                    hot.link = Some(match hot.tracing {
+                        None => ProgramLink::KProbe(
+                            <&mut KProbe>::try_from(program)?.attach(hot.fn_name, 0)?,
+                        ),
+                        Some(Tracing::FEntry(_)) => {
+                            ProgramLink::FEntry(<&mut FEntry>::try_from(program)?.attach()?)
+                        }
+                        Some(Tracing::FExit(_)) => {
+                            ProgramLink::FExit(<&mut FExit>::try_from(program)?.attach()?)
+                        }
+                    });
+                }
+                (_, link) => hot.link = link,
+            }
+        }
+        Ok(())
+    }
+
+    /// Attach the observe-only `connect()` policy hooks to the cgroup at
+    /// `dir`; they cover every process in it and below it.
+    fn attach_policy(&mut self, dir: &Path) -> Result<()> {
+        let cgroup = std::fs::File::open(dir)
+            .with_context(|| format!("Failed to open cgroup {}", dir.display()))?;
+
+        for prog_name in ["policy_connect4", "policy_connect6"] {
+            let program: &mut CgroupSockAddr = self
+                .bpf
+                .program_mut(prog_name)
+                .context(format!("Failed to get program {}", prog_name))?
+                .try_into()?;
+            program
+                .load()
+                .with_context(|| format!("Failed to load program {}", prog_name))?;
+            program.attach(&cgroup)?;
+            info!("Attached {} to cgroup {}", prog_name, dir.display());
+        }
+
+        Ok(())
+    }
+
+    /// Fill the `L7_PARSERS` slots of enabled parsers and clear the rest.
+    /// A no-op before `attach`, which calls it once the parsers are loaded.
+    fn update_l7_parsers(&mut self) -> Result<()> {
+        let slots = match self.l7_slots.as_mut() {
+            Some(slots) => slots,
+            None => return Ok(()),
+        };
+
+        for proto in L7Protocol::ALL {
+            if self.l7_parsers.contains(&proto) {
+                let program: &KProbe = self
+                    .bpf
+                    .program(proto.program())
+                    .context(format!("Failed to get program {}", proto.program()))?
+                    .try_into()?;
+                slots.set(proto.slot(), program.fd()?, 0)?;
+                info!("Enabled L7 parser {}", proto.program());
+                if self.tracing_functions.contains(&"tcp_sendmsg") {
+                    warn!(
+                        "L7 parser {} won't run: tcp_sendmsg was attached with fentry",
+                        proto.program()
+                    );
+                }
+            } else {
+                // Fails if the slot is already empty
+                let _ = slots.clear_index(&proto.slot());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Stream connection close events from the kernel.
+    ///
+    /// Spawns one reader task per online CPU on the current tokio runtime.
+    /// Can only be called once: the perf buffer is moved out of the monitor.
+    pub fn close_events(&mut self) -> Result<mpsc::UnboundedReceiver<ConnCloseEvent>> {
+        self.perf_events("CLOSE_EVENTS", "close")
+    }
+
+    /// Stream HTTP request/response events from the kernel.
+    ///
+    /// Same threading and single-call rules as [`Monitor::close_events`].
+    pub fn http_events(&mut self) -> Result<mpsc::UnboundedReceiver<HttpEvent>> {
+        self.perf_events("EVENTS", "http")
+    }
+
+    /// Stream connect attempts matching the configured denylist.
+    ///
+    /// Same threading and single-call rules as [`Monitor::close_events`].
+    pub fn policy_events(&mut self) -> Result<mpsc::UnboundedReceiver<PolicyEvent>> {
+        self.perf_events("POLICY_EVENTS", "policy")
+    }
+
+    /// Stream sampled payload captures (see [`MonitorOptions::payload_capture`]).
+    ///
+    /// Same threading and single-call rules as [`Monitor::close_events`].
+    pub fn payload_events(&mut self) -> Result<mpsc::UnboundedReceiver<PayloadEvent>> {
+        self.perf_events("PAYLOAD_EVENTS", "payload")
+    }
+
+    /// Stream sampled gaps between sends and receives (see
+    /// [`MonitorOptions::gap_sampling`]).
+    ///
+    /// Same threading and single-call rules as [`Monitor::close_events`].
+    pub fn gap_events(&mut self) -> Result<mpsc::UnboundedReceiver<GapEvent>> {
+        self.perf_events("GAP_EVENTS", "gap")
+    }
+
+    /// Stream the start of outgoing TLS ClientHellos (see
+    /// [`MonitorOptions::tls_fingerprints`]).
+    ///
+    /// Same threading and single-call rules as [`Monitor::close_events`].
+    pub fn tls_hellos(&mut self) -> Result<mpsc::UnboundedReceiver<TlsHelloEvent>> {
+        self.perf_events("TLS_HELLO_EVENTS", "tls_hello")
+    }
+
+    /// Stream sampled connects with the ids of their call stacks (see
+    /// [`MonitorOptions::stack_sampling`]), along with the map to read the
+    /// stacks from. The map is pinned, so it stays valid across reloads.
+    ///
+    /// Same threading and single-call rules as [`Monitor::close_events`].
+    pub fn connect_stacks(
+        &mut self,
+    ) -> Result<(
+        mpsc::UnboundedReceiver<ConnectStackEvent>,
+        StackTraceMap<MapData>,
+    )> {
+        let stacks = StackTraceMap::try_from(
+            self.bpf
+                .take_map("STACK_TRACES")
+                .context("Failed to get STACK_TRACES map")?,
+        )?;
+        Ok((self.perf_events("STACK_EVENTS", "connect_stack")?, stacks))
+    }
+
+    /// Forward every event from the perf buffer map `name` into a channel,
+    /// through one reader task per CPU watched by
+    /// [`ConnectionSource::check_event_readers`] as `events`.
+    fn perf_events<T: Copy + Send + 'static>(
+        &mut self,
+        name: &str,
+        events: &'static str,
+    ) -> Result<mpsc::UnboundedReceiver<T>> {
+        let perf_array = Arc::new(Mutex::new(AsyncPerfEventArray::try_from(
+            self.bpf
+                .take_map(name)
+                .context(format!("Failed to get {} map", name))?,
+        )?));
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        for cpu_id in online_cpus()? {
+            let liveness = Arc::new(ReaderLiveness::default());
+            let perf_array = perf_array.clone();
+            let tx = tx.clone();
+            let task_liveness = liveness.clone();
+            // Opening the CPU's buffer again replaces the one the previous
+            // reader held, whether it died or is merely stuck
+            let start = move || -> Result<()> {
+                if tx.is_closed() {
+                    anyhow::bail!("nothing receives {} events any more", events);
+                }
+                let buf = perf_array.lock().unwrap().open(cpu_id, None)?;
+                let generation = task_liveness.generation.fetch_add(1, Ordering::Relaxed) + 1;
+                task_liveness.heartbeat.store(now_ms(), Ordering::Relaxed);
+                let (tx, liveness) = (tx.clone(), task_liveness.clone());
+                tokio::spawn(read_perf_buffer(
+                    buf, tx, events, cpu_id, liveness, generation,
+                ));
+                Ok(())
+            };
+            start()?;
+            self.readers.push(PerfReader {
+                events,
+                cpu: cpu_id,
+                liveness,
+                start: Box::new(start),
+            });
+        }
+
+        Ok(rx)
+    }
+
+    /// bpffs directory the state maps are pinned in, None if they aren't.
+    /// With [`LoadOptions::pin_dir`] set, a separate, unprivileged exporter
+    /// can read them with [`PinnedMaps::open`]; the maps stay pinned after
+    /// this process exits, but the programs feeding them do not.
+    pub fn pin_dir(&self) -> Option<&Path> {
+        self.pin_dir.as_deref()
+    }
+
+    /// Replace the ports the kernel filters on (empty = all), leaving the
+    /// rest of the config as [`Monitor::configure`] wrote it. Takes effect for
+    /// connections seen from now on; tracked ones are kept until they close
+    /// or are removed with [`Monitor::clear_connections`].
+    ///
+    /// Unlike `configure`, nothing overflows to userspace: more than
+    /// [`MAX_TARGET_PORTS`] ports, or port 0, is an error.
+    pub fn set_ports(&mut self, ports: &[u16]) -> Result<()> {
+        if ports.len() > MAX_TARGET_PORTS {
+            anyhow::bail!(
+                "At most {} ports can be monitored, got {}",
+                MAX_TARGET_PORTS,
+                ports.len()
+            );
+        }
+        if ports.contains(&0) {
+            anyhow::bail!("Port 0 can't be monitored");
+        }
+
+        let mut config_map: Array<_, SidecarConfig> = Array::try_from(
+            self.bpf
+                .map_mut("CONFIG")
+                .context("Failed to get CONFIG map")?,
+        )?;
+        let mut config = config_map.get(&0, 0)?;
+        config.target_ports = [0; MAX_TARGET_PORTS];
+        config.target_ports[..ports.len()].copy_from_slice(ports);
+        config.num_target_ports = ports.len() as u8;
+        config.ports_overflow = 0;
+        config_map.set(0, config, 0)?;
+        Ok(())
+    }
+
+    /// Delete the entries of `keys` from the connections map, counting them
+    /// in `conn_stat::CLEARED`. No close event is sent for them, and a
+    /// connection the kernel still tracks gets a fresh entry on its next
+    /// send or receive. Returns how many entries were deleted.
+    pub fn clear_connections(&mut self, keys: &[ConnKey]) -> Result<u64> {
+        let mut connections: HashMap<_, ConnKey, ConnMetrics> = HashMap::try_from(
+            self.bpf
+                .map_mut("CONNECTIONS")
+                .context("Failed to get CONNECTIONS map")?,
+        )?;
+        // An entry closed since the snapshot is already gone
+        let cleared = keys
+            .iter()
+            .filter(|key| connections.remove(key).is_ok())
+            .count() as u64;
+
+        let mut stats: PerCpuArray<_, u64> = PerCpuArray::try_from(
+            self.bpf
+                .map_mut("CONN_STATS")
+                .context("Failed to get CONN_STATS map")?,
+        )?;
+        let mut counts = stats.get(&conn_stat::CLEARED, 0)?.to_vec();
+        counts[0] += cleared;
+        stats.set(conn_stat::CLEARED, PerCpuValues::try_from(counts)?, 0)?;
+        Ok(cleared)
+    }
+
+    /// Replace the running programs with a fresh load of the eBPF object,
+    /// re-read from [`LoadOptions::object`] if set, keeping every state map.
+    ///
+    /// The new object takes over the pinned maps, and its programs are
+    /// attached before the old ones are detached: nothing is missed, but
+    /// events in between can be counted by both. On any failure the old
+    /// programs stay attached. The object must declare the same maps with
+    /// the same layouts; only the shared layout fingerprint and the target
+    /// arch are checked. Fails without touching anything if the maps aren't
+    /// pinned (see [`LoadOptions::reloadable`]).
+    pub fn reload(&mut self) -> Result<()> {
+        let pin_dir = self.pin_dir.as_deref().context(
+            "The state maps are not pinned, so a reload would lose them \
+             (they are pinned with --pin-maps or --bpf-object, on a bpffs mount)",
+        )?;
+        let (mut bpf, sha) = load_ebpf_program(&self.load, Some(pin_dir))?;
+        verify_layout(&bpf)?;
+        verify_arch(&bpf)?;
+        if let Err(e) = BpfLogger::init(&mut bpf) {
+            warn!("Failed to initialize eBPF logger: {}", e);
+        }
+        let map_ids = map_ids(&bpf);
+
+        let old_bpf = std::mem::replace(&mut self.bpf, bpf);
+        let old_slots = self.l7_slots.take();
+        let old_tracing = std::mem::take(&mut self.tracing_functions);
+        let old_hot_paths = std::mem::take(&mut self.hot_paths);
+        let old_retransmit_tracking = self.retransmit_tracking;
+        if let Err(e) = self.attach() {
+            // Dropping the new object detaches whatever it attached
+            self.bpf = old_bpf;
+            self.l7_slots = old_slots;
+            self.tracing_functions = old_tracing;
+            self.hot_paths = old_hot_paths;
+            self.retransmit_tracking = old_retransmit_tracking;
+            return Err(e.context("Failed to attach the reloaded programs"));
+        }
+
+        // Dropping the old object detaches its programs
+        drop(old_slots);
+        drop(old_bpf);
+        self.bpf_object_sha = sha;
+        self.map_ids = map_ids;
+        Ok(())
+    }
+}
+
+impl Drop for Monitor {
+    fn drop(&mut self) {
+        if let (true, Some(dir)) = (self.private_pin_dir, &self.pin_dir) {
+            unpin_state_maps(dir);
+            let _ = std::fs::remove_dir(dir);
+        }
+    }
+}
+
+impl ConnectionSource for Monitor {
+    /// Entries can be removed concurrently by `tcp_close`, so consecutive
+    /// snapshots need not contain the same connections.
+    fn snapshot(&self) -> Result<Vec<Connection>> {
+        let connections: HashMap<_, ConnKey, ConnMetrics> = HashMap::try_from(
+            self.bpf
+                .map("CONNECTIONS")
+                .context("Failed to get CONNECTIONS map")?,
+        )?;
+        read_connections(&connections)
+    }
+
+    fn connect_failures(&self) -> Result<Vec<(ConnFailKey, u64)>> {
+        let failures: HashMap<_, ConnFailKey, u64> = HashMap::try_from(
+            self.bpf
+                .map("CONNECT_FAILURES")
+                .context("Failed to get CONNECT_FAILURES map")?,
+        )?;
+        read_connect_failures(&failures)
+    }
+
+    fn conn_stats(&self) -> Result<ConnStats> {
+        let stats: PerCpuArray<_, u64> = PerCpuArray::try_from(
+            self.bpf
+                .map("CONN_STATS")
+                .context("Failed to get CONN_STATS map")?,
+        )?;
+        read_counters(&stats)
+    }
+
+    fn filter_decisions(&self) -> Result<FilterDecisions> {
+        let decisions: PerCpuArray<_, u64> = PerCpuArray::try_from(
+            self.bpf
+                .map("FILTER_DECISIONS")
+                .context("Failed to get FILTER_DECISIONS map")?,
+        )?;
+        read_counters(&decisions)
+    }
+
+    fn exe_paths(&self) -> Result<std::collections::HashMap<u32, String>> {
+        let exes: HashMap<_, u32, [u8; EXE_PATH_LEN]> = HashMap::try_from(
+            self.bpf
+                .map("PROCESS_EXES")
+                .context("Failed to get PROCESS_EXES map")?,
+        )?;
+        read_exe_paths(&exes)
+    }
+
+    fn byte_history(&self) -> Result<Vec<ByteHistory>> {
+        let history: PerCpuArray<_, ByteHistory> = PerCpuArray::try_from(
+            self.bpf
+                .map("BYTE_HISTORY")
+                .context("Failed to get BYTE_HISTORY map")?,
+        )?;
+        read_byte_history(&history)
+    }
+
+    fn interface_traffic(&self) -> Result<Vec<(IfaceKey, IfaceTraffic)>> {
+        let traffic: PerCpuHashMap<_, IfaceKey, IfaceTraffic> = PerCpuHashMap::try_from(
+            self.bpf
+                .map("IFACE_TRAFFIC")
+                .context("Failed to get IFACE_TRAFFIC map")?,
+        )?;
+        read_interface_traffic(&traffic)
+    }
+
+    fn udp_datagram_sizes(&self) -> Result<[UdpSizeHistogram; 2]> {
+        let sizes: PerCpuArray<_, UdpSizeHistogram> = PerCpuArray::try_from(
+            self.bpf
+                .map("UDP_SIZES")
+                .context("Failed to get UDP_SIZES map")?,
+        )?;
+        read_udp_sizes(&sizes)
+    }
+
+    fn paws_drops(&self) -> Result<u64> {
+        let drops: PerCpuArray<_, u64> = PerCpuArray::try_from(
+            self.bpf
+                .map("PAWS_DROPS")
+                .context("Failed to get PAWS_DROPS map")?,
+        )?;
+        Ok(drops.get(&0, 0)?.iter().sum())
+    }
+
+    fn arg_read_errors(&self) -> Result<u64> {
+        let errors: PerCpuArray<_, u64> = PerCpuArray::try_from(
+            self.bpf
+                .map("ARG_READ_ERRORS")
+                .context("Failed to get ARG_READ_ERRORS map")?,
+        )?;
+        Ok(errors.get(&0, 0)?.iter().sum())
+    }
+
+    fn reload(&mut self) -> Result<()> {
+        Monitor::reload(self)
+    }
+
+    fn set_hot_paths_attached(&mut self, attached: bool) -> Result<()> {
+        Monitor::set_hot_paths_attached(self, attached)
+    }
+
+    fn set_ports(&mut self, ports: &[u16]) -> Result<()> {
+        Monitor::set_ports(self, ports)
+    }
+
+    fn clear_connections(&mut self, keys: &[ConnKey]) -> Result<u64> {
+        Monitor::clear_connections(self, keys)
+    }
+
+    /// Also resets the kernel's running count to `live`, which drops the
+    /// entries the LRU evicted without a close.
+    ///
+    /// The counters are read through a shared mapping of the map rather
+    /// than the map syscalls, so the peak is swapped out atomically: a CPU
+    /// raising it while it is read lands either in this peak or the next.
+    fn take_active_peak(&mut self, live: u64) -> Result<Option<u64>> {
+        let Some(Map::Array(active)) = self.bpf.map("ACTIVE_CONNS") else {
+            anyhow::bail!("Failed to get ACTIVE_CONNS map");
+        };
+        let counters = ActiveCounters::map(active)?;
+        counters
+            .get(active_conn::CURRENT)
+            .store(live, Ordering::Relaxed);
+        let peak = counters
+            .get(active_conn::PEAK)
+            .swap(live, Ordering::Relaxed);
+        Ok(Some(peak.max(live)))
+    }
+
+    fn check_event_readers(&mut self, restart: bool) -> Vec<ReaderStatus> {
+        let now = now_ms();
+        self.readers
+            .iter()
+            .map(|reader| {
+                let last = reader.liveness.heartbeat.load(Ordering::Relaxed);
+                let heartbeat_age = Duration::from_millis(now.saturating_sub(last));
+                let stalled = heartbeat_age > READER_STALL_AFTER;
+                let restarted =
+                    (stalled && restart).then(|| (reader.start)().map_err(|e| format!("{:#}", e)));
+                ReaderStatus {
+                    events: reader.events,
+                    cpu: reader.cpu,
+                    heartbeat_age,
+                    stalled,
+                    restarted,
+                }
+            })
+            .collect()
+    }
+
+    fn bpf_object_sha(&self) -> Option<&str> {
+        Some(&self.bpf_object_sha)
+    }
+
+    fn retransmit_tracking(&self) -> Option<bool> {
+        Some(self.retransmit_tracking)
+    }
+
+    fn map_ids(&self) -> Vec<(String, u32)> {
+        self.map_ids.clone()
+    }
+}
+
+/// Read-only view of maps pinned by another sidecar process.
+///
+/// Opening pinned maps needs no program-loading privileges, so an exporter
+/// built on this can run with far fewer capabilities than the loader.
+pub struct PinnedMaps {
+    connections: HashMap<MapData, ConnKey, ConnMetrics>,
+    config: Array<MapData, SidecarConfig>,
+    connect_failures: HashMap<MapData, ConnFailKey, u64>,
+    conn_stats: PerCpuArray<MapData, u64>,
+    filter_decisions: PerCpuArray<MapData, u64>,
+    process_exes: HashMap<MapData, u32, [u8; EXE_PATH_LEN]>,
+    byte_history: PerCpuArray<MapData, ByteHistory>,
+    iface_traffic: PerCpuHashMap<MapData, IfaceKey, IfaceTraffic>,
+    udp_sizes: PerCpuArray<MapData, UdpSizeHistogram>,
+    paws_drops: PerCpuArray<MapData, u64>,
+    arg_read_errors: PerCpuArray<MapData, u64>,
+}
+
+impl PinnedMaps {
+    /// Open the maps pinned under `dir` by a [`Monitor`] loaded with
+    /// [`LoadOptions::pin_dir`].
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<Self> {
+        let dir = dir.as_ref();
+        let open = |name: &str| {
+            let path = dir.join(name);
+            MapData::from_pin(&path)
+                .with_context(|| format!("Failed to open pinned map {}", path.display()))
+        };
+
+        Ok(Self {
+            connections: HashMap::try_from(Map::HashMap(open("CONNECTIONS")?))?,
+            config: Array::try_from(Map::Array(open("CONFIG")?))?,
+            connect_failures: HashMap::try_from(Map::HashMap(open("CONNECT_FAILURES")?))?,
+            conn_stats: PerCpuArray::try_from(Map::PerCpuArray(open("CONN_STATS")?))?,
+            filter_decisions: PerCpuArray::try_from(Map::PerCpuArray(open("FILTER_DECISIONS")?))?,
+            process_exes: HashMap::try_from(Map::HashMap(open("PROCESS_EXES")?))?,
+            byte_history: PerCpuArray::try_from(Map::PerCpuArray(open("BYTE_HISTORY")?))?,
+            iface_traffic: PerCpuHashMap::try_from(Map::PerCpuLruHashMap(open("IFACE_TRAFFIC")?))?,
+            udp_sizes: PerCpuArray::try_from(Map::PerCpuArray(open("UDP_SIZES")?))?,
+            paws_drops: PerCpuArray::try_from(Map::PerCpuArray(open("PAWS_DROPS")?))?,
+            arg_read_errors: PerCpuArray::try_from(Map::PerCpuArray(open("ARG_READ_ERRORS")?))?,
+        })
+    }
+
+    /// Configuration the loader wrote into the kernel.
+    pub fn config(&self) -> Result<SidecarConfig> {
+        Ok(self.config.get(&0, 0)?)
+    }
+}
+
+impl ConnectionSource for PinnedMaps {
+    fn snapshot(&self) -> Result<Vec<Connection>> {
+        read_connections(&self.connections)
+    }
+
+    fn connect_failures(&self) -> Result<Vec<(ConnFailKey, u64)>> {
+        read_connect_failures(&self.connect_failures)
+    }
+
+    fn conn_stats(&self) -> Result<ConnStats> {
+        read_counters(&self.conn_stats)
+    }
+
+    fn filter_decisions(&self) -> Result<FilterDecisions> {
+        read_counters(&self.filter_decisions)
+    }
+
+    fn exe_paths(&self) -> Result<std::collections::HashMap<u32, String>> {
+        read_exe_paths(&self.process_exes)
+    }
+
+    fn byte_history(&self) -> Result<Vec<ByteHistory>> {
+        read_byte_history(&self.byte_history)
+    }
+
+    fn interface_traffic(&self) -> Result<Vec<(IfaceKey, IfaceTraffic)>> {
+        read_interface_traffic(&self.iface_traffic)
+    }
+
+    fn udp_datagram_sizes(&self) -> Result<[UdpSizeHistogram; 2]> {
+        read_udp_sizes(&self.udp_sizes)
+    }
+
+    fn paws_drops(&self) -> Result<u64> {
+        Ok(self.paws_drops.get(&0, 0)?.iter().sum())
+    }
+
+    fn arg_read_errors(&self) -> Result<u64> {
+        Ok(self.arg_read_errors.get(&0, 0)?.iter().sum())
+    }
+}
+
+fn read_connections<T: std::borrow::Borrow<MapData>>(
+    connections: &HashMap<T, ConnKey, ConnMetrics>,
+) -> Result<Vec<Connection>> {
+    let mut snapshot = Vec::new();
+    for result in connections.iter() {
+        let (key, metrics) = result?;
+        snapshot.push(Connection { key, metrics });
+    }
+
+    Ok(snapshot)
+}
+
+fn read_connect_failures<T: std::borrow::Borrow<MapData>>(
+    failures: &HashMap<T, ConnFailKey, u64>,
+) -> Result<Vec<(ConnFailKey, u64)>> {
+    failures.iter().map(|r| r.map_err(Into::into)).collect()
+}
+
+/// Sum each of the first `N` per-CPU counters over all CPUs.
+fn read_counters<T: std::borrow::Borrow<MapData>, const N: usize>(
+    counters: &PerCpuArray<T, u64>,
+) -> Result<[u64; N]> {
+    let mut totals = [0; N];
+    for (index, total) in totals.iter_mut().enumerate() {
+        *total = counters.get(&(index as u32), 0)?.iter().sum();
+    }
+
+    Ok(totals)
+}
+
+fn read_exe_paths<T: std::borrow::Borrow<MapData>>(
+    exes: &HashMap<T, u32, [u8; EXE_PATH_LEN]>,
+) -> Result<std::collections::HashMap<u32, String>> {
+    let mut paths = std::collections::HashMap::new();
+    for result in exes.iter() {
+        let (pid, raw) = result?;
+        paths.insert(pid, exe_path(&raw));
+    }
+
+    Ok(paths)
+}
+
+fn read_byte_history<T: std::borrow::Borrow<MapData>>(
+    history: &PerCpuArray<T, ByteHistory>,
+) -> Result<Vec<ByteHistory>> {
+    Ok(history.get(&0, 0)?.iter().copied().collect())
+}
+
+fn read_interface_traffic<T: std::borrow::Borrow<MapData>>(
+    traffic: &PerCpuHashMap<T, IfaceKey, IfaceTraffic>,
+) -> Result<Vec<(IfaceKey, IfaceTraffic)>> {
+    let mut totals = Vec::new();
+    for result in traffic.iter() {
+        let (key, per_cpu) = result?;
+        let total = per_cpu
+            .iter()
+            .fold(IfaceTraffic::default(), |sum, cpu| IfaceTraffic {
+                rx_bytes: sum.rx_bytes + cpu.rx_bytes,
+                tx_bytes: sum.tx_bytes + cpu.tx_bytes,
+            });
+        totals.push((key, total));
+    }
+
+    Ok(totals)
+}
+
+fn read_udp_sizes<T: std::borrow::Borrow<MapData>>(
+    sizes: &PerCpuArray<T, UdpSizeHistogram>,
+) -> Result<[UdpSizeHistogram; 2]> {
+    let mut totals = [UdpSizeHistogram::default(); 2];
+    for (index, total) in totals.iter_mut().enumerate() {
+        for cpu in sizes.get(&(index as u32), 0)?.iter() {
+            for (sum, count) in total.counts.iter_mut().zip(cpu.counts) {
+                *sum += count;
+            }
+            total.sum += cpu.sum;
+        }
+    }
+
+    Ok(totals)
+}
+
+/// Where tracefs is mounted: its own mount, or under debugfs on older setups
+const TRACEFS_DIRS: [&str; 2] = ["/sys/kernel/tracing", "/sys/kernel/debug/tracing"];
+
+/// `SKB_DROP_REASON_TCP_RFC7323_PAWS` and the offset of `reason` in the
+/// `skb:kfree_skb` record, from the tracepoint's format file. Both differ
+/// between kernels; kernels before 5.17 have no drop reasons at all.
+fn paws_drop_reason() -> Result<(u32, u32)> {
+    let format = TRACEFS_DIRS
+        .iter()
+        .find_map(|dir| {
+            std::fs::read_to_string(Path::new(dir).join("events/skb/kfree_skb/format")).ok()
+        })
+        .context("skb:kfree_skb format not found (is tracefs mounted?)")?;
+    parse_kfree_skb_format(&format)
+        .context("skb:kfree_skb has no PAWS drop reason (kernel older than 5.17?)")
+}
+
+/// Parse a `skb:kfree_skb` format file: the offset comes from the line
+/// (tab-separated)
+///
+/// ```text
+/// field:enum skb_drop_reason reason; offset:28; size:4; signed:0;
+/// ```
+///
+/// and the value from the `{ 27, "TCP_RFC7323_PAWS" }` entry of the print
+/// format's `__print_symbolic` table. Returns `(reason, offset)`, or None if
+/// either is missing.
+///
+/// ```
+/// use sidecar::monitor::parse_kfree_skb_format;
+///
+/// let format = concat!(
+///     "name: kfree_skb\n",
+///     "format:\n",
+///     "\tfield:void * skbaddr;\toffset:8;\tsize:8;\tsigned:0;\n",
+///     "\tfield:unsigned short protocol;\toffset:24;\tsize:2;\tsigned:0;\n",
+///     "\tfield:enum skb_drop_reason reason;\toffset:28;\tsize:4;\tsigned:0;\n",
+///     "\n",
+///     "print fmt: \"reason: %s\", __print_symbolic(REC->reason, ",
+///     "{ 2, \"NOT_SPECIFIED\" }, { 27, \"TCP_RFC7323_PAWS\" }, { 28, \"OTHER\" })\n",
+/// );
+/// assert_eq!(parse_kfree_skb_format(format), Some((27, 28)));
+///
+/// // Before 5.17 the record has no reason field
+/// let old = format.replace("enum skb_drop_reason reason;", "unsigned short pad;");
+/// assert_eq!(parse_kfree_skb_format(&old), None);
+/// // Nor a PAWS reason on kernels that don't have it yet
+/// let no_paws = format.replace("TCP_RFC7323_PAWS", "TCP_OFOMERGE");
+/// assert_eq!(parse_kfree_skb_format(&no_paws), None);
+/// ```
+pub fn parse_kfree_skb_format(format: &str) -> Option<(u32, u32)> {
+    let offset = format
+        .lines()
+        .find(|line| line.contains(" reason;"))?
+        .split(';')
+        .find_map(|part| part.trim().strip_prefix("offset:"))?
+        .parse()
+        .ok()?;
+    let (before, _) = format.split_once("\"TCP_RFC7323_PAWS\"")?;
+    let reason = before
+        .rsplit_once('{')?
+        .1
+        .trim()
+        .trim_end_matches(',')
+        .trim()
+        .parse()
+        .ok()?;
+    Some((reason, offset))
+}
+
+/// Decode a NUL-terminated path from `PROCESS_EXES`. A path filling the
+/// whole buffer was truncated by the kernel and gets a `...` suffix.
+fn exe_path(raw: &[u8; EXE_PATH_LEN]) -> String {
+    let len = raw.iter().position(|&b| b == 0).unwrap_or(raw.len());
+    let mut path = String::from_utf8_lossy(&raw[..len]).into_owned();
+    if len >= EXE_PATH_LEN - 1 {
+        path.push_str("...");
+    }
+    path
+}
+
+/// Kernel BTF to load fentry/fexit programs against, or None if they are
+/// unsupported (before 5.5, or no `/sys/kernel/btf/vmlinux`).
+fn tracing_btf() -> Option<Btf> {
+    match KernelVersion::current() {
+        Ok(version) if version >= KernelVersion::new(5, 5, 0) => {}
+        Ok(version) => {
+            info!(
+                "Kernel {:?} predates fentry/fexit, attaching kprobes",
+                version
+            );
+            return None;
+        }
+        Err(e) => {
+            warn!(
+                "Failed to read the kernel version, attaching kprobes: {}",
+                e
+            );
+            return None;
+        }
+    }
+    match Btf::from_sys_fs() {
+        Ok(btf) => Some(btf),
+        Err(e) => {
+            info!("No kernel BTF ({}), attaching kprobes", e);
+            None
+        }
+    }
+}
+
+/// The `ACTIVE_CONNS` counters, mapped into this process so they can be
+/// updated with the same atomics the eBPF programs use. Unmapped on drop.
+struct ActiveCounters {
+    addr: *mut libc::c_void,
+    len: usize,
+}
+
+impl ActiveCounters {
+    /// Map the values of `active`, created `BPF_F_MMAPABLE`
+    fn map(active: &MapData) -> Result<Self> {
+        use std::os::fd::{AsFd, AsRawFd};
+
+        // An array map's values start at offset 0, padded to a whole page
+        let len = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let addr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                active.fd().as_fd().as_raw_fd(),
+                0,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            return Err(std::io::Error::last_os_error()).context("Failed to map ACTIVE_CONNS");
+        }
+        Ok(Self { addr, len })
+    }
+
+    fn get(&self, index: u32) -> &AtomicU64 {
+        assert!(index < active_conn::COUNT);
+        // In bounds, and the kernel aligns array values to 8 bytes
+        unsafe { &*(self.addr as *const AtomicU64).add(index as usize) }
+    }
+}
+
+impl Drop for ActiveCounters {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.addr, self.len) };
+    }
+}
+
+fn bump_memlock_rlimit() -> Result<()> {
+    let rlim = libc::rlimit {
+        rlim_cur: libc::RLIM_INFINITY,
+        rlim_max: libc::RLIM_INFINITY,
+    };
+    let ret = unsafe { libc::setrlimit(libc::RLIMIT_MEMLOCK, &rlim) };
+    if ret != 0 {
+        anyhow::bail!("Failed to set RLIMIT_MEMLOCK");
+    }
+    Ok(())
+}
+
+/// Load the eBPF object, taking over the state maps pinned in `pin_dir` and
+/// pinning the missing ones there; with no `pin_dir`, nothing is pinned.
+/// Returns it with the SHA-256 of its bytes.
+fn load_ebpf_program(options: &LoadOptions, pin_dir: Option<&Path>) -> Result<(Bpf, String)> {
+    let mut loader = BpfLoader::new();
+    loader.verifier_log_level(options.verifier_log.level());
+    if let Some(dir) = pin_dir {
+        loader.map_pin_path(dir);
+    }
+    let load = |loader: &mut BpfLoader, object: &[u8]| -> Result<Bpf> {
+        Ok(match pin_dir {
+            Some(_) => loader.load(object)?,
+            None => loader.load(&without_pinning(object)?)?,
+        })
+    };
+    let loaded = match options.object {
+        // Read here rather than with load_file, so the hash is of the very
+        // bytes loaded
+        Some(ref path) => std::fs::read(path)
+            .map_err(anyhow::Error::from)
+            .and_then(|object| Ok((load(&mut loader, &object)?, sha256_hex(&object))))
+            .with_context(|| format!("Failed to load eBPF object {}", path.display()))?,
+        // Include the compiled eBPF bytecode at compile time. build.rs picks
+        // the debug or release object (see SIDECAR_BPF_PROFILE).
+        None => {
+            let object = include_bytes_aligned!(env!("SIDECAR_BPF_OBJECT"));
+            (load(&mut loader, object)?, sha256_hex(object))
+        }
+    };
+
+    Ok(loaded)
+}
+
+/// A copy of the eBPF object with every map declared `pinned` unpinned.
+///
+/// The loader pins such maps whatever it is told (under `/sys/fs/bpf` by
+/// default), so the declaration itself is cleared: the `pinning` word of
+/// each `bpf_map_def` in the legacy `maps` section, which is where
+/// aya-ebpf's `#[map]` puts them.
+fn without_pinning(object: &[u8]) -> Result<Vec<u8>> {
+    use object::{Object, ObjectSection, ObjectSymbol};
+
+    // bpf_map_def.pinning: after map_type, key_size, value_size,
+    // max_entries, map_flags and id
+    const PINNING_OFFSET: usize = 24;
+    const PIN_BY_NAME: u32 = 1;
+
+    let file = object::File::parse(object).context("Failed to parse the eBPF object")?;
+    let mut unpinned = object.to_vec();
+    for section in file
+        .sections()
+        .filter(|s| s.name().is_ok_and(|n| n.starts_with("maps")))
+    {
+        let Some((section_start, _)) = section.file_range() else {
+            continue;
+        };
+        for symbol in file.symbols() {
+            if symbol.section_index() != Some(section.index())
+                || (symbol.size() as usize) < PINNING_OFFSET + 4
+            {
+                continue;
+            }
+            // Symbol values of a relocatable object are section offsets
+            let at = (section_start + symbol.address()) as usize + PINNING_OFFSET;
+            let word = unpinned
+                .get_mut(at..at + 4)
+                .context("Map definition outside the eBPF object")?;
+            if u32::from_le_bytes(word.try_into()?) == PIN_BY_NAME {
+                word.copy_from_slice(&0u32.to_le_bytes());
+            }
+        }
+    }
+    Ok(unpinned)
+}
+
+/// Remove the private pin directories of sidecar processes that are gone,
+/// e.g. killed before they could clean up.
+fn remove_stale_pin_dirs() {
+    let Ok(entries) = std::fs::read_dir(BPFFS) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let name = entry.file_name();
+        let pid = name
+            .to_str()
+            .and_then(|name| name.strip_prefix("sidecar-"))
+            .and_then(|pid| pid.parse::<u32>().ok());
+        match pid {
+            Some(pid) if !Path::new("/proc").join(pid.to_string()).exists() => {
+                unpin_state_maps(&entry.path());
+                if std::fs::remove_dir(entry.path()).is_ok() {
+                    debug!("Removed the stale pin directory {}", entry.path().display());
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn sha256_hex(bytes: &[u8]) -> String {
+    use sha2::Digest;
+    sha2::Sha256::digest(bytes)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Remove the state map pins in `dir`; maps still in use live on.
+fn unpin_state_maps(dir: &Path) {
+    for name in STATE_MAPS {
+        let _ = std::fs::remove_file(dir.join(name));
+    }
+}
+
+/// Kernel id of every map of `bpf`, by name. Maps whose info can't be read
+/// are left out.
+fn map_ids(bpf: &Bpf) -> Vec<(String, u32)> {
+    bpf.maps()
+        .filter_map(|(name, map)| {
+            let data = match map {
+                Map::Array(data)
+                | Map::BloomFilter(data)
+                | Map::CpuMap(data)
+                | Map::DevMap(data)
+                | Map::DevMapHash(data)
+                | Map::HashMap(data)
+                | Map::LpmTrie(data)
+                | Map::LruHashMap(data)
+                | Map::PerCpuArray(data)
+                | Map::PerCpuHashMap(data)
+                | Map::PerCpuLruHashMap(data)
+                | Map::PerfEventArray(data)
+                | Map::ProgramArray(data)
+                | Map::Queue(data)
+                | Map::RingBuf(data)
+                | Map::SockHash(data)
+                | Map::SockMap(data)
+                | Map::Stack(data)
+                | Map::StackTraceMap(data)
+                | Map::Unsupported(data)
+                | Map::XskMap(data) => data,
+            };
+            let info = data
+                .info()
+                .map_err(|e| debug!("No info for map {}: {}", name, e));
+            Some((name.to_string(), info.ok()?.id()))
+        })
+        .collect()
+}
+
+/// Compare the layout fingerprint embedded in the eBPF object with the one
+/// this binary was compiled against.
+fn verify_layout(bpf: &Bpf) -> Result<()> {
+    let layout_map: Array<_, LayoutInfo> = Array::try_from(
+        bpf.map(".rodata.layout")
+            .context("eBPF object has no layout info (built against an older sidecar-common?)")?,
+    )?;
+    let kernel = layout_map.get(&0, 0)?;
+    let user = LayoutInfo::current();
+
+    if kernel != user {
+        anyhow::bail!(
+            "Kernel/userspace layout mismatch - rebuild both halves from the same \
+             sidecar-common.\n  eBPF object: {:?}\n  userspace:   {:?}",
+            kernel,
+            user
+        );
+    }
+
+    debug!("Layout version {} verified", user.version);
+    Ok(())
+}
+
+/// Check the eBPF object reads kprobe arguments with this machine's calling
+/// convention; probes built for another architecture would read the wrong
+/// registers.
+fn verify_arch(bpf: &Bpf) -> Result<()> {
+    let arch_map: Array<_, [u8; BPF_ARCH_LEN]> = Array::try_from(
+        bpf.map(".rodata.arch")
+            .context("eBPF object has no target arch (built against an older sidecar-ebpf?)")?,
+    )?;
+    let name = arch_map.get(&0, 0)?;
+    let len = name.iter().position(|&b| b == 0).unwrap_or(BPF_ARCH_LEN);
+    let kernel = String::from_utf8_lossy(&name[..len]);
+
+    if kernel != std::env::consts::ARCH {
+        anyhow::bail!(
+            "eBPF object was built for {} but this machine is {} - rebuild it with \
+             CARGO_CFG_BPF_TARGET_ARCH={}",
+            kernel,
+            std::env::consts::ARCH,
+            std::env::consts::ARCH
+        );
+    }
+
+    debug!("eBPF target arch {} verified", kernel);
+    Ok(())
+}
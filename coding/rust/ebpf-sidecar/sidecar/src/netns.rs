@@ -0,0 +1,145 @@
+//! Naming network namespaces for the `netns` label.
+//!
+//! The kernel records each connection's netns inode. By default the label is
+//! that number, which matches `readlink /proc/<pid>/ns/net` (`net:[N]`) and
+//! `lsns -t net`. With resolution on, known namespaces get a name instead:
+//!
+//! - `host` - the namespace of PID 1
+//! - a named namespace from `/run/netns` (`ip netns add <name>`)
+//! - the 12-character container id of a process in the namespace, taken
+//!   from its cgroup path (Docker, containerd, CRI-O, Podman)
+//!
+//! Namespaces are discovered by scanning `/proc`, at most once per
+//! collection and only when an unknown inode shows up.
+
+use std::collections::HashMap;
+use std::os::unix::fs::MetadataExt;
+
+/// Where `ip netns` bind-mounts named namespaces
+const NAMED_NETNS_DIR: &str = "/run/netns";
+
+/// Length of a full container id in a cgroup path
+const CONTAINER_ID_LEN: usize = 64;
+
+/// Length of the container id shown in labels, as `docker ps` does
+const SHORT_ID_LEN: usize = 12;
+
+/// Netns inode of `pid`, from its `/proc/<pid>/ns/net` link.
+pub fn netns_inode(pid: u32) -> Option<u32> {
+    let link = std::fs::read_link(format!("/proc/{}/ns/net", pid)).ok()?;
+    link.to_str()?
+        .strip_prefix("net:[")?
+        .strip_suffix(']')?
+        .parse()
+        .ok()
+}
+
+/// Container id in a `/proc/<pid>/cgroup` file, shortened to 12 characters.
+///
+/// Recognises `docker-<id>.scope`, `cri-containerd-<id>.scope`,
+/// `crio-<id>.scope`, `libpod-<id>.scope` and bare `<id>` path segments.
+pub fn container_id(cgroup_file: &str) -> Option<String> {
+    const PREFIXES: [&str; 4] = ["docker-", "cri-containerd-", "crio-", "libpod-"];
+
+    for line in cgroup_file.lines() {
+        // hierarchy-ID:controller-list:path
+        let path = line.splitn(3, ':').nth(2).unwrap_or_default();
+        for segment in path.rsplit('/') {
+            let segment = segment.strip_suffix(".scope").unwrap_or(segment);
+            let id = PREFIXES
+                .iter()
+                .find_map(|prefix| segment.strip_prefix(prefix))
+                .unwrap_or(segment);
+            if id.len() == CONTAINER_ID_LEN && id.bytes().all(|b| b.is_ascii_hexdigit()) {
+                return Some(id[..SHORT_ID_LEN].to_string());
+            }
+        }
+    }
+
+    None
+}
+
+/// Turns netns inodes into `netns` label values.
+#[derive(Debug, Default)]
+pub struct NetnsResolver {
+    /// Resolve to names; otherwise every label is the inode number
+    names_enabled: bool,
+    names: HashMap<u32, String>,
+    /// `/proc` was already scanned during this collection
+    scanned: bool,
+}
+
+impl NetnsResolver {
+    /// A resolver that names namespaces when `names_enabled`, otherwise
+    /// labels them with their inode.
+    pub fn new(names_enabled: bool) -> Self {
+        Self {
+            names_enabled,
+            ..Default::default()
+        }
+    }
+
+    /// Label for `inode`: its name if known, else the number. Empty if the
+    /// kernel couldn't read the namespace.
+    pub fn resolve(&mut self, inode: u32) -> String {
+        if inode == 0 {
+            return String::new();
+        }
+        if self.names_enabled && !self.names.contains_key(&inode) && !self.scanned {
+            self.names = scan_names();
+            self.scanned = true;
+        }
+        self.names
+            .get(&inode)
+            .cloned()
+            .unwrap_or_else(|| inode.to_string())
+    }
+
+    /// Allow one more `/proc` scan; call once per collection.
+    pub fn next_collection(&mut self) {
+        self.scanned = false;
+    }
+}
+
+/// Name every namespace that has one; see the module docs for precedence.
+fn scan_names() -> HashMap<u32, String> {
+    let mut names = HashMap::new();
+
+    if let Ok(entries) = std::fs::read_dir("/proc") {
+        for entry in entries.flatten() {
+            let Some(pid) = entry
+                .file_name()
+                .to_str()
+                .and_then(|n| n.parse::<u32>().ok())
+            else {
+                continue;
+            };
+            let Some(inode) = netns_inode(pid) else {
+                continue;
+            };
+            if names.contains_key(&inode) {
+                continue;
+            }
+            let cgroup = std::fs::read_to_string(entry.path().join("cgroup")).unwrap_or_default();
+            if let Some(id) = container_id(&cgroup) {
+                names.insert(inode, id);
+            }
+        }
+    }
+
+    // Named namespaces: stat on the nsfs bind mount gives the netns inode
+    if let Ok(entries) = std::fs::read_dir(NAMED_NETNS_DIR) {
+        for entry in entries.flatten() {
+            let metadata = std::fs::metadata(entry.path());
+            if let (Ok(metadata), Some(name)) = (metadata, entry.file_name().to_str()) {
+                names.insert(metadata.ino() as u32, name.to_string());
+            }
+        }
+    }
+
+    if let Some(inode) = netns_inode(1) {
+        names.insert(inode, "host".to_string());
+    }
+
+    names
+}
@@ -0,0 +1,113 @@
+//! Request path hashes as exported, and their collisions.
+//!
+//! The kernel hashes the path of each HTTP/1.x request to 64 bits (see
+//! `sidecar_common::http_path`). Consumers keyed on fewer bits get the low
+//! `bits` of it instead (`--path-hash-bits`), at the cost of collisions:
+//! distinct paths sharing a hash, whose requests then look like one route.
+//! With 32 bits, a collision is more likely than not past about 77 000
+//! distinct paths.
+//!
+//! Collisions are found by remembering the full hash behind each exported
+//! one, together with the path's check, a second, independent 32-bit hash
+//! (`HttpEvent::path_check`). Two paths colliding on all 64 bits still
+//! differ in their check but for a 1 in 2^32 chance, so collisions are
+//! found at the default 64 bits too.
+
+use std::collections::{HashMap, HashSet};
+
+/// Exported hashes, and colliding paths, remembered; paths first seen past
+/// this many are not checked for collisions.
+pub const MAX_TRACKED_PATHS: usize = 100_000;
+
+/// A request's path hash at the exported width.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PathHash {
+    /// Low `bits` of the kernel's hash
+    pub hash: u64,
+    /// Full hash and check of the path that had `hash` first, the first
+    /// time another path is seen with it
+    pub collision: Option<(u64, u32)>,
+}
+
+/// Narrowing of kernel path hashes, and collision detection among them.
+///
+/// ```
+/// use sidecar::path_hash::PathHashes;
+///
+/// let mut hashes = PathHashes::new(32);
+/// let users = hashes.observe(0x1111_1111_aaaa_aaaa, 1).unwrap();
+/// assert_eq!(users.hash, 0xaaaa_aaaa);
+/// assert_eq!(users.collision, None);
+///
+/// // Another path with the same low 32 bits is reported once
+/// let orders = hashes.observe(0x2222_2222_aaaa_aaaa, 2).unwrap();
+/// assert_eq!(orders.collision, Some((0x1111_1111_aaaa_aaaa, 1)));
+/// assert_eq!(hashes.observe(0x2222_2222_aaaa_aaaa, 2).unwrap().collision, None);
+/// assert_eq!(hashes.collisions(), 1);
+///
+/// // At 64 bits, paths colliding on the whole hash differ in their check
+/// let mut hashes = PathHashes::new(64);
+/// assert_eq!(hashes.observe(0x3333_3333_aaaa_aaaa, 3).unwrap().collision, None);
+/// let other = hashes.observe(0x3333_3333_aaaa_aaaa, 4).unwrap();
+/// assert_eq!(other.collision, Some((0x3333_3333_aaaa_aaaa, 3)));
+/// assert_eq!(hashes.collisions(), 1);
+///
+/// // 0 is a path that couldn't be hashed
+/// assert_eq!(hashes.observe(0, 0), None);
+/// ```
+#[derive(Debug)]
+pub struct PathHashes {
+    mask: u64,
+    /// Full hash and check of the first path seen for each exported hash
+    first: HashMap<u64, (u64, u32)>,
+    /// Full hashes and checks already reported as colliding
+    collided: HashSet<(u64, u32)>,
+}
+
+impl PathHashes {
+    /// Export the low `bits` (1 to 64) of each hash.
+    pub fn new(bits: u32) -> Self {
+        assert!(
+            (1..=64).contains(&bits),
+            "path hash width must be 1-64 bits"
+        );
+        Self {
+            mask: u64::MAX >> (64 - bits),
+            first: HashMap::new(),
+            collided: HashSet::new(),
+        }
+    }
+
+    /// The exported hash of a request's `HttpEvent::path_hash`, checked
+    /// against other paths with its `HttpEvent::path_check`, or `None` if
+    /// the kernel couldn't hash its path.
+    pub fn observe(&mut self, path_hash: u64, path_check: u32) -> Option<PathHash> {
+        if path_hash == 0 {
+            return None;
+        }
+        let hash = path_hash & self.mask;
+        let path = (path_hash, path_check);
+        let collision = match self.first.get(&hash) {
+            Some(&first)
+                if first != path
+                    && self.collided.len() < MAX_TRACKED_PATHS
+                    && self.collided.insert(path) =>
+            {
+                Some(first)
+            }
+            Some(_) => None,
+            None => {
+                if self.first.len() < MAX_TRACKED_PATHS {
+                    self.first.insert(hash, path);
+                }
+                None
+            }
+        };
+        Some(PathHash { hash, collision })
+    }
+
+    /// Distinct paths seen colliding so far.
+    pub fn collisions(&self) -> usize {
+        self.collided.len()
+    }
+}
@@ -0,0 +1,129 @@
+//! Connection policy: a denylist of outbound destinations.
+//!
+//! Rules are checked by the `policy_connect4` / `policy_connect6` programs on
+//! a cgroup's `connect()` hook, separate from the tracing probes. For now
+//! they only observe: a matching connect is reported as a [`PolicyEvent`]
+//! and still goes ahead.
+//!
+//! A rule is `ADDR[/PREFIX][:PORT]`:
+//!
+//! - `203.0.113.7` - one host, any port
+//! - `10.0.0.0/8` - a network, any port
+//! - `10.1.2.3:5432` - one host and port
+//! - `192.168.0.0/16:22` - a network, one port
+//!
+//! Rules are IPv4; IPv6 sockets match only for v4-mapped destinations. When
+//! several rules match, a port-specific rule wins over an any-port one, then
+//! the longest prefix.
+//!
+//! The always-tracked destinations (`MonitorOptions::always_track`) use the
+//! same syntax and matching, in their own `ALWAYS_TRACK` trie.
+
+use anyhow::{Context, Result};
+use aya::maps::lpm_trie::Key;
+use serde::{Serialize, Serializer};
+use sidecar_common::{PolicyEvent, PolicyTarget};
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+
+/// A denied destination; see the module docs for the syntax.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PolicyRule {
+    /// Network address, host bits cleared
+    net: Ipv4Addr,
+    prefix_len: u8,
+    /// Destination port, 0 for any
+    port: u16,
+}
+
+impl PolicyRule {
+    /// Key of this rule in the kernel's `POLICY_DENYLIST` or `ALWAYS_TRACK`
+    /// trie.
+    pub(crate) fn key(&self) -> Key<PolicyTarget> {
+        // The port and padding always take part in the match
+        let prefix_len = 32 + self.prefix_len as u32;
+        Key::new(
+            prefix_len,
+            PolicyTarget {
+                dst_port: self.port,
+                _padding: 0,
+                dst_ip: u32::from(self.net).to_be(),
+            },
+        )
+    }
+}
+
+impl FromStr for PolicyRule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (net, port) = match s.rsplit_once(':') {
+            Some((net, port)) => {
+                let port: u16 = port
+                    .parse()
+                    .with_context(|| format!("Invalid port in policy rule {:?}", s))?;
+                if port == 0 {
+                    anyhow::bail!(
+                        "Port 0 in policy rule {:?}; leave the port out to match any",
+                        s
+                    );
+                }
+                (net, port)
+            }
+            None => (s, 0),
+        };
+        let (addr, prefix_len) = match net.split_once('/') {
+            Some((addr, len)) => {
+                let len: u8 = len
+                    .parse()
+                    .with_context(|| format!("Invalid prefix length in policy rule {:?}", s))?;
+                if len > 32 {
+                    anyhow::bail!("Prefix length over 32 in policy rule {:?}", s);
+                }
+                (addr, len)
+            }
+            None => (net, 32),
+        };
+        let addr: Ipv4Addr = addr
+            .parse()
+            .with_context(|| format!("Invalid IPv4 address in policy rule {:?}", s))?;
+
+        let mask = u32::MAX.checked_shl(32 - prefix_len as u32).unwrap_or(0);
+        Ok(Self {
+            net: Ipv4Addr::from(u32::from(addr) & mask),
+            prefix_len,
+            port,
+        })
+    }
+}
+
+impl std::fmt::Display for PolicyRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.net, self.prefix_len)?;
+        if self.port != 0 {
+            write!(f, ":{}", self.port)?;
+        }
+        Ok(())
+    }
+}
+
+impl Serialize for PolicyRule {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Destination address of a policy event.
+pub fn dst_ip(event: &PolicyEvent) -> Ipv4Addr {
+    Ipv4Addr::from(event.dst_ip.to_be())
+}
+
+/// Task name of the process behind a policy event.
+pub fn comm(event: &PolicyEvent) -> String {
+    let len = event
+        .comm
+        .iter()
+        .position(|&b| b == 0)
+        .unwrap_or(event.comm.len());
+    String::from_utf8_lossy(&event.comm[..len]).into_owned()
+}
@@ -0,0 +1,226 @@
+//! Resolving process-name patterns, and PIDs as seen inside a PID namespace,
+//! to host PIDs.
+//!
+//! Names are matched against `/proc/<pid>/comm`, which the kernel truncates
+//! to 15 bytes. A pattern is an exact name unless it contains `*`, which
+//! matches any run of characters (including none):
+//!
+//! - `nginx` - exactly `nginx`
+//! - `nginx*` - names starting with `nginx`
+//! - `*-worker` - names ending in `-worker`
+//! - `php*fpm*` - `php`, then `fpm` somewhere after it
+//! - `*` - every process
+//!
+//! The eBPF programs see host PIDs, the ones of the sidecar's own namespace.
+//! A PID read inside a container is translated through the `NSpid` line of
+//! `/proc/<pid>/status`, which lists a process' PID in each namespace from
+//! the outermost down to its own; see [`PidNamespace`].
+
+use anyhow::{Context, Result};
+use std::convert::Infallible;
+use std::os::unix::fs::MetadataExt;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// A process-name pattern; see the module docs for the syntax.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NamePattern(String);
+
+impl NamePattern {
+    pub fn new(pattern: &str) -> Self {
+        Self(pattern.to_string())
+    }
+
+    /// Whether `name` matches the whole pattern.
+    ///
+    /// ```
+    /// use sidecar::process::NamePattern;
+    ///
+    /// let exact = NamePattern::new("nginx");
+    /// assert!(exact.matches("nginx"));
+    /// assert!(!exact.matches("nginx-worker"));
+    ///
+    /// let prefix = NamePattern::new("nginx*");
+    /// assert!(prefix.matches("nginx"));
+    /// assert!(prefix.matches("nginx-worker"));
+    /// assert!(!prefix.matches("my-nginx"));
+    ///
+    /// let suffix = NamePattern::new("*-worker");
+    /// assert!(suffix.matches("nginx-worker"));
+    /// assert!(!suffix.matches("nginx-workers"));
+    ///
+    /// assert!(!NamePattern::new("php*fpm*").matches("fpm-php"));
+    /// ```
+    pub fn matches(&self, name: &str) -> bool {
+        let mut parts = self.0.split('*');
+        // split always yields at least one part: the literal before any '*'
+        let first = parts.next().unwrap_or_default();
+        let Some(mut rest) = name.strip_prefix(first) else {
+            return false;
+        };
+
+        let parts: Vec<&str> = parts.collect();
+        let Some((last, middle)) = parts.split_last() else {
+            // No '*': exact match
+            return rest.is_empty();
+        };
+
+        // Leftmost match for each middle literal leaves the most room for
+        // the ones after it
+        for part in middle {
+            match rest.find(part) {
+                Some(i) => rest = &rest[i + part.len()..],
+                None => return false,
+            }
+        }
+        rest.ends_with(last)
+    }
+}
+
+impl FromStr for NamePattern {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Ok(Self::new(s))
+    }
+}
+
+impl std::fmt::Display for NamePattern {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// PIDs of running processes whose name matches `pattern`, in ascending
+/// order. Processes that exit during the scan are skipped.
+pub fn find_pids(pattern: &NamePattern) -> Result<Vec<u32>> {
+    let mut pids = Vec::new();
+
+    for entry in std::fs::read_dir("/proc").context("Failed to read /proc")? {
+        let entry = entry?;
+        let Some(pid) = entry
+            .file_name()
+            .to_str()
+            .and_then(|n| n.parse::<u32>().ok())
+        else {
+            continue;
+        };
+        let Ok(comm) = std::fs::read_to_string(entry.path().join("comm")) else {
+            continue;
+        };
+        if pattern.matches(comm.trim_end_matches('\n')) {
+            pids.push(pid);
+        }
+    }
+
+    pids.sort_unstable();
+    Ok(pids)
+}
+
+/// A PID namespace, given as any of:
+///
+/// - `pid:[4026532451]` - as printed by `readlink /proc/self/ns/pid`, e.g.
+///   inside the container
+/// - `4026532451` - the namespace's inode number alone
+/// - `/proc/<pid>/ns/pid` - the namespace of host PID `<pid>`, or any other
+///   nsfs file or bind mount of one
+///
+/// ```
+/// use sidecar::process::PidNamespace;
+///
+/// let ns: PidNamespace = "pid:[4026532451]".parse().unwrap();
+/// assert_eq!(ns, PidNamespace::Inode(4026532451));
+/// assert_eq!("4026532451".parse::<PidNamespace>().unwrap(), ns);
+/// assert!(matches!("/proc/1/ns/pid".parse(), Ok(PidNamespace::Path(_))));
+///
+/// // A mangled `pid:[...]` isn't taken for a path
+/// assert!("pid:[4026532451".parse::<PidNamespace>().is_err());
+/// assert!("".parse::<PidNamespace>().is_err());
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PidNamespace {
+    Inode(u64),
+    Path(PathBuf),
+}
+
+impl PidNamespace {
+    /// The namespace's inode number, the same in every namespace.
+    pub fn inode(&self) -> Result<u64> {
+        match self {
+            Self::Inode(inode) => Ok(*inode),
+            Self::Path(path) => std::fs::metadata(path)
+                .map(|meta| meta.ino())
+                .with_context(|| format!("Failed to open PID namespace {}", path.display())),
+        }
+    }
+}
+
+impl FromStr for PidNamespace {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s.is_empty() {
+            anyhow::bail!("Empty PID namespace");
+        }
+        if let Some(inode) = s.strip_prefix("pid:[") {
+            return inode
+                .strip_suffix(']')
+                .and_then(|inode| inode.parse().ok())
+                .map(Self::Inode)
+                .with_context(|| format!("Invalid PID namespace {:?}, expected pid:[INODE]", s));
+        }
+        Ok(match s.parse() {
+            Ok(inode) => Self::Inode(inode),
+            Err(_) => Self::Path(PathBuf::from(s)),
+        })
+    }
+}
+
+impl std::fmt::Display for PidNamespace {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Inode(inode) => write!(f, "pid:[{}]", inode),
+            Self::Path(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+/// Host PID of the process that is `pid` inside `namespace`.
+///
+/// Only processes whose own PID namespace is `namespace` are considered, not
+/// those of namespaces nested in it. Fails if no such process runs, e.g.
+/// because the namespace isn't visible from the sidecar's `/proc`.
+pub fn host_pid(namespace: &PidNamespace, pid: u32) -> Result<u32> {
+    let inode = namespace.inode()?;
+    let target = format!("pid:[{}]", inode);
+
+    for entry in std::fs::read_dir("/proc").context("Failed to read /proc")? {
+        let entry = entry?;
+        let Some(host_pid) = entry
+            .file_name()
+            .to_str()
+            .and_then(|n| n.parse::<u32>().ok())
+        else {
+            continue;
+        };
+        let in_namespace = std::fs::read_link(entry.path().join("ns/pid"))
+            .is_ok_and(|link| link.as_os_str() == target.as_str());
+        if !in_namespace {
+            continue;
+        }
+        let Ok(status) = std::fs::read_to_string(entry.path().join("status")) else {
+            continue;
+        };
+        // The last NSpid entry is the PID in the process' own namespace
+        let own_pid = status
+            .lines()
+            .find_map(|line| line.strip_prefix("NSpid:"))
+            .and_then(|pids| pids.split_whitespace().last())
+            .and_then(|own| own.parse::<u32>().ok());
+        if own_pid == Some(pid) {
+            return Ok(host_pid);
+        }
+    }
+
+    anyhow::bail!("No process has PID {} in PID namespace {}", pid, namespace)
+}
@@ -0,0 +1,275 @@
+//! Relabeling of the per-connection series.
+//!
+//! The `metrics.relabel` rules of the config rewrite the labels of every
+//! exported connection before its series are set, the way Prometheus'
+//! `relabel_configs` would at scrape time:
+//!
+//! ```yaml
+//! metrics:
+//!   relabel:
+//!     # Keep only the binary's name, not its path
+//!     - source_labels: [exe]
+//!       regex: ".*/([^/]+)"
+//!       target_label: exe
+//!     # Don't export loopback connections
+//!     - source_labels: [dst_ip]
+//!       regex: "127\\..*"
+//!       action: drop
+//!     # No per-namespace series
+//!     - regex: netns
+//!       action: labeldrop
+//! ```
+//!
+//! Only the values of `scrape::CONNECTION_LABELS` can change; the label
+//! names are fixed by the registered metrics. A label is "renamed" by
+//! copying its value into another of them (`replace`) and blanking it
+//! (`labeldrop`); Prometheus treats an empty label as absent.
+//!
+//! Connections that end up with the same labels share their series: in
+//! cumulative mode their counters add up, in delta mode the last one
+//! exported wins.
+//!
+//! `metrics.group_by` lists the labels the series are keyed on instead:
+//!
+//! ```yaml
+//! metrics:
+//!   group_by: [dst_ip, dst_port, exe]
+//! ```
+//!
+//! The others are blanked after the rules run, and the connections left
+//! with the same labels are merged into one before export (see
+//! `metrics::group_by_labels`), so their gauges cover all of them too.
+
+use crate::config::{RelabelAction, RelabelConfig};
+use crate::scrape::CONNECTION_LABELS;
+use anyhow::{Context, Result};
+use regex::Regex;
+use std::sync::OnceLock;
+
+/// The rules of the config, see `install`.
+static INSTALLED: OnceLock<Relabeler> = OnceLock::new();
+
+/// One compiled `RelabelConfig`.
+#[derive(Debug, Clone)]
+struct Rule {
+    /// Indexes of the source labels in `CONNECTION_LABELS`
+    sources: Vec<usize>,
+    separator: String,
+    regex: Regex,
+    action: RelabelAction,
+    /// Index of the target label, for `replace`
+    target: Option<usize>,
+    replacement: String,
+}
+
+/// Compiled relabeling rules, applied in order, then the `group_by` key.
+#[derive(Debug, Clone, Default)]
+pub struct Relabeler {
+    rules: Vec<Rule>,
+    /// Whether each of `CONNECTION_LABELS` is left out of the key
+    blanked: [bool; 6],
+}
+
+impl Relabeler {
+    /// Compile `configs`. Fails on an invalid regex or a label that isn't
+    /// one of `CONNECTION_LABELS`.
+    ///
+    /// ```
+    /// use sidecar::config::RelabelConfig;
+    /// use sidecar::relabel::Relabeler;
+    ///
+    /// let rule: RelabelConfig = serde_yaml::from_str(
+    ///     "{source_labels: [exe], regex: '.*/([^/]+)', target_label: exe}",
+    /// ).unwrap();
+    /// let relabeler = Relabeler::new(&[rule]).unwrap();
+    ///
+    /// let mut labels = ["10.0.0.1", "10.0.0.2", "443", "/usr/sbin/nginx", "cubic", ""]
+    ///     .map(String::from);
+    /// assert!(relabeler.apply(&mut labels));
+    /// assert_eq!(labels[3], "nginx");
+    /// ```
+    pub fn new(configs: &[RelabelConfig]) -> Result<Self> {
+        let mut rules = Vec::with_capacity(configs.len());
+        for (i, config) in configs.iter().enumerate() {
+            let context = || format!("metrics.relabel[{}]", i);
+            let rule = Rule::compile(config).with_context(context)?;
+            rules.push(rule);
+        }
+        Ok(Self {
+            rules,
+            blanked: [false; 6],
+        })
+    }
+
+    /// Key the series on `labels` only, a subset of `CONNECTION_LABELS`:
+    /// the others are blanked after the rules.
+    ///
+    /// ```
+    /// use sidecar::relabel::Relabeler;
+    ///
+    /// let relabeler = Relabeler::new(&[])
+    ///     .unwrap()
+    ///     .group_by(&["dst_ip".to_string(), "dst_port".to_string()])
+    ///     .unwrap();
+    /// assert!(relabeler.merges());
+    ///
+    /// let mut labels = ["10.0.0.1", "10.0.0.2", "443", "/usr/sbin/nginx", "cubic", "host"]
+    ///     .map(String::from);
+    /// assert!(relabeler.apply(&mut labels));
+    /// assert_eq!(labels, ["", "10.0.0.2", "443", "", "", ""]);
+    ///
+    /// assert!(Relabeler::new(&[]).unwrap().group_by(&["process".to_string()]).is_err());
+    /// ```
+    pub fn group_by(mut self, labels: &[String]) -> Result<Self> {
+        let mut blanked = [true; 6];
+        for name in labels {
+            blanked[label_index(name)?] = false;
+        }
+        self.blanked = blanked;
+        Ok(self)
+    }
+
+    /// Whether the `group_by` key leaves labels out, so connections
+    /// differing only in those are merged.
+    pub fn merges(&self) -> bool {
+        self.blanked.contains(&true)
+    }
+
+    /// Rewrite `labels`, the values of `CONNECTION_LABELS`. Returns false
+    /// when a `keep` or `drop` rule drops the connection; the labels are
+    /// then left half-rewritten.
+    ///
+    /// Regexes are anchored, so a value only partly matching doesn't count:
+    ///
+    /// ```
+    /// use sidecar::config::RelabelConfig;
+    /// use sidecar::relabel::Relabeler;
+    ///
+    /// let relabeler = |yaml: &str| {
+    ///     let rule: RelabelConfig = serde_yaml::from_str(yaml).unwrap();
+    ///     Relabeler::new(&[rule]).unwrap()
+    /// };
+    /// let to = |dst_ip: &str| ["10.0.0.1", dst_ip, "443", "/usr/sbin/nginx", "cubic", ""]
+    ///     .map(String::from);
+    ///
+    /// let drop = relabeler("{source_labels: [dst_ip], regex: '10\\..*', action: drop}");
+    /// assert!(!drop.apply(&mut to("10.0.0.2")));
+    /// assert!(drop.apply(&mut to("110.0.0.2")));
+    ///
+    /// let keep = relabeler("{source_labels: [dst_ip], regex: '10\\..*', action: keep}");
+    /// assert!(keep.apply(&mut to("10.0.0.2")));
+    /// assert!(!keep.apply(&mut to("110.0.0.2")));
+    ///
+    /// // `10` only matches a prefix of the value
+    /// let partial = relabeler("{source_labels: [dst_ip], regex: '10', action: keep}");
+    /// assert!(!partial.apply(&mut to("10.0.0.2")));
+    /// ```
+    pub fn apply(&self, labels: &mut [String; 6]) -> bool {
+        if !self.rules.iter().all(|rule| rule.apply(labels)) {
+            return false;
+        }
+        for (value, &blanked) in labels.iter_mut().zip(&self.blanked) {
+            if blanked {
+                value.clear();
+            }
+        }
+        true
+    }
+}
+
+impl Rule {
+    fn compile(config: &RelabelConfig) -> Result<Self> {
+        let sources = config
+            .source_labels
+            .iter()
+            .map(|name| label_index(name))
+            .collect::<Result<Vec<_>>>()?;
+        let target = config
+            .target_label
+            .as_deref()
+            .map(label_index)
+            .transpose()?;
+        let uses_sources = matches!(
+            config.action,
+            RelabelAction::Replace | RelabelAction::Keep | RelabelAction::Drop
+        );
+        if uses_sources && sources.is_empty() {
+            anyhow::bail!("{:?} needs source_labels", config.action);
+        }
+        if config.action == RelabelAction::Replace && target.is_none() {
+            anyhow::bail!("replace needs a target_label");
+        }
+        // Anchored like Prometheus, so `10\..*` doesn't match 110.0.0.1
+        let regex = Regex::new(&format!("^(?:{})$", config.regex))
+            .with_context(|| format!("Invalid regex {:?}", config.regex))?;
+        Ok(Self {
+            sources,
+            separator: config.separator.clone(),
+            regex,
+            action: config.action,
+            target,
+            replacement: config.replacement.clone(),
+        })
+    }
+
+    fn apply(&self, labels: &mut [String; 6]) -> bool {
+        let source = || {
+            let values: Vec<&str> = self.sources.iter().map(|&i| &*labels[i]).collect();
+            values.join(&self.separator)
+        };
+        match self.action {
+            RelabelAction::Replace => {
+                let value = source();
+                if let (Some(captures), Some(target)) = (self.regex.captures(&value), self.target) {
+                    let mut replaced = String::new();
+                    captures.expand(&self.replacement, &mut replaced);
+                    labels[target] = replaced;
+                }
+                true
+            }
+            RelabelAction::Keep => self.regex.is_match(&source()),
+            RelabelAction::Drop => !self.regex.is_match(&source()),
+            RelabelAction::LabelDrop => {
+                for (name, value) in CONNECTION_LABELS.iter().zip(labels.iter_mut()) {
+                    if self.regex.is_match(name) {
+                        value.clear();
+                    }
+                }
+                true
+            }
+        }
+    }
+}
+
+fn label_index(name: &str) -> Result<usize> {
+    CONNECTION_LABELS
+        .iter()
+        .position(|label| *label == name)
+        .with_context(|| {
+            format!(
+                "Unknown label {:?}; relabeling and group_by support {}",
+                name,
+                CONNECTION_LABELS.join(", ")
+            )
+        })
+}
+
+/// Use `relabeler` for every export from now on. Set once at startup,
+/// before any collection; later calls are ignored.
+pub fn install(relabeler: Relabeler) {
+    let _ = INSTALLED.set(relabeler);
+}
+
+/// Apply the installed rules to `labels`, see `Relabeler::apply`. Without
+/// `install` every connection is kept as is.
+pub fn apply(labels: &mut [String; 6]) -> bool {
+    INSTALLED
+        .get()
+        .is_none_or(|relabeler| relabeler.apply(labels))
+}
+
+/// Whether the installed `group_by` key merges connections, see
+/// `Relabeler::merges`.
+pub fn merges() -> bool {
+    INSTALLED.get().is_some_and(Relabeler::merges)
+}
@@ -0,0 +1,289 @@
+//! Filtered scrapes of the metrics endpoint.
+//!
+//! `/metrics?min_bytes=1000000&dst_port=443` returns only the per-connection
+//! series of the connections that pass every filter in the query. Which
+//! connections pass is decided on the [`ConnectionView`], the connections of
+//! the latest export as they were labelled; the series themselves are then
+//! picked out of the registry by those labels, so a filtered scrape shows the
+//! same values as an unfiltered one. Series that aren't per connection (map
+//! statistics, per-destination health, histograms without connection labels)
+//! are returned unfiltered.
+//!
+//! With `--metrics-shards`, each of several endpoints serves a [`Shard`] of
+//! the per-connection series, picked out of the registry the same way.
+
+use bytes::Bytes;
+use prometheus::proto::MetricFamily;
+use sidecar_common::{ConnKey, ConnMetrics};
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Labels that identify a connection's series, in `ExportedConnection::labels`
+/// order.
+pub const CONNECTION_LABELS: [&str; 6] =
+    ["src_ip", "dst_ip", "dst_port", "exe", "cc_algo", "netns"];
+
+/// One exported connection (or endpoint, when grouped).
+#[derive(Debug, Clone)]
+pub struct ExportedConnection {
+    /// Values of `CONNECTION_LABELS`, as exported
+    pub labels: [String; 6],
+    /// The connection's 4-tuple; that of the first connection merged into
+    /// the series when grouped, with source port 0 by `--group-by endpoint`
+    pub key: ConnKey,
+    /// Bytes sent and received, as exported: per interval in delta mode
+    pub bytes: u64,
+    /// Counters as exported: per interval in delta mode
+    pub metrics: ConnMetrics,
+    /// Seconds since the connection was first seen
+    pub duration_secs: f64,
+}
+
+/// Filters of a `/metrics` query. Every filter given must pass.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ScrapeFilter {
+    /// Keep connections that moved at least this many bytes, sent plus
+    /// received
+    pub min_bytes: Option<u64>,
+    /// Keep connections to this destination port
+    pub dst_port: Option<u16>,
+    /// Keep connections to this destination address, as exported (masked
+    /// with `--anonymize-ips`)
+    pub dst_ip: Option<String>,
+}
+
+impl ScrapeFilter {
+    /// Parse the query string of a `/metrics` request. Unknown parameters
+    /// and unparsable values are rejected so that a typo doesn't silently
+    /// return everything.
+    ///
+    /// ```
+    /// use sidecar::scrape::ScrapeFilter;
+    ///
+    /// let filter = ScrapeFilter::parse("min_bytes=1000000&dst_port=443").unwrap();
+    /// assert_eq!(filter.min_bytes, Some(1_000_000));
+    /// assert_eq!(filter.dst_port, Some(443));
+    /// assert!(ScrapeFilter::parse("dst_prot=443").is_err());
+    /// ```
+    pub fn parse(query: &str) -> Result<Self, String> {
+        let mut filter = Self::default();
+        for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let invalid = || format!("Invalid value for {}: {:?}", name, value);
+            match name {
+                "min_bytes" => filter.min_bytes = Some(value.parse().map_err(|_| invalid())?),
+                "dst_port" => filter.dst_port = Some(value.parse().map_err(|_| invalid())?),
+                "dst_ip" if !value.is_empty() => filter.dst_ip = Some(value.to_string()),
+                "dst_ip" => return Err(invalid()),
+                _ => {
+                    return Err(format!(
+                        "Unknown filter {:?}; supported: min_bytes, dst_port, dst_ip",
+                        name
+                    ))
+                }
+            }
+        }
+        Ok(filter)
+    }
+
+    /// Whether `conn` passes every filter.
+    pub fn matches(&self, conn: &ExportedConnection) -> bool {
+        self.min_bytes.is_none_or(|min| conn.bytes >= min)
+            && self
+                .dst_port
+                .is_none_or(|port| conn.labels[2] == port.to_string())
+            && self.dst_ip.as_ref().is_none_or(|ip| conn.labels[1] == *ip)
+    }
+}
+
+/// The connections of the latest export, shared between the exporter and
+/// the metrics server. Cloning shares the view.
+#[derive(Clone, Default)]
+pub struct ConnectionView {
+    connections: Arc<Mutex<Vec<ExportedConnection>>>,
+}
+
+impl ConnectionView {
+    /// An empty view, until the first export.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replace the view with the connections of a new export.
+    pub fn replace(&self, connections: Vec<ExportedConnection>) {
+        *self.connections.lock().unwrap() = connections;
+    }
+
+    /// Label sets of the connections that pass `filter`.
+    pub fn select(&self, filter: &ScrapeFilter) -> HashSet<[String; 6]> {
+        self.connections
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|conn| filter.matches(conn))
+            .map(|conn| conn.labels.clone())
+            .collect()
+    }
+}
+
+/// One of the `--metrics-shards` metrics endpoints. Each serves the
+/// per-connection series whose labels hash to its index; the first also
+/// serves every series that isn't per connection, so that those are scraped
+/// once.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Shard {
+    /// Position of the endpoint, from 0
+    pub index: u32,
+    /// Number of endpoints
+    pub count: u32,
+}
+
+impl Shard {
+    /// The only endpoint, serving every series.
+    pub const WHOLE: Shard = Shard { index: 0, count: 1 };
+
+    /// Whether the per-connection series labelled `labels` are served by
+    /// this shard.
+    ///
+    /// The labels are hashed rather than the connection's 4-tuple: when
+    /// several connections share one label set, as with `--group-by
+    /// endpoint`, they make up a single series, which must not be served by
+    /// two shards.
+    ///
+    /// ```
+    /// use sidecar::scrape::Shard;
+    ///
+    /// let labels = ["10.0.0.2", "10.0.0.5", "443", "/usr/bin/curl", "cubic", ""]
+    ///     .map(String::from);
+    /// let shards: Vec<_> = (0..4).map(|index| Shard { index, count: 4 }).collect();
+    /// assert_eq!(shards.iter().filter(|shard| shard.owns(&labels)).count(), 1);
+    /// assert!(Shard::WHOLE.owns(&labels));
+    /// ```
+    pub fn owns(&self, labels: &[String; 6]) -> bool {
+        // FNV-1a, so a series stays on its shard across restarts and builds
+        let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+        for byte in labels.iter().flat_map(|value| value.bytes().chain([0xff])) {
+            hash = (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01b3);
+        }
+        hash % self.count as u64 == self.index as u64
+    }
+}
+
+/// What one metrics endpoint serves on `/metrics`: its shard of the series
+/// and, with `--metrics-cache-ttl`, the encoding it last served. Cloning
+/// shares the cache.
+#[derive(Clone)]
+pub struct MetricsEndpoint {
+    /// Series the endpoint serves
+    pub shard: Shard,
+    /// Longest a cached encoding is served for (None = never cached)
+    cache_ttl: Option<Duration>,
+    /// The last encoding and when it was made
+    cached: Arc<Mutex<Option<(Instant, Bytes)>>>,
+}
+
+impl MetricsEndpoint {
+    /// An endpoint serving `shard`, re-encoding at most every `cache_ttl`.
+    pub fn new(shard: Shard, cache_ttl: Option<Duration>) -> Self {
+        Self {
+            shard,
+            cache_ttl,
+            cached: Arc::default(),
+        }
+    }
+
+    /// The unfiltered scrape: the cached encoding while younger than the
+    /// TTL, else a fresh one from `encode`, which replaces it. Scrapes that
+    /// arrive while one is being encoded wait for it and get the same copy,
+    /// so a burst of scrapes encodes once.
+    ///
+    /// ```
+    /// use sidecar::scrape::{MetricsEndpoint, Shard};
+    /// use std::time::Duration;
+    ///
+    /// let endpoint = MetricsEndpoint::new(Shard::WHOLE, Some(Duration::from_secs(60)));
+    /// let mut encoded = 0;
+    /// for _ in 0..3 {
+    ///     let body = endpoint.encoded(|| {
+    ///         encoded += 1;
+    ///         b"up 1\n".to_vec()
+    ///     });
+    ///     assert_eq!(&body[..], b"up 1\n");
+    /// }
+    /// assert_eq!(encoded, 1);
+    /// ```
+    pub fn encoded(&self, encode: impl FnOnce() -> Vec<u8>) -> Bytes {
+        let Some(ttl) = self.cache_ttl else {
+            return encode().into();
+        };
+        let mut cached = self.cached.lock().unwrap();
+        if let Some((at, body)) = cached.as_ref() {
+            if at.elapsed() < ttl {
+                return body.clone();
+            }
+        }
+        let body = Bytes::from(encode());
+        *cached = Some((Instant::now(), body.clone()));
+        body
+    }
+}
+
+/// Drop the per-connection series of connections not in `keep`, and the
+/// families left without series. Families without connection labels are
+/// kept whole.
+pub fn filter_families(
+    families: Vec<MetricFamily>,
+    keep: &HashSet<[String; 6]>,
+) -> Vec<MetricFamily> {
+    retain_series(families, |labels| {
+        labels.is_none_or(|labels| keep.contains(labels))
+    })
+}
+
+/// Keep the series of `shard`, and the families left with any.
+pub fn shard_families(families: Vec<MetricFamily>, shard: Shard) -> Vec<MetricFamily> {
+    retain_series(families, |labels| match labels {
+        Some(labels) => shard.owns(labels),
+        None => shard.index == 0,
+    })
+}
+
+/// Keep the series `keep` accepts, given their connection labels (None for
+/// series without them), and drop the families left without series.
+fn retain_series(
+    families: Vec<MetricFamily>,
+    keep: impl Fn(Option<&[String; 6]>) -> bool,
+) -> Vec<MetricFamily> {
+    families
+        .into_iter()
+        .filter_map(|mut family| {
+            let metrics = family.take_metric();
+            let total = metrics.len();
+            let kept: Vec<_> = metrics
+                .into_iter()
+                .filter(|metric| {
+                    let label = |name: &str| {
+                        metric
+                            .get_label()
+                            .iter()
+                            .find(|l| l.get_name() == name)
+                            .map(|l| l.get_value().to_string())
+                    };
+                    let values = CONNECTION_LABELS.map(label);
+                    if values.iter().any(Option::is_none) {
+                        return keep(None);
+                    }
+                    keep(Some(&values.map(Option::unwrap_or_default)))
+                })
+                .collect();
+            // A family that had series and lost them all is left out, so
+            // the response doesn't list empty metrics
+            if kept.is_empty() && total > 0 {
+                return None;
+            }
+            family.set_metric(kept.into());
+            Some(family)
+        })
+        .collect()
+}
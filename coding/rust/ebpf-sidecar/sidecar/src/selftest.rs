@@ -0,0 +1,388 @@
+//! `sidecar selftest` - end-to-end check of the pipeline on this kernel.
+//!
+//! Loads the programs, sends a known number of bytes each way over a
+//! localhost connection inside this process, and checks that the kernel
+//! recorded them, that a forced retransmit is counted on that connection,
+//! and that the metrics endpoint serves well-formed Prometheus text. Each
+//! check prints PASS or FAIL; any failure makes the command exit non-zero.
+//! Everything it creates (programs, sockets, server) goes away on exit.
+
+use crate::{export_connection_metrics, run_metrics_server, GroupBy};
+use anyhow::{Context, Result};
+use sidecar::netns::NetnsResolver;
+use sidecar::scrape::{ConnectionView, MetricsEndpoint, Shard};
+use sidecar::{metrics, Connection, ConnectionSource, Monitor, MonitorOptions};
+use std::net::SocketAddr;
+use std::os::fd::AsRawFd;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time;
+
+/// Bytes sent over the test connection; small enough for a single send
+const PAYLOAD_BYTES: usize = 4096;
+
+/// Bytes the server answers with, received by the client
+const REPLY_BYTES: usize = 512;
+
+/// Bytes sent while the server drops everything, to force a retransmit
+const RETRANSMIT_PAYLOAD_BYTES: usize = 64;
+
+/// How long incoming segments are dropped; covers the 200ms minimum RTO
+/// and a backed-off second retransmit
+const RETRANSMIT_WAIT: Duration = Duration::from_millis(800);
+
+/// How long to wait for the metrics server to start listening
+const SERVER_START_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Run every check, printing a report. Fails if any check failed.
+pub async fn run() -> Result<()> {
+    let mut report = Report::default();
+
+    report.step("running as root", check_root(), String::clone);
+    let monitor = report.step("load and attach eBPF programs", load_monitor(), |_| {
+        format!("tracking pid {}", std::process::id())
+    });
+    let mut traffic = report.step(
+        "generate localhost traffic",
+        generate_traffic().await,
+        |t| {
+            format!(
+                "{} bytes, {} back, :{} -> :{}",
+                PAYLOAD_BYTES, REPLY_BYTES, t.client_port, t.server_port
+            )
+        },
+    );
+
+    if let (Some(monitor), Some(traffic)) = (&monitor, &mut traffic) {
+        let conn = report.step(
+            "connection recorded in map",
+            find_connection(monitor, traffic),
+            |c| {
+                format!(
+                    "{}:{} -> {}:{}",
+                    c.src_ip(),
+                    c.key.src_port,
+                    c.dst_ip(),
+                    c.key.dst_port
+                )
+            },
+        );
+        if let Some(conn) = conn {
+            report.step("byte count matches", check_bytes(&conn), String::clone);
+            report.step(
+                "retransmit counted on the connection",
+                check_retransmits(monitor, traffic).await,
+                String::clone,
+            );
+        }
+        report.step(
+            "metrics endpoint serves Prometheus text",
+            check_metrics(monitor, traffic).await,
+            String::clone,
+        );
+    }
+
+    report.finish()
+}
+
+/// Outcome of the checks run so far.
+#[derive(Default)]
+struct Report {
+    passed: usize,
+    failed: usize,
+}
+
+impl Report {
+    /// Print the result of one check and hand back its value if it passed.
+    fn step<T>(
+        &mut self,
+        name: &str,
+        result: Result<T>,
+        describe: impl Fn(&T) -> String,
+    ) -> Option<T> {
+        match result {
+            Ok(value) => {
+                println!("PASS  {:<42} {}", name, describe(&value));
+                self.passed += 1;
+                Some(value)
+            }
+            Err(e) => {
+                println!("FAIL  {:<42} {:#}", name, e);
+                self.failed += 1;
+                None
+            }
+        }
+    }
+
+    fn finish(self) -> Result<()> {
+        println!("{} passed, {} failed", self.passed, self.failed);
+        if self.failed > 0 {
+            anyhow::bail!("self-test failed");
+        }
+        Ok(())
+    }
+}
+
+/// The test connection, kept open until the checks are done.
+struct Traffic {
+    client: TcpStream,
+    server: TcpStream,
+    client_port: u16,
+    server_port: u16,
+}
+
+fn check_root() -> Result<String> {
+    let euid = unsafe { libc::geteuid() };
+    if euid != 0 {
+        anyhow::bail!("euid is {}; loading eBPF programs needs root", euid);
+    }
+    Ok("euid 0".to_string())
+}
+
+/// Load the programs, tracking only this process so the check is not
+/// confused by other traffic on the host.
+fn load_monitor() -> Result<Monitor> {
+    let mut monitor = Monitor::load()?;
+    monitor.configure(&MonitorOptions {
+        target_pid: std::process::id(),
+        ..Default::default()
+    })?;
+    monitor.attach()?;
+    Ok(monitor)
+}
+
+async fn generate_traffic() -> Result<Traffic> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let server_port = listener.local_addr()?.port();
+
+    let mut client = TcpStream::connect(("127.0.0.1", server_port)).await?;
+    let (mut server, _) = listener.accept().await?;
+    let client_port = client.local_addr()?.port();
+
+    client.write_all(&[0x5a; PAYLOAD_BYTES]).await?;
+    let mut received = [0; PAYLOAD_BYTES];
+    server.read_exact(&mut received).await?;
+    server.write_all(&[0xa5; REPLY_BYTES]).await?;
+    let mut reply = [0; REPLY_BYTES];
+    client.read_exact(&mut reply).await?;
+
+    Ok(Traffic {
+        client,
+        server,
+        client_port,
+        server_port,
+    })
+}
+
+/// The client side of the test connection, as recorded by the kernel.
+fn find_connection(monitor: &Monitor, traffic: &Traffic) -> Result<Connection> {
+    monitor
+        .snapshot()?
+        .into_iter()
+        .find(|c| c.key.src_port == traffic.client_port && c.key.dst_port == traffic.server_port)
+        .context("no CONNECTIONS entry for the test connection")
+}
+
+fn check_bytes(conn: &Connection) -> Result<String> {
+    let sent = conn.metrics.bytes_sent;
+    if sent != PAYLOAD_BYTES as u64 {
+        anyhow::bail!("bytes_sent is {}, expected {}", sent, PAYLOAD_BYTES);
+    }
+    let recv = conn.metrics.bytes_recv;
+    if recv != REPLY_BYTES as u64 {
+        anyhow::bail!("bytes_recv is {}, expected {}", recv, REPLY_BYTES);
+    }
+    Ok(format!("bytes_sent = {}, bytes_recv = {}", sent, recv))
+}
+
+/// Drop every segment reaching the server end for `RETRANSMIT_WAIT` so the
+/// client has to retransmit, then check the kernel counted it on the
+/// client's entry: the retransmit path must build the same key as the path
+/// that created the entry.
+async fn check_retransmits(monitor: &Monitor, traffic: &mut Traffic) -> Result<String> {
+    let before = find_connection(monitor, traffic)?.metrics.retransmits;
+
+    set_drop_filter(&traffic.server, true)?;
+    traffic
+        .client
+        .write_all(&[0x5a; RETRANSMIT_PAYLOAD_BYTES])
+        .await?;
+    time::sleep(RETRANSMIT_WAIT).await;
+    set_drop_filter(&traffic.server, false)?;
+
+    let after = find_connection(monitor, traffic)?.metrics.retransmits;
+    if after <= before {
+        anyhow::bail!(
+            "retransmits stayed at {} while segments were dropped for {:?}",
+            after,
+            RETRANSMIT_WAIT
+        );
+    }
+    Ok(format!("retransmits {} -> {}", before, after))
+}
+
+/// Attach a classic BPF socket filter dropping everything `stream`
+/// receives, or detach it.
+fn set_drop_filter(stream: &TcpStream, drop: bool) -> Result<()> {
+    let fd = stream.as_raw_fd();
+    let ret = if drop {
+        // A single "return 0": keep no bytes of any packet
+        let mut filter = [libc::sock_filter {
+            code: (libc::BPF_RET | libc::BPF_K) as u16,
+            jt: 0,
+            jf: 0,
+            k: 0,
+        }];
+        let program = libc::sock_fprog {
+            len: filter.len() as u16,
+            filter: filter.as_mut_ptr(),
+        };
+        unsafe {
+            libc::setsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                libc::SO_ATTACH_FILTER,
+                &program as *const libc::sock_fprog as *const libc::c_void,
+                std::mem::size_of::<libc::sock_fprog>() as libc::socklen_t,
+            )
+        }
+    } else {
+        unsafe {
+            libc::setsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                libc::SO_DETACH_FILTER,
+                std::ptr::null(),
+                0,
+            )
+        }
+    };
+    if ret != 0 {
+        return Err(std::io::Error::last_os_error()).context("Failed to set the socket filter");
+    }
+    Ok(())
+}
+
+/// Export one collection, scrape it over HTTP and validate the result.
+async fn check_metrics(monitor: &Monitor, traffic: &Traffic) -> Result<String> {
+    // Reserve a free port for the server; the tiny race with other binders
+    // is acceptable for a one-shot check
+    let port = std::net::TcpListener::bind("127.0.0.1:0")?
+        .local_addr()?
+        .port();
+    let addr: SocketAddr = ([127, 0, 0, 1], port).into();
+    let view = ConnectionView::new();
+    let server = run_metrics_server(
+        addr,
+        false,
+        None,
+        view.clone(),
+        None,
+        MetricsEndpoint::new(Shard::WHOLE, None),
+        Default::default(),
+    );
+    let server = tokio::spawn(server);
+
+    let collection = metrics::Collection::read(monitor)?;
+    let exported = export_connection_metrics(
+        &collection,
+        &[],
+        GroupBy::Connection,
+        &mut metrics::ExeResolver::default(),
+        &mut NetnsResolver::default(),
+        &[],
+        None,
+    );
+    view.replace(exported);
+
+    let body = scrape(addr, "/metrics").await;
+    let filtered = scrape(addr, &format!("/metrics?min_bytes={}", u64::MAX)).await;
+    server.abort();
+    let body = body?;
+
+    let samples = validate_prometheus(&body)?;
+    let series = format!("dst_port=\"{}\"", traffic.server_port);
+    let value = |name: &str| {
+        body.lines()
+            .filter(|line| line.starts_with(name) && line.contains(&series))
+            .find_map(|line| line.rsplit(' ').next()?.parse::<f64>().ok())
+    };
+    if value("sidecar_connection_bytes_sent_total{").is_none() {
+        anyhow::bail!("no sidecar_connection_bytes_sent_total series for the test connection");
+    }
+    match value("sidecar_connection_bytes_received_total{") {
+        Some(received) if received > 0.0 => {}
+        Some(_) => anyhow::bail!("sidecar_connection_bytes_received_total is 0"),
+        None => anyhow::bail!("no sidecar_connection_bytes_received_total series"),
+    }
+    if filtered?.contains("sidecar_connection_bytes_sent_total{") {
+        anyhow::bail!("a min_bytes filter no connection passes still returned connection series");
+    }
+
+    Ok(format!("{} samples", samples))
+}
+
+/// GET `path` from the metrics server, retrying until it is listening.
+async fn scrape(addr: SocketAddr, path: &str) -> Result<String> {
+    let deadline = time::Instant::now() + SERVER_START_TIMEOUT;
+    let mut stream = loop {
+        match TcpStream::connect(addr).await {
+            Ok(stream) => break stream,
+            Err(_) if time::Instant::now() < deadline => {
+                time::sleep(Duration::from_millis(50)).await
+            }
+            Err(e) => return Err(e).context("metrics server did not start"),
+        }
+    };
+
+    // HTTP/1.0 so the server closes the connection after the response
+    let request = format!("GET {} HTTP/1.0\r\nHost: localhost\r\n\r\n", path);
+    stream.write_all(request.as_bytes()).await?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await?;
+
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .context("malformed HTTP response")?;
+    let status = head.lines().next().unwrap_or_default();
+    if !status.contains(" 200 ") {
+        anyhow::bail!("unexpected response: {}", status);
+    }
+
+    Ok(body.to_string())
+}
+
+/// Check every sample line is `name{labels} value` with a numeric value.
+/// Returns the number of samples.
+fn validate_prometheus(body: &str) -> Result<usize> {
+    let mut samples = 0;
+
+    for line in body.lines() {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (series, value) = line
+            .rsplit_once(' ')
+            .with_context(|| format!("no value in line {:?}", line))?;
+        value
+            .parse::<f64>()
+            .with_context(|| format!("non-numeric value in line {:?}", line))?;
+
+        let name = series.split('{').next().unwrap_or_default();
+        let valid_name = name.chars().enumerate().all(|(i, c)| {
+            c.is_ascii_alphabetic() || c == '_' || c == ':' || (i > 0 && c.is_ascii_digit())
+        });
+        if name.is_empty() || !valid_name {
+            anyhow::bail!("invalid metric name in line {:?}", line);
+        }
+
+        samples += 1;
+    }
+
+    if samples == 0 {
+        anyhow::bail!("no samples in response");
+    }
+    Ok(samples)
+}
@@ -0,0 +1,376 @@
+//! Call stacks of the connects sampled with `--capture-stacks`, symbolized
+//! and folded into a flamegraph-style profile.
+//!
+//! The kernel stores each distinct stack once in `STACK_TRACES` and reports
+//! the ids of a connect's kernel and user stacks. Kernel addresses resolve
+//! against `/proc/kallsyms`; user addresses against the ELF symbol tables of
+//! the files `/proc/<pid>/maps` shows mapped at them, opened through
+//! `/proc/<pid>/root` so binaries in other mount namespaces resolve too.
+//! A process that exited before its event was read can't be resolved, and
+//! its user frames show as `[unknown]`.
+//!
+//! Only symbol tables are read, no debug info: stripped binaries resolve
+//! through their dynamic symbols alone, inlined functions count as their
+//! caller, and names are left mangled.
+
+use anyhow::{Context, Result};
+use aya::maps::{MapData, StackTraceMap};
+use object::{Object, ObjectSegment, ObjectSymbol, SymbolKind};
+use sidecar_common::ConnectStackEvent;
+use std::collections::{BTreeMap, HashMap};
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// Distinct stacks kept in a profile; connects with new stacks past this
+/// are counted under `OVERFLOW_STACK`
+const MAX_PROFILE_STACKS: usize = 10_000;
+
+/// Stack the connects past `MAX_PROFILE_STACKS` are counted under
+const OVERFLOW_STACK: &str = "[other]";
+
+/// ELF files whose symbols are kept; the cache starts over past this
+const MAX_CACHED_BINARIES: usize = 256;
+
+/// Frame of an address no symbol covers
+const UNKNOWN_FRAME: &str = "[unknown]";
+
+/// Frame standing in for a stack the kernel couldn't record
+const LOST_FRAME: &str = "[lost]";
+
+/// One line of `/proc/<pid>/maps` backed by a file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MapsEntry {
+    /// First address of the mapping
+    pub start: u64,
+    /// Address just past it
+    pub end: u64,
+    /// Offset in the file of `start`
+    pub offset: u64,
+    /// File mapped, as the process sees it
+    pub path: String,
+}
+
+impl MapsEntry {
+    /// Parse a line of `/proc/<pid>/maps`. None for anonymous mappings,
+    /// pseudo-files such as `[stack]` and malformed lines.
+    ///
+    /// ```
+    /// use sidecar::stacks::MapsEntry;
+    ///
+    /// let line = "7f2c1a400000-7f2c1a5b5000 r-xp 00028000 fd:01 1837 \
+    ///             /usr/lib/x86_64-linux-gnu/libc.so.6";
+    /// let entry = MapsEntry::parse(line).unwrap();
+    /// assert_eq!(entry.start, 0x7f2c1a400000);
+    /// assert_eq!(entry.offset, 0x28000);
+    /// assert_eq!(entry.path, "/usr/lib/x86_64-linux-gnu/libc.so.6");
+    ///
+    /// let stack = "7ffd5e3f1000-7ffd5e412000 rw-p 00000000 00:00 0 [stack]";
+    /// assert_eq!(MapsEntry::parse(stack), None);
+    /// assert_eq!(MapsEntry::parse("7f2c1a5b5000-7f2c1a5b9000 rw-p 00000000 00:00 0"), None);
+    /// ```
+    pub fn parse(line: &str) -> Option<Self> {
+        let mut fields = line.split_whitespace();
+        let (start, end) = fields.next()?.split_once('-')?;
+        let _perms = fields.next()?;
+        let offset = fields.next()?;
+        let _dev = fields.next()?;
+        let _inode = fields.next()?;
+        // The path is the rest of the line, spaces included
+        let path = fields.collect::<Vec<_>>().join(" ");
+        if !path.starts_with('/') {
+            return None;
+        }
+        Some(Self {
+            start: u64::from_str_radix(start, 16).ok()?,
+            end: u64::from_str_radix(end, 16).ok()?,
+            offset: u64::from_str_radix(offset, 16).ok()?,
+            path,
+        })
+    }
+}
+
+/// Fold a connect's stacks, each innermost frame first as the kernel
+/// records them, into one line of the folded format flamegraph tools read:
+/// `comm`, then the user frames, then the kernel ones (suffixed `_[k]`),
+/// outermost first and separated by `;`.
+///
+/// ```
+/// use sidecar::stacks::fold;
+///
+/// let user = ["__connect".to_string(), "main".to_string()];
+/// let kernel = ["tcp_connect".to_string(), "__sys_connect".to_string()];
+/// assert_eq!(
+///     fold("curl", &user, &kernel),
+///     "curl;main;__connect;__sys_connect_[k];tcp_connect_[k]"
+/// );
+/// ```
+pub fn fold(comm: &str, user: &[String], kernel: &[String]) -> String {
+    let mut folded = frame_name(comm);
+    for frame in user.iter().rev() {
+        folded.push(';');
+        folded.push_str(&frame_name(frame));
+    }
+    for frame in kernel.iter().rev() {
+        folded.push(';');
+        folded.push_str(&frame_name(frame));
+        folded.push_str("_[k]");
+    }
+    folded
+}
+
+/// `name` with the separator of folded stacks replaced
+fn frame_name(name: &str) -> String {
+    name.replace(';', ":")
+}
+
+/// Connect counts per folded stack, shared between the task recording them
+/// and the metrics server. Cloning shares the profile.
+#[derive(Clone, Default)]
+pub struct StackProfile {
+    stacks: Arc<Mutex<HashMap<String, u64>>>,
+}
+
+impl StackProfile {
+    /// An empty profile.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Count a connect with the folded stack `stack` (see [`fold`]).
+    pub fn record(&self, stack: String) {
+        let mut stacks = self.stacks.lock().unwrap();
+        let stack = if stacks.len() >= MAX_PROFILE_STACKS && !stacks.contains_key(&stack) {
+            OVERFLOW_STACK.to_string()
+        } else {
+            stack
+        };
+        *stacks.entry(stack).or_insert(0) += 1;
+    }
+
+    /// The profile in folded format, one `stack count` line per stack, the
+    /// most frequent first.
+    ///
+    /// ```
+    /// use sidecar::stacks::StackProfile;
+    ///
+    /// let profile = StackProfile::new();
+    /// profile.record("curl;main".to_string());
+    /// profile.record("nginx;accept".to_string());
+    /// profile.record("curl;main".to_string());
+    /// assert_eq!(profile.folded(), "curl;main 2\nnginx;accept 1\n");
+    /// ```
+    pub fn folded(&self) -> String {
+        let stacks = self.stacks.lock().unwrap();
+        let mut sorted: Vec<_> = stacks.iter().collect();
+        sorted.sort_by(|a, b| b.1.cmp(a.1).then_with(|| a.0.cmp(b.0)));
+        sorted
+            .into_iter()
+            .map(|(stack, count)| format!("{} {}\n", stack, count))
+            .collect()
+    }
+}
+
+/// Function symbols of one ELF file, and where its segments are loaded.
+struct ElfSymbols {
+    /// (file offset, size, virtual address) of each segment
+    segments: Vec<(u64, u64, u64)>,
+    /// (address, size, name) of each function, by address
+    functions: Vec<(u64, u64, String)>,
+}
+
+impl ElfSymbols {
+    /// Read the function symbols, static and dynamic, of the ELF file at
+    /// `path`.
+    fn read(path: &Path) -> Result<Self> {
+        let data =
+            std::fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+        let file = object::File::parse(&*data)
+            .with_context(|| format!("Failed to parse {}", path.display()))?;
+        let segments = file
+            .segments()
+            .map(|segment| {
+                let (offset, size) = segment.file_range();
+                (offset, size, segment.address())
+            })
+            .collect();
+        let mut functions: Vec<_> = file
+            .symbols()
+            .chain(file.dynamic_symbols())
+            .filter(|symbol| symbol.kind() == SymbolKind::Text && symbol.address() != 0)
+            .filter_map(|symbol| {
+                let name = symbol.name().ok()?;
+                Some((symbol.address(), symbol.size(), name.to_string()))
+            })
+            .collect();
+        functions.sort();
+        functions.dedup_by_key(|(address, _, _)| *address);
+        Ok(Self {
+            segments,
+            functions,
+        })
+    }
+
+    /// Name of the function at `offset` in the file.
+    fn resolve(&self, offset: u64) -> Option<&str> {
+        let address = self
+            .segments
+            .iter()
+            .find(|(start, size, _)| (*start..start + size).contains(&offset))
+            .map(|(start, _, address)| address + (offset - start))?;
+        let i = self
+            .functions
+            .partition_point(|(start, _, _)| *start <= address);
+        let (start, size, name) = self.functions.get(i.checked_sub(1)?)?;
+        // Symbols without a size extend to the next one
+        (*size == 0 || address < start + size).then_some(name.as_str())
+    }
+}
+
+/// Turns the stack ids of [`ConnectStackEvent`]s into folded stacks in a
+/// [`StackProfile`].
+pub struct StackAttributor {
+    traces: StackTraceMap<MapData>,
+    profile: StackProfile,
+    /// Kernel symbols by address; empty if `/proc/kallsyms` can't be read
+    kernel_symbols: BTreeMap<u64, String>,
+    /// Resolved kernel stacks by id. Kernel stacks are never deleted, and
+    /// the kernel drops colliding stacks rather than replacing them, so an
+    /// id keeps its stack.
+    kernel_stacks: HashMap<u32, Vec<String>>,
+    /// Symbols of the binaries seen, by device and inode; None for files
+    /// that aren't ELF or can't be read
+    binaries: HashMap<(u64, u64), Option<Arc<ElfSymbols>>>,
+}
+
+impl StackAttributor {
+    /// Read stacks from `traces`, the map [`crate::Monitor::connect_stacks`]
+    /// returns, into `profile`.
+    pub fn new(traces: StackTraceMap<MapData>, profile: StackProfile) -> Self {
+        let kernel_symbols = aya::util::kernel_symbols().unwrap_or_else(|e| {
+            log::warn!(
+                "Failed to read /proc/kallsyms, kernel frames stay unknown: {}",
+                e
+            );
+            BTreeMap::new()
+        });
+        Self {
+            traces,
+            profile,
+            kernel_symbols,
+            kernel_stacks: HashMap::new(),
+            binaries: HashMap::new(),
+        }
+    }
+
+    /// Count `event`'s connect in the profile. Returns false if either of
+    /// its stacks is lost, whether the kernel couldn't record it or it was
+    /// gone from the map; the connect is still counted, under `[lost]`.
+    /// Reads `/proc` and binaries synchronously, so call it off the async
+    /// runtime.
+    pub fn record(&mut self, event: &ConnectStackEvent) -> bool {
+        let kernel = self.kernel_stack(event.kernel_stack_id);
+        let user = self
+            .take_user_stack(event.user_stack_id)
+            .map(|ips| self.user_frames(event.pid, &ips));
+        let complete = kernel.is_some() && user.is_some();
+        let lost = || vec![LOST_FRAME.to_string()];
+        let comm = String::from_utf8_lossy(&event.comm);
+        let folded = fold(
+            comm.trim_end_matches('\0'),
+            &user.unwrap_or_else(lost),
+            &kernel.unwrap_or_else(lost),
+        );
+        self.profile.record(folded);
+        complete
+    }
+
+    /// The instruction pointers of stack `id`, innermost first.
+    fn stack(&self, id: i32) -> Option<Vec<u64>> {
+        let id = u32::try_from(id).ok()?;
+        match self.traces.get(&id, 0) {
+            Ok(stack) => Some(stack.frames().iter().map(|frame| frame.ip).collect()),
+            Err(e) => {
+                log::debug!("Failed to read stack {}: {}", id, e);
+                None
+            }
+        }
+    }
+
+    /// The instruction pointers of user stack `id`, innermost first,
+    /// deleting it from the map. User stacks are rarely seen twice (each
+    /// process has its own addresses), so deleting them keeps the map from
+    /// filling up; a connect with the same stack read after is lost.
+    fn take_user_stack(&mut self, id: i32) -> Option<Vec<u64>> {
+        let ips = self.stack(id)?;
+        if let Err(e) = self.traces.remove(&(id as u32)) {
+            log::debug!("Failed to delete stack {}: {}", id, e);
+        }
+        Some(ips)
+    }
+
+    /// The functions of kernel stack `id`, innermost first.
+    fn kernel_stack(&mut self, id: i32) -> Option<Vec<String>> {
+        if let Some(frames) = u32::try_from(id)
+            .ok()
+            .and_then(|id| self.kernel_stacks.get(&id))
+        {
+            return Some(frames.clone());
+        }
+        let frames: Vec<_> = self
+            .stack(id)?
+            .into_iter()
+            .map(|ip| match self.kernel_symbols.range(..=ip).next_back() {
+                Some((_, name)) => name.clone(),
+                None => UNKNOWN_FRAME.to_string(),
+            })
+            .collect();
+        self.kernel_stacks.insert(id as u32, frames.clone());
+        Some(frames)
+    }
+
+    /// The functions at `ips` in process `pid`, innermost first.
+    fn user_frames(&mut self, pid: u32, ips: &[u64]) -> Vec<String> {
+        let maps: Vec<_> = std::fs::read_to_string(format!("/proc/{}/maps", pid))
+            .map(|maps| maps.lines().filter_map(MapsEntry::parse).collect())
+            .unwrap_or_default();
+        ips.iter()
+            .enumerate()
+            .map(|(i, &ip)| {
+                // Past the leaf, frames hold return addresses, which may
+                // already belong to the next function
+                let ip = if i == 0 { ip } else { ip.saturating_sub(1) };
+                maps.iter()
+                    .find(|entry| (entry.start..entry.end).contains(&ip))
+                    .and_then(|entry| self.resolve_user(pid, entry, ip))
+                    .unwrap_or_else(|| UNKNOWN_FRAME.to_string())
+            })
+            .collect()
+    }
+
+    /// The function at `ip`, within `entry` of process `pid`'s maps.
+    fn resolve_user(&mut self, pid: u32, entry: &MapsEntry, ip: u64) -> Option<String> {
+        let path = format!("/proc/{}/root{}", pid, entry.path);
+        let metadata = std::fs::metadata(&path).ok()?;
+        if self.binaries.len() >= MAX_CACHED_BINARIES {
+            self.binaries.clear();
+        }
+        let symbols = self
+            .binaries
+            .entry((metadata.dev(), metadata.ino()))
+            .or_insert_with(|| match ElfSymbols::read(Path::new(&path)) {
+                Ok(symbols) => Some(Arc::new(symbols)),
+                Err(e) => {
+                    log::debug!("{:#}", e);
+                    None
+                }
+            })
+            .clone()?;
+        let name = symbols.resolve(ip - entry.start + entry.offset);
+        Some(match name {
+            Some(name) => name.to_string(),
+            // Better than nothing: which binary the frame is in
+            None => format!("[{}]", entry.path.rsplit('/').next().unwrap_or(&entry.path)),
+        })
+    }
+}
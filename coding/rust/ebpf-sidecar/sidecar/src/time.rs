@@ -0,0 +1,95 @@
+//! Converting kernel timestamps to wall-clock time.
+//!
+//! The eBPF programs stamp events with `bpf_ktime_get_ns()`, nanoseconds of
+//! `CLOCK_MONOTONIC` (time since boot, not counting suspend). To turn those
+//! into wall-clock times, the offset between `CLOCK_REALTIME` and
+//! `CLOCK_MONOTONIC` is taken once, at startup, and added to every stamp.
+//!
+//! # Limitations
+//! - Suspend: the monotonic clock stops while the host is suspended but the
+//!   wall clock does not, so every suspend after startup makes converted
+//!   times early by the suspend's length.
+//! - Wall-clock steps: NTP steps or a manual `date` after startup are not
+//!   picked up; gradual NTP slewing is, within the same bound.
+//!
+//! Both only matter for long-running sidecars; restarting takes a fresh
+//! offset.
+
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The offset taken at startup, see `init`.
+static STARTUP_OFFSET: OnceLock<ClockOffset> = OnceLock::new();
+
+/// Wall-clock time minus monotonic time, in nanoseconds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClockOffset {
+    realtime_minus_monotonic_ns: i128,
+}
+
+impl ClockOffset {
+    /// Sample both clocks now.
+    pub fn now() -> Self {
+        let monotonic = clock_ns(libc::CLOCK_MONOTONIC);
+        let realtime = clock_ns(libc::CLOCK_REALTIME);
+        Self::from_nanos(realtime - monotonic)
+    }
+
+    /// A fixed offset, e.g. to replay recorded events against the offset of
+    /// the host they were recorded on.
+    pub fn from_nanos(realtime_minus_monotonic_ns: i128) -> Self {
+        Self {
+            realtime_minus_monotonic_ns,
+        }
+    }
+
+    /// Wall-clock time of a `bpf_ktime_get_ns()` stamp. Times that would fall
+    /// before the Unix epoch are clamped to it.
+    ///
+    /// ```
+    /// use sidecar::time::ClockOffset;
+    /// use std::time::{Duration, UNIX_EPOCH};
+    ///
+    /// // Booted at 1_700_000_000s wall-clock time
+    /// let offset = ClockOffset::from_nanos(1_700_000_000_000_000_000);
+    /// assert_eq!(
+    ///     offset.to_epoch(2_500_000_000),
+    ///     UNIX_EPOCH + Duration::new(1_700_000_002, 500_000_000)
+    /// );
+    /// // A stamp that would land before the epoch is clamped to it
+    /// assert_eq!(ClockOffset::from_nanos(-5_000_000_000).to_epoch(1), UNIX_EPOCH);
+    /// ```
+    pub fn to_epoch(&self, monotonic_ns: u64) -> SystemTime {
+        let epoch_ns = (monotonic_ns as i128 + self.realtime_minus_monotonic_ns).max(0);
+        let secs = (epoch_ns / 1_000_000_000) as u64;
+        let nanos = (epoch_ns % 1_000_000_000) as u32;
+        UNIX_EPOCH + Duration::new(secs, nanos)
+    }
+}
+
+/// Take the startup offset. Call early in `main`; otherwise it is taken on
+/// the first conversion.
+pub fn init() {
+    STARTUP_OFFSET.get_or_init(ClockOffset::now);
+}
+
+/// Wall-clock time of a `bpf_ktime_get_ns()` stamp, using the offset taken at
+/// startup.
+pub fn boot_ns_to_epoch(ns: u64) -> SystemTime {
+    STARTUP_OFFSET.get_or_init(ClockOffset::now).to_epoch(ns)
+}
+
+/// Current `CLOCK_MONOTONIC` time, comparable with kernel timestamps.
+pub fn monotonic_ns() -> u64 {
+    clock_ns(libc::CLOCK_MONOTONIC) as u64
+}
+
+fn clock_ns(clock: libc::clockid_t) -> i128 {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    // Cannot fail for these clock ids with a valid pointer
+    unsafe { libc::clock_gettime(clock, &mut ts) };
+    ts.tv_sec as i128 * 1_000_000_000 + ts.tv_nsec as i128
+}
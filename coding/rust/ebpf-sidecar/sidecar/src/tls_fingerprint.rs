@@ -0,0 +1,312 @@
+//! JA4 fingerprints of TLS ClientHellos.
+//!
+//! The kernel copies the start of each ClientHello the TLS parser sees
+//! (`TlsHelloEvent`, at most `TLS_HELLO_MAX` bytes); the fields a JA4
+//! fingerprint is made of are read from it here. A fingerprint identifies
+//! the TLS library and configuration a client uses, whatever the
+//! destination, e.g. `t13d1516h2_8daaf6152771_b186095e22b6`:
+//!
+//! - `t` for TCP, the TLS version, `d` if the hello names a server (SNI)
+//!   or `i` if not, the number of cipher suites and of extensions, and the
+//!   first and last character of the first ALPN protocol
+//! - the first 12 hex digits of the SHA-256 of the sorted cipher suites
+//! - the same of the sorted extensions, SNI and ALPN left out, followed by
+//!   the signature algorithms in the order sent
+//!
+//! GREASE values (RFC 8701) are ignored throughout. Only hellos that fit in
+//! the copied bytes are fingerprinted: one cut short by the bound, spread
+//! over several TLS records or sends, or split across iovecs can't be read
+//! to its end, and a fingerprint of part of it would be wrong.
+
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+
+/// Distinct fingerprints exported as their own series; later ones are
+/// counted together as `other`.
+pub const MAX_FINGERPRINTS: usize = 1_000;
+
+const CONTENT_HANDSHAKE: u8 = 0x16;
+const HANDSHAKE_CLIENT_HELLO: u8 = 1;
+
+const EXT_SERVER_NAME: u16 = 0x0000;
+const EXT_SIGNATURE_ALGORITHMS: u16 = 0x000d;
+const EXT_ALPN: u16 = 0x0010;
+const EXT_SUPPORTED_VERSIONS: u16 = 0x002b;
+
+/// The fields of a ClientHello a JA4 fingerprint is made of, GREASE values
+/// included.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClientHello {
+    /// `legacy_version` of the hello
+    pub version: u16,
+    /// Cipher suites, in the order offered
+    pub ciphers: Vec<u16>,
+    /// Extension types, in the order sent
+    pub extensions: Vec<u16>,
+    /// The first protocol of the ALPN extension
+    pub alpn: Option<Vec<u8>>,
+    /// Versions of the `supported_versions` extension
+    pub supported_versions: Vec<u16>,
+    /// Schemes of the `signature_algorithms` extension, in the order sent
+    pub signature_algorithms: Vec<u16>,
+}
+
+impl ClientHello {
+    /// Read the ClientHello starting at a TLS record header, or `None` if
+    /// `data` doesn't hold a whole one.
+    ///
+    /// ```
+    /// use sidecar::tls_fingerprint::ClientHello;
+    ///
+    /// let body: Vec<u8> = [
+    ///     &[0x03, 0x03][..],                // legacy_version: TLS 1.2
+    ///     &[0; 32],                         // random
+    ///     &[0],                             // no session id
+    ///     &[0, 4, 0x13, 0x01, 0x0a, 0x0a],  // a cipher suite and GREASE
+    ///     &[1, 0],                          // null compression
+    ///     &[0, 20],                         // extensions:
+    ///     &[0x00, 0x2b, 0, 3, 2, 0x03, 0x04], //   supported_versions: 1.3
+    ///     &[0x00, 0x10, 0, 5, 0, 3, 2, b'h', b'2'], // ALPN: h2
+    ///     &[0x00, 0x17, 0, 0],              //   extended_master_secret
+    /// ]
+    /// .concat();
+    /// let mut hello = vec![0x16, 0x03, 0x01, 0, body.len() as u8 + 4];
+    /// hello.extend([1, 0, 0, body.len() as u8]);
+    /// hello.extend(&body);
+    ///
+    /// let parsed = ClientHello::parse(&hello).unwrap();
+    /// assert_eq!(parsed.ciphers, [0x1301, 0x0a0a]);
+    /// assert_eq!(parsed.alpn.as_deref(), Some(&b"h2"[..]));
+    /// assert_eq!(parsed.ja4(), "t13i0103h2_0f2cb44170f4_5ea61440e398");
+    ///
+    /// // Cut short
+    /// assert_eq!(ClientHello::parse(&hello[..hello.len() - 1]), None);
+    /// ```
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        let mut header = Reader::new(data);
+        if header.u8()? != CONTENT_HANDSHAKE {
+            return None;
+        }
+        header.u16()?; // record version
+        let length = header.u16()? as usize;
+        let mut record = Reader::new(header.bytes(length)?);
+        if record.u8()? != HANDSHAKE_CLIENT_HELLO {
+            return None;
+        }
+        let length = u32::from_be_bytes([0, record.u8()?, record.u8()?, record.u8()?]);
+        let mut body = Reader::new(record.bytes(length as usize)?);
+
+        let mut hello = ClientHello {
+            version: body.u16()?,
+            ..Default::default()
+        };
+        body.bytes(32)?; // random
+        let session_id = body.u8()? as usize;
+        body.bytes(session_id)?;
+        let ciphers = body.u16()? as usize;
+        hello.ciphers = Reader::new(body.bytes(ciphers)?).u16s()?;
+        let compression = body.u8()? as usize;
+        body.bytes(compression)?;
+        if body.is_empty() {
+            // No extensions at all
+            return Some(hello);
+        }
+
+        let extensions = body.u16()? as usize;
+        let mut extensions = Reader::new(body.bytes(extensions)?);
+        while !extensions.is_empty() {
+            let kind = extensions.u16()?;
+            let length = extensions.u16()? as usize;
+            let mut ext = Reader::new(extensions.bytes(length)?);
+            hello.extensions.push(kind);
+            match kind {
+                EXT_ALPN => {
+                    let list = ext.u16()? as usize;
+                    let mut list = Reader::new(ext.bytes(list)?);
+                    if !list.is_empty() {
+                        let first = list.u8()? as usize;
+                        hello.alpn = Some(list.bytes(first)?.to_vec());
+                    }
+                }
+                EXT_SUPPORTED_VERSIONS => {
+                    let list = ext.u8()? as usize;
+                    hello.supported_versions = Reader::new(ext.bytes(list)?).u16s()?;
+                }
+                EXT_SIGNATURE_ALGORITHMS => {
+                    let list = ext.u16()? as usize;
+                    hello.signature_algorithms = Reader::new(ext.bytes(list)?).u16s()?;
+                }
+                _ => {}
+            }
+        }
+        Some(hello)
+    }
+
+    /// The hello's JA4 fingerprint, as a TCP client's (`t`).
+    pub fn ja4(&self) -> String {
+        let ciphers = without_grease(&self.ciphers);
+        let extensions = without_grease(&self.extensions);
+
+        let version = without_grease(&self.supported_versions)
+            .into_iter()
+            .max()
+            .unwrap_or(self.version);
+        let sni = if extensions.contains(&EXT_SERVER_NAME) {
+            'd'
+        } else {
+            'i'
+        };
+        let prefix = format!(
+            "t{}{}{:02}{:02}{}",
+            version_code(version),
+            sni,
+            ciphers.len().min(99),
+            extensions.len().min(99),
+            alpn_code(self.alpn.as_deref().unwrap_or_default())
+        );
+
+        let mut sorted_ciphers = ciphers;
+        sorted_ciphers.sort_unstable();
+        let mut sorted_extensions: Vec<u16> = extensions
+            .into_iter()
+            .filter(|&e| e != EXT_SERVER_NAME && e != EXT_ALPN)
+            .collect();
+        sorted_extensions.sort_unstable();
+        let mut extension_list = hex_list(&sorted_extensions);
+        let algorithms = without_grease(&self.signature_algorithms);
+        if !algorithms.is_empty() {
+            extension_list.push('_');
+            extension_list.push_str(&hex_list(&algorithms));
+        }
+
+        format!(
+            "{}_{}_{}",
+            prefix,
+            truncated_hash(&hex_list(&sorted_ciphers), sorted_ciphers.is_empty()),
+            truncated_hash(&extension_list, sorted_extensions.is_empty())
+        )
+    }
+}
+
+/// Labels for `sidecar_tls_fingerprints_total`: each fingerprint as
+/// itself, up to `MAX_FINGERPRINTS` of them, then `other`.
+#[derive(Debug, Default)]
+pub struct FingerprintLabels {
+    seen: HashSet<String>,
+}
+
+impl FingerprintLabels {
+    /// The label to count `fingerprint` under.
+    pub fn label(&mut self, fingerprint: String) -> String {
+        if self.seen.contains(&fingerprint) {
+            return fingerprint;
+        }
+        if self.seen.len() >= MAX_FINGERPRINTS {
+            return "other".to_string();
+        }
+        self.seen.insert(fingerprint.clone());
+        fingerprint
+    }
+}
+
+/// Whether `value` is a GREASE value: 0x0a0a, 0x1a1a, ... 0xfafa.
+fn is_grease(value: u16) -> bool {
+    value & 0x0f0f == 0x0a0a && value >> 8 == value & 0xff
+}
+
+fn without_grease(values: &[u16]) -> Vec<u16> {
+    values.iter().copied().filter(|&v| !is_grease(v)).collect()
+}
+
+/// JA4's two characters for a TLS version.
+fn version_code(version: u16) -> &'static str {
+    match version {
+        0x0304 => "13",
+        0x0303 => "12",
+        0x0302 => "11",
+        0x0301 => "10",
+        0x0300 => "s3",
+        0x0002 => "s2",
+        0xfeff => "d1",
+        0xfefd => "d2",
+        0xfefc => "d3",
+        _ => "00",
+    }
+}
+
+/// First and last character of an ALPN protocol; of its hex digits if
+/// either isn't alphanumeric, `00` for none.
+fn alpn_code(alpn: &[u8]) -> String {
+    match (alpn.first(), alpn.last()) {
+        (Some(&first), Some(&last))
+            if first.is_ascii_alphanumeric() && last.is_ascii_alphanumeric() =>
+        {
+            format!("{}{}", first as char, last as char)
+        }
+        (Some(&first), Some(&last)) => {
+            let first = format!("{:02x}", first);
+            let last = format!("{:02x}", last);
+            format!("{}{}", &first[..1], &last[1..])
+        }
+        _ => "00".to_string(),
+    }
+}
+
+/// `values` as comma-separated 4-digit hex.
+fn hex_list(values: &[u16]) -> String {
+    values
+        .iter()
+        .map(|v| format!("{:04x}", v))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// First 12 hex digits of the SHA-256 of `list`, or zeros for an empty one.
+fn truncated_hash(list: &str, empty: bool) -> String {
+    if empty {
+        return "000000000000".to_string();
+    }
+    let digest = Sha256::digest(list.as_bytes());
+    digest[..6].iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Big-endian reads from a byte slice, `None` past its end.
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        if len > self.data.len() {
+            return None;
+        }
+        let (head, rest) = self.data.split_at(len);
+        self.data = rest;
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.bytes(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.bytes(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    /// The rest as a list of u16s.
+    fn u16s(&mut self) -> Option<Vec<u16>> {
+        let mut values = Vec::with_capacity(self.data.len() / 2);
+        while !self.is_empty() {
+            values.push(self.u16()?);
+        }
+        Some(values)
+    }
+}
@@ -63,9 +63,15 @@ fn main() -> Result<()> {
 fn build_ebpf(release: bool) -> Result<()> {
     println!("🔧 Building eBPF programs...");
 
+    // The arch whose calling convention kprobes read arguments with; set
+    // CARGO_CFG_BPF_TARGET_ARCH to build probes for another machine
+    let arch = std::env::var("CARGO_CFG_BPF_TARGET_ARCH")
+        .unwrap_or_else(|_| std::env::consts::ARCH.to_string());
+    println!("   target arch: {}", arch);
+
     let mut cmd = Command::new("cargo");
     cmd.current_dir("sidecar-ebpf")
-        .env("CARGO_CFG_BPF_TARGET_ARCH", std::env::consts::ARCH)
+        .env("CARGO_CFG_BPF_TARGET_ARCH", &arch)
         .args([
             "+nightly",
             "build",